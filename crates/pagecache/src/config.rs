@@ -16,6 +16,27 @@ use bincode::{deserialize, serialize, Infinite};
 use super::*;
 use io::LogReader;
 
+// What `Config::verify_cipher` seals to check a cipher's key.
+const KEY_CHECK: &[u8] = b"pagecache key check";
+
+// What the configuration file starts with, followed by the
+// `FORMAT_VERSION` of the system it was written for.
+const FORMAT_MAGIC: &[u8] = b"pagecache format";
+
+// The version of the layout of everything a system writes: the
+// headers of log messages, and the pages stored in them, including
+// the nodes of sled's trees. Bump it whenever one of them changes,
+// so that a system written in another layout is refused when it's
+// opened instead of being misread.
+//
+// Version 1 is every system written before the version was
+// recorded, whose configuration file has no header. Version 2
+// checksums each log message and writes base nodes in a compact
+// layout, so version 1 systems can't be read, and are refused.
+// There is no migration: their data has to be exported by the
+// version that wrote them and imported into a new system.
+const FORMAT_VERSION: u32 = 2;
+
 impl Deref for Config {
     type Target = ConfigBuilder;
     fn deref(&self) -> &Self::Target {
@@ -73,6 +94,8 @@ pub struct ConfigBuilder {
     #[doc(hidden)]
     pub segment_mode: SegmentMode,
     #[doc(hidden)]
+    pub io_backend: IoBackend,
+    #[doc(hidden)]
     pub snapshot_after_ops: usize,
     #[doc(hidden)]
     pub snapshot_path: Option<PathBuf>,
@@ -90,6 +113,74 @@ pub struct ConfigBuilder {
     pub zstd_compression_factor: i32,
     #[doc(hidden)]
     pub merge_operator: Option<usize>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub merge_operators: MergeOperatorRegistry,
+    #[doc(hidden)]
+    pub merge_operator_name: Option<String>,
+    #[doc(hidden)]
+    pub splitting_merge_operator: Option<usize>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub splitting_merge_operators: Registry<SplittingMergeFn>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub migrators: Registry<MigrateFn>,
+    #[doc(hidden)]
+    pub max_value_len: Option<usize>,
+    #[doc(hidden)]
+    pub intern_values: bool,
+    #[doc(hidden)]
+    pub max_leaf_len: Option<usize>,
+    #[doc(hidden)]
+    pub max_key_len: Option<usize>,
+    #[doc(hidden)]
+    pub key_set: bool,
+    #[doc(hidden)]
+    pub int_keys: bool,
+    #[doc(hidden)]
+    pub log_frag_conflicts: bool,
+    #[doc(hidden)]
+    pub log_apply_timings: bool,
+    #[doc(hidden)]
+    pub block_compress_values: bool,
+    #[doc(hidden)]
+    pub leaf_bloom_filters: bool,
+    #[doc(hidden)]
+    pub split_ratio: f32,
+    #[doc(hidden)]
+    pub compress_values_above: Option<usize>,
+    #[doc(hidden)]
+    pub blob_threshold: Option<usize>,
+    #[doc(hidden)]
+    pub max_node_size: Option<usize>,
+    #[doc(hidden)]
+    pub merge_threshold: Option<usize>,
+    #[doc(hidden)]
+    pub read_ahead: usize,
+    #[doc(hidden)]
+    pub expire_every_ms: Option<u64>,
+    #[doc(hidden)]
+    pub compaction_threshold: f64,
+    #[doc(hidden)]
+    pub compact_every_ms: Option<u64>,
+    #[doc(hidden)]
+    pub max_unflushed_bytes: Option<usize>,
+    #[doc(hidden)]
+    pub max_resident_bytes: Option<usize>,
+    #[doc(hidden)]
+    pub metrics_every_ms: Option<u64>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub storage: Option<SharedStorage>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub cipher: Option<SharedCipher>,
+    #[doc(hidden)]
+    pub encrypted: bool,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub metrics_sink: Option<SharedMetricsSink>,
 }
 
 unsafe impl Send for ConfigBuilder {}
@@ -142,7 +233,39 @@ impl Default for ConfigBuilder {
             tmp_path: PathBuf::from(tmp_path),
             temporary: false,
             segment_mode: SegmentMode::Gc,
+            io_backend: IoBackend::Buffered,
             merge_operator: None,
+            merge_operators: MergeOperatorRegistry::default(),
+            merge_operator_name: None,
+            splitting_merge_operator: None,
+            splitting_merge_operators: Registry::default(),
+            migrators: Registry::default(),
+            max_value_len: None,
+            intern_values: false,
+            max_leaf_len: None,
+            max_key_len: None,
+            key_set: false,
+            int_keys: false,
+            log_frag_conflicts: false,
+            log_apply_timings: false,
+            block_compress_values: false,
+            leaf_bloom_filters: false,
+            split_ratio: 0.5,
+            compress_values_above: None,
+            blob_threshold: None,
+            max_node_size: None,
+            merge_threshold: None,
+            read_ahead: 2,
+            expire_every_ms: Some(1000),
+            compaction_threshold: 0.5,
+            compact_every_ms: None,
+            max_unflushed_bytes: None,
+            max_resident_bytes: None,
+            metrics_every_ms: Some(1000),
+            storage: None,
+            cipher: None,
+            encrypted: false,
+            metrics_sink: None,
         }
     }
 }
@@ -190,11 +313,101 @@ impl ConfigBuilder {
 
     /// Set the merge operator that can be relied on during merges in
     /// the `PageCache`.
-    pub fn merge_operator(
+    pub fn merge_operator(self, mo: MergeOperator) -> ConfigBuilder {
+        self.merge_fn(mo)
+    }
+
+    /// Set the merge operator to a closure, which unlike a plain
+    /// `MergeOperator` may capture state. It is added to
+    /// `merge_operators`, and `merge_operator` is set to its id.
+    pub fn merge_fn<F>(mut self, f: F) -> ConfigBuilder
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let id = self.merge_operators.register(Box::new(f));
+        self.merge_operator = Some(id);
+        self
+    }
+
+    /// Names the merge operator, which is recorded with the
+    /// database. Once a name has been recorded, the database may
+    /// only be opened again with a merge operator of the same name,
+    /// which catches reopening it with the wrong operator.
+    pub fn merge_operator_name<S: Into<String>>(
         mut self,
-        mo: MergeOperator,
+        name: S,
     ) -> ConfigBuilder {
-        self.merge_operator = Some(mo as usize);
+        self.merge_operator_name = Some(name.into());
+        self
+    }
+
+    /// Set a merge operator that can ask for the node holding the
+    /// merged value to be split immediately. Used in place of
+    /// `merge_operator`, so only one of the two may be set. Like
+    /// `merge_fn`, it may be a closure that captures state.
+    pub fn splitting_merge_operator<F>(
+        mut self,
+        f: F,
+    ) -> ConfigBuilder
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8])
+                -> (Option<Vec<u8>>, bool)
+            + Send
+            + Sync
+            + 'static,
+    {
+        let registry = &self.splitting_merge_operators;
+        let id = registry.register(Box::new(f));
+        self.splitting_merge_operator = Some(id);
+        self
+    }
+
+    /// Registers a migrator for `Frag::Migrate` frags to refer to by
+    /// id. Ids are assigned in registration order, starting at 0,
+    /// so migrators must be registered in the same order every time
+    /// the system is opened.
+    pub fn migrator<F>(self, migrator: F) -> ConfigBuilder
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.migrators.register(Box::new(migrator));
+        self
+    }
+
+    /// Keeps the log in `storage` instead of a file under the
+    /// database's path. Clones of the builder share it.
+    pub fn storage<S>(mut self, storage: S) -> ConfigBuilder
+    where
+        S: Storage + 'static,
+    {
+        self.storage = Some(SharedStorage(Arc::new(storage)));
+        self
+    }
+
+    /// Encrypts the log and snapshots with `cipher`. A database
+    /// created with a cipher must always be opened with one that
+    /// can open what it sealed, and one created without a cipher
+    /// can't be given one later.
+    pub fn encryption<C>(mut self, cipher: C) -> ConfigBuilder
+    where
+        C: Cipher + 'static,
+    {
+        self.cipher = Some(SharedCipher(Arc::new(cipher)));
+        self.encrypted = true;
+        self
+    }
+
+    /// Reports the process-wide `MetricsSnapshot` to `sink` every
+    /// `metrics_every_ms` while the database is open. Clones of
+    /// the builder share it.
+    pub fn metrics_sink<S>(mut self, sink: S) -> ConfigBuilder
+    where
+        S: MetricsSink + 'static,
+    {
+        self.metrics_sink = Some(SharedMetricsSink(Arc::new(sink)));
         self
     }
 
@@ -214,22 +427,45 @@ impl ConfigBuilder {
         (io_buf_size, get_io_buf_size, set_io_buf_size, usize, "size of each io flush buffer. MUST be multiple of 512!"),
         (min_items_per_segment, get_min_items_per_segment, set_min_items_per_segment, usize, "minimum data chunks/pages in a segment."),
         (blink_fanout, get_blink_fanout, set_blink_fanout, u8, "b-link node fanout, minimum of 2"),
-        (page_consolidation_threshold, get_page_consolidation_threshold, set_page_consolidation_threshold, usize, "page consolidation threshold"),
-        (temporary, get_temporary, set_temporary, bool, "if this database should be removed after the ConfigBuilder is dropped"),
-        (read_only, get_read_only, set_read_only, bool, "whether to run in read-only mode"),
+        (page_consolidation_threshold, get_page_consolidation_threshold, set_page_consolidation_threshold, usize, "the longest chain of fragments a page may have. A page whose chain grows past this is consolidated into a single fragment by the write that lengthened it, or by the next read of it, so hot pages can't build up chains that every read has to replay"),
+        (temporary, get_temporary, set_temporary, bool, "if this database should be removed after the ConfigBuilder is dropped, and skip fsyncing its log"),
+        (read_only, get_read_only, set_read_only, bool, "whether to open an existing database without writing to any of its files, serving reads from what recovery found in the log. Writes are refused with Error::Unsupported"),
         (cache_bits, get_cache_bits, set_cache_bits, usize, "log base 2 of the number of cache shards"),
-        (cache_capacity, get_cache_capacity, set_cache_capacity, usize, "maximum size for the system page cache"),
+        (cache_capacity, get_cache_capacity, set_cache_capacity, usize, "maximum number of bytes of pages to keep in memory, weighed by their serialized size. Past it, the least recently used pages are paged out, to be read back from the log when next needed"),
         (use_os_cache, get_use_os_cache, set_use_os_cache, bool, "whether to use the OS page cache"),
-        (use_compression, get_use_compression, set_use_compression, bool, "whether to use zstd compression"),
+        (use_compression, get_use_compression, set_use_compression, bool, "whether to compress each serialized page fragment with zstd before it is written to the log, and decompress it when the page is read back in. Only takes effect when the zstd feature is enabled, and can't be changed once a database has been created"),
         (zstd_compression_factor, get_zstd_compression_factor, set_zstd_compression_factor, i32, "the compression factor to use with zstd compression"),
-        (flush_every_ms, get_flush_every_ms, set_flush_every_ms, Option<u64>, "number of ms between IO buffer flushes"),
-        (snapshot_after_ops, get_snapshot_after_ops, set_snapshot_after_ops, usize, "number of operations between page table snapshots"),
+        (flush_every_ms, get_flush_every_ms, set_flush_every_ms, Option<u64>, "number of ms between flushes of the log's IO buffers by a background thread, which bounds how many recent writes a crash can lose. None only writes buffers out when they fill or are flushed explicitly"),
+        (snapshot_after_ops, get_snapshot_after_ops, set_snapshot_after_ops, usize, "number of operations between page table snapshots. Recovery starts from the latest snapshot and only replays the log written after it"),
         (cache_fixup_threshold, get_cache_fixup_threshold, set_cache_fixup_threshold, usize, "the maximum length of a cached page fragment chain"),
         (segment_cleanup_threshold, get_segment_cleanup_threshold, set_segment_cleanup_threshold, f64, "the proportion of remaining valid pages in the segment"),
         (min_free_segments, get_min_free_segments, set_min_free_segments, usize, "the minimum number of free segments to have on-deck before a compaction occurs"),
         (zero_copy_storage, get_zero_copy_storage, set_zero_copy_storage, bool, "disabling of the log segment copy cleaner"),
         (segment_mode, get_segment_mode, set_segment_mode, SegmentMode, "the file segment selection mode"),
-        (snapshot_path, get_snapshot_path, set_snapshot_path, Option<PathBuf>, "snapshot file location")
+        (io_backend, get_io_backend, set_io_backend, IoBackend, "how the log file is written. IoBackend::Direct bypasses the OS page cache, and has a background thread share one sync between concurrent flushes. It needs the direct_io feature on Linux, and can't be used with a temporary database or a custom storage. Unlike most options, it may change between opens"),
+        (snapshot_path, get_snapshot_path, set_snapshot_path, Option<PathBuf>, "snapshot file location"),
+        (max_value_len, get_max_value_len, set_max_value_len, Option<usize>, "values longer than this are truncated when written to a tree, as are merged values longer than this. Each set value that is cut short is counted in MetricsSnapshot::values_truncated. Can't be changed once a database has been created"),
+        (intern_values, get_intern_values, set_intern_values, bool, "whether tree leaves store each distinct value once in a per-node dictionary"),
+        (max_leaf_len, get_max_leaf_len, set_max_leaf_len, Option<usize>, "hard cap on the number of records in a tree leaf, which is split before a write would exceed it"),
+        (max_key_len, get_max_key_len, set_max_key_len, Option<usize>, "maximum length of a tree key, above which writes are refused"),
+        (key_set, get_key_set, set_key_set, bool, "whether tree leaves store only keys, discarding values, for use as an ordered set"),
+        (int_keys, get_int_keys, set_int_keys, bool, "whether tree leaves delta-encode 8 byte big-endian integer keys when serialized"),
+        (log_frag_conflicts, get_log_frag_conflicts, set_log_frag_conflicts, bool, "whether tree consolidation logs every pair of frags in a chain that write the same key"),
+        (log_apply_timings, get_log_apply_timings, set_log_apply_timings, bool, "whether tree consolidation logs the time it spent applying each kind of frag"),
+        (block_compress_values, get_block_compress_values, set_block_compress_values, bool, "whether tree leaves write all of their values as one block, compressed with zstd when that feature is enabled. Has no effect when use_compression already compresses everything written to the log"),
+        (leaf_bloom_filters, get_leaf_bloom_filters, set_leaf_bloom_filters, bool, "whether tree leaves keep a Bloom filter over their keys in memory, so lookups of absent keys can usually skip searching the leaf"),
+        (split_ratio, get_split_ratio, set_split_ratio, f32, "how far through a tree node's records it is split, where 0.5 splits evenly and values near 1.0 keep the left node nearly full for append-mostly workloads"),
+        (compress_values_above, get_compress_values_above, set_compress_values_above, Option<usize>, "values at least this long are compressed one at a time with zstd when tree leaves are written, using zstd_compression_factor"),
+        (blob_threshold, get_blob_threshold, set_blob_threshold, Option<usize>, "values at least this long are written to a page of their own when set in a tree, and tree leaves only store that page's id on disk, so consolidating, splitting or paging in a leaf doesn't rewrite them. The page is freed once the value is overwritten or removed. Only plain tree leaves support it"),
+        (max_node_size, get_max_node_size, set_max_node_size, Option<usize>, "tree nodes whose keys and values take up more than this many bytes are split, evenly by bytes, in addition to the blink_fanout limit on their number of entries"),
+        (merge_threshold, get_merge_threshold, set_merge_threshold, Option<usize>, "tree nodes left with fewer than this many entries by a delete are merged with a sibling, and a root left with a single child index node is collapsed into it"),
+        (read_ahead, get_read_ahead, set_read_ahead, usize, "number of leaves following the one an iterator has just moved on to that a background thread pages in, so long scans read the log while that leaf is being consumed. Point reads never read ahead, and 0 turns it off"),
+        (expire_every_ms, get_expire_every_ms, set_expire_every_ms, Option<u64>, "number of ms between sweeps that remove a tree's expired keys, which start once a key is written with a TTL. None leaves expired keys in place, hidden from reads, until Tree::sweep_expired is called"),
+        (compaction_threshold, get_compaction_threshold, set_compaction_threshold, f64, "segments of the log in which at most this proportion of the pages written there are still live have them moved out by a compaction, so the segment can be reused"),
+        (compact_every_ms, get_compact_every_ms, set_compact_every_ms, Option<u64>, "number of ms between background compactions of a Db's log, using compaction_threshold. None only compacts when Db::compact is called"),
+        (max_unflushed_bytes, get_max_unflushed_bytes, set_max_unflushed_bytes, Option<usize>, "writes to pages wait while more than this many bytes written to the log aren't durable yet, until enough of it has been flushed, so writers can't get further ahead of the disk than this. None only waits once the IO buffers are full"),
+        (max_resident_bytes, get_max_resident_bytes, set_max_resident_bytes, Option<usize>, "writes to pages wait while more than this many bytes of pages are resident in memory, until the least recently used of them are flushed and paged out, so a burst of writes can't hold more than this in memory. None leaves it to cache_capacity, which is only enforced as pages are read"),
+        (metrics_every_ms, get_metrics_every_ms, set_metrics_every_ms, Option<u64>, "number of ms between reports to the metrics_sink, if one is set. None never reports")
     );
 }
 
@@ -238,7 +474,7 @@ impl ConfigBuilder {
 #[derive(Debug)]
 pub struct Config {
     inner: Arc<ConfigBuilder>,
-    file: Arc<AtomicPtr<Arc<fs::File>>>,
+    file: Arc<AtomicPtr<Arc<dyn Storage>>>,
     build_locker: Arc<Mutex<()>>,
     refs: Arc<AtomicUsize>,
 }
@@ -262,10 +498,10 @@ impl Drop for Config {
     fn drop(&mut self) {
         // if our ref count is 0 we can drop and close our file properly.
         if self.refs.fetch_sub(1, Ordering::Relaxed) == 0 {
-            let f_ptr: *mut Arc<fs::File> = self.file
+            let f_ptr: *mut Arc<dyn Storage> = self.file
                 .swap(std::ptr::null_mut(), Ordering::Relaxed);
             if !f_ptr.is_null() {
-                let f: Box<Arc<fs::File>> =
+                let f: Box<Arc<dyn Storage>> =
                     unsafe { Box::from_raw(f_ptr) };
                 drop(f);
             }
@@ -284,13 +520,50 @@ impl Drop for Config {
     }
 }
 
+// Takes an advisory lock on the log file, which is held for as long
+// as the file stays open, so that a second writer opening the same
+// database fails at once rather than interleaving its writes with
+// ours and destroying the log. Read-only opens don't take it.
+#[cfg(unix)]
+fn lock_exclusively(
+    file: &fs::File,
+    path: &Path,
+) -> CacheResult<(), ()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        Err(Error::Unsupported(format!(
+            "the database at {:?} is already open for writing, \
+             by another process or elsewhere in this one",
+            path.parent().unwrap_or(path)
+        )))
+    } else {
+        Err(err.into())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusively(
+    _file: &fs::File,
+    _path: &Path,
+) -> CacheResult<(), ()> {
+    Ok(())
+}
+
 impl Config {
     // Retrieve a thread-local file handle to the
     // configured underlying storage,
     // or create a new one if this is the first time the
     // thread is accessing it.
     #[doc(hidden)]
-    pub fn file(&self) -> CacheResult<Arc<fs::File>, ()> {
+    pub fn file(&self) -> CacheResult<Arc<dyn Storage>, ()> {
         if self.file.load(Ordering::Relaxed).is_null() {
             let _lock = self.build_locker.lock().unwrap();
             if self.file.load(Ordering::Relaxed).is_null() {
@@ -360,12 +633,36 @@ impl Config {
         let snap_dir = Path::new(&abs_prefix).parent().unwrap();
 
         if !snap_dir.exists() {
+            if self.inner.read_only {
+                return Ok(vec![]);
+            }
             std::fs::create_dir_all(snap_dir)?;
         }
 
         Ok(snap_dir.read_dir()?.filter_map(filter).collect())
     }
 
+    // returns the bytes taken up by the files of this system: the
+    // log, unless another Storage holds it, the configuration and
+    // the snapshots
+    #[doc(hidden)]
+    pub fn size_on_disk(&self) -> std::io::Result<u64> {
+        let mut paths = self.get_snapshot_files()?;
+        paths.push(self.db_path());
+        paths.push(self.conf_path());
+
+        let mut size = 0;
+        for path in paths {
+            match fs::metadata(&path) {
+                Ok(metadata) => size += metadata.len(),
+                Err(ref e)
+                    if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(size)
+    }
+
     fn initialize(&self) -> CacheResult<(), ()> {
         // only validate, setup directory, and open file once
         self.validate()?;
@@ -383,6 +680,18 @@ impl Config {
             Some(dir) => dir,
         };
 
+        // a read-only open must not create anything, so there has
+        // to be a database there already
+        if self.inner.read_only
+            && self.inner.storage.is_none()
+            && !path.exists()
+        {
+            return Err(Error::Unsupported(format!(
+                "no database at {:?} to open read-only",
+                dir
+            )));
+        }
+
         // create data directory if it doesn't exist yet
         if dir != Path::new("") {
             if dir.is_file() {
@@ -404,18 +713,51 @@ impl Config {
         }
 
         self.verify_conf_changes_ok()?;
+        self.verify_cipher()?;
+
+        if let Some(ref storage) = self.inner.storage {
+            let storage = storage.0.clone();
+            let storage_ptr = Box::into_raw(Box::new(storage));
+            self.file.store(storage_ptr, Ordering::SeqCst);
+            return Ok(());
+        }
 
         // open the data file
         let mut options = fs::OpenOptions::new();
-        options.create(true);
         options.read(true);
-        options.write(true);
+        if !self.inner.read_only {
+            options.create(true);
+            options.write(true);
+        }
+        #[cfg(all(feature = "direct_io", target_os = "linux"))]
+        {
+            if self.inner.io_backend == IoBackend::Direct {
+                io::bypass_cache(&mut options);
+            }
+        }
 
         match options.open(&path) {
             Ok(file) => {
+                if !self.inner.read_only {
+                    lock_exclusively(&file, &path)?;
+                }
+
+                #[cfg(all(feature = "direct_io", target_os = "linux"))]
+                let file: Arc<dyn Storage> = match self.inner.io_backend
+                {
+                    IoBackend::Direct => {
+                        Arc::new(io::DirectStorage::start(file))
+                    }
+                    IoBackend::Buffered => Arc::new(file),
+                };
+                #[cfg(not(all(
+                    feature = "direct_io",
+                    target_os = "linux"
+                )))]
+                let file: Arc<dyn Storage> = Arc::new(file);
+
                 // turn file into a raw pointer for future use
-                let file_ptr =
-                    Box::into_raw(Box::new(Arc::new(file)));
+                let file_ptr = Box::into_raw(Box::new(file));
                 self.file.store(file_ptr, Ordering::SeqCst);
             }
             Err(e) => {
@@ -451,6 +793,54 @@ impl Config {
             self.inner.blink_fanout >= 2,
             "tree nodes must have at least 2 children"
         );
+        supported!(
+            self.inner.max_leaf_len.map_or(true, |cap| cap >= 3),
+            "max_leaf_len must allow at least 3 records to split"
+        );
+        supported!(
+            self.inner.max_node_size.map_or(true, |max| max > 0),
+            "max_node_size must be nonzero"
+        );
+        supported!(
+            self.inner.merge_threshold.map_or(true, |min| {
+                min <= self.inner.blink_fanout as usize / 2
+            }),
+            "merge_threshold must be at most half of blink_fanout"
+        );
+        supported!(
+            self.inner
+                .max_unflushed_bytes
+                .map_or(true, |max| max > 0),
+            "max_unflushed_bytes must be nonzero"
+        );
+        supported!(
+            self.inner.max_resident_bytes.map_or(true, |max| max > 0),
+            "max_resident_bytes must be nonzero"
+        );
+        supported!(
+            self.inner.read_ahead <= 16,
+            "read_ahead need not be more than 16 leaves"
+        );
+        supported!(
+            self.inner.split_ratio >= 0.
+                && self.inner.split_ratio <= 1.,
+            "split_ratio must be between 0.0 and 1.0"
+        );
+        supported!(
+            self.inner.blob_threshold.is_none()
+                || !(self.inner.key_set
+                    || self.inner.intern_values
+                    || self.inner.int_keys
+                    || self.inner.block_compress_values
+                    || self.inner.compress_values_above.is_some()),
+            "blob_threshold only applies to plain tree leaves"
+        );
+        supported!(
+            self.inner.merge_operator.is_none()
+                || self.inner.splitting_merge_operator.is_none(),
+            "only one of merge_operator and \
+             splitting_merge_operator may be set"
+        );
         supported!(self.inner.page_consolidation_threshold >= 1, "must consolidate pages after a non-zero number of updates");
         supported!(self.inner.page_consolidation_threshold < 1 << 20, "must consolidate pages after fewer than 1 million updates");
         supported!(
@@ -468,6 +858,34 @@ impl Config {
             self.inner.segment_cleanup_threshold >= 0.01,
             "segment_cleanup_threshold must be >= 1%"
         );
+        supported!(
+            self.inner.compaction_threshold > 0.0
+                && self.inner.compaction_threshold <= 1.0,
+            "compaction_threshold must be above 0 and at most 1"
+        );
+        supported!(
+            self.inner.io_backend == IoBackend::Buffered
+                || cfg!(all(feature = "direct_io", target_os = "linux")),
+            "IoBackend::Direct needs the direct_io feature, on Linux"
+        );
+        supported!(
+            self.inner.io_backend == IoBackend::Buffered
+                || (self.inner.storage.is_none()
+                    && !self.inner.temporary),
+            "IoBackend::Direct only applies to a log file on disk, \
+             not to a custom storage or a temporary database"
+        );
+        supported!(
+            self.inner.io_backend == IoBackend::Buffered
+                || self.inner.io_buf_size % 4096 == 0,
+            "IoBackend::Direct needs an io_buf_size that is a \
+             multiple of 4096, so that segments start on a block"
+        );
+        supported!(
+            !(self.inner.read_only && self.inner.temporary),
+            "a temporary database starts out empty, so it can't \
+             be opened read-only"
+        );
         supported!(
             self.inner.zstd_compression_factor >= 1,
             "compression factor must be >= 0"
@@ -484,14 +902,66 @@ impl Config {
             Ok(Some(mut old)) => {
                 let old_tmp = old.tmp_path;
                 old.tmp_path = self.inner.tmp_path.clone();
-                if old.merge_operator.is_some() {
-                    supported!(self.inner.merge_operator.is_some(),
+                if old.merge_operator.is_some()
+                    || old.splitting_merge_operator.is_some()
+                {
+                    supported!(self.inner.merge_operator.is_some()
+                        || self.inner.splitting_merge_operator.is_some(),
                         "this system was previously opened with a \
                         merge operator. must supply one FOREVER after \
                         choosing to do so once, BWAHAHAHAHAHAHA!!!!");
                 }
 
+                supported!(
+                    !old.encrypted || self.inner.encrypted,
+                    "this system was created with encryption, and \
+                     must be opened with a cipher"
+                );
+                supported!(
+                    old.encrypted || !self.inner.encrypted,
+                    "this system was created without encryption, \
+                     and can't be opened with a cipher"
+                );
+
+                // merged values are truncated each time a page is
+                // read back, so a different limit would change them
+                supported!(
+                    old.max_value_len == self.inner.max_value_len,
+                    format!(
+                        "this system was created with a max_value_len \
+                         of {:?}, and must be opened with it again",
+                        old.max_value_len
+                    )
+                );
+
+                if let Some(ref name) = old.merge_operator_name {
+                    supported!(
+                        self.inner.merge_operator_name.as_ref()
+                            == Some(name),
+                        format!(
+                            "this system was previously opened \
+                             with the merge operator {:?}, and must \
+                             be opened with it again",
+                            name
+                        )
+                    );
+                }
+
                 old.merge_operator = self.inner.merge_operator;
+                old.merge_operators =
+                    self.inner.merge_operators.clone();
+                old.merge_operator_name =
+                    self.inner.merge_operator_name.clone();
+                old.migrators = self.inner.migrators.clone();
+                old.splitting_merge_operator =
+                    self.inner.splitting_merge_operator;
+                old.splitting_merge_operators =
+                    self.inner.splitting_merge_operators.clone();
+                old.storage = self.inner.storage.clone();
+                old.cipher = self.inner.cipher.clone();
+                old.metrics_sink = self.inner.metrics_sink.clone();
+                old.read_only = self.inner.read_only;
+                old.io_backend = self.inner.io_backend;
 
                 supported!(
                     &*self.inner == &old,
@@ -506,15 +976,17 @@ impl Config {
                 old.tmp_path = old_tmp;
                 Ok(())
             }
+            Ok(None) if self.inner.read_only => Ok(()),
             Ok(None) => self.write_config().map_err(|e| e.into()),
             Err(e) => Err(e.into()),
         }
     }
 
     fn write_config(&self) -> CacheResult<(), ()> {
-        let bytes = serialize(&*self.inner, Infinite).unwrap();
-        let crc64: [u8; 8] =
-            unsafe { std::mem::transmute(crc64(&*bytes)) };
+        let mut bytes = FORMAT_MAGIC.to_vec();
+        bytes.extend(serialize(&FORMAT_VERSION, Infinite).unwrap());
+        bytes.extend(serialize(&*self.inner, Infinite).unwrap());
+        let crc64: [u8; 8] = crc64(&*bytes).to_ne_bytes();
 
         let path = self.conf_path();
 
@@ -532,7 +1004,7 @@ impl Config {
         Ok(())
     }
 
-    fn read_config(&self) -> std::io::Result<Option<ConfigBuilder>> {
+    fn read_config(&self) -> CacheResult<Option<ConfigBuilder>, ()> {
         let path = self.conf_path();
 
         let f_res =
@@ -545,7 +1017,7 @@ impl Config {
                 return Ok(None);
             }
             Err(other) => {
-                return Err(other);
+                return Err(other.into());
             }
             Ok(f) => f,
         };
@@ -563,8 +1035,7 @@ impl Config {
         let mut crc_expected_bytes = [0u8; 8];
         f.seek(std::io::SeekFrom::End(-8)).unwrap();
         f.read_exact(&mut crc_expected_bytes).unwrap();
-        let crc_expected: u64 =
-            unsafe { std::mem::transmute(crc_expected_bytes) };
+        let crc_expected = u64::from_ne_bytes(crc_expected_bytes);
 
         let crc_actual = crc64(&*buf);
 
@@ -572,7 +1043,91 @@ impl Config {
             warn!("crc for settings file {:?} failed! can't verify that config is safe", path);
         }
 
-        Ok(deserialize::<ConfigBuilder>(&*buf).ok())
+        // systems written before the format was versioned have no
+        // header at all, and are version 1
+        let version_at = FORMAT_MAGIC.len();
+        let version = if buf.starts_with(FORMAT_MAGIC) {
+            deserialize::<u32>(&buf[version_at..]).ok()
+        } else {
+            Some(1)
+        };
+        match version {
+            Some(FORMAT_VERSION) => {}
+            Some(v) if v > FORMAT_VERSION => {
+                return Err(Error::Unsupported(format!(
+                    "this system was written in on-disk format \
+                     version {}, by a newer version of pagecache \
+                     than this one, which reads version {}",
+                    v, FORMAT_VERSION
+                )));
+            }
+            Some(v) => {
+                return Err(Error::Unsupported(format!(
+                    "this system was written in on-disk format \
+                     version {}, but this version of pagecache only \
+                     reads version {}. Its data has to be exported \
+                     by the version that wrote it and imported into \
+                     a new system",
+                    v, FORMAT_VERSION
+                )));
+            }
+            None => {
+                return Err(Error::Unsupported(
+                    "the on-disk format version of this system \
+                     can't be read"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        let config_at = version_at + 4;
+        Ok(deserialize::<ConfigBuilder>(&buf[config_at..]).ok())
+    }
+
+    // Checks that the configured cipher can open what this system
+    // was sealed with, so that the log isn't mistaken for a torn
+    // one when the key is wrong. The first time the system is
+    // opened, this seals a known value for later opens to check.
+    fn verify_cipher(&self) -> CacheResult<(), ()> {
+        let cipher = match self.inner.cipher {
+            Some(ref cipher) => cipher,
+            None => return Ok(()),
+        };
+        let path = self.key_check_path();
+        match fs::read(&path) {
+            Ok(sealed) => {
+                supported!(
+                    cipher.0.open(&sealed).as_ref().map(|p| &**p)
+                        == Some(KEY_CHECK),
+                    "the provided cipher can't decrypt this system"
+                );
+                Ok(())
+            }
+            Err(ref e)
+                if e.kind() == std::io::ErrorKind::NotFound
+                    && self.inner.read_only =>
+            {
+                Ok(())
+            }
+            Err(ref e)
+                if e.kind() == std::io::ErrorKind::NotFound =>
+            {
+                let mut f = fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .open(&path)?;
+                f.write_all(&cipher.0.seal(KEY_CHECK))?;
+                f.sync_all()?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn key_check_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("keycheck");
+        path
     }
 
     fn db_path(&self) -> PathBuf {
@@ -628,11 +1183,7 @@ impl Config {
                 k
             );
             for (lsn, lid) in v.iter() {
-                f.read_message(
-                    lid,
-                    self.io_buf_size,
-                    self.use_compression
-                ).unwrap()
+                f.read_message(lid, self).unwrap()
                 .expect(&*format!("could not read log data for pid {} at lsn {} lid {}", k, lsn, lid));
             }
         }
@@ -648,11 +1199,7 @@ impl Config {
                 k
             );
             for (lsn, lid) in v.iter() {
-                f.read_message(
-                    lid,
-                    self.io_buf_size,
-                    self.use_compression
-                ).unwrap()
+                f.read_message(lid, self).unwrap()
                 .expect(&*format!("could not read log data for pid {} at lsn {} lid {}", k, lsn, lid));
             }
         }
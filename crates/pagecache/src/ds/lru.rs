@@ -46,6 +46,93 @@ impl Lru {
 
         rel_ids
     }
+
+    /// Called when a page is written to, with the size of what was
+    /// written, which the page now holds in memory on top of what
+    /// it held before. A page replaced by what was written holds
+    /// only that. Never evicts anything, see `shrink_to`.
+    pub fn wrote(&self, pid: PageID, sz: usize, replaced: bool) {
+        let shard_idx = pid % self.shards.len();
+        let rel_idx = pid / self.shards.len();
+        let mut shard = self.shards[shard_idx].lock().expect(
+            "Lru was poisoned by a \
+             thread that panicked \
+             inside a critical section",
+        );
+        shard.track(rel_idx, |old| {
+            if replaced {
+                sz
+            } else {
+                old + sz
+            }
+        });
+    }
+
+    /// Called when a page is read without its size changing, to
+    /// mark it as the most recently used. Never evicts anything.
+    pub fn touched(&self, pid: PageID) {
+        let shard_idx = pid % self.shards.len();
+        let rel_idx = pid / self.shards.len();
+        let mut shard = self.shards[shard_idx].lock().expect(
+            "Lru was poisoned by a \
+             thread that panicked \
+             inside a critical section",
+        );
+        shard.touched(rel_idx);
+    }
+
+    /// Stops tracking pages until no more than `max` bytes of them
+    /// are resident, returning the pages to try to page-out. The
+    /// least recently used pages of one shard are taken before
+    /// those of the next, so a page may go before one that was used
+    /// less recently in another shard. A page that is about to be
+    /// written to can be kept, so that paging it out can't make the
+    /// write fail.
+    pub fn shrink_to(
+        &self,
+        max: usize,
+        keep: Option<PageID>,
+    ) -> Vec<PageID> {
+        let mut over = self.resident_bytes().saturating_sub(max);
+        let mut to_evict = vec![];
+        for (shard_idx, shard_mu) in self.shards.iter().enumerate() {
+            if over == 0 {
+                break;
+            }
+            let mut shard = shard_mu.lock().expect(
+                "Lru was poisoned by a \
+                 thread that panicked \
+                 inside a critical section",
+            );
+            let kept = keep
+                .filter(|pid| pid % self.shards.len() == shard_idx)
+                .map(|pid| pid / self.shards.len());
+            let kept_sz = kept
+                .and_then(|rel_idx| shard.entries.get(rel_idx))
+                .map_or(0, |entry| entry.sz);
+            let before = shard.sz;
+            let capacity = before.saturating_sub(over);
+            for rel_id in shard.evict_over(capacity, 0) {
+                if Some(rel_id) == kept {
+                    shard.track(rel_id, |_| kept_sz);
+                } else {
+                    to_evict
+                        .push(rel_id * self.shards.len() + shard_idx);
+                }
+            }
+            over = over.saturating_sub(before - shard.sz);
+        }
+        to_evict
+    }
+
+    /// The total size of every page that is being tracked as
+    /// resident.
+    pub fn resident_bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().sz)
+            .sum()
+    }
 }
 
 #[derive(Clone)]
@@ -87,28 +174,46 @@ impl Shard {
         rel_idx: PageID,
         sz: usize,
     ) -> Vec<PageID> {
+        self.track(rel_idx, |_| sz);
+        let capacity = self.capacity;
+        // don't evict what we just added
+        self.evict_over(capacity, 1)
+    }
+
+    // Marks a page as the most recently used, tracking it if it
+    // wasn't, and sets its size from the size it had.
+    fn track<F>(&mut self, rel_idx: PageID, size: F)
+    where
+        F: FnOnce(usize) -> usize,
+    {
         if self.entries.len() <= rel_idx {
             self.entries.resize(rel_idx + 1, Entry::default());
         }
 
-        {
-            let entry = &mut self.entries[rel_idx];
+        let entry = &mut self.entries[rel_idx];
 
-            self.sz -= entry.sz;
-            entry.sz = sz;
-            self.sz += sz;
+        self.sz -= entry.sz;
+        entry.sz = size(entry.sz);
+        self.sz += entry.sz;
 
-            if entry.ptr.is_null() {
-                entry.ptr = self.list.push_head(rel_idx);
-            } else {
-                entry.ptr = self.list.promote(entry.ptr);
-            }
+        if entry.ptr.is_null() {
+            entry.ptr = self.list.push_head(rel_idx);
+        } else {
+            entry.ptr = self.list.promote(entry.ptr);
         }
+    }
 
+    // Stops tracking the least recently used pages until they take
+    // up no more than `capacity`, or only `keep` pages are left,
+    // returning them.
+    fn evict_over(
+        &mut self,
+        capacity: usize,
+        keep: usize,
+    ) -> Vec<PageID> {
         let mut to_evict = vec![];
-        while self.sz > self.capacity {
-            if self.list.len() == 1 {
-                // don't evict what we just added
+        while self.sz > capacity {
+            if self.list.len() <= keep {
                 break;
             }
 
@@ -123,4 +228,14 @@ impl Shard {
 
         to_evict
     }
+
+    fn touched(&mut self, rel_idx: PageID) {
+        // a page that has been evicted stays untracked until it
+        // is next paged in with its size
+        if let Some(entry) = self.entries.get_mut(rel_idx) {
+            if !entry.ptr.is_null() {
+                entry.ptr = self.list.promote(entry.ptr);
+            }
+        }
+    }
 }
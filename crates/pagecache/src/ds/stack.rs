@@ -1,8 +1,11 @@
 // lock-free stack
 use std::fmt::{self, Debug};
+use std::mem::ManuallyDrop;
 use std::ops::Deref;
 use std::ptr;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::Arc;
 
 use epoch::{pin, unprotected, Atomic, Guard, Owned, Shared};
 
@@ -13,6 +16,8 @@ use debug_delay;
 pub struct Node<T: Send + 'static> {
     inner: T,
     next: Atomic<Node<T>>,
+    // for an entry pushed by `cap_pending`, set once it's linked
+    linked: Option<Arc<AtomicBool>>,
 }
 
 impl<T: Send + 'static> Drop for Node<T> {
@@ -27,8 +32,16 @@ impl<T: Send + 'static> Drop for Node<T> {
     }
 }
 
+// The tag of a head pushed by `cap_pending`.
+const PENDING: usize = 1;
+
 /// A simple lock-free stack, with the ability to atomically
-/// append or entirely swap-out entries.
+/// append or entirely swap-out entries. An entry can also be
+/// pushed as pending, after which it is left out of what the stack
+/// holds, and nothing else can be pushed onto it or swapped in for
+/// it, until the flag it was pushed with is set or it is reverted.
+/// Entries pushed onto several stacks with one flag become part of
+/// them all at once.
 pub struct Stack<T: Send + 'static> {
     head: Atomic<Node<T>>,
 }
@@ -95,6 +108,7 @@ impl<T: Send + 'static> Stack<T> {
         let node = Owned::new(Node {
             inner: inner,
             next: Atomic::null(),
+            linked: None,
         });
 
         unsafe {
@@ -132,11 +146,11 @@ impl<T: Send + 'static> Stack<T> {
                     {
                         Ok(_) => unsafe {
                             guard.defer(move || {
-                                head.into_owned();
+                                free_popped(head.into_owned())
                             });
                             return Some(ptr::read(&h.inner));
                         },
-                        Err(h) => head = h.current,
+                        Err(h) => head = committed(h.current, &guard),
                     }
                 }
                 None => return None,
@@ -155,6 +169,7 @@ impl<T: Send + 'static> Stack<T> {
         let node = Owned::new(Node {
             inner: new,
             next: Atomic::null(),
+            linked: None,
         });
 
         node.next.store(old, SeqCst);
@@ -169,12 +184,71 @@ impl<T: Send + 'static> Stack<T> {
                     node.deref().next.store(Shared::null(), SeqCst);
                     guard.defer(move || node.into_owned());
                 }
-                Err(e.current)
+                Err(committed(e.current, guard))
             }
             Ok(_) => Ok(node),
         }
     }
 
+    /// compare and push an entry that stays pending until `linked`
+    /// is set, or until `revert` is called with the head this
+    /// returns
+    pub fn cap_pending<'g>(
+        &self,
+        old: Shared<Node<T>>,
+        new: T,
+        linked: &Arc<AtomicBool>,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, Node<T>>, Shared<'g, Node<T>>> {
+        debug_delay();
+        let node = Owned::new(Node {
+            inner: new,
+            next: Atomic::null(),
+            linked: Some(linked.clone()),
+        });
+
+        node.next.store(old, SeqCst);
+
+        let node = node.into_shared(guard).with_tag(PENDING);
+
+        let res = self.head.compare_and_set(old, node, SeqCst, guard);
+
+        match res {
+            Err(e) => {
+                unsafe {
+                    node.deref().next.store(Shared::null(), SeqCst);
+                    let node = node.with_tag(0);
+                    guard.defer(move || node.into_owned());
+                }
+                Err(committed(e.current, guard))
+            }
+            Ok(_) => Ok(node),
+        }
+    }
+
+    /// pop a pending head whose flag hasn't been set, leaving the
+    /// stack as it was before
+    pub fn revert<'g>(
+        &self,
+        pending: Shared<'g, Node<T>>,
+        guard: &'g Guard,
+    ) {
+        debug_delay();
+        let old = committed(pending, guard);
+        self.head
+            .compare_and_set(pending, old, SeqCst, guard)
+            .expect("nothing else can replace a pending head");
+        unsafe {
+            // readers may still be following the reverted node
+            // to the ones below it, which stay in the stack
+            let node = pending.with_tag(0);
+            guard.defer(move || {
+                let node = node.into_owned();
+                node.next.store(Shared::null(), SeqCst);
+            });
+        }
+    }
+
     /// attempt consolidation
     pub fn cas<'g>(
         &self,
@@ -197,15 +271,44 @@ impl<T: Send + 'static> Stack<T> {
                     unsafe { guard.defer(move || new.into_owned()) };
                 }
 
-                Err(e.current)
+                Err(committed(e.current, guard))
             }
         }
     }
 
     /// Returns the current head pointer of the stack, which can
-    /// later be used as the key for cas and cap operations.
+    /// later be used as the key for cas and cap operations. A
+    /// pending head is skipped, so the key is the one below it,
+    /// which fails to match until it is linked or reverted.
     pub fn head<'g>(&self, guard: &'g Guard) -> Shared<'g, Node<T>> {
-        self.head.load(SeqCst, guard)
+        committed(self.head.load(SeqCst, guard), guard)
+    }
+}
+
+// Frees a node popped off of a stack, whose `inner` was already
+// read out of it, leaving the entries below it alone.
+unsafe fn free_popped<T: Send + 'static>(node: Owned<Node<T>>) {
+    node.next.store(Shared::null(), SeqCst);
+    let node = Box::into_raw(node.into_box());
+    ptr::drop_in_place(&mut (*node).linked);
+    drop(Box::from_raw(node as *mut ManuallyDrop<Node<T>>));
+}
+
+// The entry below `head` if `head` is pending and not linked yet,
+// or `head` itself.
+fn committed<'g, T: Send + 'static>(
+    head: Shared<'g, Node<T>>,
+    guard: &'g Guard,
+) -> Shared<'g, Node<T>> {
+    if head.tag() != PENDING {
+        return head;
+    }
+    let node = unsafe { head.deref() };
+    match node.linked {
+        Some(ref linked) if !linked.load(SeqCst) => {
+            node.next.load(SeqCst, guard)
+        }
+        _ => head,
     }
 }
 
@@ -266,6 +369,7 @@ where
         let node = Owned::new(Node {
             inner: item,
             next: Atomic::null(),
+            linked: None,
         });
 
         if let Some(last) = last {
@@ -280,7 +384,6 @@ where
 
 #[test]
 fn basic_functionality() {
-    use std::sync::Arc;
     use std::thread;
 
     let ll = Arc::new(Stack::default());
@@ -309,3 +412,27 @@ fn basic_functionality() {
     });
     t.join().unwrap();
 }
+
+#[test]
+fn pending_heads() {
+    let guard = pin();
+    let stack = Stack::default();
+    stack.push(1);
+    let one = stack.head(&guard);
+
+    // a pending head is left out, and can't be pushed onto
+    let linked = Arc::new(AtomicBool::new(false));
+    let pending = stack.cap_pending(one, 2, &linked, &guard).unwrap();
+    assert_eq!(stack.head(&guard), one);
+    assert_eq!(stack.cap(one, 3, &guard), Err(one));
+    stack.revert(pending, &guard);
+    assert_eq!(stack.head(&guard), one);
+
+    let two = stack.cap_pending(one, 2, &linked, &guard).unwrap();
+    linked.store(true, SeqCst);
+    assert_eq!(stack.head(&guard), two);
+    assert!(stack.cap(two, 3, &guard).is_ok());
+    let items: Vec<_> =
+        StackIter::from_ptr(stack.head(&guard), &guard).collect();
+    assert_eq!(items, vec![&3, &2, &1]);
+}
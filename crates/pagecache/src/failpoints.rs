@@ -0,0 +1,71 @@
+//! Fault injection for crash-testing applications, enabled by the
+//! `failpoints` feature. Failures are injected at named points in
+//! the log's writes and fsyncs, segment rotation, snapshotting and
+//! startup. The points are shared by the whole process, so tests
+//! that set them should not run concurrently.
+
+use super::*;
+
+/// Every point at which a failure can be injected.
+pub const FAIL_POINTS: &[&str] = &[
+    // writing a log buffer, and fsyncing it
+    "buffer write",
+    "buffer sync",
+    "buffer write post",
+    // closing a segment with its trailer
+    "trailer write",
+    "trailer sync",
+    "trailer write post",
+    // moving on to a new segment, and zeroing freed ones
+    "segment next",
+    "zero segment",
+    "zero segment post",
+    "zero garbage segment",
+    "zero garbage segment post",
+    // starting up
+    "initial allocation",
+    "initial allocation post",
+    "write_config bytes",
+    "write_config crc",
+    "write_config post",
+    // writing a snapshot
+    "snap write",
+    "snap write len",
+    "snap write crc",
+    "snap write post",
+    "snap write mv",
+    "snap write mv post",
+    "snap write rm old",
+];
+
+/// Makes the point `name` fail each time it is reached, after
+/// letting the first `skip` passes through, until it is cleared.
+/// The operation that reaches it returns `Error::FailPoint`. A
+/// failure in the log's write path also stops the log as though
+/// the process crashed there, so that every later write fails too,
+/// and the database has to be restarted, which recovers it the way
+/// it would after a real crash.
+pub fn fail_at(name: &str, skip: usize) -> CacheResult<(), ()> {
+    if !FAIL_POINTS.contains(&name) {
+        return Err(Error::Unsupported(format!(
+            "there is no fail point named {:?}",
+            name
+        )));
+    }
+    let actions = if skip == 0 {
+        "return".to_owned()
+    } else {
+        format!("{}*off->return", skip)
+    };
+    fail::cfg(name, &actions).map_err(Error::Unsupported)
+}
+
+/// Stops the point `name` from failing.
+pub fn clear(name: &str) {
+    fail::remove(name);
+}
+
+/// Stops every point from failing.
+pub fn clear_all() {
+    fail::teardown();
+}
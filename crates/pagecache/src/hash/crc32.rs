@@ -0,0 +1,48 @@
+// CRC32 as used by zlib, ethernet and png:
+//
+// Name                       : "CRC-32", also known as "CRC-32/ISO-HDLC"
+// Width                      : 32 bit
+// Poly                       : 04C11DB7 (reflected: EDB88320)
+// Initialization             : FFFFFFFF
+// Reflect Input byte         : True
+// Reflect Output CRC         : True
+// Xor constant to output CRC : FFFFFFFF
+// Output for "123456789"     : CBF43926
+//
+
+const CRC32TAB: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in buf {
+        let idx = (crc ^ u32::from(b)) & 0xFF;
+        crc = CRC32TAB[idx as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use super::*;
+
+/// Encrypts the page data that the log and snapshots write to
+/// disk, set with `ConfigBuilder::encryption`. `pagecache` ships no
+/// cipher of its own: wrap an AEAD such as AES-GCM or
+/// ChaCha20-Poly1305 from a crate you trust, using whatever key
+/// management you need.
+///
+/// Each log message and snapshot is sealed on its own, before it
+/// is checksummed and written. Where a message will land isn't
+/// known when it is sealed, so every sealed buffer has to carry its
+/// own nonce, and any key id needed to pick the key it was sealed
+/// with. The configuration file is written in the clear, but holds
+/// no page data.
+pub trait Cipher: Send + Sync {
+    /// Encrypts and authenticates `plaintext`, returning the bytes
+    /// to write. The same plaintext may be sealed many times, so
+    /// this must use a fresh nonce each time.
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Decrypts bytes returned by `seal`, or returns `None` if they
+    /// weren't sealed with this cipher's key or have been altered.
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// The `Cipher` a `ConfigBuilder` was given, shared by its clones.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct SharedCipher(pub Arc<dyn Cipher>);
+
+impl Debug for SharedCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedCipher")
+    }
+}
+
+// Ciphers can't be compared, so two are only equal if they are
+// clones of each other.
+impl PartialEq for SharedCipher {
+    fn eq(&self, other: &SharedCipher) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
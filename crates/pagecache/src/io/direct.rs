@@ -0,0 +1,338 @@
+// The log file behind `IoBackend::Direct`. It is opened with
+// O_DIRECT, so segment writes go straight to the device instead of
+// being copied into the OS page cache and written back later.
+// Writes, truncations and syncs are handed to a reactor thread,
+// which works through everything queued since its last round in
+// order, then answers every sync among them with one fdatasync.
+// Threads flushing different IO buffers at once then wait on the
+// same sync instead of taking turns on their own.
+//
+// O_DIRECT only moves whole blocks, to and from block-aligned
+// memory and offsets. The log pads every IO buffer it writes out
+// to whole blocks when it uses this backend, so a write only has
+// to be copied into aligned memory, and one that isn't aligned is
+// refused rather than merged with what the file holds around it.
+// Reads may cover any range, which is widened to the blocks it
+// touches.
+
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::Pio;
+
+use super::*;
+
+// The most requests the reactor takes on before syncing, so that a
+// steady stream of writes can't hold a sync back forever.
+const MAX_BATCH: usize = 64;
+
+// A write of whole blocks, starting at the block at `at`.
+struct BlockWrite {
+    blocks: AlignedBuf,
+    at: LogID,
+    done: Sender<io::Result<()>>,
+}
+
+enum Request {
+    Write(BlockWrite),
+    Truncate(LogID, Sender<io::Result<()>>),
+    Sync(Sender<io::Result<()>>),
+}
+
+// Memory aligned to `DIRECT_ALIGN`, taken from a buffer one block
+// longer than it.
+struct AlignedBuf {
+    buf: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn zeroed(len: usize) -> AlignedBuf {
+        let buf = vec![0; len + DIRECT_ALIGN];
+        let misalignment = buf.as_ptr() as usize % DIRECT_ALIGN;
+        AlignedBuf {
+            buf: buf,
+            offset: (DIRECT_ALIGN - misalignment) % DIRECT_ALIGN,
+            len: len,
+        }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.offset..self.offset + self.len]
+    }
+}
+
+/// Sets the flag that makes a log file bypass the OS page cache.
+pub(crate) fn bypass_cache(options: &mut OpenOptions) {
+    options.custom_flags(libc::O_DIRECT);
+}
+
+/// A log file opened with `O_DIRECT`, written by a reactor thread.
+pub(crate) struct DirectStorage {
+    file: Arc<File>,
+    // a `Sender` can't be shared between threads, so writers take
+    // turns queueing. Dropping it stops the reactor.
+    requests: Mutex<Option<Sender<Request>>>,
+    reactor: Option<JoinHandle<()>>,
+}
+
+impl DirectStorage {
+    /// Starts the reactor for a file opened after `bypass_cache`.
+    pub(crate) fn start(file: File) -> DirectStorage {
+        let file = Arc::new(file);
+        let reactor_file = file.clone();
+        let (tx, rx) = channel();
+        let reactor = thread::Builder::new()
+            .name("pagecache_direct_io".to_owned())
+            .spawn(move || run(&reactor_file, &rx))
+            .unwrap();
+        DirectStorage {
+            file: file,
+            requests: Mutex::new(Some(tx)),
+            reactor: Some(reactor),
+        }
+    }
+
+    // Queues a request, then waits for the reactor to answer it.
+    fn submit<F>(&self, request: F) -> io::Result<()>
+    where
+        F: FnOnce(Sender<io::Result<()>>) -> Request,
+    {
+        let (tx, rx) = channel();
+        let sent = match *self.requests.lock().unwrap() {
+            Some(ref requests) => requests.send(request(tx)).is_ok(),
+            None => false,
+        };
+        let stopped = || {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "the direct IO reactor thread has stopped",
+            )
+        };
+        if !sent {
+            return Err(stopped());
+        }
+        rx.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl Drop for DirectStorage {
+    fn drop(&mut self) {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.take();
+        }
+        if let Some(reactor) = self.reactor.take() {
+            if let Err(e) = reactor.join() {
+                error!("error joining direct IO reactor: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Storage for DirectStorage {
+    fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        let (at, start) = align_down(offset);
+        let end = start + buf.len();
+        let mut blocks = AlignedBuf::zeroed(align_up(end));
+        let read = read_blocks(&self.file, &mut blocks, at)?;
+        if read < end {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        buf.copy_from_slice(&blocks[start..end]);
+        Ok(())
+    }
+
+    fn write_at(
+        &self,
+        buf: &[u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if offset % DIRECT_ALIGN as LogID != 0
+            || buf.len() % DIRECT_ALIGN != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a direct write of {} bytes at {} doesn't cover \
+                     whole blocks of {} bytes",
+                    buf.len(),
+                    offset,
+                    DIRECT_ALIGN
+                ),
+            ));
+        }
+        let mut blocks = AlignedBuf::zeroed(buf.len());
+        blocks.copy_from_slice(buf);
+        self.submit(|done| {
+            Request::Write(BlockWrite {
+                blocks: blocks,
+                at: offset,
+                done: done,
+            })
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.submit(Request::Sync)
+    }
+
+    fn truncate(&self, len: LogID) -> io::Result<()> {
+        self.submit(|done| Request::Truncate(len, done))
+    }
+}
+
+// The block-aligned offset at or before `offset`, and how far past
+// it `offset` is.
+fn align_down(offset: LogID) -> (LogID, usize) {
+    let into = (offset % DIRECT_ALIGN as LogID) as usize;
+    (offset - into as LogID, into)
+}
+
+fn align_up(len: usize) -> usize {
+    (len + DIRECT_ALIGN - 1) / DIRECT_ALIGN * DIRECT_ALIGN
+}
+
+// Reads as many of the blocks at `at` as the file holds, returning
+// how many bytes were read.
+fn read_blocks(
+    file: &File,
+    mut blocks: &mut [u8],
+    mut at: LogID,
+) -> io::Result<usize> {
+    let mut read = 0;
+    while !blocks.is_empty() {
+        match FileExt::read_at(file, blocks, at) {
+            Ok(0) => break,
+            // a read that ends part way through a block has
+            // reached the end of the file
+            Ok(n) if n % DIRECT_ALIGN != 0 => return Ok(read + n),
+            Ok(n) => {
+                read += n;
+                at += n as LogID;
+                let tmp = blocks;
+                blocks = &mut tmp[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn sync(file: &File) -> io::Result<()> {
+    let ret = unsafe { libc::fdatasync(file.as_raw_fd()) };
+    if ret != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn run(file: &File, requests: &Receiver<Request>) {
+    while let Ok(first) = requests.recv() {
+        let mut syncs = vec![];
+        let mut next = Some(first);
+        let mut taken = 0;
+        while let Some(request) = next.take() {
+            match request {
+                Request::Write(write) => {
+                    let res =
+                        file.pwrite_all(&write.blocks, write.at);
+                    let _ = write.done.send(res);
+                }
+                Request::Truncate(len, done) => {
+                    let _ = done.send(file.set_len(len));
+                }
+                Request::Sync(done) => syncs.push(done),
+            }
+            taken += 1;
+            if taken < MAX_BATCH {
+                next = requests.try_recv().ok();
+            }
+        }
+
+        if syncs.is_empty() {
+            continue;
+        }
+        match sync(file) {
+            Ok(()) => {
+                for done in syncs {
+                    let _ = done.send(Ok(()));
+                }
+            }
+            Err(e) => {
+                error!("failed to sync the log: {}", e);
+                for done in syncs {
+                    let _ = done.send(Err(io::Error::new(
+                        e.kind(),
+                        e.to_string(),
+                    )));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_direct_storage() {
+    let path = "/tmp/pagecache_test_direct_storage";
+    let _ = std::fs::remove_file(path);
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+    bypass_cache(&mut options);
+    let storage = DirectStorage::start(options.open(path).unwrap());
+
+    let mut buf = [0u8; 3];
+    assert!(storage.read_at(&mut buf, 0).is_err());
+
+    // only whole blocks are written
+    assert!(storage.write_at(&[1, 2, 3], 2).is_err());
+    assert!(storage.write_at(&[1; DIRECT_ALIGN + 1], 0).is_err());
+    let mut blocks = vec![7; 2 * DIRECT_ALIGN];
+    blocks[2..5].copy_from_slice(&[1, 2, 3]);
+    storage.write_at(&blocks, 0).unwrap();
+    let second = DIRECT_ALIGN as LogID;
+    storage.write_at(&[9; DIRECT_ALIGN], second).unwrap();
+    storage.sync().unwrap();
+
+    // reads may start and end part way through blocks
+    storage.read_at(&mut buf, 1).unwrap();
+    assert_eq!(buf, [7, 1, 2]);
+    storage.read_at(&mut buf, 4095).unwrap();
+    assert_eq!(buf, [7, 9, 9]);
+    assert!(storage.read_at(&mut buf, 2 * second - 2).is_err());
+
+    storage.truncate(3).unwrap();
+    storage.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, [7, 7, 1]);
+    assert!(storage.read_at(&mut buf, 1).is_err());
+
+    drop(storage);
+    std::fs::remove_file(path).unwrap();
+}
@@ -41,6 +41,10 @@ struct IoBuf {
     lsn: AtomicUsize,
     capacity: AtomicUsize,
     maxed: AtomicBool,
+    // how many bytes at the start of the buffer were already on
+    // disk when it was set up, and only get written again to
+    // keep its write aligned
+    lead: AtomicUsize,
     linearizer: Mutex<()>,
 }
 
@@ -63,6 +67,11 @@ pub(super) struct IoBufs {
     stable_lsn: AtomicLsn,
     max_reserved_lsn: AtomicLsn,
     segment_accountant: Mutex<SegmentAccountant>,
+    // set once writing or syncing the log has failed, after which
+    // nothing more is written to it
+    poisoned: AtomicBool,
+    // the kind and description of the error that poisoned the log
+    poison: Mutex<Option<(io::ErrorKind, String)>>,
 
     // used for signifying that we're simulating a crash
     #[cfg(feature = "failpoints")]
@@ -91,11 +100,7 @@ impl IoBufs {
             snapshot.last_lid = 0;
             (0, 0)
         } else {
-            match file.read_message(
-                snapshot_last_lid,
-                io_buf_size,
-                config.use_compression,
-            ) {
+            match file.read_message(snapshot_last_lid, &config) {
                 Ok(LogRead::Flush(_lsn, _buf, len)) => (
                     snapshot_max_lsn
                         + len as Lsn
@@ -131,6 +136,12 @@ impl IoBufs {
             next_lid
         );
 
+        if next_lsn == 0 && config.read_only {
+            return Err(Error::Unsupported(
+                "can't open an empty log read-only".to_owned(),
+            ));
+        }
+
         if next_lsn == 0 {
             // recovering at segment boundary
             assert_eq!(next_lid, next_lsn as LogID);
@@ -142,8 +153,9 @@ impl IoBufs {
             iobuf.store_segment_header(0, next_lsn);
 
             maybe_fail!("initial allocation");
-            file.pwrite_all(&*vec![0; config.io_buf_size], lid)?;
-            file.sync_all()?;
+            file.write_at(&*vec![0; config.io_buf_size], lid)?;
+            file.sync()?;
+            M.fsynced();
             maybe_fail!("initial allocation post");
 
             debug!(
@@ -151,14 +163,27 @@ impl IoBufs {
                 next_lid, next_lsn
             );
         } else {
-            // the tip offset is not completely full yet, reuse it
+            // the tip offset is not completely full yet, reuse it.
+            // A tip part way through a block is moved back to its
+            // start, along with what the block already holds, so
+            // that the buffer's write is aligned.
             let iobuf = &bufs[current_buf];
-            let offset = next_lid % io_buf_size as LogID;
-            iobuf.set_lid(next_lid);
+            let lead =
+                (next_lid % write_align(&config) as LogID) as usize;
+            let lid = next_lid - lead as LogID;
+            if lead > 0 {
+                let data =
+                    unsafe { (*iobuf.buf.get()).as_mut_slice() };
+                file.read_at(&mut data[..lead], lid)?;
+            }
+            let offset = lid % io_buf_size as LogID;
+            iobuf.set_lid(lid);
             iobuf.set_capacity(
                 io_buf_size - offset as usize - SEG_TRAILER_LEN,
             );
-            iobuf.set_lsn(next_lsn);
+            iobuf.set_lsn(next_lsn - lead as Lsn);
+            iobuf.set_header(lead as Header);
+            iobuf.set_lead(lead);
 
             debug!(
                 "starting log at split offset {}, recovered lsn {}",
@@ -180,6 +205,8 @@ impl IoBufs {
             max_reserved_lsn: AtomicLsn::new(stable),
             config: config,
             segment_accountant: Mutex::new(segment_accountant),
+            poisoned: AtomicBool::new(false),
+            poison: Mutex::new(None),
             #[cfg(feature = "failpoints")]
             _failpoint_crashing: AtomicBool::new(false),
         })
@@ -205,6 +232,48 @@ impl IoBufs {
         ret
     }
 
+    /// Returns whether an IO error writing or syncing the log has
+    /// stopped it from being written to.
+    pub(super) fn is_poisoned(&self) -> bool {
+        self.poisoned.load(SeqCst)
+    }
+
+    // Stops the log from being written to after writing or syncing
+    // it failed, as nothing after the failed write can be made
+    // durable, and wakes up anyone waiting on a flush to notice.
+    fn poison(&self, e: io::Error) -> Error<()> {
+        error!("stopped writing to the log after IO error: {}", e);
+        {
+            let mut poison = self.poison.lock().unwrap();
+            if poison.is_none() {
+                *poison = Some((e.kind(), e.to_string()));
+            }
+        }
+        self.poisoned.store(true, SeqCst);
+        let _intervals = self.intervals.lock().unwrap();
+        self.interval_updated.notify_all();
+        Error::Io(e)
+    }
+
+    // Fails with the error that poisoned the log, if one has.
+    fn check_poisoned(&self) -> CacheResult<(), ()> {
+        if !self.is_poisoned() {
+            return Ok(());
+        }
+        let poison = self.poison.lock().unwrap();
+        let (kind, ref description) = *poison
+            .as_ref()
+            .expect("the log was poisoned without an error");
+        Err(Error::Io(io::Error::new(
+            kind,
+            format!(
+                "the log can't be written to after an earlier \
+                 IO error: {}",
+                description
+            ),
+        )))
+    }
+
     fn idx(&self) -> usize {
         debug_delay();
         let current_buf = self.current_buf.load(SeqCst);
@@ -218,7 +287,7 @@ impl IoBufs {
     }
 
     // Adds a header to the buffer, and optionally compresses
-    // the buffer.
+    // and encrypts the buffer.
     // NB the caller is responsible for later setting the Lsn
     // bytes after a reservation has been acquired.
     fn encapsulate(&self, raw_buf: Vec<u8>) -> Vec<u8> {
@@ -227,7 +296,7 @@ impl IoBufs {
             let _measure = Measure::new(&M.compress);
             compress(
                 &*raw_buf,
-                self.config.get_zstd_compression_factor(),
+                self.config.zstd_compression_factor,
             ).unwrap()
         } else {
             raw_buf
@@ -236,13 +305,18 @@ impl IoBufs {
         #[cfg(not(feature = "zstd"))]
         let buf = raw_buf;
 
-        let crc16 = crc16_arr(&buf);
+        let buf = match self.config.cipher {
+            Some(ref cipher) => cipher.0.seal(&buf),
+            None => buf,
+        };
+
+        let crc32 = crc32(&buf);
 
         let header = MessageHeader {
             kind: MessageKind::Success,
             lsn: 0,
             len: buf.len(),
-            crc16: crc16,
+            crc32: crc32,
         };
 
         let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
@@ -270,6 +344,13 @@ impl IoBufs {
     ) -> CacheResult<Reservation, ()> {
         let _measure = Measure::new(&M.reserve);
 
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't write to a log opened read-only".to_owned(),
+            ));
+        }
+        self.check_poisoned()?;
+
         let io_bufs = self.config.io_bufs;
 
         // right shift 32 on 32-bit pointer systems panics
@@ -334,6 +415,7 @@ impl IoBufs {
                 // current_buf.
                 trace_once!("written ahead of sealed, spinning");
                 M.log_looped();
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(Relaxed) {
@@ -351,6 +433,7 @@ impl IoBufs {
                     "old io buffer not written yet, spinning"
                 );
                 M.log_looped();
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(Relaxed) {
@@ -371,6 +454,7 @@ impl IoBufs {
                 // has already been bumped by sealer.
                 trace_once!("io buffer already sealed, spinning");
                 M.log_looped();
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(Relaxed) {
@@ -393,6 +477,7 @@ impl IoBufs {
                 trace_once!("io buffer too full, spinning");
                 self.maybe_seal_and_write_iobuf(idx, header, true)?;
                 M.log_looped();
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(Relaxed) {
@@ -415,6 +500,7 @@ impl IoBufs {
                     "CAS failed while claiming buffer slot, spinning"
                 );
                 M.log_looped();
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(Relaxed) {
@@ -464,6 +550,7 @@ impl IoBufs {
             );
 
             self.bump_max_reserved_lsn(reservation_lsn);
+            M.log_reserved();
 
             return Ok(Reservation {
                 idx: idx,
@@ -526,6 +613,7 @@ impl IoBufs {
 
         // NB before we write the 0th byte of the file, stable  is -1
         while self.stable() < lsn {
+            self.check_poisoned()?;
             let idx = self.idx();
             let header = self.bufs[idx].get_header();
             if offset(header) == 0 || is_sealed(header) {
@@ -540,6 +628,7 @@ impl IoBufs {
             let waiter = self.intervals.lock().unwrap();
 
             if self.stable() < lsn {
+                self.check_poisoned()?;
                 #[cfg(feature = "failpoints")]
                 {
                     if self._failpoint_crashing.load(SeqCst) {
@@ -564,8 +653,13 @@ impl IoBufs {
     /// Called by users who wish to force the current buffer
     /// to flush some pending writes.
     pub(super) fn flush(&self) -> CacheResult<(), ()> {
-        let max_reserved_lsn = self.max_reserved_lsn.load(SeqCst);
-        self.make_stable(max_reserved_lsn)
+        self.make_stable(self.max_reserved())
+    }
+
+    /// Returns the highest Lsn reserved so far, which a flush
+    /// must make stable.
+    pub(super) fn max_reserved(&self) -> Lsn {
+        self.max_reserved_lsn.load(SeqCst)
     }
 
     // ensure self.max_reserved_lsn is set to this Lsn
@@ -614,14 +708,21 @@ impl IoBufs {
             return Ok(());
         }
 
-        let should_pad = from_reserve
-            && capacity - offset(header) as usize >= MSG_HEADER_LEN;
+        // the next buffer starts where this one ends, on a block
+        // of its own, so the rest of this one's last block is
+        // filled with a message that is skipped. If that doesn't
+        // fit in the segment, the next buffer starts the next one.
+        let end = offset(header) as usize;
+        let fill = block_fill(end, write_align(&self.config));
+        let rolls = from_reserve || end + fill > capacity;
+
+        let should_pad = rolls && capacity - end >= MSG_HEADER_LEN;
+        let should_fill = !rolls && fill > 0;
 
         let sealed = if should_pad {
-            mk_sealed(bump_offset(
-                header,
-                capacity as LogID - offset(header),
-            ))
+            mk_sealed(bump_offset(header, (capacity - end) as Header))
+        } else if should_fill {
+            mk_sealed(bump_offset(header, fill as Header))
         } else {
             mk_sealed(header)
         };
@@ -637,7 +738,7 @@ impl IoBufs {
 
             trace!("{} sealed", idx);
 
-            if from_reserve || maxed {
+            if rolls || maxed {
                 // NB we linearize this together with sealing
                 // the header here to guarantee that in write_to_log,
                 // which may be executing as soon as the seal is set
@@ -652,28 +753,31 @@ impl IoBufs {
             return Ok(());
         }
 
-        if should_pad {
-            let offset = offset(header) as usize;
+        if should_pad || should_fill {
             let data = unsafe { (*iobuf.buf.get()).as_mut_slice() };
-            let len = capacity - offset - MSG_HEADER_LEN;
+            let len = res_len - end - MSG_HEADER_LEN;
 
             // take the crc of the random bytes already after where we
             // would place our header.
             let padding_bytes = vec![EVIL_BYTE; len];
-            let crc16 = crc16_arr(&*padding_bytes);
+            let crc32 = crc32(&*padding_bytes);
 
             let header = MessageHeader {
-                kind: MessageKind::Pad,
-                lsn: lsn + offset as Lsn,
+                kind: if should_pad {
+                    MessageKind::Pad
+                } else {
+                    MessageKind::Failed
+                },
+                lsn: lsn + end as Lsn,
                 len: len,
-                crc16: crc16,
+                crc32: crc32,
             };
 
             let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
 
-            data[offset..offset + MSG_HEADER_LEN]
+            data[end..end + MSG_HEADER_LEN]
                 .copy_from_slice(&header_bytes);
-            data[offset + MSG_HEADER_LEN..capacity]
+            data[end + MSG_HEADER_LEN..res_len]
                 .copy_from_slice(&*padding_bytes);
         }
 
@@ -696,7 +800,7 @@ impl IoBufs {
         // open new slot
         let mut next_lsn = lsn;
 
-        let next_offset = if from_reserve || maxed {
+        let next_offset = if rolls || maxed {
             // roll lsn to the next offset
             let lsn_idx = lsn / io_buf_size as Lsn;
             next_lsn = (lsn_idx + 1) * io_buf_size as Lsn;
@@ -752,6 +856,8 @@ impl IoBufs {
                 );
                 spins = 0;
             }
+            // the buffer being written never will be
+            self.check_poisoned()?;
             #[cfg(feature = "failpoints")]
             {
                 if self._failpoint_crashing.load(Relaxed) {
@@ -767,7 +873,7 @@ impl IoBufs {
         // to start writing into this buffer, so do that after it's all
         // set up. expect this thread to block until the buffer completes
         // its entire lifecycle as soon as we do that.
-        if from_reserve || maxed {
+        if rolls || maxed {
             next_iobuf.set_capacity(io_buf_size - SEG_TRAILER_LEN);
             next_iobuf.store_segment_header(sealed, next_lsn);
         } else {
@@ -817,24 +923,43 @@ impl IoBufs {
         );
 
         let res_len = offset(header) as usize;
+        let lead = iobuf.get_lead();
+
+        // only a buffer that ends its segment can end part way
+        // through a block, and what follows it there is never read
+        let align = write_align(&self.config);
+        let write_len = (res_len + align - 1) / align * align;
 
         let data = unsafe { (*iobuf.buf.get()).as_mut_slice() };
 
+        // nothing may follow a write that failed
+        self.check_poisoned()?;
+
         let f = self.config.file()?;
         io_fail!(self, "buffer write");
-        f.pwrite_all(&data[..res_len], lid)?;
-        f.sync_all()?;
+        f.write_at(&data[..write_len], lid)
+            .map_err(|e| self.poison(e))?;
+        io_fail!(self, "buffer sync");
+        // a temporary database is never recovered after a crash, so
+        // its writes only need to reach the OS
+        if !self.config.temporary {
+            f.sync().map_err(|e| self.poison(e))?;
+            M.fsynced();
+        }
         io_fail!(self, "buffer write post");
 
-        if res_len > 0 {
+        if res_len > lead {
             debug!(
                 "wrote lsns {}-{} to disk at offsets {}-{}",
-                base_lsn,
+                base_lsn + lead as Lsn,
                 base_lsn + res_len as Lsn - 1,
-                lid,
+                lid + lead as LogID,
                 lid + res_len as LogID - 1
             );
-            self.mark_interval(base_lsn, res_len);
+            self.mark_interval(
+                base_lsn + lead as Lsn,
+                res_len - lead,
+            );
         }
 
         // write a trailer if we're maxed
@@ -858,9 +983,21 @@ impl IoBufs {
 
             let trailer_bytes: [u8; SEG_TRAILER_LEN] = trailer.into();
 
+            // the trailer is written along with the rest of the
+            // block that it ends
+            let at = (trailer_lid - lid) as usize;
+            let from = at / align * align;
+            let to = at + SEG_TRAILER_LEN;
+            data[at..to].copy_from_slice(&trailer_bytes);
+
             io_fail!(self, "trailer write");
-            f.pwrite_all(&trailer_bytes, trailer_lid)?;
-            f.sync_all()?;
+            f.write_at(&data[from..to], lid + from as LogID)
+                .map_err(|e| self.poison(e))?;
+            io_fail!(self, "trailer sync");
+            if !self.config.temporary {
+                f.sync().map_err(|e| self.poison(e))?;
+                M.fsynced();
+            }
             io_fail!(self, "trailer write post");
             iobuf.set_maxed(false);
 
@@ -892,6 +1029,7 @@ impl IoBufs {
 
         // signal that this IO buffer is now uninitialized
         let max = std::usize::MAX as LogID;
+        iobuf.set_lead(0);
         iobuf.set_lid(max);
         trace!("{} log <- MAX", idx);
 
@@ -987,12 +1125,23 @@ impl Drop for IoBufs {
             }
         }
 
+        // a read-only log never has anything to flush, and a
+        // poisoned one can't be flushed
+        if self.config.read_only || self.is_poisoned() {
+            return;
+        }
+
         if let Err(e) = self.flush() {
             error!("failed to flush from IoBufs::drop: {}", e);
         }
 
         if let Ok(f) = self.config.file() {
-            f.sync_all().unwrap();
+            match f.sync() {
+                Ok(()) => M.fsynced(),
+                Err(e) => {
+                    error!("failed to sync from IoBufs::drop: {}", e)
+                }
+            }
         }
 
         debug!("IoBufs dropped");
@@ -1001,6 +1150,10 @@ impl Drop for IoBufs {
 
 impl periodic::Callback for std::sync::Arc<IoBufs> {
     fn call(&self) {
+        // the error was already logged when the log was poisoned
+        if self.is_poisoned() {
+            return;
+        }
         if let Err(e) = self.flush() {
             #[cfg(feature = "failpoints")]
             {
@@ -1062,6 +1215,7 @@ impl IoBuf {
             lsn: AtomicUsize::new(0),
             capacity: AtomicUsize::new(0),
             maxed: AtomicBool::new(false),
+            lead: AtomicUsize::new(0),
             linearizer: Mutex::new(()),
         }
     }
@@ -1128,6 +1282,16 @@ impl IoBuf {
         self.maxed.load(SeqCst)
     }
 
+    fn set_lead(&self, lead: usize) {
+        debug_delay();
+        self.lead.store(lead, SeqCst);
+    }
+
+    fn get_lead(&self) -> usize {
+        debug_delay();
+        self.lead.load(SeqCst)
+    }
+
     fn get_lsn(&self) -> Lsn {
         debug_delay();
         self.lsn.load(SeqCst) as Lsn
@@ -1238,6 +1402,28 @@ fn salt(v: Header) -> Header {
     v >> 32 << 32
 }
 
+/// The size of the blocks that every write of the log covers
+/// whole, so that `IoBackend::Direct` never has to read in the
+/// blocks around a write to fill them out.
+pub(super) fn write_align(config: &Config) -> usize {
+    match config.io_backend {
+        IoBackend::Direct => DIRECT_ALIGN,
+        IoBackend::Buffered => 1,
+    }
+}
+
+// How many bytes a message that is skipped has to take up to
+// carry a buffer ending at `end` to the next block, which is never
+// fewer than its header.
+fn block_fill(end: usize, align: usize) -> usize {
+    let fill = (align - end % align) % align;
+    if fill == 0 || fill >= MSG_HEADER_LEN {
+        fill
+    } else {
+        fill + align
+    }
+}
+
 #[inline(always)]
 fn yield_now() {
     #[cfg(nightly)]
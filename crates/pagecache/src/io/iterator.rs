@@ -8,7 +8,6 @@ pub struct LogIter {
     pub segment_iter: Box<Iterator<Item = (Lsn, LogID)>>,
     pub segment_base: Option<LogID>,
     pub segment_len: usize,
-    pub use_compression: bool,
     pub max_lsn: Lsn,
     pub cur_lsn: Lsn,
     pub trailer: Option<Lsn>,
@@ -45,8 +44,10 @@ impl Iterator for LogIter {
                             that contain the initial cur_lsn value or higher"
                     );
 
-                    #[cfg(target_os = "linux")]
-                    self.fadvise_willneed(next_lid);
+                    if let Ok(f) = self.config.file() {
+                        let len = self.config.io_buf_size;
+                        f.will_need(next_lid, len);
+                    }
 
                     if let Err(e) =
                         self.read_segment(next_lsn, next_lid)
@@ -72,11 +73,7 @@ impl Iterator for LogIter {
                 + (self.cur_lsn % self.segment_len as Lsn) as LogID;
 
             if let Ok(f) = self.config.file() {
-                match f.read_message(
-                    lid,
-                    self.segment_len,
-                    self.use_compression,
-                ) {
+                match f.read_message(lid, &self.config) {
                     Ok(LogRead::Flush(lsn, buf, on_disk_len)) => {
                         if lsn != self.cur_lsn {
                             error!("read Flush with bad lsn");
@@ -199,28 +196,6 @@ impl LogIter {
 
         Ok(())
     }
-
-    #[cfg(target_os = "linux")]
-    fn fadvise_willneed(&self, lid: LogID) {
-        use std::os::unix::io::AsRawFd;
-
-        if let Ok(f) = self.config.file() {
-            let ret = unsafe {
-                libc::posix_fadvise(
-                    f.as_raw_fd(),
-                    lid as libc::off_t,
-                    self.config.io_buf_size as libc::off_t,
-                    libc::POSIX_FADV_WILLNEED,
-                )
-            };
-            if ret != 0 {
-                panic!(
-                    "failed to call fadvise: {}",
-                    std::io::Error::from_raw_os_error(ret)
-                );
-            }
-        }
-    }
 }
 
 fn valid_entry_offset(lid: LogID, segment_len: usize) -> bool {
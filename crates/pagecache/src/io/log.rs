@@ -4,7 +4,7 @@ use self::reader::LogReader;
 use super::*;
 
 #[doc(hidden)]
-pub const MSG_HEADER_LEN: usize = 15;
+pub const MSG_HEADER_LEN: usize = 17;
 
 #[doc(hidden)]
 pub const SEG_HEADER_LEN: usize = 10;
@@ -64,10 +64,16 @@ impl Log {
     ) -> CacheResult<Log, ()> {
         let iobufs =
             Arc::new(IoBufs::start(config.clone(), snapshot)?);
+        // nothing is ever written to a read-only log
+        let flush_every_ms = if config.read_only {
+            None
+        } else {
+            config.flush_every_ms
+        };
         let flusher = periodic::Periodic::new(
             "log flusher".to_owned(),
             iobufs.clone(),
-            config.flush_every_ms,
+            flush_every_ms,
         );
 
         Ok(Log {
@@ -96,6 +102,16 @@ impl Log {
         self.iobufs.flush()
     }
 
+    /// Returns a handle to wait on everything reserved so far
+    /// becoming durable, without blocking the caller until it
+    /// is needed.
+    pub fn flush_async(&self) -> FlushHandle {
+        FlushHandle {
+            iobufs: self.iobufs.clone(),
+            lsn: self.iobufs.max_reserved(),
+        }
+    }
+
     /// Reserve space in the log for a pending linearized operation.
     pub fn reserve(
         &self,
@@ -136,7 +152,6 @@ impl Log {
             segment_base: None,
             segment_iter: segment_iter,
             segment_len: io_buf_size,
-            use_compression: self.config.use_compression,
             trailer: None,
         }
     }
@@ -151,11 +166,7 @@ impl Log {
         self.make_stable(lsn)?;
         let f = self.config.file()?;
 
-        let read = f.read_message(
-            lid,
-            self.config.io_buf_size,
-            self.config.use_compression,
-        );
+        let read = f.read_message(lid, &self.config);
 
         read.and_then(|log_read| match log_read {
             LogRead::Flush(read_lsn, _, _) => {
@@ -171,12 +182,29 @@ impl Log {
         self.iobufs.stable()
     }
 
+    /// Returns how many bytes have been reserved in the log that
+    /// haven't been made durable yet.
+    pub fn unflushed_bytes(&self) -> usize {
+        let unflushed =
+            self.iobufs.max_reserved() - self.stable_offset();
+        std::cmp::max(unflushed, 0) as usize
+    }
+
     /// blocks until the specified log sequence number has
     /// been made stable on disk
     pub fn make_stable(&self, lsn: Lsn) -> CacheResult<(), ()> {
         self.iobufs.make_stable(lsn)
     }
 
+    /// Returns whether an IO error writing or syncing the log,
+    /// like running out of space, has stopped anything more from
+    /// being written to it. Writes and flushes then fail with an
+    /// `Error::Io` right away, while everything that was already
+    /// stable can still be read.
+    pub fn was_poisoned(&self) -> bool {
+        self.iobufs.is_poisoned()
+    }
+
     // SegmentAccountant access for coordination with the `PageCache`
     pub(in io) fn with_sa<B, F>(&self, f: F) -> B
     where
@@ -186,6 +214,42 @@ impl Log {
     }
 }
 
+/// A pending flush of the log, returned by `Log::flush_async`. It
+/// is done once every write reserved before it was created has
+/// been made durable, either by the periodic flusher that runs
+/// every `flush_every_ms`, by another flush, or by `wait`.
+pub struct FlushHandle {
+    iobufs: Arc<IoBufs>,
+    lsn: Lsn,
+}
+
+unsafe impl Send for FlushHandle {}
+unsafe impl Sync for FlushHandle {}
+
+impl FlushHandle {
+    /// The Lsn that must be stable for this flush to be done.
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+
+    /// Returns whether the flush is done, without blocking.
+    pub fn is_done(&self) -> bool {
+        self.iobufs.stable() >= self.lsn
+    }
+
+    /// Blocks until the flush is done, writing out the buffers
+    /// that hold its writes if nothing else has yet.
+    pub fn wait(self) -> CacheResult<(), ()> {
+        self.iobufs.make_stable(self.lsn)
+    }
+}
+
+impl Debug for FlushHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FlushHandle {{ lsn: {} }}", self.lsn)
+    }
+}
+
 /// Represents the kind of message written to the log
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum MessageKind {
@@ -201,7 +265,7 @@ pub(crate) struct MessageHeader {
     pub kind: MessageKind,
     pub lsn: Lsn,
     pub len: usize,
-    pub crc16: [u8; 2],
+    pub crc32: u32,
 }
 
 /// A segment's header contains the new base LSN and a reference
@@ -301,7 +365,7 @@ impl LogRead {
 }
 
 // NB we use a lot of xors below to differentiate between zeroed out
-// data on disk and an lsn or crc16 of 0
+// data on disk and an lsn or crc of 0
 
 impl From<[u8; MSG_HEADER_LEN]> for MessageHeader {
     fn from(buf: [u8; MSG_HEADER_LEN]) -> MessageHeader {
@@ -322,13 +386,16 @@ impl From<[u8; MSG_HEADER_LEN]> for MessageHeader {
         len_arr.copy_from_slice(&*len_buf);
         let len: u32 = unsafe { std::mem::transmute(len_arr) };
 
-        let crc16 = [buf[13] ^ 0xFF, buf[14] ^ 0xFF];
+        let crc32_buf = &buf[13..17];
+        let mut crc32_arr = [0u8; 4];
+        crc32_arr.copy_from_slice(&*crc32_buf);
+        let crc32 = u32::from_ne_bytes(crc32_arr);
 
         MessageHeader {
             kind: kind,
             lsn: lsn,
             len: len as usize,
-            crc16: crc16,
+            crc32: crc32 ^ 0xFFFF_FFFF,
         }
     }
 }
@@ -354,8 +421,8 @@ impl Into<[u8; MSG_HEADER_LEN]> for MessageHeader {
             unsafe { std::mem::transmute(self.len as u32) };
         buf[9..13].copy_from_slice(&len_arr);
 
-        buf[13] = self.crc16[0] ^ 0xFF;
-        buf[14] = self.crc16[1] ^ 0xFF;
+        let crc32_arr = (self.crc32 ^ 0xFFFF_FFFF).to_ne_bytes();
+        buf[13..17].copy_from_slice(&crc32_arr);
 
         buf
     }
@@ -28,13 +28,48 @@ pub trait Materializer {
         Self: Sized;
 
     /// Used to merge chains of partial pages into a form
-    /// that is useful for the `PageCache` owner.
-    fn merge(&self, &[&Self::PageFrag]) -> Self::PageFrag;
+    /// that is useful for the `PageCache` owner. A chain that
+    /// can't be merged is reported by the `PageCache` as
+    /// `Error::Corruption` at the frag that `MergeError` blames.
+    fn merge(
+        &self,
+        &[&Self::PageFrag],
+    ) -> Result<Self::PageFrag, MergeError>;
 
     /// Used to feed custom recovery information back to a higher-level abstraction
     /// during startup. For example, a B-Link tree must know what the current
     /// root node is before it can start serving requests.
     fn recover(&self, &Self::PageFrag) -> Option<Self::Recovery>;
+
+    /// The pages whose contents a frag read back from the log
+    /// needs before it can be merged, because it only stored their
+    /// ids, as a tree leaf does for values that were written to
+    /// pages of their own. None by default.
+    fn references(&self, _: &Self::PageFrag) -> Vec<PageID> {
+        vec![]
+    }
+
+    /// Fills the pages listed by `references` into the frag they
+    /// were listed for, materialized and in the same order. A page
+    /// that has been freed since the frag was written is `None`,
+    /// which only happens when a later frag of the same page has
+    /// replaced whatever referred to it.
+    fn fill(
+        &self,
+        _: &mut Self::PageFrag,
+        _: Vec<Option<Self::PageFrag>>,
+    ) {
+    }
+}
+
+/// A chain of frags that a `Materializer` could not merge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeError {
+    /// The position in the chain of the frag that could not be
+    /// merged, counting from the oldest.
+    pub idx: usize,
+    /// Why it could not be merged.
+    pub reason: String,
 }
 
 /// A materializer for things that have nothing to
@@ -55,8 +90,11 @@ impl Materializer for NullMaterializer {
         NullMaterializer
     }
 
-    fn merge(&self, _: &[&Self::PageFrag]) -> Self::PageFrag {
-        ()
+    fn merge(
+        &self,
+        _: &[&Self::PageFrag],
+    ) -> Result<Self::PageFrag, MergeError> {
+        Ok(())
     }
 
     fn recover(&self, _: &Self::PageFrag) -> Option<Self::Recovery> {
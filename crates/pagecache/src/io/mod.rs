@@ -6,12 +6,15 @@ use std::io;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::SeqCst;
 
-use bincode::{deserialize, serialize, Infinite};
+use bincode::{deserialize, serialize, serialized_size, Infinite};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use super::*;
 
+mod cipher;
+#[cfg(all(feature = "direct_io", target_os = "linux"))]
+mod direct;
 mod iobuf;
 mod iterator;
 mod log;
@@ -22,6 +25,7 @@ mod reader;
 mod reservation;
 mod segment;
 mod snapshot;
+mod storage;
 
 #[doc(hidden)]
 pub use self::log::{
@@ -33,13 +37,26 @@ pub(super) use self::reader::LogReader;
 #[doc(hidden)]
 pub use self::snapshot::{read_snapshot_or_default, Snapshot};
 
-pub use self::log::Log;
-pub use self::materializer::{Materializer, NullMaterializer};
-pub use self::page_cache::{CacheEntry, PageCache, PageGet};
+pub use self::cipher::Cipher;
+pub use self::log::{FlushHandle, Log};
+pub use self::materializer::{
+    Materializer, MergeError, NullMaterializer,
+};
+pub use self::page_cache::{
+    CacheEntry, Logged, PageCache, PageGet, Pressure,
+};
 pub use self::reservation::Reservation;
-pub use self::segment::SegmentMode;
+pub use self::segment::{SegmentMode, SegmentStats};
+pub use self::storage::{IoBackend, MemStorage, Storage};
+
+#[doc(hidden)]
+pub use self::cipher::SharedCipher;
+#[doc(hidden)]
+pub use self::storage::SharedStorage;
 
-use self::iobuf::IoBufs;
+#[cfg(all(feature = "direct_io", target_os = "linux"))]
+pub(crate) use self::direct::{bypass_cache, DirectStorage};
+use self::iobuf::{write_align, IoBufs};
 use self::iterator::LogIter;
 use self::log::{
     MessageHeader, MessageKind, SegmentHeader, SegmentTrailer,
@@ -61,3 +78,8 @@ const FAILED_FLUSH: u8 = 0;
 
 // This message represents a pad.
 const SEGMENT_PAD: u8 = 2;
+
+// The size and alignment of every write of a log file opened with
+// `IoBackend::Direct`, which is a multiple of the logical block
+// size of common devices.
+const DIRECT_ALIGN: usize = 4096;
@@ -1,7 +1,9 @@
 use std::collections::BinaryHeap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
 use std::sync::{Arc, Mutex};
 
-use epoch::{Guard, Owned, Shared};
+use epoch::{pin, Guard, Owned, Shared};
 
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
@@ -49,6 +51,18 @@ where
     Compact(PageFrag),
     Free,
     Allocate,
+    // appends to several pages that recovery replays all or none of
+    Batch(Vec<(PageID, PageFrag)>),
+}
+
+/// What an update read back by `PageCache::logged_between` did to
+/// a page.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Logged<PageFrag> {
+    /// A frag was appended to the page, or replaced it.
+    Frag(PageFrag),
+    /// The page was freed.
+    Free,
 }
 
 /// The result of a `get` call in the `PageCache`.
@@ -132,6 +146,51 @@ where
     }
 }
 
+/// How far writes to a `PageCache` are ahead of the disk, as
+/// returned by `PageCache::pressure`, for shedding load before
+/// writers start to wait.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pressure {
+    /// Bytes written to the log that aren't durable yet.
+    pub unflushed_bytes: usize,
+    /// The configured `max_unflushed_bytes`, past which writes
+    /// wait for the log to catch up.
+    pub max_unflushed_bytes: Option<usize>,
+    /// Bytes of pages resident in memory, see
+    /// `PageCache::resident_bytes`.
+    pub resident_bytes: usize,
+    /// The configured `max_resident_bytes`, past which writes wait
+    /// for pages to be paged out.
+    pub max_resident_bytes: Option<usize>,
+}
+
+impl Pressure {
+    /// How close writers are to being held back, as the larger of
+    /// the fraction of `max_unflushed_bytes` that is unflushed and
+    /// the fraction of `max_resident_bytes` that is resident.
+    /// Writes wait at 1.0 and above. Always 0.0 without either.
+    pub fn level(&self) -> f64 {
+        let fraction = |bytes: usize, max: Option<usize>| {
+            max.map_or(0.0, |max| bytes as f64 / max as f64)
+        };
+        let unflushed =
+            fraction(self.unflushed_bytes, self.max_unflushed_bytes);
+        let resident =
+            fraction(self.resident_bytes, self.max_resident_bytes);
+        unflushed.max(resident)
+    }
+
+    /// Returns true if writes are waiting for the log to catch up,
+    /// or for pages to be paged out.
+    pub fn is_throttling(&self) -> bool {
+        let over = |bytes: usize, max: Option<usize>| {
+            max.map_or(false, |max| bytes > max)
+        };
+        over(self.unflushed_bytes, self.max_unflushed_bytes)
+            || over(self.resident_bytes, self.max_resident_bytes)
+    }
+}
+
 /// A lock-free pagecache which supports fragmented pages
 /// for dramatically improving write throughput.
 ///
@@ -166,13 +225,16 @@ where
 ///
 ///     // Used to merge chains of partial pages into a form
 ///     // that is useful for the `PageCache` owner.
-///     fn merge(&self, frags: &[&Self::PageFrag]) -> Self::PageFrag {
+///     fn merge(
+///         &self,
+///         frags: &[&Self::PageFrag],
+///     ) -> Result<Self::PageFrag, pagecache::MergeError> {
 ///         let mut consolidated = String::new();
 ///         for frag in frags.into_iter() {
 ///             consolidated.push_str(&*frag);
 ///         }
 ///
-///         consolidated
+///         Ok(consolidated)
 ///     }
 ///
 ///     // Used to feed custom recovery information back to a higher-level abstraction
@@ -222,6 +284,7 @@ where
     lru: Lru,
     updates: AtomicUsize,
     last_snapshot: Arc<Mutex<Option<Snapshot<R>>>>,
+    _reporter: Option<periodic::Periodic<SharedMetricsSink>>,
 }
 
 unsafe impl<PM, P, R> Send for PageCache<PM, P, R>
@@ -276,6 +339,14 @@ where
         let materializer =
             Arc::new(PM::new(config.clone(), &snapshot.recovery));
 
+        let reporter = config.metrics_sink.clone().map(|sink| {
+            periodic::Periodic::new(
+                "metrics reporter".to_owned(),
+                sink,
+                config.metrics_every_ms,
+            )
+        });
+
         let mut pc = PageCache {
             t: materializer,
             config: config.clone(),
@@ -286,6 +357,7 @@ where
             lru: lru,
             updates: AtomicUsize::new(0),
             last_snapshot: Arc::new(Mutex::new(Some(snapshot))),
+            _reporter: reporter,
         };
 
         // now we read it back in
@@ -299,6 +371,12 @@ where
         self.log.flush()
     }
 
+    /// Returns a handle that is done once every write made so far
+    /// is durable. See `FlushHandle`.
+    pub fn flush_async(&self) -> FlushHandle {
+        self.log.flush_async()
+    }
+
     /// Return the recovered state from the snapshot
     pub fn recovered_state(&self) -> Option<R> {
         let mu = match self.last_snapshot.lock() {
@@ -313,12 +391,158 @@ where
         }
     }
 
+    /// Returns how many bytes of pages are resident in memory, as
+    /// weighed against `cache_capacity` by their serialized size.
+    pub fn resident_bytes(&self) -> usize {
+        self.lru.resident_bytes()
+    }
+
+    /// Returns how far writes are ahead of the disk.
+    pub fn pressure(&self) -> Pressure {
+        Pressure {
+            unflushed_bytes: self.log.unflushed_bytes(),
+            max_unflushed_bytes: self.config.max_unflushed_bytes,
+            resident_bytes: self.resident_bytes(),
+            max_resident_bytes: self.config.max_resident_bytes,
+        }
+    }
+
+    // Holds a writer back while more than `max_unflushed_bytes` of
+    // the log isn't durable, by flushing until it no longer is,
+    // and while more than `max_resident_bytes` of pages are
+    // resident, by paging out the least recently used of them other
+    // than `pid`, which is about to be written to.
+    fn throttle(
+        &self,
+        pid: Option<PageID>,
+        guard: &Guard,
+    ) -> CacheResult<(), ()> {
+        if let Some(max) = self.config.max_unflushed_bytes {
+            let unflushed = self.log.unflushed_bytes();
+            if unflushed > max {
+                let _measure = Measure::new(&M.throttle);
+                let behind = (unflushed - max) as Lsn;
+                self.log
+                    .make_stable(self.log.stable_offset() + behind)?;
+            }
+        }
+        if let Some(max) = self.config.max_resident_bytes {
+            if self.lru.resident_bytes() > max {
+                let _measure = Measure::new(&M.throttle);
+                let to_evict = self.lru.shrink_to(max, pid);
+                self.page_out(to_evict, guard)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the highest LSN that has been made durable.
+    pub fn stable_lsn(&self) -> Lsn {
+        self.log.stable_offset()
+    }
+
+    /// Returns whether an IO error has stopped the log from being
+    /// written to, see `Log::was_poisoned`.
+    pub fn was_poisoned(&self) -> bool {
+        self.log.was_poisoned()
+    }
+
+    /// Reads back the updates logged from `lsn` up to, but not
+    /// including, `until`, oldest first, as the lsn of the message
+    /// each was logged in, the page it went to, and what it did.
+    /// The pages that a frag refers to, as the materializer's
+    /// `references` lists them, are filled into it as when it is
+    /// paged in. Only what is durable can be read back, so `until`
+    /// should be no more than `stable_lsn`. Writers aren't held
+    /// up meanwhile. Fails with `Error::Unsupported` if the segment
+    /// that `lsn` was written to has been reused since, as what was
+    /// written there can no longer be read back.
+    pub fn logged_between(
+        &self,
+        lsn: Lsn,
+        until: Lsn,
+    ) -> CacheResult<Vec<(Lsn, PageID, Logged<P>)>, ()> {
+        // like advance_snapshot, keep the log append-only while
+        // iterating over it
+        let _snapshot = self.last_snapshot.lock().unwrap();
+        self.log.with_sa(|sa| sa.pause_rewriting());
+
+        let io_buf_size = self.config.io_buf_size as Lsn;
+        let segment_lsn = lsn - (lsn % io_buf_size);
+        let first_segment = self.log.with_sa(|sa| {
+            sa.segment_snapshot_iter_from(segment_lsn)
+                .next()
+                .map(|(segment_lsn, _)| segment_lsn)
+        });
+        if first_segment != Some(segment_lsn) {
+            self.log.with_sa(|sa| sa.resume_rewriting());
+            return Err(Error::Unsupported(format!(
+                "the log segment holding lsn {} has been reused, \
+                 so what was written there can't be read back",
+                lsn
+            )));
+        }
+
+        let guard = pin();
+        let mut logged = vec![];
+        let mut res = Ok(());
+        for (msg_lsn, lid, bytes) in self.log.iter_from(segment_lsn) {
+            if msg_lsn < lsn {
+                continue;
+            }
+            if msg_lsn >= until {
+                break;
+            }
+            let res_update = deserialize::<LoggedUpdate<P>>(&*bytes);
+            let update = match res_update {
+                Ok(update) => update,
+                Err(_) => {
+                    res = Err(Error::Corruption { at: lid });
+                    break;
+                }
+            };
+            let frags = match update.update {
+                Update::Append(frag) | Update::Compact(frag) => {
+                    vec![(update.pid, frag)]
+                }
+                Update::Batch(frags) => frags,
+                Update::Free => {
+                    logged.push((msg_lsn, update.pid, Logged::Free));
+                    continue;
+                }
+                Update::Allocate => continue,
+            };
+            for (pid, mut frag) in frags {
+                let filled = self.fill_references(&mut frag, &guard);
+                if let Err(e) = filled {
+                    res = Err(e.danger_cast());
+                    break;
+                }
+                logged.push((msg_lsn, pid, Logged::Frag(frag)));
+            }
+            if res.is_err() {
+                break;
+            }
+        }
+
+        self.log.with_sa(|sa| sa.resume_rewriting());
+        res.map(|_| logged)
+    }
+
     /// Create a new page, trying to reuse old freed pages if possible
     /// to maximize underlying `Radix` pointer density.
     pub fn allocate<'g>(
         &self,
         guard: &'g Guard,
     ) -> CacheResult<PageID, ()> {
+        // checked before anything is taken from the free list,
+        // since the write would fail only after that
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't allocate a page in read-only mode".to_owned(),
+            ));
+        }
+
         let pid = self.free
             .lock()
             .unwrap()
@@ -442,6 +666,8 @@ where
         new: P,
         guard: &'g Guard,
     ) -> CacheResult<PagePtr<'g, P>, Option<PagePtr<'g, P>>> {
+        self.throttle(Some(pid), guard)
+            .map_err(|e| e.danger_cast())?;
         let stack_ptr = match self.inner.get(pid, guard) {
             None => return Err(Error::CasFailed(None)),
             Some(s) => s,
@@ -459,6 +685,7 @@ where
         let bytes = measure(&M.serialize, || {
             serialize(&prepend, Infinite).unwrap()
         });
+        let size = bytes.len();
         let log_reservation =
             self.log.reserve(bytes).map_err(|e| e.danger_cast())?;
         let lsn = log_reservation.lsn();
@@ -484,50 +711,275 @@ where
             // is waiting to acquire a new reservation blocked by this?
             log_reservation.complete().map_err(|e| e.danger_cast())?;
 
-            if let Some(to_clean) = to_clean {
-                match self.get(to_clean, guard)? {
-                    PageGet::Materialized(page, key) => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            key,
-                            Update::Compact(page),
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Free(key) => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            key,
-                            Update::Free,
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Allocated => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            Shared::null(),
-                            Update::Allocate,
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Unallocated => {
-                        panic!("get returned Unallocated");
-                    }
+            self.lru.wrote(pid, size, old.is_null());
+            self.after_link(to_clean, guard)?;
+        }
+
+        let new_head = result.map_err(|e| Error::CasFailed(Some(e)))?;
+
+        // a page isn't left with a longer chain than a read of it
+        // would consolidate, even if it's only ever written to
+        let chain_len = StackIter::from_ptr(new_head, guard).count();
+        if chain_len > self.config.page_consolidation_threshold {
+            if let PageGet::Materialized(_, head) =
+                self.page_in(pid, new_head, stack_ptr, guard)?
+            {
+                return Ok(head);
+            }
+        }
+        Ok(new_head)
+    }
+
+    /// Atomically appends a `PageFrag` to each of several distinct
+    /// pages, each given with the key it is expected to be linked
+    /// onto. They are logged as one record, so recovery replays
+    /// either every frag or none of them. Returns
+    /// `Err(CasFailed(()))` without linking anything if any page
+    /// no longer has its expected key, in which case the caller
+    /// should read the pages again and rebuild its frags. Until
+    /// every frag is linked, readers of these pages see none of
+    /// them, and other links to them fail.
+    pub fn link_all<'g>(
+        &self,
+        frags: Vec<(PageID, PagePtr<'g, P>, P)>,
+        guard: &'g Guard,
+    ) -> CacheResult<(), ()> {
+        self.throttle(None, guard)?;
+        let mut stacks = Vec::with_capacity(frags.len());
+        for &(pid, _, _) in &frags {
+            match self.inner.get(pid, guard) {
+                Some(s) => stacks.push(s),
+                None => return Err(Error::CasFailed(())),
+            }
+        }
+        if frags.is_empty() {
+            return Ok(());
+        }
+        // a page is read back from a batch by its pid, so it can
+        // only appear once
+        let mut pids: Vec<PageID> =
+            frags.iter().map(|&(pid, _, _)| pid).collect();
+        pids.sort();
+        pids.dedup();
+        if pids.len() != frags.len() {
+            return Err(Error::Unsupported(
+                "link_all takes at most one frag per page".to_owned(),
+            ));
+        }
+
+        // don't bother logging frags that can't be linked
+        let stale = frags.iter().zip(&stacks).any(
+            |(&(_, old, _), stack_ptr)| unsafe {
+                stack_ptr.deref().head(guard) != old
+            },
+        );
+        if stale {
+            return Err(Error::CasFailed(()));
+        }
+
+        let (olds, frags): (Vec<_>, Vec<_>) = frags
+            .into_iter()
+            .map(|(pid, old, frag)| (old, (pid, frag)))
+            .unzip();
+        let prepend: LoggedUpdate<P> = LoggedUpdate {
+            pid: frags[0].0,
+            update: Update::Batch(frags),
+        };
+        let bytes = measure(&M.serialize, || {
+            serialize(&prepend, Infinite).unwrap()
+        });
+        let log_reservation =
+            self.log.reserve(bytes).map_err(|e| e.danger_cast())?;
+        let lsn = log_reservation.lsn();
+        let lid = log_reservation.lid();
+
+        let frags = match prepend.update {
+            Update::Batch(frags) => frags,
+            _ => unreachable!(),
+        };
+
+        // Each frag is linked as pending, which keeps it from
+        // readers and makes any other link to its page fail until
+        // `linked` is set, when all of them become visible at
+        // once. If one page has changed, the ones already linked
+        // are taken back instead.
+        let linked = Arc::new(AtomicBool::new(false));
+        let mut sizes = Vec::with_capacity(frags.len());
+        let mut pending = Vec::with_capacity(frags.len());
+        let mut conflict = false;
+        for (((pid, frag), old), stack_ptr) in
+            frags.into_iter().zip(olds).zip(&stacks)
+        {
+            sizes.push((pid, serialized_size(&frag) as usize));
+            let stack = unsafe { stack_ptr.deref() };
+            let cache_entry = CacheEntry::Resident(frag, lsn, lid);
+            let res =
+                stack.cap_pending(old, cache_entry, &linked, guard);
+            match res {
+                Ok(head) => pending.push((stack, head)),
+                Err(_) => {
+                    conflict = true;
+                    break;
                 }
             }
+        }
 
-            let count = self.updates.fetch_add(1, SeqCst) + 1;
-            let should_snapshot =
-                count % self.config.snapshot_after_ops == 0;
-            if should_snapshot {
-                self.advance_snapshot().map_err(|e| e.danger_cast())?;
+        if conflict {
+            for (stack, head) in pending {
+                stack.revert(head, guard);
+            }
+            log_reservation.abort().map_err(|e| e.danger_cast())?;
+            return Err(Error::CasFailed(()));
+        }
+
+        linked.store(true, SeqCst);
+
+        let to_clean = self.log.with_sa(|sa| {
+            for &pid in &pids {
+                sa.mark_link(pid, lsn, lid);
             }
+            sa.clean(None)
+        });
+
+        // NB complete must happen AFTER calls to SA, as in link.
+        log_reservation.complete().map_err(|e| e.danger_cast())?;
+
+        for (pid, size) in sizes {
+            self.lru.wrote(pid, size, false);
         }
 
-        result.map_err(|e| Error::CasFailed(Some(e)))
+        self.after_link(to_clean, guard)
+            .map_err(|e| e.danger_cast())
+    }
+
+    // Rewrites a page that the segment accountant wants moved out
+    // of a segment it is cleaning, and snapshots periodically.
+    fn after_link<'g>(
+        &self,
+        to_clean: Option<PageID>,
+        guard: &'g Guard,
+    ) -> CacheResult<(), Option<PagePtr<'g, P>>> {
+        if let Some(to_clean) = to_clean {
+            self.rewrite_page(to_clean, guard)?;
+        }
+
+        let count = self.updates.fetch_add(1, SeqCst) + 1;
+        let should_snapshot =
+            count % self.config.snapshot_after_ops == 0;
+        if should_snapshot {
+            self.advance_snapshot().map_err(|e| e.danger_cast())?;
+        }
+
+        Ok(())
+    }
+
+    // Writes the whole of a page out again, so that the segments
+    // holding its old frags can be reused. A write racing with it
+    // makes it give up, leaving the page where it is.
+    fn rewrite_page<'g>(
+        &self,
+        pid: PageID,
+        guard: &'g Guard,
+    ) -> CacheResult<(), Option<PagePtr<'g, P>>> {
+        let (key, update) = match self.get(pid, guard)? {
+            PageGet::Materialized(page, key) => {
+                (key, Update::Compact(page))
+            }
+            PageGet::Free(key) => (key, Update::Free),
+            PageGet::Allocated => (Shared::null(), Update::Allocate),
+            PageGet::Unallocated => {
+                panic!("get returned Unallocated");
+            }
+        };
+        let _ =
+            self.replace_recurse_once(pid, key, update, guard, true);
+        Ok(())
+    }
+
+    /// Moves the live pages out of every inactive log segment in
+    /// which at most `threshold` of the pages written there are
+    /// still live, then flushes the log. The emptied segments are
+    /// reused by later writes, and the file is truncated when the
+    /// last one is reused. Returns how many bytes fewer the log's
+    /// segments take up than before, which may be less than was
+    /// freed if writes filled new segments meanwhile.
+    pub fn compact(&self, threshold: f64) -> CacheResult<usize, ()> {
+        let (in_use, pids) = self.log.with_sa(|sa| {
+            (sa.segments_in_use(), sa.drain_below(threshold))
+        });
+        let guard = pin();
+        for pid in pids {
+            self.rewrite_page(pid, &guard)
+                .map_err(|e| e.danger_cast())?;
+        }
+        self.log.flush()?;
+        let in_use_after =
+            self.log.with_sa(|sa| sa.segments_in_use());
+        let freed = in_use.saturating_sub(in_use_after);
+        Ok(freed * self.config.io_buf_size)
+    }
+
+    /// Writes a snapshot of the page table now, rather than
+    /// waiting for `snapshot_after_ops` operations, so that
+    /// recovery only replays the log written after this point.
+    /// Returns without writing one if another is under way.
+    pub fn snapshot(&self) -> CacheResult<(), ()> {
+        self.advance_snapshot()
+    }
+
+    /// Returns how many frags make up a page: its base and the
+    /// updates stacked on top of it since it was last consolidated.
+    pub fn chain_len(&self, pid: PageID) -> usize {
+        let guard = pin();
+        match self.inner.get(pid, &guard) {
+            None => 0,
+            Some(stack_ptr) => {
+                let head = unsafe { stack_ptr.deref().head(&guard) };
+                StackIter::from_ptr(head, &guard).count()
+            }
+        }
+    }
+
+    /// Reads back every frag of a page from the log, checking its
+    /// checksum, and returns how many were read along with the
+    /// log ids of any that failed. The log should be flushed first,
+    /// as frags that are still buffered can't be read back.
+    pub fn verify_page(
+        &self,
+        pid: PageID,
+    ) -> CacheResult<(usize, Vec<LogID>), ()> {
+        let guard = pin();
+        let stack_ptr = match self.inner.get(pid, &guard) {
+            None => return Ok((0, vec![])),
+            Some(s) => s,
+        };
+        let head = unsafe { stack_ptr.deref().head(&guard) };
+
+        let mut read = 0;
+        let mut failed = vec![];
+        for cache_entry_ptr in StackIter::from_ptr(head, &guard) {
+            let (lsn, lid) = match *cache_entry_ptr {
+                CacheEntry::Resident(_, lsn, lid)
+                | CacheEntry::MergedResident(_, lsn, lid)
+                | CacheEntry::PartialFlush(lsn, lid)
+                | CacheEntry::Flush(lsn, lid)
+                | CacheEntry::Free(lsn, lid) => (lsn, lid),
+            };
+            read += 1;
+            let ok = match self.log.read(lsn, lid)? {
+                LogRead::Flush(read_lsn, _, _) => read_lsn == lsn,
+                _ => false,
+            };
+            if !ok {
+                failed.push(lid);
+            }
+        }
+        Ok((read, failed))
+    }
+
+    /// Reports how the log's segments are used.
+    pub fn segment_stats(&self) -> SegmentStats {
+        self.log.with_sa(|sa| sa.stats())
     }
 
     /// Replace an existing page with a different set of `PageFrag`s.
@@ -541,6 +993,8 @@ where
         new: P,
         guard: &'g Guard,
     ) -> CacheResult<PagePtr<'g, P>, Option<PagePtr<'g, P>>> {
+        self.throttle(Some(pid), guard)
+            .map_err(|e| e.danger_cast())?;
         self.replace_recurse_once(
             pid,
             old,
@@ -571,6 +1025,7 @@ where
         let bytes = measure(&M.serialize, || {
             serialize(&replace, Infinite).unwrap()
         });
+        let size = bytes.len();
         let log_reservation =
             self.log.reserve(bytes).map_err(|e| e.danger_cast())?;
         let lsn = log_reservation.lsn();
@@ -615,40 +1070,13 @@ where
             // the segment to inactive, resulting in a race otherwise.
             log_reservation.complete().map_err(|e| e.danger_cast())?;
 
+            if !node.is_null() {
+                self.lru.wrote(pid, size, true);
+            }
+
             if let Some(to_clean) = to_clean {
                 assert_ne!(pid, to_clean);
-                match self.get(to_clean, guard)? {
-                    PageGet::Materialized(page, key) => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            key,
-                            Update::Compact(page),
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Free(key) => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            key,
-                            Update::Free,
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Allocated => {
-                        let _ = self.replace_recurse_once(
-                            to_clean,
-                            Shared::null(),
-                            Update::Allocate,
-                            guard,
-                            true,
-                        );
-                    }
-                    PageGet::Unallocated => {
-                        panic!("get returned Unallocated");
-                    }
-                }
+                self.rewrite_page(to_clean, guard)?;
             }
 
             let count = self.updates.fetch_add(1, SeqCst) + 1;
@@ -713,6 +1141,8 @@ where
                     if lids.is_empty() {
                         // Short circuit merging and fix-up if we only
                         // have one frag.
+                        M.cache_hit();
+                        self.lru.touched(pid);
                         return Ok(PageGet::Materialized(
                             page_frag.clone(),
                             head,
@@ -750,7 +1180,7 @@ where
             {
                 let pulled_res: Vec<_> = to_pull
                     .par_iter()
-                    .map(|&(lsn, lid)| self.rayon_pull(lsn, lid))
+                    .map(|&(lsn, lid)| self.rayon_pull(pid, lsn, lid))
                     .collect();
 
                 for res in pulled_res {
@@ -761,8 +1191,18 @@ where
 
             #[cfg(not(feature = "rayon"))]
             for &(lsn, lid) in to_pull {
-                fetched.push(self.pull(lsn, lid)?);
+                fetched.push(self.pull(pid, lsn, lid)?);
             }
+
+            for frag in &mut fetched {
+                self.fill_references(frag, guard)?;
+            }
+        }
+
+        if fetched.is_empty() {
+            M.cache_hit();
+        } else {
+            M.cache_missed();
         }
 
         let combined: Vec<&P> = to_merge
@@ -772,10 +1212,27 @@ where
             .rev()
             .collect();
 
-        let merged =
+        let merge_res =
             measure(&M.merge_page, || self.t.merge(&*combined));
+        let merged = match merge_res {
+            Ok(merged) => merged,
+            Err(e) => {
+                // combined runs from the oldest frag, lids from the
+                // newest
+                let newest_first =
+                    combined.len().saturating_sub(e.idx + 1);
+                let (_, lid) = lids[newest_first];
+                error!(
+                    "failed to merge pid {} at lid {}: {}",
+                    pid, lid, e.reason
+                );
+                return Err(Error::Corruption { at: lid });
+            }
+        };
 
-        let size = std::mem::size_of_val(&merged);
+        // pages are weighed against cache_capacity by their
+        // serialized size, which tracks the bytes they hold
+        let size = serialized_size(&merged) as usize;
         let to_evict = self.lru.accessed(pid, size);
         trace!(
             "accessed pid {} -> paging out pid {:?}",
@@ -797,7 +1254,10 @@ where
                 guard,
                 true,
             ) {
-                Ok(new_head) => head = new_head,
+                Ok(new_head) => {
+                    M.consolidated();
+                    head = new_head;
+                }
                 Err(Error::CasFailed(None)) => {
                     return Ok(PageGet::Unallocated)
                 }
@@ -942,19 +1402,46 @@ where
     #[cfg(feature = "rayon")]
     fn rayon_pull<'g>(
         &self,
+        pid: PageID,
         lsn: Lsn,
         lid: LogID,
     ) -> CacheResult<P, Option<RayonPagePtr<'g, P>>> {
-        self.pull(lsn, lid).map_err(|e1| e1.danger_cast())
+        self.pull(pid, lsn, lid).map_err(|e1| e1.danger_cast())
+    }
+
+    // Reads in the pages that a frag pulled from the log refers to,
+    // and hands them to the materializer to fill into it.
+    fn fill_references<'g>(
+        &self,
+        frag: &mut P,
+        guard: &'g Guard,
+    ) -> CacheResult<(), Option<PagePtr<'g, P>>> {
+        let references = self.t.references(frag);
+        if references.is_empty() {
+            return Ok(());
+        }
+        let mut pages = Vec::with_capacity(references.len());
+        for pid in references {
+            match self.get(pid, guard)? {
+                PageGet::Materialized(page, _) => {
+                    pages.push(Some(page))
+                }
+                _ => pages.push(None),
+            }
+        }
+        self.t.fill(frag, pages);
+        Ok(())
     }
 
     fn pull<'g>(
         &self,
+        pid: PageID,
         lsn: Lsn,
         lid: LogID,
     ) -> CacheResult<P, Option<PagePtr<'g, P>>> {
         trace!("pulling lsn {} lid {} from disk", lsn, lid);
         let _measure = Measure::new(&M.pull);
+        M.paged_in();
         let bytes = match self.log.read(lsn, lid).map_err(|_| ()) {
             Ok(LogRead::Flush(read_lsn, data, _len)) => {
                 assert_eq!(
@@ -978,6 +1465,16 @@ where
         match logged_update.update {
             Update::Compact(page_frag)
             | Update::Append(page_frag) => Ok(page_frag),
+            Update::Batch(frags) => frags
+                .into_iter()
+                .find(|&(batch_pid, _)| batch_pid == pid)
+                .map(|(_, page_frag)| page_frag)
+                .ok_or_else(|| {
+                    Error::ReportableBug(format!(
+                        "pid {} is missing from batch at lid {}",
+                        pid, lid
+                    ))
+                }),
             _ => {
                 return Err(Error::ReportableBug(
                     "non-append/compact found in pull".to_owned(),
@@ -37,7 +37,7 @@ impl Pio for std::fs::File {
         mut offset: LogID,
     ) -> io::Result<()> {
         while !buf.is_empty() {
-            match self.read_at(buf, offset) {
+            match FileExt::read_at(self, buf, offset) {
                 Ok(0) => break,
                 Ok(n) => {
                     offset += n as LogID;
@@ -65,7 +65,7 @@ impl Pio for std::fs::File {
         mut offset: LogID,
     ) -> io::Result<()> {
         while !buf.is_empty() {
-            match self.write_at(buf, offset) {
+            match FileExt::write_at(self, buf, offset) {
                 Ok(0) => {
                     return Err(io::Error::new(
                         io::ErrorKind::WriteZero,
@@ -1,10 +1,6 @@
-use std::fs::File;
-
 #[cfg(feature = "zstd")]
 use zstd::block::decompress;
 
-use super::Pio;
-
 use super::*;
 
 pub(crate) trait LogReader {
@@ -26,12 +22,11 @@ pub(crate) trait LogReader {
     fn read_message(
         &self,
         id: LogID,
-        segment_len: usize,
-        use_compression: bool,
+        config: &Config,
     ) -> CacheResult<LogRead, ()>;
 }
 
-impl LogReader for File {
+impl<S: Storage + ?Sized> LogReader for S {
     fn read_segment_header(
         &self,
         lid: LogID,
@@ -39,7 +34,7 @@ impl LogReader for File {
         trace!("reading segment header at {}", lid);
 
         let mut seg_header_buf = [0u8; SEG_HEADER_LEN];
-        self.pread_exact(&mut seg_header_buf, lid)?;
+        self.read_at(&mut seg_header_buf, lid)?;
 
         Ok(seg_header_buf.into())
     }
@@ -51,7 +46,7 @@ impl LogReader for File {
         trace!("reading segment trailer at {}", lid);
 
         let mut seg_trailer_buf = [0u8; SEG_TRAILER_LEN];
-        self.pread_exact(&mut seg_trailer_buf, lid)?;
+        self.read_at(&mut seg_trailer_buf, lid)?;
 
         Ok(seg_trailer_buf.into())
     }
@@ -61,7 +56,7 @@ impl LogReader for File {
         lid: LogID,
     ) -> CacheResult<MessageHeader, ()> {
         let mut msg_header_buf = [0u8; MSG_HEADER_LEN];
-        self.pread_exact(&mut msg_header_buf, lid)?;
+        self.read_at(&mut msg_header_buf, lid)?;
 
         Ok(msg_header_buf.into())
     }
@@ -70,10 +65,10 @@ impl LogReader for File {
     fn read_message(
         &self,
         lid: LogID,
-        segment_len: usize,
-        _use_compression: bool,
+        config: &Config,
     ) -> CacheResult<LogRead, ()> {
         let _measure = Measure::new(&M.read);
+        let segment_len = config.io_buf_size;
         let seg_start =
             lid / segment_len as LogID * segment_len as LogID;
         trace!(
@@ -115,10 +110,10 @@ impl LogReader for File {
         unsafe {
             buf.set_len(header.len);
         }
-        self.pread_exact(&mut buf, lid + MSG_HEADER_LEN as LogID)?;
+        self.read_at(&mut buf, lid + MSG_HEADER_LEN as LogID)?;
 
-        let checksum = crc16_arr(&buf);
-        if checksum != header.crc16 {
+        let checksum = crc32(&buf);
+        if checksum != header.crc32 {
             trace!(
                 "read a message with a bad checksum with header {:?}",
                 header
@@ -138,17 +133,33 @@ impl LogReader for File {
             _ => {}
         }
 
+        // the checksum covers the sealed bytes, so a message that
+        // passed it but won't open was tampered with
+        let buf = match config.cipher {
+            Some(ref cipher) => match cipher.0.open(&buf) {
+                Some(buf) => buf,
+                None => return Ok(LogRead::Corrupted(header.len)),
+            },
+            None => buf,
+        };
+
         #[cfg(feature = "zstd")]
         let res = {
-            if _use_compression {
+            if config.use_compression {
                 let _measure = Measure::new(&M.decompress);
-                Ok(LogRead::Flush(
-                    header.lsn,
-                    decompress(&*buf, segment_len).unwrap(),
-                    len,
-                ))
+                // a message that passed its checksum but won't
+                // decompress is treated like a torn one, rather
+                // than panicking during recovery
+                match decompress(&*buf, segment_len) {
+                    Ok(buf) => Ok(LogRead::Flush(
+                        header.lsn,
+                        buf,
+                        header.len,
+                    )),
+                    Err(_) => Ok(LogRead::Corrupted(header.len)),
+                }
             } else {
-                Ok(LogRead::Flush(header.lsn, buf, len))
+                Ok(LogRead::Flush(header.lsn, buf, header.len))
             }
         };
 
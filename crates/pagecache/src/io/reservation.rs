@@ -19,7 +19,9 @@ impl<'a> Drop for Reservation<'a> {
         // We auto-abort if the user never uses a reservation.
         let should_flush = !self.data.is_empty() && !self.flushed;
         if should_flush {
-            self.flush(false).unwrap();
+            if let Err(e) = self.flush(false) {
+                error!("failed to abort dropped reservation: {}", e);
+            }
         }
     }
 }
@@ -62,7 +62,6 @@
 //!    we have encountered a lost segment, and we will not
 //!    continue the recovery past the detected gap.
 use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
-use std::fs::File;
 use std::mem;
 use std::sync::{Arc, Mutex};
 
@@ -129,6 +128,26 @@ pub enum SegmentState {
     Draining,
 }
 
+/// How the log's segments are used, as reported by
+/// `PageCache::segment_stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    /// Segments that are free for reuse.
+    pub free: usize,
+    /// Segments that are being written to.
+    pub active: usize,
+    /// Segments that are full and hold live pages.
+    pub inactive: usize,
+    /// Segments whose pages are being moved elsewhere so that
+    /// the segment can be freed.
+    pub draining: usize,
+    /// Pages written to segments in use that are still live there.
+    pub live_pages: usize,
+    /// Pages written to segments in use that have since been
+    /// rewritten elsewhere or freed.
+    pub dead_pages: usize,
+}
+
 use self::SegmentState::*;
 
 impl Default for SegmentState {
@@ -569,10 +588,21 @@ impl SegmentAccountant {
                 lsn, lid
             );
             to_zero.push(lsn);
+            if self.config.read_only {
+                // forgetting the segment is enough to recover
+                // correctly, and another process may still be
+                // writing it
+                continue;
+            }
             let f = self.config.file()?;
             maybe_fail!("zero garbage segment");
-            f.pwrite_all(&*vec![EVIL_BYTE; SEG_HEADER_LEN], lid)?;
-            f.sync_all()?;
+            let len = std::cmp::max(
+                SEG_HEADER_LEN,
+                write_align(&self.config),
+            );
+            f.write_at(&*vec![EVIL_BYTE; len], lid)?;
+            f.sync()?;
+            M.fsynced();
             maybe_fail!("zero garbage segment post");
         }
 
@@ -759,6 +789,65 @@ impl SegmentAccountant {
         }
     }
 
+    /// Called by `PageCache::compact`. Starts draining every
+    /// inactive segment in which at most `threshold` of the pages
+    /// written there are still live, and returns the pages left in
+    /// all of the draining segments, to be rewritten elsewhere.
+    pub fn drain_below(&mut self, threshold: f64) -> Vec<PageID> {
+        let mut pids = BTreeSet::new();
+        for idx in 0..self.segments.len() {
+            let lsn = match self.segments[idx].lsn {
+                Some(lsn) => lsn,
+                None => continue,
+            };
+            let segment_start =
+                (idx * self.config.io_buf_size) as LogID;
+            if self.segments[idx].is_inactive()
+                && self.segments[idx].live_pct() <= threshold
+            {
+                self.segments[idx].inactive_to_draining(lsn);
+                self.to_clean.insert(segment_start);
+                self.possibly_clean_or_free_segment(idx, lsn);
+            }
+            if self.segments[idx].is_draining() {
+                pids.extend(self.segments[idx].present.iter());
+            }
+        }
+        pids.into_iter().collect()
+    }
+
+    /// The number of segments that aren't free for reuse.
+    pub fn segments_in_use(&self) -> usize {
+        self.segments.iter().filter(|s| s.state != Free).count()
+    }
+
+    /// Counts the segments in each state, and the pages in those
+    /// that aren't free.
+    pub fn stats(&self) -> SegmentStats {
+        let mut stats = SegmentStats::default();
+        for segment in &self.segments {
+            match segment.state {
+                Free => {
+                    stats.free += 1;
+                    continue;
+                }
+                Active => stats.active += 1,
+                Inactive => stats.inactive += 1,
+                Draining => stats.draining += 1,
+            }
+            // removals from the active segment are deferred until
+            // it becomes inactive, but the pages are already dead
+            stats.live_pages += segment
+                .present
+                .iter()
+                .filter(|pid| !segment.deferred_remove.contains(pid))
+                .count();
+            stats.dead_pages +=
+                segment.removed.len() + segment.deferred_remove.len();
+        }
+        stats
+    }
+
     /// Called by the `PageCache` to find pages that are in
     /// segments elligible for cleaning that it should
     /// try to rewrite elsewhere.
@@ -896,6 +985,8 @@ impl SegmentAccountant {
             "unaligned Lsn provided to next!"
         );
 
+        maybe_fail!("segment next");
+
         // pop free or add to end
         let lid = if self.pause_rewriting {
             self.bump_tip()
@@ -947,11 +1038,12 @@ impl SegmentAccountant {
         );
         let f = self.config.file()?;
         maybe_fail!("zero segment");
-        f.pwrite_all(
+        f.write_at(
             &*vec![EVIL_BYTE; self.config.io_buf_size],
             lid,
         )?;
-        f.sync_all()?;
+        f.sync()?;
+        M.fsynced();
         maybe_fail!("zero segment post");
 
         let last_given = self.safety_buffer[self.config.io_bufs - 1];
@@ -1051,8 +1143,10 @@ impl SegmentAccountant {
         debug!("truncating file to length {}", at);
 
         let f = self.config.file()?;
-        f.set_len(at)?;
-        f.sync_all().map_err(|e| e.into())
+        f.truncate(at)?;
+        f.sync()?;
+        M.fsynced();
+        Ok(())
     }
 
     fn ensure_ordering_initialized(&mut self) -> CacheResult<(), ()> {
@@ -1124,7 +1218,7 @@ pub fn scan_segment_lsns(
 
     // Check that the last <# io buffers> segments properly
     // link their previous segment pointers.
-    Ok(clean_tail_tears(ordering, config, &f))
+    Ok(clean_tail_tears(ordering, config, &*f))
 }
 
 // This ensures that the last <# io buffers> segments on
@@ -1135,7 +1229,7 @@ pub fn scan_segment_lsns(
 fn clean_tail_tears(
     mut ordering: BTreeMap<Lsn, LogID>,
     config: &Config,
-    f: &File,
+    f: &dyn Storage,
 ) -> BTreeMap<Lsn, LogID> {
     let safety_buffer = config.io_bufs;
     let logical_tail: Vec<Lsn> = ordering
@@ -1288,7 +1382,6 @@ pub fn raw_segment_iter_from(
         segment_base: None,
         segment_iter: segment_iter,
         segment_len: config.io_buf_size,
-        use_compression: config.use_compression,
         trailer: None,
     })
 }
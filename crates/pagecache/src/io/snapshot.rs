@@ -1,11 +1,18 @@
 use std::collections::{HashMap, HashSet};
 use std::io::{Read, Seek, Write};
 
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 #[cfg(feature = "zstd")]
 use zstd::block::{compress, decompress};
 
 use super::*;
 
+// how many log items are deserialized at once during recovery,
+// in parallel if the rayon feature is enabled, before they are
+// applied to the snapshot in order
+const REPLAY_BATCH: usize = 1024;
+
 /// A snapshot of the state required to quickly restart
 /// the `PageCache` and `SegmentAccountant`.
 /// TODO consider splitting `Snapshot` into separate
@@ -89,9 +96,9 @@ impl<R> Snapshot<R> {
     fn apply<P>(
         &mut self,
         materializer: &Materializer<PageFrag = P, Recovery = R>,
+        prepend: LoggedUpdate<P>,
         lsn: Lsn,
         log_id: LogID,
-        bytes: &[u8],
         io_buf_size: usize,
     ) where
         P: 'static
@@ -103,27 +110,6 @@ impl<R> Snapshot<R> {
             + Sync,
         R: Debug + Clone + Serialize + DeserializeOwned + Send,
     {
-        // unwrapping this because it's already passed the crc check
-        // in the log iterator
-        trace!(
-            "trying to deserialize buf for lid {} lsn {}",
-            log_id,
-            lsn
-        );
-        let deserialization = deserialize::<LoggedUpdate<P>>(&*bytes);
-
-        if let Err(e) = deserialization {
-            error!(
-                "failed to deserialize buffer for item in log: lsn {} \
-                    lid {}: {:?}",
-                lsn,
-                log_id,
-                e
-            );
-            return;
-        }
-
-        let prepend = deserialization.unwrap();
         let pid = prepend.pid;
 
         if pid >= self.max_pid {
@@ -134,34 +120,29 @@ impl<R> Snapshot<R> {
 
         match prepend.update {
             Update::Append(partial_page) => {
-                // Because we rewrite pages over time, we may have relocated
-                // a page's initial Compact to a later segment. We should skip
-                // over pages here unless we've encountered a Compact for them.
-                if let Some(lids) = self.pt.get_mut(&pid) {
-                    trace!(
-                        "append of pid {} at lid {} lsn {}",
+                self.append(
+                    materializer,
+                    pid,
+                    partial_page,
+                    lsn,
+                    log_id,
+                );
+            }
+            Update::Batch(frags) => {
+                // the log only holds whole records, so recovery
+                // sees every frag of a batch or none of them
+                for (pid, partial_page) in frags {
+                    if pid >= self.max_pid {
+                        self.max_pid = pid + 1;
+                    }
+                    self.append(
+                        materializer,
                         pid,
+                        partial_page,
+                        lsn,
                         log_id,
-                        lsn
                     );
-
-                    if lids.is_free() {
-                        trace!(
-                            "we have not yet encountered an \
-                             allocation of this page, skipping push"
-                        );
-                        return;
-                    }
-
-                    if let Some(r) =
-                        materializer.recover(&partial_page)
-                    {
-                        self.recovery = Some(r);
-                    }
-
-                    lids.push((lsn, log_id));
                 }
-                self.free.remove(&pid);
             }
             Update::Compact(partial_page) => {
                 trace!(
@@ -222,6 +203,53 @@ impl<R> Snapshot<R> {
         }
     }
 
+    fn append<P>(
+        &mut self,
+        materializer: &Materializer<PageFrag = P, Recovery = R>,
+        pid: PageID,
+        partial_page: P,
+        lsn: Lsn,
+        log_id: LogID,
+    ) where
+        P: 'static
+            + Debug
+            + Clone
+            + Serialize
+            + DeserializeOwned
+            + Send
+            + Sync,
+        R: Debug + Clone + Serialize + DeserializeOwned + Send,
+    {
+        // Because we rewrite pages over time, we may have relocated
+        // a page's initial Compact to a later segment. We should skip
+        // over pages here unless we've encountered a Compact for them.
+        if let Some(lids) = self.pt.get_mut(&pid) {
+            trace!(
+                "append of pid {} at lid {} lsn {}",
+                pid,
+                log_id,
+                lsn
+            );
+
+            if lids.is_free() {
+                trace!(
+                    "we have not yet encountered an \
+                     allocation of this page, skipping push"
+                );
+                return;
+            }
+
+            if let Some(r) =
+                materializer.recover(&partial_page)
+            {
+                self.recovery = Some(r);
+            }
+
+            lids.push((lsn, log_id));
+        }
+        self.free.remove(&pid);
+    }
+
     fn replace_pid(
         &mut self,
         pid: PageID,
@@ -258,6 +286,95 @@ impl<R> Snapshot<R> {
     }
 }
 
+fn deserialize_item<P>(
+    lsn: Lsn,
+    log_id: LogID,
+    bytes: &[u8],
+) -> Option<LoggedUpdate<P>>
+where
+    P: Serialize + DeserializeOwned,
+{
+    trace!(
+        "trying to deserialize buf for lid {} lsn {}",
+        log_id,
+        lsn
+    );
+    match deserialize::<LoggedUpdate<P>>(bytes) {
+        Ok(prepend) => Some(prepend),
+        Err(e) => {
+            error!(
+                "failed to deserialize buffer for item in log: \
+                 lsn {} lid {}: {:?}",
+                lsn,
+                log_id,
+                e
+            );
+            None
+        }
+    }
+}
+
+// Deserializes a batch of log items, which is where most of the
+// time in recovery goes, then applies them to the snapshot in Lsn
+// order, because later frags build on earlier ones.
+fn replay_batch<PM, P, R>(
+    snapshot: &mut Snapshot<R>,
+    materializer: &PM,
+    batch: &mut Vec<(Lsn, LogID, Vec<u8>)>,
+    io_buf_size: usize,
+) where
+    PM: Materializer<Recovery = R, PageFrag = P>,
+    P: 'static
+        + Debug
+        + Clone
+        + Serialize
+        + DeserializeOwned
+        + Send
+        + Sync,
+    R: Debug + Clone + Serialize + DeserializeOwned + Send,
+{
+    let updates: Vec<Option<LoggedUpdate<P>>> = if PM::is_null() {
+        batch.iter().map(|_| None).collect()
+    } else {
+        #[cfg(feature = "rayon")]
+        {
+            batch
+                .par_iter()
+                .map(|&(lsn, log_id, ref bytes)| {
+                    deserialize_item(lsn, log_id, bytes)
+                })
+                .collect()
+        }
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            batch
+                .iter()
+                .map(|&(lsn, log_id, ref bytes)| {
+                    deserialize_item(lsn, log_id, bytes)
+                })
+                .collect()
+        }
+    };
+
+    for ((lsn, log_id, _), update) in batch.drain(..).zip(updates) {
+        let segment_idx = log_id as SegmentID / io_buf_size;
+
+        // invalidate any removed pids
+        snapshot.replacements.remove(&segment_idx);
+
+        if let Some(prepend) = update {
+            snapshot.apply(
+                materializer,
+                prepend,
+                lsn,
+                log_id,
+                io_buf_size,
+            );
+        }
+    }
+}
+
 pub(super) fn advance_snapshot<PM, P, R>(
     iter: LogIter,
     mut snapshot: Snapshot<R>,
@@ -282,9 +399,9 @@ where
 
     let io_buf_size = config.io_buf_size;
 
-    for (lsn, log_id, bytes) in iter {
-        let segment_idx = log_id as SegmentID / io_buf_size;
+    let mut batch = Vec::with_capacity(REPLAY_BATCH);
 
+    for (lsn, log_id, bytes) in iter {
         trace!(
             "in advance_snapshot looking at item with lsn {} lid {}",
             lsn,
@@ -307,21 +424,29 @@ where
         snapshot.max_lsn = lsn;
         snapshot.last_lid = log_id;
 
-        // invalidate any removed pids
-        snapshot.replacements.remove(&segment_idx);
-
-        if !PM::is_null() {
-            snapshot.apply(
+        batch.push((lsn, log_id, bytes));
+        if batch.len() == REPLAY_BATCH {
+            replay_batch(
+                &mut snapshot,
                 &materializer,
-                lsn,
-                log_id,
-                &*bytes,
+                &mut batch,
                 io_buf_size,
             );
         }
     }
 
-    write_snapshot(config, &snapshot)?;
+    replay_batch(
+        &mut snapshot,
+        &materializer,
+        &mut batch,
+        io_buf_size,
+    );
+
+    // a read-only system recovers the same way every time it's
+    // opened, rather than saving its progress
+    if !config.read_only {
+        write_snapshot(config, &snapshot)?;
+    }
 
     trace!("generated new snapshot: {:?}", snapshot);
 
@@ -399,6 +524,17 @@ where
         return Ok(None);
     }
 
+    let buf = match config.cipher {
+        Some(ref cipher) => match cipher.0.open(&buf) {
+            Some(buf) => buf,
+            None => {
+                error!("failed to decrypt snapshot file {:?}", path);
+                return Ok(None);
+            }
+        },
+        None => buf,
+    };
+
     #[cfg(feature = "zstd")]
     let bytes = if config.use_compression {
         let len_expected: u64 =
@@ -434,6 +570,11 @@ where
     #[cfg(not(feature = "zstd"))]
     let bytes = raw_bytes;
 
+    let bytes = match config.cipher {
+        Some(ref cipher) => cipher.0.seal(&bytes),
+        None => bytes,
+    };
+
     let crc64: [u8; 8] =
         unsafe { std::mem::transmute(crc64(&*bytes)) };
     let len_bytes: [u8; 8] =
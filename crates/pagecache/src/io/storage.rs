@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::sync::{Arc, RwLock};
+
+use super::Pio;
+
+use super::*;
+
+/// Where the log keeps its segments. The log only ever reads and
+/// writes whole ranges at explicit offsets, so anything that can do
+/// that can hold it. `std::fs::File` is the default, used unless
+/// `ConfigBuilder::storage` provides another. The configuration
+/// and snapshots are still kept as files under the database's
+/// path.
+pub trait Storage: Send + Sync {
+    /// Fills `buf` with the bytes starting at `offset`, failing
+    /// with `UnexpectedEof` if any of them lie past the end of
+    /// the storage. Recovery scans segments until a read fails.
+    fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: LogID,
+    ) -> io::Result<()>;
+
+    /// Writes all of `buf` at `offset`, growing the storage if
+    /// needed.
+    fn write_at(&self, buf: &[u8], offset: LogID)
+        -> io::Result<()>;
+
+    /// Makes every completed write durable.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Cuts the storage off at `len` bytes, or zero-extends it to
+    /// `len` bytes.
+    fn truncate(&self, len: LogID) -> io::Result<()>;
+
+    /// Hints that the `len` bytes at `offset` are about to be read
+    /// sequentially. Does nothing unless overridden.
+    fn will_need(&self, _offset: LogID, _len: usize) {}
+}
+
+impl Storage for File {
+    fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        self.pread_exact(buf, offset)
+    }
+
+    fn write_at(
+        &self,
+        buf: &[u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        self.pwrite_all(buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn truncate(&self, len: LogID) -> io::Result<()> {
+        self.set_len(len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn will_need(&self, offset: LogID, len: usize) {
+        use std::os::unix::io::AsRawFd;
+
+        let ret = unsafe {
+            libc::posix_fadvise(
+                self.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if ret != 0 {
+            panic!(
+                "failed to call fadvise: {}",
+                std::io::Error::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+
+/// A `Storage` that keeps the log in memory, for databases whose
+/// contents don't need to outlive the process. Everything written
+/// to it is lost when the last `Config` using it is dropped.
+///
+/// # Examples
+///
+/// ```
+/// let config = pagecache::ConfigBuilder::new()
+///     .temporary(true)
+///     .segment_mode(pagecache::SegmentMode::Linear)
+///     .storage(pagecache::MemStorage::default())
+///     .build();
+/// let log = pagecache::Log::start_raw_log(config).unwrap();
+/// let (lsn, _lid) = log.write(b"hello".to_vec()).unwrap();
+/// log.make_stable(lsn).unwrap();
+/// let (_, _, buf) = log.iter_from(lsn).next().unwrap();
+/// assert_eq!(buf, b"hello".to_vec());
+/// ```
+#[derive(Debug, Default)]
+pub struct MemStorage {
+    bytes: RwLock<Vec<u8>>,
+}
+
+impl Storage for MemStorage {
+    fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        let bytes = self.bytes.read().unwrap();
+        let start = offset as usize;
+        let end = start + buf.len();
+        if end > bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        buf.copy_from_slice(&bytes[start..end]);
+        Ok(())
+    }
+
+    fn write_at(
+        &self,
+        buf: &[u8],
+        offset: LogID,
+    ) -> io::Result<()> {
+        let mut bytes = self.bytes.write().unwrap();
+        let start = offset as usize;
+        let end = start + buf.len();
+        if end > bytes.len() {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, len: LogID) -> io::Result<()> {
+        self.bytes.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// How the log file is written, set with `ConfigBuilder::io_backend`.
+/// It has no effect on a log kept in a custom `Storage`.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum IoBackend {
+    /// Write through the OS page cache, syncing the file on the
+    /// thread that flushed an IO buffer.
+    Buffered,
+    /// Open the log file with `O_DIRECT`, so writes bypass the OS
+    /// page cache, and hand writes and syncs to a background
+    /// thread, which makes all of the writes queued up since its
+    /// last sync durable with a single `fdatasync`. Concurrent
+    /// flushes of different IO buffers then share their syncs.
+    /// Each flush is padded out to whole blocks of 4096 bytes, so
+    /// a log that is flushed often fills its segments sooner.
+    /// Requires the `direct_io` feature, Linux, a filesystem that
+    /// supports `O_DIRECT`, and an `io_buf_size` that is a
+    /// multiple of 4096.
+    Direct,
+}
+
+/// The `Storage` a `ConfigBuilder` was given, shared by its
+/// clones.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct SharedStorage(pub Arc<dyn Storage>);
+
+impl Debug for SharedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedStorage")
+    }
+}
+
+// Storage can't be compared, so two are only equal if they are
+// clones of each other.
+impl PartialEq for SharedStorage {
+    fn eq(&self, other: &SharedStorage) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[test]
+fn test_mem_storage() {
+    let storage = MemStorage::default();
+    let mut buf = [0u8; 3];
+    assert!(storage.read_at(&mut buf, 0).is_err());
+
+    storage.write_at(&[1, 2, 3], 2).unwrap();
+    storage.read_at(&mut buf, 1).unwrap();
+    assert_eq!(buf, [0, 1, 2]);
+    assert!(storage.read_at(&mut buf, 3).is_err());
+
+    storage.truncate(3).unwrap();
+    storage.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, [0, 0, 1]);
+    assert!(storage.read_at(&mut buf, 1).is_err());
+}
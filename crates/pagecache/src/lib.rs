@@ -41,8 +41,25 @@ pub use ds::{Radix, Stack};
 /// general-purpose configuration
 pub use config::{Config, ConfigBuilder};
 pub use io::*;
+pub use registry::{
+    expiry_merge, MergeFn, MergeOperatorRegistry, MigrateFn, Registry,
+    SplittingMergeFn,
+};
 pub use result::{CacheResult, Error};
 
+/// process-wide counters of pagecache activity
+pub use metrics::{MetricsSink, MetricsSnapshot};
+#[doc(hidden)]
+pub use metrics::SharedMetricsSink;
+
+/// checksum used for protecting snapshots and other large buffers
+pub use hash::crc64;
+
+/// zstd block compression, for callers that compress their own
+/// data in addition to what the log compresses.
+#[cfg(feature = "zstd")]
+pub use zstd::block::{compress, decompress};
+
 macro_rules! maybe_fail {
     ($e:expr) => {
         #[cfg(feature = "failpoints")]
@@ -63,15 +80,18 @@ macro_rules! rep_no_copy {
 mod config;
 /// auxilliary data structures
 mod ds;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
 mod hash;
 mod io;
 mod metrics;
 mod periodic;
+mod registry;
 mod result;
 
 // use log::{Iter, MessageHeader, SegmentHeader, SegmentTrailer};
 use ds::*;
-use hash::{crc16_arr, crc64};
+use hash::{crc16_arr, crc32};
 use historian::Histo;
 use metrics::Metrics;
 
@@ -194,3 +214,4 @@ pub fn debug_delay() {
 pub type MergeOperator =
     fn(key: &[u8], last_value: Option<&[u8]>, new_merge: &[u8])
         -> Option<Vec<u8>>;
+
@@ -1,9 +1,78 @@
+use std::fmt::{self, Debug};
 use std::iter::repeat;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::{Acquire, Relaxed};
+use std::sync::Arc;
 
 use historian::Histo;
 
+use super::*;
+
+/// Counts of what every pagecache in this process has done since it
+/// started, as returned by `Metrics::snapshot`. Each count only
+/// grows, so a sink can report rates by subtracting the previous
+/// snapshot it was given.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Page reads answered from memory.
+    pub cache_hits: usize,
+    /// Page reads that had to read fragments from the log.
+    pub cache_misses: usize,
+    /// Page fragments read from the log.
+    pub page_ins: usize,
+    /// Pages whose fragments were consolidated into one.
+    pub consolidations: usize,
+    /// Tree nodes split in two.
+    pub splits: usize,
+    /// Space reserved in the log's buffers for new messages.
+    pub log_reservations: usize,
+    /// Calls to `Storage::sync` made by the log.
+    pub fsyncs: usize,
+    /// Tree operations retried after losing a race to update a
+    /// page.
+    pub tree_cas_retries: usize,
+    /// Log operations retried after losing a race for a buffer.
+    pub log_cas_retries: usize,
+    /// Values cut short to `max_value_len` as they were written to
+    /// a tree.
+    pub values_truncated: usize,
+}
+
+/// Receives a `MetricsSnapshot` every `metrics_every_ms`, once set
+/// with `ConfigBuilder::metrics_sink`, to forward it to a
+/// monitoring system such as Prometheus or statsd. It is called
+/// from a background thread, so it should not block for long.
+pub trait MetricsSink: Send + Sync {
+    /// Records the counts as they are now.
+    fn report(&self, metrics: &MetricsSnapshot);
+}
+
+/// The `MetricsSink` a `ConfigBuilder` was given, shared by its
+/// clones.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct SharedMetricsSink(pub Arc<dyn MetricsSink>);
+
+impl Debug for SharedMetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedMetricsSink")
+    }
+}
+
+// Sinks can't be compared, so two are only equal if they are
+// clones of each other.
+impl PartialEq for SharedMetricsSink {
+    fn eq(&self, other: &SharedMetricsSink) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl periodic::Callback for SharedMetricsSink {
+    fn call(&self) {
+        self.0.report(&M.snapshot());
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Metrics {
     pub advance_snapshot: Histo,
@@ -15,6 +84,7 @@ pub struct Metrics {
     pub page_in: Histo,
     pub merge_page: Histo,
     pub page_out: Histo,
+    pub throttle: Histo,
     pub pull: Histo,
     pub serialize: Histo,
     pub deserialize: Histo,
@@ -27,6 +97,14 @@ pub struct Metrics {
     pub read: Histo,
     pub tree_loops: AtomicUsize,
     pub log_loops: AtomicUsize,
+    pub cache_hits: AtomicUsize,
+    pub cache_misses: AtomicUsize,
+    pub page_ins: AtomicUsize,
+    pub consolidations: AtomicUsize,
+    pub splits: AtomicUsize,
+    pub log_reservations: AtomicUsize,
+    pub fsyncs: AtomicUsize,
+    pub values_truncated: AtomicUsize,
     pub accountant_lock: Histo,
     pub accountant_hold: Histo,
 }
@@ -40,6 +118,56 @@ impl Metrics {
         self.log_loops.fetch_add(1, Relaxed);
     }
 
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Relaxed);
+    }
+
+    pub fn cache_missed(&self) {
+        self.cache_misses.fetch_add(1, Relaxed);
+    }
+
+    pub fn paged_in(&self) {
+        self.page_ins.fetch_add(1, Relaxed);
+    }
+
+    pub fn consolidated(&self) {
+        self.consolidations.fetch_add(1, Relaxed);
+    }
+
+    pub fn split(&self) {
+        self.splits.fetch_add(1, Relaxed);
+    }
+
+    pub fn log_reserved(&self) {
+        self.log_reservations.fetch_add(1, Relaxed);
+    }
+
+    pub fn fsynced(&self) {
+        self.fsyncs.fetch_add(1, Relaxed);
+    }
+
+    pub fn value_truncated(&self) {
+        self.values_truncated.fetch_add(1, Relaxed);
+    }
+
+    /// Reads every counter. They are read one at a time while
+    /// other threads may be bumping them, so the counts can be
+    /// slightly out of step with each other.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            cache_hits: self.cache_hits.load(Acquire),
+            cache_misses: self.cache_misses.load(Acquire),
+            page_ins: self.page_ins.load(Acquire),
+            consolidations: self.consolidations.load(Acquire),
+            splits: self.splits.load(Acquire),
+            log_reservations: self.log_reservations.load(Acquire),
+            fsyncs: self.fsyncs.load(Acquire),
+            tree_cas_retries: self.tree_loops.load(Acquire),
+            log_cas_retries: self.log_loops.load(Acquire),
+            values_truncated: self.values_truncated.load(Acquire),
+        }
+    }
+
     pub fn print_profile(&self) {
         println!(
             "pagecache profile:\n\
@@ -107,6 +235,7 @@ impl Metrics {
             f("merge", &self.merge_page),
             f("pull", &self.pull),
             f("page_out", &self.page_out),
+            f("throttle", &self.throttle),
         ]);
 
         println!("{}", repeat("-").take(103).collect::<String>());
@@ -0,0 +1,166 @@
+use std::fmt::{self, Debug};
+use std::sync::{Arc, RwLock};
+
+/// A merge function held by a `MergeOperatorRegistry`. Unlike a
+/// `MergeOperator`, it may be a closure that captures state.
+pub type MergeFn =
+    dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>
+        + Send
+        + Sync;
+
+/// A merge function that also returns whether the node holding the
+/// merged value should be split right away. It may capture state.
+pub type SplittingMergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8])
+    -> (Option<Vec<u8>>, bool)
+    + Send
+    + Sync;
+
+/// A migrator held by a `Registry`. Like a `MergeFn`, it may capture
+/// state.
+pub type MigrateFn = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+/// Holds functions behind integer handles, so that a configuration
+/// can refer to one by id without turning a function pointer into a
+/// `usize` and back. Clones share the same set of functions.
+pub struct Registry<F: ?Sized> {
+    ops: Arc<RwLock<Vec<Arc<F>>>>,
+}
+
+/// Holds the merge functions of a configuration.
+pub type MergeOperatorRegistry = Registry<MergeFn>;
+
+impl<F: ?Sized> Registry<F> {
+    /// Adds a function, returning the handle it can be looked up
+    /// by. Handles are assigned in registration order, starting at
+    /// 0.
+    pub fn register(&self, op: Box<F>) -> usize {
+        let mut ops = self.ops.write().unwrap();
+        ops.push(Arc::from(op));
+        ops.len() - 1
+    }
+
+    /// Returns the function registered under `id`, if any.
+    pub fn get(&self, id: usize) -> Option<Arc<F>> {
+        self.ops.read().unwrap().get(id).cloned()
+    }
+}
+
+impl<F: ?Sized> Clone for Registry<F> {
+    fn clone(&self) -> Registry<F> {
+        Registry {
+            ops: self.ops.clone(),
+        }
+    }
+}
+
+impl<F: ?Sized> Default for Registry<F> {
+    fn default() -> Registry<F> {
+        Registry {
+            ops: Arc::new(RwLock::new(vec![])),
+        }
+    }
+}
+
+/// Builds a merge function for entries that expire. Every value,
+/// stored or merged in, starts with its expiry as a big-endian
+/// unix-millis timestamp, and one whose expiry is not after
+/// `now_fn()` is treated as absent, as are values shorter than 8
+/// bytes. Merging in a value with a payload after the expiry
+/// replaces the entry, while merging in a bare 8 byte expiry
+/// refreshes a live entry's expiry and keeps its payload. If the
+/// result has expired, or there is no live entry to refresh, the
+/// merge returns `None` and the record is removed.
+///
+/// Nothing sweeps expired entries: one is only physically removed
+/// when its key is next merged into, which is when the merge
+/// function gets to see it. Readers have to check the expiry
+/// themselves until then.
+pub fn expiry_merge<F>(
+    now_fn: F,
+) -> impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>
+       + Send
+       + Sync
+       + 'static
+where
+    F: Fn() -> u64 + Send + Sync + 'static,
+{
+    move |_key, old, new| {
+        let now = now_fn();
+        let live =
+            |value: &[u8]| expiry(value).map_or(false, |e| e > now);
+        if !live(new) {
+            return None;
+        }
+        if new.len() > 8 {
+            return Some(new.to_vec());
+        }
+        old.filter(|old| live(old)).map(|old| {
+            let mut refreshed = new.to_vec();
+            refreshed.extend_from_slice(&old[8..]);
+            refreshed
+        })
+    }
+}
+
+fn expiry(value: &[u8]) -> Option<u64> {
+    if value.len() < 8 {
+        return None;
+    }
+    let mut expiry = 0;
+    for &byte in &value[..8] {
+        expiry = (expiry << 8) | byte as u64;
+    }
+    Some(expiry)
+}
+
+impl<F: ?Sized> Debug for Registry<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Registry {{ len: {} }}",
+            self.ops.read().unwrap().len()
+        )
+    }
+}
+
+// Functions can't be compared, so two registries are only equal if
+// they are clones of each other.
+impl<F: ?Sized> PartialEq for Registry<F> {
+    fn eq(&self, other: &Registry<F>) -> bool {
+        Arc::ptr_eq(&self.ops, &other.ops)
+    }
+}
+
+#[test]
+fn test_registry_holds_capturing_closures() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let registry = MergeOperatorRegistry::default();
+
+    let plain = registry.register(Box::new(|_, old, new| {
+        let mut ret = old.map(|o| o.to_vec()).unwrap_or_default();
+        ret.extend_from_slice(new);
+        Some(ret)
+    }));
+    let counter = calls.clone();
+    let counting = registry.register(Box::new(move |_, _, new| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Some(new.to_vec())
+    }));
+    assert_eq!((plain, counting), (0, 1));
+
+    let shared = registry.clone();
+    let concat = shared.get(plain).unwrap();
+    assert_eq!(concat(b"k", Some(b"a"), b"b"), Some(b"ab".to_vec()));
+
+    let count = registry.get(counting).unwrap();
+    for _ in 0..3 {
+        assert_eq!(count(b"k", None, b"v"), Some(b"v".to_vec()));
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    assert!(registry.get(2).is_none());
+    assert_eq!(registry, shared);
+    assert!(registry != MergeOperatorRegistry::default());
+}
@@ -0,0 +1,1351 @@
+// A database of several trees in one pagecache and log. Besides
+// the default tree, named trees are listed in a catalog, itself a
+// tree whose first root is marked with `CATALOG_HEAD` so recovery
+// can find it. The catalog maps each name to the first root of its
+// tree, followed by the name of the key codec of a tree opened with
+// `open_typed_tree`, if any. The chain of root hoists recorded in
+// the pagecache's recovered state leads from the first root to the
+// tree's current root.
+// Another hidden tree, marked with `IDS_HEAD`, records how far
+// `generate_id` has reserved ids.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::{Deref, RangeBounds};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use portable::{DumpItem, DumpReader, DumpWriter};
+use tree::{
+    first_root, logged_writes, merged_right, now_millis,
+    quiesce_writes, start_pages, TreeCache, CATALOG_HEAD, IDS_HEAD,
+    NAMED_HEAD,
+};
+
+// How many ids `generate_id` reserves at a time. Each reservation
+// is a durable write, and a crash skips whatever was left of it.
+const IDS_PER_RESERVATION: u64 = 1_000_000;
+
+// The key in the id tree holding the end of the last reservation.
+const RESERVED_KEY: &[u8] = b"reserved";
+
+use super::*;
+
+/// A database holding a default `Tree`, which it derefs to, and
+/// any number of named trees. Every tree shares the same pagecache
+/// and log, and is recovered along with the others.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let db = sled::Db::start(config).unwrap();
+///
+/// let users = db.open_tree(b"users").unwrap();
+/// users.set(b"alice".to_vec(), vec![1]).unwrap();
+/// db.set(b"alice".to_vec(), vec![2]).unwrap();
+///
+/// assert_eq!(users.get(b"alice"), Ok(Some(vec![1].into())));
+/// assert_eq!(db.get(b"alice"), Ok(Some(vec![2].into())));
+/// assert_eq!(db.tree_names(), Ok(vec![b"users".to_vec()]));
+///
+/// assert_eq!(db.drop_tree(b"users"), Ok(true));
+/// assert_eq!(db.tree_names(), Ok(vec![]));
+/// ```
+#[derive(Clone)]
+pub struct Db {
+    pages: Arc<TreeCache>,
+    config: Config,
+    default: Tree,
+    // the root chains as recovered at startup, which lead to the
+    // current root of any tree not opened since
+    roots: Arc<Vec<(PageID, PageID)>>,
+    named: Arc<Mutex<Named>>,
+    ids: Arc<Mutex<Ids>>,
+    // compacts the log every `compact_every_ms`, if that is set,
+    // until every handle is dropped
+    _compactor: Arc<Option<Compactor>>,
+}
+
+// The state of `generate_id`. Every id below `reserved` may have
+// been handed out, either by this process or before a crash.
+struct Ids {
+    tree: Option<Tree>,
+    next: u64,
+    reserved: u64,
+}
+
+// Every handle to a named tree must share its root, so that a
+// root hoist through one is seen by the others. Opening and
+// dropping are serialized by the mutex around this.
+struct Named {
+    catalog: Option<Tree>,
+    trees: HashMap<Vec<u8>, Tree>,
+}
+
+/// The space a `Db` takes up and the shape of its trees, as
+/// reported by `Db::stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DbStats {
+    /// The bytes taken up by the database's files, as reported by
+    /// `Db::size_on_disk`.
+    pub size_on_disk: u64,
+    /// The default tree's stats.
+    pub default: TreeStats,
+    /// Each named tree's stats, in name order.
+    pub trees: Vec<(Vec<u8>, TreeStats)>,
+    /// How the log's segments are used. Only a live proportion of
+    /// each segment in use holds current pages, and `Db::compact`
+    /// frees the segments where that is low.
+    pub segments: SegmentStats,
+    /// The bytes of pages held in memory, as reported by
+    /// `Db::resident_bytes`.
+    pub resident_bytes: usize,
+}
+
+/// What `Db::verify_integrity` found in each of a database's
+/// trees.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// The default tree's report.
+    pub default: TreeIntegrity,
+    /// Each named tree's report, in name order.
+    pub trees: Vec<(Vec<u8>, TreeIntegrity)>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if no tree has any problems.
+    pub fn is_ok(&self) -> bool {
+        self.default.is_ok()
+            && self.trees.iter().all(|&(_, ref tree)| tree.is_ok())
+    }
+}
+
+/// A read-only view of every tree of a `Db` as it was when
+/// `Db::read_view` was called, which derefs to the view of the
+/// default tree. See `TreeView`.
+#[derive(Clone)]
+pub struct ReadView {
+    default: TreeView,
+    named: HashMap<Vec<u8>, TreeView>,
+}
+
+impl ReadView {
+    /// The view of the named tree, or `None` if there was no tree
+    /// called `name` when the view was taken.
+    pub fn tree(&self, name: &[u8]) -> Option<&TreeView> {
+        self.named.get(name)
+    }
+
+    // The view of every tree, named or `None` for the default tree,
+    // with the default tree first and the rest in name order.
+    fn trees(&self) -> Vec<(Option<Vec<u8>>, &TreeView)> {
+        let mut names: Vec<&Vec<u8>> = self.named.keys().collect();
+        names.sort();
+        let mut trees = vec![(None, &self.default)];
+        for name in names {
+            trees.push((Some(name.clone()), &self.named[name]));
+        }
+        trees
+    }
+}
+
+impl Deref for ReadView {
+    type Target = TreeView;
+
+    fn deref(&self) -> &TreeView {
+        &self.default
+    }
+}
+
+unsafe impl Send for Db {}
+unsafe impl Sync for Db {}
+
+impl Deref for Db {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.default
+    }
+}
+
+impl Db {
+    /// Load existing or create a new `Db`.
+    pub fn start(config: Config) -> DbResult<Db, ()> {
+        let pages = start_pages(&config)?;
+        let roots = pages.recovered_state().unwrap_or_default();
+
+        let default = Tree::start_default(
+            pages.clone(),
+            config.clone(),
+            &roots,
+        )?;
+        let catalog = first_root(&roots, CATALOG_HEAD).map(|first| {
+            Tree::open(pages.clone(), config.clone(), &roots, first)
+        });
+        let id_tree = first_root(&roots, IDS_HEAD).map(|first| {
+            Tree::open(pages.clone(), config.clone(), &roots, first)
+        });
+        // nothing below the last reservation can be handed out
+        // again, so the next id starts a new one
+        let reserved = match id_tree {
+            Some(ref tree) => match tree.get(RESERVED_KEY)? {
+                Some(reserved) => decode_u64(&reserved)?,
+                None => 0,
+            },
+            None => 0,
+        };
+
+        let compactor = Compactor::start(&pages, &config);
+
+        Ok(Db {
+            pages: pages,
+            config: config,
+            default: default,
+            roots: Arc::new(roots),
+            named: Arc::new(Mutex::new(Named {
+                catalog: catalog,
+                trees: HashMap::new(),
+            })),
+            ids: Arc::new(Mutex::new(Ids {
+                tree: id_tree,
+                next: reserved,
+                reserved: reserved,
+            })),
+            _compactor: Arc::new(compactor),
+        })
+    }
+
+    /// Moves the live pages out of every segment of the log that
+    /// holds mostly dead data, so the space can be reused, and
+    /// returns how many bytes were freed. A segment is compacted
+    /// when at most `compaction_threshold` of the pages written to
+    /// it are still live there. Writes usually do a little of this
+    /// as they go, but a database that is no longer being written
+    /// to keeps whatever dead data it has until it is compacted.
+    /// Setting `compact_every_ms` compacts in the background.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.set(b"a".to_vec(), vec![1]).unwrap();
+    /// let _freed = db.compact().unwrap();
+    /// assert_eq!(db.get(b"a"), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn compact(&self) -> DbResult<usize, ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't compact in read-only mode".to_owned(),
+            ));
+        }
+        self.pages.compact(self.config.compaction_threshold)
+    }
+
+    /// Writes a snapshot of the page table, so that the next start
+    /// only replays the log written after it. Snapshots are also
+    /// written every `snapshot_after_ops` operations, so this is
+    /// only needed to shorten recovery, e.g. before a planned
+    /// restart.
+    pub fn snapshot(&self) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't snapshot in read-only mode".to_owned(),
+            ));
+        }
+        self.pages.snapshot()
+    }
+
+    /// Returns a view of every tree as it is now, which gets and
+    /// scans read from while writers carry on. A long scan through
+    /// it sees a single point in time across all trees, rather than
+    /// a mix of old and new records. Taking it waits for the writes
+    /// in flight to finish, without holding up new ones. While it
+    /// is open, writes to any tree save the values
+    /// they overwrite for it, and `remove_range`, `clear`,
+    /// `bulk_load`, writes with a TTL and `drop_tree` fail with
+    /// `Error::Unsupported`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.set(b"a".to_vec(), vec![1]).unwrap();
+    ///
+    /// let view = db.read_view().unwrap();
+    /// db.set(b"a".to_vec(), vec![2]).unwrap();
+    /// db.set(b"b".to_vec(), vec![2]).unwrap();
+    /// assert_eq!(view.get(b"a"), Ok(Some(vec![1].into())));
+    /// assert_eq!(view.iter().count(), 1);
+    /// assert_eq!(db.get(b"a"), Ok(Some(vec![2].into())));
+    /// ```
+    pub fn read_view(&self) -> DbResult<ReadView, ()> {
+        self.with_trees(|trees| {
+            let mut named = HashMap::new();
+            for &(ref name, ref tree) in &trees[1..] {
+                let name = name.clone().unwrap_or_default();
+                named.insert(name, TreeView::new(tree));
+            }
+            let view = ReadView {
+                default: TreeView::new(&trees[0].1),
+                named: named,
+            };
+            // every view is open before any tree's writes are
+            // waited for, so a write to several trees can't be
+            // seen through some of the views and not others
+            let trees: Vec<&Tree> =
+                trees.iter().map(|&(_, ref tree)| tree).collect();
+            quiesce_writes(&trees);
+            Ok(view)
+        })
+    }
+
+    /// Returns the bytes taken up by the database's files: the log,
+    /// the configuration and any snapshots. A log kept by a
+    /// `Storage` other than a file isn't counted. The log grows a
+    /// segment at a time, and is only truncated when its last
+    /// segments are freed, so this can stay high after keys are
+    /// removed until `compact` runs.
+    pub fn size_on_disk(&self) -> DbResult<u64, ()> {
+        Ok(self.config.size_on_disk()?)
+    }
+
+    /// Returns how many bytes of pages are held in memory, shared
+    /// by every tree. Pages are weighed by their serialized size,
+    /// and once this passes `cache_capacity` the least recently
+    /// used ones are paged out.
+    pub fn resident_bytes(&self) -> usize {
+        self.pages.resident_bytes()
+    }
+
+    /// Returns the space the database takes up, on disk and in its
+    /// log's segments, and the shape of each of its trees. Each
+    /// tree is walked in turn, holding up writes to it meanwhile,
+    /// so the numbers of different trees may come from different
+    /// points in time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users").unwrap();
+    /// users.set(b"alice".to_vec(), vec![1]).unwrap();
+    /// db.flush().unwrap();
+    ///
+    /// let stats = db.stats().unwrap();
+    /// assert!(stats.size_on_disk > 0);
+    /// assert_eq!(stats.default.records, 0);
+    /// assert_eq!(stats.trees[0].0, b"users".to_vec());
+    /// assert_eq!(stats.trees[0].1.records, 1);
+    /// ```
+    pub fn stats(&self) -> DbResult<DbStats, ()> {
+        let trees = {
+            let mut named = self.named.lock().unwrap();
+            let mut trees = vec![];
+            for name in self.names(&named)? {
+                let tree = self.open_named(&mut named, &name)?;
+                trees.push((name, tree));
+            }
+            trees
+        };
+        let mut tree_stats = Vec::with_capacity(trees.len());
+        for (name, tree) in trees {
+            tree_stats.push((name, tree.stats()?));
+        }
+        Ok(DbStats {
+            size_on_disk: self.size_on_disk()?,
+            default: self.default.stats()?,
+            trees: tree_stats,
+            segments: self.pages.segment_stats(),
+            resident_bytes: self.resident_bytes(),
+        })
+    }
+
+    /// Checks the structure of every tree and the checksums of the
+    /// log frags that make up their pages, as
+    /// `Tree::verify_integrity` does for one tree. Meant to be run
+    /// after an unclean shutdown, before trusting the data. Like
+    /// `stats`, each tree is walked in turn, holding up writes to
+    /// it meanwhile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.open_tree(b"users").unwrap();
+    /// let report = db.verify_integrity().unwrap();
+    /// assert!(report.is_ok());
+    /// assert_eq!(report.trees[0].0, b"users".to_vec());
+    /// ```
+    pub fn verify_integrity(&self) -> DbResult<IntegrityReport, ()> {
+        let trees = {
+            let mut named = self.named.lock().unwrap();
+            let mut trees = vec![];
+            for name in self.names(&named)? {
+                let tree = self.open_named(&mut named, &name)?;
+                trees.push((name, tree));
+            }
+            trees
+        };
+        let mut reports = Vec::with_capacity(trees.len());
+        for (name, tree) in trees {
+            reports.push((name, tree.verify_integrity()?));
+        }
+        Ok(IntegrityReport {
+            default: self.default.verify_integrity()?,
+            trees: reports,
+        })
+    }
+
+    /// Returns counts of cache hits and misses, page-ins,
+    /// consolidations, splits, log reservations, fsyncs and CAS
+    /// retries. They are shared by every database in the process,
+    /// and count from when the first one started. To forward them
+    /// to a monitoring system as they change, set a
+    /// `ConfigBuilder::metrics_sink`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let before = db.metrics();
+    /// db.set(b"a".to_vec(), vec![1]).unwrap();
+    /// db.get(b"a").unwrap();
+    /// let after = db.metrics();
+    /// assert!(after.log_reservations > before.log_reservations);
+    /// assert!(after.cache_hits > before.cache_hits);
+    /// ```
+    pub fn metrics(&self) -> MetricsSnapshot {
+        M.snapshot()
+    }
+
+    /// Returns a new id, greater than every id returned before,
+    /// even by an earlier run of the database. Ids are reserved in
+    /// blocks of a million, each reservation flushed to disk before
+    /// any id in it is handed out, so most calls only take a lock.
+    /// After a crash or restart, whatever was left of the last
+    /// block is skipped, so ids are unique and increasing, but not
+    /// dense.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let a = db.generate_id().unwrap();
+    /// let b = db.generate_id().unwrap();
+    /// assert!(b > a);
+    /// ```
+    pub fn generate_id(&self) -> DbResult<u64, ()> {
+        let mut ids = self.ids.lock().unwrap();
+        if ids.next == ids.reserved {
+            let reserved = ids.reserved + IDS_PER_RESERVATION;
+            self.reserve_ids(&mut ids, reserved)?;
+        }
+        let id = ids.next;
+        ids.next += 1;
+        Ok(id)
+    }
+
+    // Durably reserves every id below `reserved`.
+    fn reserve_ids(
+        &self,
+        ids: &mut Ids,
+        reserved: u64,
+    ) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't reserve ids in read-only mode".to_owned(),
+            ));
+        }
+        if ids.tree.is_none() {
+            ids.tree = Some(Tree::create(
+                self.pages.clone(),
+                self.config.clone(),
+                IDS_HEAD,
+            )?);
+        }
+        {
+            let tree = ids.tree.as_ref().unwrap();
+            tree.set(RESERVED_KEY.to_vec(), encode_u64(reserved))?;
+            tree.flush()?;
+        }
+        ids.reserved = reserved;
+        Ok(())
+    }
+
+    /// Writes every tree to a new file at `path` as `export` does,
+    /// for `import_from` to load into another database, and syncs
+    /// it to disk before returning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let path = std::env::temp_dir()
+    ///     .join(format!("sled_export_{}", std::process::id()));
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.open_tree(b"users").unwrap().set(b"a".to_vec(), vec![1]);
+    /// db.export_to(&path).unwrap();
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let restored = sled::Db::start(config).unwrap();
+    /// restored.import_from(&path).unwrap();
+    /// let users = restored.open_tree(b"users").unwrap();
+    /// assert_eq!(users.get(b"a"), Ok(Some(vec![1].into())));
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn export_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> DbResult<(), ()> {
+        let file = File::create(path)?;
+        self.export(BufWriter::new(&file))?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the highest log sequence number that is durable on
+    /// disk. Read it just before an export, and pass it to
+    /// `export_since` later to export only what has changed since.
+    pub fn stable_lsn(&self) -> Lsn {
+        self.pages.stable_lsn()
+    }
+
+    /// Returns whether an IO error writing to disk, like running out
+    /// of space or a failed fsync, has left the database read-only.
+    /// Every write and flush after that fails with an `Error::Io`
+    /// without touching the disk, while reads keep working, though
+    /// writes that never became durable are lost once it's
+    /// dropped. Reopen the database to start writing again once
+    /// the cause has been dealt with.
+    pub fn was_poisoned(&self) -> bool {
+        self.pages.was_poisoned()
+    }
+
+    /// Writes what has changed since `lsn`, a `stable_lsn` read
+    /// before an earlier export of this database began, to a new
+    /// file at `path`. Importing it with `import_from` after the
+    /// earlier export brings a database up to date with this one.
+    ///
+    /// The export holds the writes logged from `lsn` on, up to
+    /// when it starts, so its size follows how much has been
+    /// written since rather than the size of the database.
+    /// Dropping and creating trees is included. Writers carry on
+    /// while it's read from the log, and aren't included. Writes
+    /// made while the earlier export was taken are applied again,
+    /// which only matters for merges. Fails with
+    /// `Error::Unsupported` if the log has been cleaned past `lsn`
+    /// since, after which only a full export will do. Log sequence
+    /// numbers are only meaningful to the database they were read
+    /// from, so after importing into a new one, take a full export
+    /// of it before exporting changes from it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let dir = std::env::temp_dir();
+    /// let id = std::process::id();
+    /// let full = dir.join(format!("sled_export_full_{}", id));
+    /// let changes = dir.join(format!("sled_export_changes_{}", id));
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.set(b"a".to_vec(), vec![1]).unwrap();
+    /// let lsn = db.stable_lsn();
+    /// db.export_to(&full).unwrap();
+    ///
+    /// db.set(b"b".to_vec(), vec![2]).unwrap();
+    /// db.del(b"a").unwrap();
+    /// db.export_since(lsn, &changes).unwrap();
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let restored = sled::Db::start(config).unwrap();
+    /// restored.import_from(&full).unwrap();
+    /// restored.import_from(&changes).unwrap();
+    /// assert_eq!(restored.get(b"a"), Ok(None));
+    /// assert_eq!(restored.get(b"b"), Ok(Some(vec![2].into())));
+    /// # std::fs::remove_file(&full).unwrap();
+    /// # std::fs::remove_file(&changes).unwrap();
+    /// ```
+    pub fn export_since<P: AsRef<Path>>(
+        &self,
+        lsn: Lsn,
+        path: P,
+    ) -> DbResult<(), ()> {
+        let ids_reserved = self.ids.lock().unwrap().reserved;
+        // make what has been written so far durable, so it can be
+        // read back from the log
+        self.flush()?;
+        let until = self.stable_lsn();
+        let mut trees = self.logged_trees()?;
+        // the catalog's writes are where trees are created and
+        // dropped
+        let catalog = self.named.lock().unwrap().catalog.clone();
+        let catalog = catalog.map(|catalog| {
+            trees.push((None, catalog));
+            trees.len() - 1
+        });
+        let writes = self.writes_between(&trees, lsn, until)?;
+
+        let file = File::create(path)?;
+        let w = BufWriter::new(&file);
+        let mut dump = DumpWriter::new(w, ids_reserved)?;
+        for (idx, key, op, _) in writes {
+            let name = trees[idx].0.clone();
+            let in_catalog = catalog == Some(idx);
+            let item = match op {
+                ChangeOp::Set(..) if in_catalog => {
+                    DumpItem::Create(key)
+                }
+                ChangeOp::Del if in_catalog => DumpItem::Drop(key),
+                _ if in_catalog => continue,
+                ChangeOp::Set(v, expires) => {
+                    DumpItem::Record(name, key, v, expires)
+                }
+                ChangeOp::Del => DumpItem::Del(name, key),
+                ChangeOp::Merge(v) => DumpItem::Merge(name, key, v),
+                ChangeOp::Clear(hi) => DumpItem::Clear(name, key, hi),
+            };
+            dump.item(&item)?;
+        }
+        dump.finish()?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes every record of every tree to `w` as of one point in
+    /// time, in a versioned format that doesn't depend on how
+    /// sled lays out its log and pages, for `import` to load into
+    /// another database, including one of a later version of sled
+    /// whose on-disk format has changed. Each record is written
+    /// with the name of its tree, its TTL and a checksum, and the
+    /// ids handed out by `generate_id` are carried over. The
+    /// export reads through a `read_view`, so reads and writes
+    /// carry on meanwhile, except for what a view makes fail while
+    /// it's open, like writes with a TTL.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// db.open_tree(b"users").unwrap().set(b"a".to_vec(), vec![1]);
+    /// let mut dump = vec![];
+    /// db.export(&mut dump).unwrap();
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let restored = sled::Db::start(config).unwrap();
+    /// restored.import(&*dump).unwrap();
+    /// let users = restored.open_tree(b"users").unwrap();
+    /// assert_eq!(users.get(b"a"), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn export<W: Write>(&self, w: W) -> DbResult<(), ()> {
+        // read before the view is taken, so that every id handed
+        // out to a write the view sees is counted
+        let ids_reserved = self.ids.lock().unwrap().reserved;
+        let view = self.read_view()?;
+        let mut dump = DumpWriter::new(w, ids_reserved)?;
+        for (name, tree) in view.trees() {
+            // so that a tree is created even if it's empty
+            if let Some(ref name) = name {
+                dump.item(&DumpItem::Create(name.clone()))?;
+            }
+            tree.visit_records(|k, v, expires| {
+                let (name, v) = (name.clone(), v.to_vec());
+                let item = DumpItem::Record(name, k, v, expires);
+                dump.item(&item)?;
+                Ok(())
+            })?;
+        }
+        dump.finish()?;
+        Ok(())
+    }
+
+    /// Loads a dump written by `export`, `export_to` or
+    /// `export_since` into this database, creating any trees it
+    /// doesn't have yet, and dropping those that a dump of changes
+    /// drops. Records overwrite keys already present, records
+    /// whose TTL has passed since the export are skipped, and
+    /// `generate_id` won't hand out any id handed out before the
+    /// export. Dumps of changes must be imported in the order they
+    /// were taken, after the export they follow.
+    ///
+    /// Fails with `Error::Corruption`, at the offset into the dump
+    /// where the damage was found, if an item doesn't match its
+    /// checksum, and with `Error::Unsupported` if `r` isn't a dump
+    /// or is one of a format version this sled can't read. If the
+    /// import fails part way through, what it loaded is undone:
+    /// the keys it wrote get back what they held, and the trees it
+    /// created are dropped. To be able to, it keeps what each key
+    /// it writes held in memory until it's done, and trees are
+    /// only dropped once the whole dump is loaded. Keys written by
+    /// others during the import may be undone along with it.
+    pub fn import<R: Read>(&self, r: R) -> DbResult<(), ()> {
+        let mut dump = DumpReader::new(r)?;
+        let mut undo = ImportUndo::default();
+        if let Err(e) = self.import_items(&mut dump, &mut undo) {
+            if let Err(undo_e) = self.undo_import(undo) {
+                error!("failed to undo an import: {:?}", undo_e);
+            }
+            return Err(e);
+        }
+        for name in &undo.dropped {
+            self.drop_tree(name)?;
+        }
+
+        {
+            let mut ids = self.ids.lock().unwrap();
+            if dump.ids_reserved > ids.reserved {
+                self.reserve_ids(&mut ids, dump.ids_reserved)?;
+                ids.next = dump.ids_reserved;
+            }
+        }
+        self.flush()
+    }
+
+    // Loads every item of `dump`, recording in `undo` how to undo
+    // them.
+    fn import_items<R: Read>(
+        &self,
+        dump: &mut DumpReader<R>,
+        undo: &mut ImportUndo,
+    ) -> DbResult<(), ()> {
+        let now = now_millis();
+        while let Some(item) = dump.next_item()? {
+            match item {
+                DumpItem::Record(name, k, v, expires) => {
+                    if expires.map_or(false, |at| at <= now) {
+                        continue;
+                    }
+                    let tree = self.import_tree(&name, undo)?;
+                    undo.save(&name, &tree, &k)?;
+                    tree.set_inner(k, v.into(), expires)?;
+                }
+                DumpItem::Del(name, k) => {
+                    let tree = self.import_tree(&name, undo)?;
+                    undo.save(&name, &tree, &k)?;
+                    tree.del(&k)?;
+                }
+                DumpItem::Merge(name, k, v) => {
+                    let tree = self.import_tree(&name, undo)?;
+                    undo.save(&name, &tree, &k)?;
+                    tree.merge(k, v)?;
+                }
+                DumpItem::Clear(name, lo, Some(hi)) => {
+                    let tree = self.import_tree(&name, undo)?;
+                    let range = lo.clone()..hi.clone();
+                    undo.save_range(&name, &tree, range)?;
+                    tree.remove_range(lo..hi)?;
+                }
+                DumpItem::Clear(name, lo, None) => {
+                    let tree = self.import_tree(&name, undo)?;
+                    undo.save_range(&name, &tree, lo.clone()..)?;
+                    tree.remove_range(lo..)?;
+                }
+                DumpItem::Create(name) => {
+                    undo.dropped.retain(|dropped| *dropped != name);
+                    self.import_tree(&Some(name), undo)?;
+                }
+                DumpItem::Drop(name) => {
+                    let exists = undo.trees.contains_key(&name)
+                        || self.tree_names()?.contains(&name);
+                    if !exists || undo.dropped.contains(&name) {
+                        continue;
+                    }
+                    // cleared for now, so that it can be undone
+                    let name = Some(name);
+                    let tree = self.import_tree(&name, undo)?;
+                    undo.save_range::<Vec<u8>, _>(&name, &tree, ..)?;
+                    tree.remove_range::<Vec<u8>, _>(..)?;
+                    undo.dropped.extend(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The tree that an imported item belongs to, created if this
+    // database doesn't have it yet.
+    fn import_tree(
+        &self,
+        name: &Option<Vec<u8>>,
+        undo: &mut ImportUndo,
+    ) -> DbResult<Tree, ()> {
+        let name = match *name {
+            Some(ref name) => name,
+            None => return Ok(self.default.clone()),
+        };
+        if let Some(tree) = undo.trees.get(name) {
+            return Ok(tree.clone());
+        }
+        if !self.tree_names()?.contains(name) {
+            undo.created.push(name.clone());
+        }
+        let tree = self.open_tree(name)?;
+        undo.trees.insert(name.clone(), tree.clone());
+        Ok(tree)
+    }
+
+    // Puts back what a failed import changed.
+    fn undo_import(&self, undo: ImportUndo) -> DbResult<(), ()> {
+        for name in &undo.created {
+            self.drop_tree(name)?;
+        }
+        for (name, old) in undo.old {
+            let tree = match name {
+                Some(ref name) => &undo.trees[name],
+                None => &self.default,
+            };
+            for (key, record) in old {
+                match record {
+                    Some((v, expires)) => {
+                        tree.set_inner(key, v, expires)?
+                    }
+                    None => {
+                        tree.del(&key)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Calls `f` with every tree, named or `None` for the default
+    // tree. Trees can't be opened or dropped until it returns.
+    fn with_trees<F, R>(&self, f: F) -> DbResult<R, ()>
+    where
+        F: FnOnce(&[(Option<Vec<u8>>, Tree)]) -> DbResult<R, ()>,
+    {
+        let mut named = self.named.lock().unwrap();
+        let names = self.names(&named)?;
+        let mut trees = vec![(None, self.default.clone())];
+        for name in names {
+            let tree = self.open_named(&mut named, &name)?;
+            trees.push((Some(name), tree));
+        }
+        f(&trees)
+    }
+
+    /// Returns a stream of the changes made to every tree from
+    /// `lsn` on, as they become durable, for a follower to apply
+    /// with `apply_change` to keep a copy of this database. Start
+    /// from a `stable_lsn` read before an `export_to` that the
+    /// follower imported, or from 0 to replay a database whose log
+    /// hasn't been cleaned since it was created. Changes made while
+    /// the export was taken are applied twice, which only matters
+    /// for merges. See `ReplicationStream` for what the stream
+    /// yields.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let leader = sled::Db::start(config).unwrap();
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let follower = sled::Db::start(config).unwrap();
+    ///
+    /// let mut changes = leader.replication_stream(0);
+    /// leader.set(b"a".to_vec(), vec![1]).unwrap();
+    /// leader.flush().unwrap();
+    /// while follower.get(b"a").unwrap().is_none() {
+    ///     let change = changes.next().unwrap().unwrap();
+    ///     follower.apply_change(&change).unwrap();
+    /// }
+    /// assert_eq!(follower.get(b"a"), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn replication_stream(&self, lsn: Lsn) -> ReplicationStream {
+        ReplicationStream::new(self.clone(), lsn)
+    }
+
+    // The changes logged from `lsn` up to `until`, as
+    // `replication_stream` yields them.
+    pub(crate) fn changes_between(
+        &self,
+        lsn: Lsn,
+        until: Lsn,
+    ) -> DbResult<Vec<Change>, ()> {
+        let trees = self.logged_trees()?;
+        let writes = self.writes_between(&trees, lsn, until)?;
+        Ok(writes
+            .into_iter()
+            .map(|(idx, key, op, msg_lsn)| {
+                Change::new(trees[idx].0.clone(), key, op, msg_lsn)
+            })
+            .collect())
+    }
+
+    // Every tree whose logged writes are read back, named or `None`
+    // for the default tree.
+    fn logged_trees(
+        &self,
+    ) -> DbResult<Vec<(Option<Vec<u8>>, Tree)>, ()> {
+        let mut trees = vec![(None, self.default.clone())];
+        for name in self.tree_names()? {
+            let tree = self.open_tree(&name)?;
+            trees.push((Some(name), tree));
+        }
+        Ok(trees)
+    }
+
+    // The writes logged from `lsn` up to `until` to the leaves of
+    // `trees`, in the order they were made, each with the index in
+    // `trees` of the tree it went to and the lsn it was logged at.
+    fn writes_between(
+        &self,
+        trees: &[(Option<Vec<u8>>, Tree)],
+        lsn: Lsn,
+        until: Lsn,
+    ) -> DbResult<Vec<(usize, Key, ChangeOp, Lsn)>, ()> {
+        let logged = self.pages
+            .logged_between(lsn, until)
+            .map_err(|e| e.danger_cast())?;
+
+        // The tree that the writes to each page went to, by its
+        // index in `trees`, and the `lo` of the leaf that they are
+        // encoded against, or `None` if they don't belong to any
+        // tree. The log is read from the end back, so that a page
+        // freed by a merge is known to have belonged to the leaf
+        // it was merged into by the time its writes are read.
+        let mut leaves: HashMap<PageID, Option<(usize, Key)>> =
+            HashMap::new();
+        let mut writes = vec![];
+        for (msg_lsn, pid, logged) in logged.into_iter().rev() {
+            let frag = match logged {
+                Logged::Frag(frag) => frag,
+                // the page may have been reused since, so what was
+                // written to it before belongs to a tree only if a
+                // merge says so
+                Logged::Free => {
+                    leaves.insert(pid, None);
+                    continue;
+                }
+            };
+            let leaf = match leaves.get(&pid) {
+                Some(leaf) => leaf.clone(),
+                None => find_leaf(trees, pid)?,
+            };
+            leaves.insert(pid, leaf.clone());
+            if let Some((idx, ref lo)) = leaf {
+                let logged = logged_writes(&frag, lo);
+                for (key, op) in logged.into_iter().rev() {
+                    writes.push((idx, key, op, msg_lsn));
+                }
+            }
+            if let Some((right, right_lo)) = merged_right(&frag) {
+                let merged = leaf.map(|(idx, _)| (idx, right_lo));
+                leaves.insert(right, merged);
+            }
+        }
+        writes.reverse();
+        Ok(writes)
+    }
+
+    /// Applies a change from another database's
+    /// `replication_stream`, failing with `Error::Corruption` if it
+    /// has been damaged on its way here. Trees are created as
+    /// changes to them arrive, and records whose TTL has passed are
+    /// skipped. Merges are applied with this database's merge
+    /// operator, which has to be the same as the leader's.
+    pub fn apply_change(&self, change: &Change) -> DbResult<(), ()> {
+        if !change.is_intact() {
+            return Err(Error::Corruption {
+                at: change.lsn as LogID,
+            });
+        }
+        let tree = match change.tree {
+            Some(ref name) => self.open_tree(name)?,
+            None => self.default.clone(),
+        };
+        let key = change.key.clone();
+        let now = now_millis();
+        match change.op {
+            ChangeOp::Set(_, Some(at)) if at <= now => Ok(()),
+            ChangeOp::Set(ref v, expires) => {
+                tree.set_inner(key, v.clone().into(), expires)
+            }
+            ChangeOp::Del => tree.del(&key).map(|_| ()),
+            ChangeOp::Merge(ref v) => tree.merge(key, v.clone()),
+            ChangeOp::Clear(Some(ref hi)) => {
+                tree.remove_range(key..hi.clone()).map(|_| ())
+            }
+            ChangeOp::Clear(None) => {
+                tree.remove_range(key..).map(|_| ())
+            }
+        }
+    }
+
+    /// Loads a dump written by `export_to` or `export_since` from
+    /// the file at `path`, as `import` does.
+    pub fn import_from<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> DbResult<(), ()> {
+        self.import(BufReader::new(File::open(path)?))
+    }
+
+    /// Opens the tree called `name`, creating it if it doesn't
+    /// exist yet. Every handle to the same name shares one tree.
+    pub fn open_tree(&self, name: &[u8]) -> DbResult<Tree, ()> {
+        let mut named = self.named.lock().unwrap();
+        self.open_named(&mut named, name)
+    }
+
+    // `open_tree`, for callers already holding the lock on `named`.
+    fn open_named(
+        &self,
+        named: &mut Named,
+        name: &[u8],
+    ) -> DbResult<Tree, ()> {
+        if let Some(tree) = named.trees.get(name) {
+            return Ok(tree.clone());
+        }
+
+        let found = match named.catalog {
+            Some(ref catalog) => catalog.get(name)?,
+            None => None,
+        };
+        let tree = match found {
+            Some(ref first) => Tree::open(
+                self.pages.clone(),
+                self.config.clone(),
+                &self.roots,
+                decode_entry(first)?.0,
+            ),
+            None => {
+                if self.config.read_only {
+                    return Err(Error::Unsupported(
+                        "can't create a tree in read-only mode"
+                            .to_owned(),
+                    ));
+                }
+                let tree = Tree::create(
+                    self.pages.clone(),
+                    self.config.clone(),
+                    NAMED_HEAD,
+                )?;
+                let first = encode_pid(tree.root_id());
+                self.catalog(named)?.set(name.to_vec(), first)?;
+                tree
+            }
+        };
+
+        named.trees.insert(name.to_vec(), tree.clone());
+        Ok(tree)
+    }
+
+    /// Opens the tree called `name` as a `TypedTree`, creating it
+    /// if it doesn't exist yet. The name of the key codec `KC` is
+    /// recorded the first time the tree is opened this way, and
+    /// opening it later with a differently named key codec fails
+    /// with `Error::Unsupported`, since its keys would be decoded
+    /// wrongly and found out of order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{Ordered, Reversed, TypedTree};
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    ///
+    /// let scores: TypedTree<u64, String, Reversed<Ordered>> =
+    ///     db.open_typed_tree(b"scores").unwrap();
+    /// scores.set(&10, &"alice".to_owned()).unwrap();
+    /// scores.set(&30, &"bob".to_owned()).unwrap();
+    ///
+    /// let first = scores.iter().next().unwrap().unwrap();
+    /// assert_eq!(first, (30, "bob".to_owned()));
+    ///
+    /// let wrong: sled::DbResult<TypedTree<u64, String>, ()> =
+    ///     db.open_typed_tree(b"scores");
+    /// assert!(wrong.is_err());
+    /// ```
+    pub fn open_typed_tree<K, V, KC, VC>(
+        &self,
+        name: &[u8],
+    ) -> DbResult<TypedTree<K, V, KC, VC>, ()>
+    where
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let codec = KC::name();
+        let mut named = self.named.lock().unwrap();
+        let tree = self.open_named(&mut named, name)?;
+
+        let catalog = self.catalog(&mut named)?;
+        let entry = catalog.get(name)?.ok_or_else(|| {
+            Error::ReportableBug(format!(
+                "tree {:?} was opened but isn't in the catalog",
+                name
+            ))
+        })?;
+        let (first, recorded) = decode_entry(&entry)?;
+        if recorded == codec.as_bytes() {
+            return Ok(TypedTree::new(tree));
+        } else if !recorded.is_empty() {
+            return Err(Error::Unsupported(format!(
+                "tree {:?} has keys encoded by {:?}, not {:?}",
+                name,
+                String::from_utf8_lossy(recorded),
+                codec
+            )));
+        }
+
+        if !self.config.read_only {
+            let mut entry = encode_pid(first);
+            entry.extend_from_slice(codec.as_bytes());
+            catalog.set(name.to_vec(), entry)?;
+        }
+        Ok(TypedTree::new(tree))
+    }
+
+    /// Removes the tree called `name` and frees its pages,
+    /// returning `false` if there was no such tree. Handles to the
+    /// tree must not be used after it is dropped.
+    pub fn drop_tree(&self, name: &[u8]) -> DbResult<bool, ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "can't drop a tree in read-only mode".to_owned(),
+            ));
+        }
+        let mut named = self.named.lock().unwrap();
+        let first = match named.catalog {
+            Some(ref catalog) => match catalog.get(name)? {
+                Some(first) => decode_entry(&first)?.0,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+
+        let tree = match named.trees.get(name) {
+            Some(tree) if tree.is_viewed() => {
+                return Err(Error::Unsupported(
+                    "can't drop a tree while a read view of it is \
+                     open"
+                        .to_owned(),
+                ))
+            }
+            _ => named.trees.remove(name),
+        };
+        let tree = match tree {
+            Some(tree) => tree,
+            None => Tree::open(
+                self.pages.clone(),
+                self.config.clone(),
+                &self.roots,
+                first,
+            ),
+        };
+
+        // forget the name before freeing anything, so a crash
+        // part way through leaks pages rather than leaving the
+        // catalog pointing at freed ones
+        self.catalog(&mut named)?.del(name)?;
+        tree.free_pages()?;
+        Ok(true)
+    }
+
+    /// Returns the names of every named tree, in order. The
+    /// default tree has no name and isn't included.
+    pub fn tree_names(&self) -> DbResult<Vec<Vec<u8>>, ()> {
+        let named = self.named.lock().unwrap();
+        self.names(&named)
+    }
+
+    // `tree_names`, for callers already holding the lock on `named`.
+    fn names(&self, named: &Named) -> DbResult<Vec<Vec<u8>>, ()> {
+        match named.catalog {
+            Some(ref catalog) => catalog
+                .iter()
+                .map(|res| res.map(|(name, _)| name))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    // The catalog, created the first time a tree is named.
+    fn catalog<'a>(
+        &self,
+        named: &'a mut Named,
+    ) -> DbResult<&'a Tree, ()> {
+        if named.catalog.is_none() {
+            named.catalog = Some(Tree::create(
+                self.pages.clone(),
+                self.config.clone(),
+                CATALOG_HEAD,
+            )?);
+        }
+        Ok(named.catalog.as_ref().unwrap())
+    }
+}
+
+// What an import has done so far, for `Db::import` to undo if it
+// fails part way through.
+#[derive(Default)]
+struct ImportUndo {
+    // the handles to the named trees it has written to
+    trees: HashMap<Vec<u8>, Tree>,
+    // the trees it created
+    created: Vec<Vec<u8>>,
+    // the trees it dropped, which are only cleared until it's done
+    dropped: Vec<Vec<u8>>,
+    // what each key it wrote to held before, with when it expired,
+    // or `None` if it didn't exist, by the name of its tree
+    old: HashMap<Option<Vec<u8>>, BTreeMap<Key, Option<OldRecord>>>,
+}
+
+// A value, and when it expires if it was written with a TTL.
+type OldRecord = (Value, Option<u64>);
+
+impl ImportUndo {
+    // Saves what `key` holds, unless it has been saved already or
+    // its tree was created by the import.
+    fn save(
+        &mut self,
+        name: &Option<Vec<u8>>,
+        tree: &Tree,
+        key: &[u8],
+    ) -> DbResult<(), ()> {
+        if let Some(ref name) = *name {
+            if self.created.contains(name) {
+                return Ok(());
+            }
+        }
+        let old = self
+            .old
+            .entry(name.clone())
+            .or_insert_with(BTreeMap::new);
+        if !old.contains_key(key) {
+            let record = match tree.get(key)? {
+                Some(v) => Some((v, tree.expiry_of(key)?)),
+                None => None,
+            };
+            old.insert(key.to_vec(), record);
+        }
+        Ok(())
+    }
+
+    // Saves what every key in `range` holds, as `save` does.
+    fn save_range<K, R>(
+        &mut self,
+        name: &Option<Vec<u8>>,
+        tree: &Tree,
+        range: R,
+    ) -> DbResult<(), ()>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        for key in tree.keys(range) {
+            self.save(name, tree, &key?)?;
+        }
+        Ok(())
+    }
+}
+
+// The thread started for `compact_every_ms`.
+struct Compactor {
+    // dropping this wakes the thread up to exit
+    shutdown: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    // Starts compacting every `compact_every_ms`, unless that is
+    // `None` or the database is read-only.
+    fn start(
+        pages: &Arc<TreeCache>,
+        config: &Config,
+    ) -> Option<Compactor> {
+        let every_ms = match config.compact_every_ms {
+            Some(every_ms) if !config.read_only => every_ms,
+            _ => return None,
+        };
+        let pages = pages.clone();
+        let threshold = config.compaction_threshold;
+        let (tx, rx) = channel();
+        let handle = thread::Builder::new()
+            .name("sled_compactor".to_owned())
+            .spawn(move || loop {
+                match rx.recv_timeout(Duration::from_millis(every_ms))
+                {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                if let Err(e) = pages.compact(threshold) {
+                    error!("failed to compact the log: {:?}", e);
+                }
+            })
+            .unwrap();
+        Some(Compactor {
+            shutdown: Some(tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.shutdown.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("error joining compactor: {:?}", e);
+            }
+        }
+    }
+}
+
+// The tree among `trees` that the leaf at `pid` belongs to, by its
+// index, and the leaf's `lo`.
+fn find_leaf(
+    trees: &[(Option<Vec<u8>>, Tree)],
+    pid: PageID,
+) -> DbResult<Option<(usize, Key)>, ()> {
+    for (idx, &(_, ref tree)) in trees.iter().enumerate() {
+        if let Some(lo) = tree.leaf_lo(pid)? {
+            return Ok(Some((idx, lo)));
+        }
+    }
+    Ok(None)
+}
+
+fn encode_pid(pid: PageID) -> Vec<u8> {
+    encode_u64(pid as u64)
+}
+
+fn encode_u64(n: u64) -> Vec<u8> {
+    (0..8).rev().map(|i| (n >> (i * 8)) as u8).collect()
+}
+
+fn decode_u64(buf: &[u8]) -> DbResult<u64, ()> {
+    if buf.len() != 8 {
+        return Err(Error::ReportableBug(format!(
+            "id reservation of {} bytes is not a u64",
+            buf.len()
+        )));
+    }
+    Ok(buf.iter().fold(0, |n, &b| (n << 8) | u64::from(b)))
+}
+
+// Splits a catalog entry into the tree's first root and the name
+// of its key codec, which is empty if none has been recorded.
+fn decode_entry(buf: &[u8]) -> DbResult<(PageID, &[u8]), ()> {
+    if buf.len() < 8 {
+        return Err(Error::ReportableBug(format!(
+            "catalog entry of {} bytes doesn't start with a page id",
+            buf.len()
+        )));
+    }
+    let (pid, codec) = buf.split_at(8);
+    let pid = pid.iter().fold(0, |pid, &b| (pid << 8) | b as PageID);
+    Ok((pid, codec))
+}
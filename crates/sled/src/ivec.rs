@@ -0,0 +1,289 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use pagecache::PageID;
+
+// values up to this long are stored inline rather than behind an
+// Arc, which keeps an IVec as small as a Vec
+const INLINE_LEN: usize = 22;
+
+/// A buffer holding a value read from a `Tree`. Values of up to 22
+/// bytes are stored inline, and longer ones behind a reference
+/// count that every copy of the value shares, so handing a value
+/// out of a node or cloning the node doesn't copy its bytes.
+/// Derefs to `[u8]`, and compares equal to a `Vec<u8>` or slice
+/// holding the same bytes.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// t.set(vec![1], vec![10; 100]).unwrap();
+/// let value: sled::IVec = t.get(&[1]).unwrap().unwrap();
+/// assert_eq!(value.len(), 100);
+/// assert_eq!(value, vec![10; 100]);
+/// let owned: Vec<u8> = value.into();
+/// ```
+#[derive(Clone)]
+pub struct IVec(Inner);
+
+#[derive(Clone)]
+enum Inner {
+    Inline(u8, [u8; INLINE_LEN]),
+    Remote(Arc<[u8]>),
+    // a value that a tree wrote to a page of its own, which leaves
+    // refer to by its id when they're written. Empty until it's
+    // filled in, when read back as part of a leaf. The page id is
+    // kept behind the pointer so that it doesn't make every IVec
+    // larger.
+    Blob(Arc<Blob>),
+}
+
+struct Blob {
+    pid: PageID,
+    bytes: Arc<[u8]>,
+}
+
+impl IVec {
+    // the empty value, usable where a `static` is needed
+    pub(crate) const EMPTY: IVec =
+        IVec(Inner::Inline(0, [0; INLINE_LEN]));
+
+    fn inline(bytes: &[u8]) -> IVec {
+        let mut buf = [0; INLINE_LEN];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        IVec(Inner::Inline(bytes.len() as u8, buf))
+    }
+
+    // A reference to the value written to page `pid`, which reads
+    // as empty until it's filled in with `with_blob`.
+    pub(crate) fn blob_ref(pid: PageID) -> IVec {
+        IVec::blobbed(pid, Arc::from(&[][..]))
+    }
+
+    // This value, marked as the one written to page `pid`.
+    pub(crate) fn with_blob(self, pid: PageID) -> IVec {
+        let bytes = match self.0 {
+            Inner::Inline(len, ref buf) => {
+                Arc::from(&buf[..len as usize])
+            }
+            Inner::Remote(buf) => buf,
+            Inner::Blob(blob) => blob.bytes.clone(),
+        };
+        IVec::blobbed(pid, bytes)
+    }
+
+    fn blobbed(pid: PageID, bytes: Arc<[u8]>) -> IVec {
+        IVec(Inner::Blob(Arc::new(Blob {
+            pid: pid,
+            bytes: bytes,
+        })))
+    }
+
+    // The page this value was written to, if it was written to one
+    // of its own.
+    pub(crate) fn blob(&self) -> Option<PageID> {
+        match self.0 {
+            Inner::Blob(ref blob) => Some(blob.pid),
+            _ => None,
+        }
+    }
+
+    // This value without the page it was written to, for storing
+    // it somewhere else.
+    pub(crate) fn unblobbed(self) -> IVec {
+        match self.0 {
+            Inner::Blob(ref blob) => {
+                IVec(Inner::Remote(blob.bytes.clone()))
+            }
+            _ => self,
+        }
+    }
+}
+
+impl Default for IVec {
+    fn default() -> IVec {
+        IVec::inline(&[])
+    }
+}
+
+impl Deref for IVec {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {
+            Inner::Inline(len, ref buf) => &buf[..len as usize],
+            Inner::Remote(ref buf) => buf,
+            Inner::Blob(ref blob) => &blob.bytes,
+        }
+    }
+}
+
+impl AsRef<[u8]> for IVec {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Borrow<[u8]> for IVec {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'a> From<&'a [u8]> for IVec {
+    fn from(bytes: &[u8]) -> IVec {
+        if bytes.len() <= INLINE_LEN {
+            IVec::inline(bytes)
+        } else {
+            IVec(Inner::Remote(Arc::from(bytes)))
+        }
+    }
+}
+
+impl From<Vec<u8>> for IVec {
+    fn from(bytes: Vec<u8>) -> IVec {
+        if bytes.len() <= INLINE_LEN {
+            IVec::inline(&bytes)
+        } else {
+            IVec(Inner::Remote(Arc::from(bytes)))
+        }
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for IVec {
+    fn from(bytes: &Vec<u8>) -> IVec {
+        IVec::from(&**bytes)
+    }
+}
+
+impl From<IVec> for Vec<u8> {
+    fn from(ivec: IVec) -> Vec<u8> {
+        ivec.to_vec()
+    }
+}
+
+impl fmt::Debug for IVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Hash for IVec {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl PartialEq for IVec {
+    fn eq(&self, other: &IVec) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for IVec {}
+
+impl PartialOrd for IVec {
+    fn partial_cmp(&self, other: &IVec) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IVec {
+    fn cmp(&self, other: &IVec) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl PartialEq<[u8]> for IVec {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl<'a> PartialEq<&'a [u8]> for IVec {
+    fn eq(&self, other: &&[u8]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Vec<u8>> for IVec {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<IVec> for Vec<u8> {
+    fn eq(&self, other: &IVec) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a> PartialEq<IVec> for &'a [u8] {
+    fn eq(&self, other: &IVec) -> bool {
+        **self == **other
+    }
+}
+
+// serialized just like a Vec<u8>, so switching the value type
+// didn't change the format of anything already on disk
+impl Serialize for IVec {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for IVec {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<IVec, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(IVec::from)
+    }
+}
+
+#[test]
+fn test_ivec() {
+    use bincode::{deserialize, serialize, Infinite};
+
+    for len in &[0, 1, INLINE_LEN, INLINE_LEN + 1, 1000] {
+        let bytes: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+        let ivec = IVec::from(bytes.clone());
+        assert_eq!(ivec, bytes);
+        assert_eq!(&*ivec, &*bytes);
+        assert_eq!(ivec.clone(), ivec);
+        assert_eq!(Vec::from(ivec.clone()), bytes);
+        assert_eq!(format!("{:?}", ivec), format!("{:?}", bytes));
+
+        let encoded = serialize(&ivec, Infinite).unwrap();
+        assert_eq!(encoded, serialize(&bytes, Infinite).unwrap());
+        let decoded: IVec = deserialize(&encoded).unwrap();
+        assert_eq!(decoded, ivec);
+    }
+
+    // longer values share their bytes between clones
+    let long = IVec::from(vec![1; 100]);
+    assert_eq!(long.as_ptr(), long.clone().as_ptr());
+
+    // a value written to a page of its own is no larger
+    let blob = long.clone().with_blob(7);
+    assert_eq!(blob.blob(), Some(7));
+    assert_eq!(blob, long);
+    assert_eq!(blob.clone().unblobbed().blob(), None);
+    assert_eq!(
+        std::mem::size_of::<IVec>(),
+        std::mem::size_of::<Vec<u8>>()
+    );
+
+    assert!(IVec::from(vec![1]) < IVec::from(vec![1, 0]));
+    assert_eq!(IVec::default(), IVec::from(vec![]));
+}
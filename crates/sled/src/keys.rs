@@ -0,0 +1,268 @@
+//! Encodings of keys as bytes that sort in the same order as the
+//! keys themselves, so that `Tree::scan` and `Tree::range` visit
+//! typed keys in their natural order. Integers are written
+//! big-endian, with the sign bit flipped for signed ones, floats
+//! have their sign bit flipped and, when negative, every other bit
+//! too, and strings are escaped and terminated so that tuples of
+//! them sort element by element.
+//!
+//! # Examples
+//!
+//! ```
+//! use sled::keys;
+//!
+//! let config = sled::ConfigBuilder::new().temporary(true).build();
+//! let t = sled::Tree::start(config).unwrap();
+//!
+//! for &(user, score) in &[("b", -2i64), ("a", 7), ("a", -300)] {
+//!     let key = keys::encode(&(user.to_owned(), score));
+//!     t.set(key, vec![]).unwrap();
+//! }
+//!
+//! let lo = keys::encode(&("a".to_owned(), i64::min_value()));
+//! let hi = keys::encode(&("b".to_owned(), i64::min_value()));
+//! let a: Vec<(String, i64)> = t
+//!     .range(lo..hi)
+//!     .map(|res| keys::decode(&res.unwrap().0).unwrap())
+//!     .collect();
+//! assert_eq!(a, vec![("a".to_owned(), -300), ("a".to_owned(), 7)]);
+//! ```
+
+/// Encodes `key` as bytes that sort in the same order as the keys
+/// they encode.
+pub fn encode<T: OrderedKey>(key: &T) -> Vec<u8> {
+    let mut out = vec![];
+    key.write_ordered(&mut out);
+    out
+}
+
+/// Decodes bytes written by `encode`, returning `None` if they
+/// aren't exactly one valid encoding of a `T`.
+pub fn decode<T: OrderedKey>(mut bytes: &[u8]) -> Option<T> {
+    let key = T::read_ordered(&mut bytes)?;
+    if bytes.is_empty() {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// A type that can be written as bytes that sort in the same order
+/// as its values, so that a `Tree` iterates over its keys in their
+/// natural order. Every encoding is self-delimiting, so that tuples
+/// of them sort by their first element, then their second, and so
+/// on.
+pub trait OrderedKey: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn write_ordered(&self, out: &mut Vec<u8>);
+
+    /// Reads a value from the front of `buf`, advancing it past the
+    /// bytes read, or returns `None` if they aren't a valid
+    /// encoding.
+    fn read_ordered(buf: &mut &[u8]) -> Option<Self>;
+}
+
+// Splits `len` bytes off the front of `buf`.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+// Unsigned integers are written big-endian, which sorts them
+// numerically.
+macro_rules! ordered_unsigned {
+    ($($t:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn read_ordered(buf: &mut &[u8]) -> Option<$t> {
+                let len = std::mem::size_of::<$t>();
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                bytes.copy_from_slice(take(buf, len)?);
+                Some(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+// Signed integers have their sign bit flipped before being written
+// big-endian, so that negative numbers sort below positive ones.
+macro_rules! ordered_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                flipped.write_ordered(out);
+            }
+
+            fn read_ordered(buf: &mut &[u8]) -> Option<$t> {
+                let flipped = <$u>::read_ordered(buf)?;
+                Some((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+ordered_unsigned!(u8, u16, u32, u64, u128);
+ordered_signed!(
+    i8 => u8,
+    i16 => u16,
+    i32 => u32,
+    i64 => u64,
+    i128 => u128
+);
+
+// Floats have their sign bit flipped, so that positive numbers
+// sort above negative ones, and negative numbers have every other
+// bit flipped too, so that larger magnitudes sort lower. -0.0
+// sorts just below 0.0, and NaNs sort beyond the infinities on the
+// side of their sign bit.
+macro_rules! ordered_float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let flipped = if bits & sign == 0 {
+                    bits ^ sign
+                } else {
+                    !bits
+                };
+                flipped.write_ordered(out);
+            }
+
+            fn read_ordered(buf: &mut &[u8]) -> Option<$t> {
+                let flipped = <$u>::read_ordered(buf)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if flipped & sign == 0 {
+                    !flipped
+                } else {
+                    flipped ^ sign
+                };
+                Some(<$t>::from_bits(bits))
+            }
+        }
+    )*};
+}
+
+ordered_float!(f32 => u32, f64 => u64);
+
+// Byte strings have each zero byte escaped as 0x00 0xFF and end
+// with 0x00 0x00, so that a string sorts before any longer string
+// it is a prefix of, and the end of the string can be found when
+// more bytes follow it in a tuple.
+impl OrderedKey for Vec<u8> {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        for &b in self {
+            out.push(b);
+            if b == 0 {
+                out.push(0xFF);
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+
+    fn read_ordered(buf: &mut &[u8]) -> Option<Vec<u8>> {
+        let mut out = vec![];
+        loop {
+            match *take(buf, 1)? {
+                [0] => match *take(buf, 1)? {
+                    [0] => return Some(out),
+                    [0xFF] => out.push(0),
+                    _ => return None,
+                },
+                [b] => out.push(b),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl OrderedKey for String {
+    fn write_ordered(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().write_ordered(out);
+    }
+
+    fn read_ordered(buf: &mut &[u8]) -> Option<String> {
+        String::from_utf8(Vec::read_ordered(buf)?).ok()
+    }
+}
+
+macro_rules! ordered_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: OrderedKey),*> OrderedKey for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn write_ordered(&self, out: &mut Vec<u8>) {
+                let ($(ref $name,)*) = *self;
+                $($name.write_ordered(out);)*
+            }
+
+            fn read_ordered(buf: &mut &[u8]) -> Option<Self> {
+                Some(($($name::read_ordered(buf)?,)*))
+            }
+        }
+    };
+}
+
+ordered_tuple!(A, B);
+ordered_tuple!(A, B, C);
+ordered_tuple!(A, B, C, D);
+
+#[test]
+fn test_keys_sort_like_values() {
+    fn check<T: OrderedKey + PartialOrd + ::std::fmt::Debug>(
+        xs: &[T],
+    ) {
+        for a in xs {
+            assert_eq!(decode::<T>(&encode(a)).as_ref(), Some(a));
+            for b in xs {
+                assert_eq!(
+                    a.partial_cmp(b),
+                    encode(a).partial_cmp(&encode(b)),
+                    "{:?} vs {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    check(&[0u64, 1, 255, 256, u64::max_value()]);
+    check(&[i64::min_value(), -256, -1, 0, 1, 255, i64::max_value()]);
+    check(&[
+        ::std::f64::NEG_INFINITY,
+        -1e300,
+        -2.5,
+        -0.5,
+        0.0,
+        1e-300,
+        3.0,
+        ::std::f64::INFINITY,
+    ]);
+    check(&[
+        String::new(),
+        "\u{0}".to_owned(),
+        "\u{0}\u{0}".to_owned(),
+        "a".to_owned(),
+        "a\u{0}".to_owned(),
+        "ab".to_owned(),
+        "\u{ff}".to_owned(),
+    ]);
+    check(&[
+        ("".to_owned(), 3u8),
+        ("a".to_owned(), 0),
+        ("a".to_owned(), 1),
+        ("a\u{0}".to_owned(), 0),
+        ("b".to_owned(), 0),
+    ]);
+
+    assert_eq!(decode::<u64>(&[0; 9]), None);
+    assert_eq!(decode::<String>(&[b'a', 0]), None);
+    assert_eq!(decode::<String>(&[0xff, 0, 0]), None);
+}
@@ -8,7 +8,7 @@
 //! let t = sled::Tree::start(config).unwrap();
 //!
 //! t.set(b"yo!".to_vec(), b"v1".to_vec());
-//! assert_eq!(t.get(b"yo!"), Ok(Some(b"v1".to_vec())));
+//! assert_eq!(t.get(b"yo!"), Ok(Some(b"v1".to_vec().into())));
 //!
 //! t.cas(
 //!     b"yo!".to_vec(),       // key
@@ -17,7 +17,7 @@
 //! ).unwrap();
 //!
 //! let mut iter = t.scan(b"a non-present key before yo!");
-//! assert_eq!(iter.next(), Some(Ok((b"yo!".to_vec(), b"v2".to_vec()))));
+//! assert_eq!(iter.next(), Some(Ok((b"yo!".to_vec(), b"v2".to_vec().into()))));
 //! assert_eq!(iter.next(), None);
 //!
 //! t.del(b"yo!");
@@ -38,20 +38,63 @@ extern crate crossbeam_epoch as epoch;
 extern crate serde;
 #[macro_use]
 extern crate log as _log;
+#[cfg(test)]
+extern crate quickcheck;
 
 /// atomic lock-free tree
-pub use tree::{Iter, Tree};
+pub use tree::{
+    Batch, Durability, Encoding, Event, IndexIter, IntegrityProblem,
+    Iter, KeyBucket, Keys, NodeDump, RevIter, SplitPolicy, Subscriber,
+    TransactionalTree, Tree, TreeDump, TreeIntegrity, TreeStats,
+    TreeView, Values, ViewIter,
+};
+
+/// several named trees in one database
+pub use db::{Db, DbStats, IntegrityReport, ReadView};
+
+/// following a database's changes from another process
+pub use replication::{Change, ChangeOp, ReplicationStream};
+
+/// a shared, cheaply cloned value buffer
+pub use ivec::IVec;
+
+/// futures of tree operations, run on a background pool
+#[cfg(feature = "async")]
+pub use nonblocking::{AsyncTree, Offloaded};
+
+/// trees of typed keys and values
+pub use typed::{
+    Bincode, CaseInsensitive, Codec, Ordered, Reversed, TypedIter,
+    TypedTree,
+};
+
+/// order-preserving encodings of typed keys
+pub use keys::OrderedKey;
+
+/// fault injection for crash-testing applications
+#[cfg(feature = "failpoints")]
+pub use pagecache::failpoints;
 
 use pagecache::*;
 
 pub use pagecache::{
-    CacheResult as DbResult, Config, ConfigBuilder, Error,
+    expiry_merge, CacheResult as DbResult, Config, ConfigBuilder,
+    Error, FlushHandle, LogID, Lsn, MetricsSink, MetricsSnapshot,
+    PageID, Pressure, SegmentStats,
 };
 
+mod db;
+mod ivec;
+pub mod keys;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod portable;
+mod replication;
 mod tree;
+mod typed;
 
 type Key = Vec<u8>;
 type KeyRef<'a> = &'a [u8];
-type Value = Vec<u8>;
+type Value = IVec;
 
 type TreePtr<'g> = pagecache::PagePtr<'g, tree::Frag>;
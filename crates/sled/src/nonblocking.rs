@@ -0,0 +1,187 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use super::*;
+
+// How many threads an `AsyncTree` runs operations on by default.
+const DEFAULT_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A handle to a `Tree` whose operations return futures rather
+/// than blocking, for use from async code. Each operation runs on
+/// a small pool of threads owned by the handle, so the caller's
+/// executor thread never waits on a log write, a page being read
+/// in, or a flush. The futures don't depend on any particular
+/// runtime: the pool wakes whichever task polled them once the
+/// operation is done. An operation starts when it is called,
+/// rather than when its future is first polled, and runs to the
+/// end even if the future is dropped. Clones share the pool, whose
+/// threads exit once every clone is dropped.
+///
+/// # Examples
+///
+/// ```edition2018
+/// use sled::{AsyncTree, DbResult};
+///
+/// async fn set_and_flush(t: &AsyncTree) -> DbResult<(), ()> {
+///     t.set(b"a".to_vec(), vec![1]).await?;
+///     assert_eq!(t.get(b"a".to_vec()).await?, Some(vec![1].into()));
+///     t.flush().await
+/// }
+///
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = AsyncTree::new(sled::Tree::start(config).unwrap());
+/// let _done = set_and_flush(&t);
+/// ```
+#[derive(Clone)]
+pub struct AsyncTree {
+    tree: Tree,
+    jobs: Arc<Mutex<Sender<Job>>>,
+}
+
+impl AsyncTree {
+    /// Wraps `tree`, running its operations on 4 threads.
+    pub fn new(tree: Tree) -> AsyncTree {
+        AsyncTree::with_threads(tree, DEFAULT_THREADS)
+    }
+
+    /// Wraps `tree`, running its operations on `threads` threads.
+    /// At most that many operations wait on IO at once, and the
+    /// rest queue up behind them.
+    pub fn with_threads(tree: Tree, threads: usize) -> AsyncTree {
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..std::cmp::max(threads, 1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name("sled_async".to_owned())
+                .spawn(move || run_jobs(&rx))
+                .unwrap();
+        }
+        AsyncTree {
+            tree: tree,
+            jobs: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    /// The `Tree` this handle runs operations on.
+    pub fn inner(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Retrieve a value from the `Tree` if it exists, see
+    /// `Tree::get`.
+    pub fn get(
+        &self,
+        key: Key,
+    ) -> Offloaded<DbResult<Option<Value>, ()>> {
+        self.offload(move |tree| tree.get(&key))
+    }
+
+    /// Set a key to a new value, see `Tree::set`.
+    pub fn set<V>(
+        &self,
+        key: Key,
+        value: V,
+    ) -> Offloaded<DbResult<(), ()>>
+    where
+        V: Into<Value>,
+    {
+        let value = value.into();
+        self.offload(move |tree| tree.set(key, value))
+    }
+
+    /// Delete a value, returning the old value if it existed, see
+    /// `Tree::del`.
+    pub fn del(
+        &self,
+        key: Key,
+    ) -> Offloaded<DbResult<Option<Value>, ()>> {
+        self.offload(move |tree| tree.del(&key))
+    }
+
+    /// Atomically applies every operation in `batch`, see
+    /// `Tree::apply_batch`.
+    pub fn apply_batch(
+        &self,
+        batch: Batch,
+    ) -> Offloaded<DbResult<(), ()>> {
+        self.offload(move |tree| tree.apply_batch(batch))
+    }
+
+    /// Flushes any pending IO buffers to disk, finishing once every
+    /// write made before the call is durable, see `Tree::flush`.
+    pub fn flush(&self) -> Offloaded<DbResult<(), ()>> {
+        let flushed = self.tree.flush_async();
+        self.offload(move |_| flushed.wait())
+    }
+
+    // Runs `f` on the pool, returning a future of its result.
+    fn offload<F, T>(&self, f: F) -> Offloaded<T>
+    where
+        F: FnOnce(&Tree) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot {
+            result: None,
+            waker: None,
+        }));
+        let done = slot.clone();
+        let tree = self.tree.clone();
+        let job: Job = Box::new(move || {
+            let result = f(&tree);
+            let mut slot = done.lock().unwrap();
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        // the pool only stops once every handle is dropped
+        let jobs = self.jobs.lock().unwrap();
+        jobs.send(job).expect("the async pool stopped early");
+        Offloaded { slot: slot }
+    }
+}
+
+/// The result of an operation of an `AsyncTree`, which is ready
+/// once the operation is done.
+pub struct Offloaded<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<T>,
+    // the task to wake once `result` is set
+    waker: Option<Waker>,
+}
+
+impl<T> Future for Offloaded<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn run_jobs(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            // every handle was dropped
+            Err(_) => return,
+        };
+        job();
+    }
+}
@@ -0,0 +1,360 @@
+// The format of every export: what `Db::export` and `export_to`
+// write, `export_since` writes the changes since an earlier export
+// in, and `Db::import` and `import_from` read. Every field is laid
+// out by hand, so the format only changes along with its version,
+// whatever happens to the log, the page format or the crate's
+// dependencies. Integers are big endian, and byte strings are a
+// u32 length followed by the bytes. A name is the name of a tree,
+// or the length NO_NAME alone for the default tree.
+//
+//   header: MAGIC, a u32 version, the u64 end of the ids reserved
+//     by `generate_id`, then a u64 crc64 of the header before it
+//   each item: its kind, its fields, then a u64 crc64 of the item
+//     before it. The kinds are
+//     RECORD: a name, the key and the value, then the u64 time the
+//       record expires in ms since the epoch, or 0 if it has no TTL
+//     DEL: a name and the key removed
+//     MERGE: a name, the key and the value merged into it
+//     CLEAR: a name, the first key removed, then the key removed
+//       up to, or NO_NAME alone to remove up to the end of the tree
+//     CREATE: the name of a tree created
+//     DROP: the name of a tree dropped
+//   trailer: END, the u64 number of items, then a u64 crc64 of the
+//     trailer before it, which tells a complete dump from a
+//     truncated one
+
+use std::io::{self, Read, Write};
+
+use super::*;
+
+const MAGIC: &[u8] = b"sled dump";
+const VERSION: u32 = 2;
+
+const RECORD: u8 = 1;
+const END: u8 = 2;
+const DEL: u8 = 3;
+const MERGE: u8 = 4;
+const CLEAR: u8 = 5;
+const CREATE: u8 = 6;
+const DROP: u8 = 7;
+
+const NO_NAME: u32 = std::u32::MAX;
+
+// An item of a dump. A tree is named by its name, or `None` for
+// the default tree.
+#[derive(Debug, PartialEq)]
+pub(crate) enum DumpItem {
+    // a key, its value, and when it expires if it has a TTL
+    Record(Option<Vec<u8>>, Key, Vec<u8>, Option<u64>),
+    Del(Option<Vec<u8>>, Key),
+    Merge(Option<Vec<u8>>, Key, Vec<u8>),
+    // removes the keys from the first up to the second, or up to
+    // the end of the tree if there is no second
+    Clear(Option<Vec<u8>>, Key, Option<Key>),
+    Create(Vec<u8>),
+    Drop(Vec<u8>),
+}
+
+// Writes a dump to `w`, one item at a time.
+pub(crate) struct DumpWriter<W: Write> {
+    w: W,
+    items: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub(crate) fn new(
+        mut w: W,
+        ids_reserved: u64,
+    ) -> io::Result<DumpWriter<W>> {
+        let mut buf = MAGIC.to_vec();
+        put_u32(&mut buf, VERSION);
+        put_u64(&mut buf, ids_reserved);
+        seal(&mut w, buf)?;
+        Ok(DumpWriter { w: w, items: 0 })
+    }
+
+    pub(crate) fn item(&mut self, item: &DumpItem) -> io::Result<()> {
+        let mut buf = vec![];
+        match *item {
+            DumpItem::Record(ref tree, ref key, ref value, at) => {
+                buf.push(RECORD);
+                put_name(&mut buf, tree)?;
+                put_bytes(&mut buf, key)?;
+                put_bytes(&mut buf, value)?;
+                put_u64(&mut buf, at.unwrap_or(0));
+            }
+            DumpItem::Del(ref tree, ref key) => {
+                buf.push(DEL);
+                put_name(&mut buf, tree)?;
+                put_bytes(&mut buf, key)?;
+            }
+            DumpItem::Merge(ref tree, ref key, ref value) => {
+                buf.push(MERGE);
+                put_name(&mut buf, tree)?;
+                put_bytes(&mut buf, key)?;
+                put_bytes(&mut buf, value)?;
+            }
+            DumpItem::Clear(ref tree, ref lo, ref hi) => {
+                buf.push(CLEAR);
+                put_name(&mut buf, tree)?;
+                put_bytes(&mut buf, lo)?;
+                put_name(&mut buf, hi)?;
+            }
+            DumpItem::Create(ref name) => {
+                buf.push(CREATE);
+                put_bytes(&mut buf, name)?;
+            }
+            DumpItem::Drop(ref name) => {
+                buf.push(DROP);
+                put_bytes(&mut buf, name)?;
+            }
+        }
+        seal(&mut self.w, buf)?;
+        self.items += 1;
+        Ok(())
+    }
+
+    // Writes the trailer, returning what the dump was written to.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let mut buf = vec![END];
+        put_u64(&mut buf, self.items);
+        seal(&mut self.w, buf)?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+// Reads a dump from `r`, checking each part against its checksum.
+pub(crate) struct DumpReader<R: Read> {
+    r: R,
+    // how far into the dump we've read, for reporting corruption
+    offset: u64,
+    items: u64,
+    pub(crate) ids_reserved: u64,
+}
+
+impl<R: Read> DumpReader<R> {
+    pub(crate) fn new(r: R) -> DbResult<DumpReader<R>, ()> {
+        let mut reader = DumpReader {
+            r: r,
+            offset: 0,
+            items: 0,
+            ids_reserved: 0,
+        };
+        let mut buf = vec![];
+        let magic = reader.take(&mut buf, MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(Error::Unsupported(
+                "the stream is not a sled dump".to_owned(),
+            ));
+        }
+        let version = reader.u32(&mut buf)?;
+        if version != VERSION {
+            return Err(Error::Unsupported(format!(
+                "can't import dump format version {}",
+                version
+            )));
+        }
+        reader.ids_reserved = reader.u64(&mut buf)?;
+        reader.check(&buf)?;
+        Ok(reader)
+    }
+
+    // The next item, or `None` once the trailer has been read.
+    pub(crate) fn next_item(
+        &mut self,
+    ) -> DbResult<Option<DumpItem>, ()> {
+        let mut buf = vec![];
+        let item = match self.take(&mut buf, 1)?[0] {
+            RECORD => {
+                let tree = self.name(&mut buf)?;
+                let key = self.bytes(&mut buf)?;
+                let value = self.bytes(&mut buf)?;
+                let expires = match self.u64(&mut buf)? {
+                    0 => None,
+                    at => Some(at),
+                };
+                DumpItem::Record(tree, key, value, expires)
+            }
+            DEL => {
+                let tree = self.name(&mut buf)?;
+                DumpItem::Del(tree, self.bytes(&mut buf)?)
+            }
+            MERGE => {
+                let tree = self.name(&mut buf)?;
+                let key = self.bytes(&mut buf)?;
+                DumpItem::Merge(tree, key, self.bytes(&mut buf)?)
+            }
+            CLEAR => {
+                let tree = self.name(&mut buf)?;
+                let lo = self.bytes(&mut buf)?;
+                DumpItem::Clear(tree, lo, self.name(&mut buf)?)
+            }
+            CREATE => DumpItem::Create(self.bytes(&mut buf)?),
+            DROP => DumpItem::Drop(self.bytes(&mut buf)?),
+            END => {
+                let items = self.u64(&mut buf)?;
+                self.check(&buf)?;
+                if items != self.items {
+                    return Err(Error::Corruption {
+                        at: self.offset as LogID,
+                    });
+                }
+                return Ok(None);
+            }
+            _ => {
+                return Err(Error::Corruption {
+                    at: self.offset as LogID,
+                })
+            }
+        };
+        self.check(&buf)?;
+        self.items += 1;
+        Ok(Some(item))
+    }
+
+    fn bytes(&mut self, buf: &mut Vec<u8>) -> DbResult<Vec<u8>, ()> {
+        let len = self.u32(buf)? as usize;
+        Ok(self.take(buf, len)?.to_vec())
+    }
+
+    // A name, or `None` for NO_NAME.
+    fn name(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> DbResult<Option<Vec<u8>>, ()> {
+        match self.u32(buf)? {
+            NO_NAME => Ok(None),
+            len => Ok(Some(self.take(buf, len as usize)?.to_vec())),
+        }
+    }
+
+    // Appends the next `len` bytes to `buf`, returning them. A
+    // corrupted length can't make us allocate more than the stream
+    // actually holds.
+    fn take<'b>(
+        &mut self,
+        buf: &'b mut Vec<u8>,
+        len: usize,
+    ) -> DbResult<&'b [u8], ()> {
+        let start = buf.len();
+        let read =
+            (&mut self.r).take(len as u64).read_to_end(buf)?;
+        self.offset += read as u64;
+        if read < len {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the dump ends part way through",
+            )));
+        }
+        Ok(&buf[start..])
+    }
+
+    fn u32(&mut self, buf: &mut Vec<u8>) -> DbResult<u32, ()> {
+        let bytes = self.take(buf, 4)?;
+        Ok(bytes.iter().fold(0, |n, &b| (n << 8) | u32::from(b)))
+    }
+
+    fn u64(&mut self, buf: &mut Vec<u8>) -> DbResult<u64, ()> {
+        let bytes = self.take(buf, 8)?;
+        Ok(bytes.iter().fold(0, |n, &b| (n << 8) | u64::from(b)))
+    }
+
+    // Reads the checksum that follows `buf`, and checks it.
+    fn check(&mut self, buf: &[u8]) -> DbResult<(), ()> {
+        let at = self.offset;
+        let crc = self.u64(&mut vec![])?;
+        if crc != crc64(buf) {
+            return Err(Error::Corruption { at: at as LogID });
+        }
+        Ok(())
+    }
+}
+
+// Writes `buf` followed by its checksum.
+fn seal<W: Write>(w: &mut W, mut buf: Vec<u8>) -> io::Result<()> {
+    let crc = crc64(&buf);
+    put_u64(&mut buf, crc);
+    w.write_all(&buf)
+}
+
+fn put_name(
+    buf: &mut Vec<u8>,
+    name: &Option<Vec<u8>>,
+) -> io::Result<()> {
+    match *name {
+        Some(ref name) => put_bytes(buf, name),
+        None => {
+            put_u32(buf, NO_NAME);
+            Ok(())
+        }
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() >= NO_NAME as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a dump can't hold a key, value or name of 4GB or more",
+        ));
+    }
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend((0..4).rev().map(|i| (n >> (i * 8)) as u8));
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend((0..8).rev().map(|i| (n >> (i * 8)) as u8));
+}
+
+#[test]
+fn test_dump_format_is_stable() {
+    let items = vec![
+        DumpItem::Record(None, b"k".to_vec(), b"v".to_vec(), None),
+        DumpItem::Record(
+            Some(b"t".to_vec()),
+            vec![],
+            vec![],
+            Some(9),
+        ),
+        DumpItem::Clear(None, b"a".to_vec(), None),
+        DumpItem::Drop(b"t".to_vec()),
+    ];
+    let mut w = DumpWriter::new(vec![], 7).unwrap();
+    for item in &items {
+        w.item(item).unwrap();
+    }
+    let dump = w.finish().unwrap();
+
+    // laid out by hand, so that a change to the format fails here
+    let mut header = b"sled dump".to_vec();
+    header.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7]);
+    let mut first = vec![RECORD, 255, 255, 255, 255];
+    first.extend_from_slice(&[0, 0, 0, 1, b'k', 0, 0, 0, 1, b'v']);
+    first.extend_from_slice(&[0; 8]);
+    let mut second = vec![RECORD, 0, 0, 0, 1, b't'];
+    second.extend_from_slice(&[0; 8]);
+    second.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 9]);
+    let mut third = vec![CLEAR, 255, 255, 255, 255];
+    third.extend_from_slice(&[0, 0, 0, 1, b'a', 255, 255, 255, 255]);
+    let fourth = vec![DROP, 0, 0, 0, 1, b't'];
+    let trailer = vec![END, 0, 0, 0, 0, 0, 0, 0, 4];
+    let mut expected = vec![];
+    for part in vec![header, first, second, third, fourth, trailer] {
+        let crc = crc64(&part);
+        expected.extend_from_slice(&part);
+        put_u64(&mut expected, crc);
+    }
+    assert_eq!(dump, expected);
+
+    let mut r = DumpReader::new(&*dump).unwrap();
+    assert_eq!(r.ids_reserved, 7);
+    for item in items {
+        assert_eq!(r.next_item(), Ok(Some(item)));
+    }
+    assert_eq!(r.next_item(), Ok(None));
+}
@@ -0,0 +1,154 @@
+// Streams the changes made to a `Db` to a follower. Changes are
+// read back out of the frags logged since the last poll, up to
+// what is durable, so each write is sent as it was made.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use bincode::{serialize, Infinite};
+
+use super::*;
+
+// How long a stream waits before looking again when nothing new
+// has been written.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a `Change` does, to its key or starting at it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// Sets the key to a value, which expires at the given time in
+    /// milliseconds since the epoch if it was set with a TTL.
+    Set(Vec<u8>, Option<u64>),
+    /// Removes the key.
+    Del,
+    /// Merges a value into the key with the merge operator.
+    Merge(Vec<u8>),
+    /// Removes every key from the change's key up to, but not
+    /// including, this one, or up to the end of the tree if there
+    /// is none.
+    Clear(Option<Vec<u8>>),
+}
+
+/// A change to one of a database's trees, yielded by a
+/// `ReplicationStream` and applied with `Db::apply_change`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// The name of the tree, or `None` for the default tree.
+    pub tree: Option<Vec<u8>>,
+    /// The key the change applies to, or starts at for a `Clear`.
+    pub key: Vec<u8>,
+    /// What the change does.
+    pub op: ChangeOp,
+    /// Where the write was logged. Changes come out in order of
+    /// this, and the writes of a batch or transaction share it. A
+    /// new stream started at it yields this change again, along
+    /// with the others that share it, and one started past it
+    /// yields the changes after them.
+    pub lsn: Lsn,
+    /// A crc64 of the other fields, checked by `is_intact`.
+    pub crc64: u64,
+}
+
+impl Change {
+    pub(crate) fn new(
+        tree: Option<Vec<u8>>,
+        key: Vec<u8>,
+        op: ChangeOp,
+        lsn: Lsn,
+    ) -> Change {
+        let mut change = Change {
+            tree: tree,
+            key: key,
+            op: op,
+            lsn: lsn,
+            crc64: 0,
+        };
+        change.crc64 = change.checksum();
+        change
+    }
+
+    fn checksum(&self) -> u64 {
+        let fields = (&self.tree, &self.key, &self.op, self.lsn);
+        crc64(&serialize(&fields, Infinite).unwrap())
+    }
+
+    /// Whether the change is still as it was yielded, for checking
+    /// it after it has been sent to a follower.
+    pub fn is_intact(&self) -> bool {
+        self.checksum() == self.crc64
+    }
+}
+
+/// The changes made to a `Db`, returned by
+/// `Db::replication_stream`. Iterating blocks until a change is
+/// durable. The stream never flushes the log itself, so changes
+/// come out as `flush_every_ms` or a `flush` makes them durable,
+/// and the stream never ends by itself.
+///
+/// Each write is yielded as it was made, in the order it was
+/// logged, with the `lsn` it was logged at: a `Set` or `Del` of a
+/// key, a `Merge` into it, or a `Clear` of the keys that
+/// `remove_range` or `Tree::clear` removed from a leaf. A follower
+/// that applies them in order passes through the same states as
+/// the database, and one that restarts can resume from the `lsn`
+/// of the last change it applied, which yields the changes that
+/// share that `lsn` again. Trees filled by `bulk_load`, and values
+/// rewritten by a migration, aren't streamed, as they don't write
+/// record by record. A stream that falls so far behind that the
+/// log segments it hasn't read yet are reused fails with
+/// `Error::Unsupported`, and the follower has to import a new
+/// export.
+pub struct ReplicationStream {
+    db: Db,
+    // where the next read of the log starts
+    lsn: Lsn,
+    ready: VecDeque<Change>,
+}
+
+impl ReplicationStream {
+    pub(crate) fn new(db: Db, lsn: Lsn) -> ReplicationStream {
+        ReplicationStream {
+            db: db,
+            lsn: lsn,
+            ready: VecDeque::new(),
+        }
+    }
+
+    // Queues the changes made durable since the last poll, or
+    // waits a while if there are none.
+    fn poll(&mut self) -> DbResult<(), ()> {
+        // what is written after this is read by the next poll
+        let stable = self.db.stable_lsn();
+        if stable <= self.lsn {
+            thread::sleep(POLL_INTERVAL);
+            return Ok(());
+        }
+        let changes = self.db.changes_between(self.lsn, stable)?;
+        self.ready.extend(changes);
+        self.lsn = stable;
+        Ok(())
+    }
+}
+
+impl Iterator for ReplicationStream {
+    type Item = DbResult<Change, ()>;
+
+    fn next(&mut self) -> Option<DbResult<Change, ()>> {
+        while self.ready.is_empty() {
+            if let Err(e) = self.poll() {
+                return Some(Err(e));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+#[test]
+fn test_change_checksum() {
+    let op = ChangeOp::Set(vec![1], None);
+    let mut change = Change::new(None, vec![0], op, 7);
+    assert!(change.is_intact());
+    change.key.push(1);
+    assert!(!change.is_intact());
+}
@@ -0,0 +1,186 @@
+// A batch of writes that `Tree::apply_batch` applies atomically.
+// The batch only records operations; planning them against leaves
+// and logging them happens in the tree.
+
+use super::*;
+
+/// A set of `set`, `del` and `merge` operations, applied in the
+/// order they were added by `Tree::apply_batch`. Either every
+/// operation in the batch becomes durable and visible, or none do.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// t.set(vec![1], vec![1]).unwrap();
+///
+/// let mut batch = sled::Batch::default();
+/// batch.set(vec![2], vec![2]);
+/// batch.set(vec![3], vec![3]);
+/// batch.del(vec![1]);
+/// t.apply_batch(batch).unwrap();
+///
+/// assert_eq!(t.get(&[1]), Ok(None));
+/// assert_eq!(t.get(&[2]), Ok(Some(vec![2].into())));
+/// assert_eq!(t.get(&[3]), Ok(Some(vec![3].into())));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    pub(super) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum BatchOp {
+    Set(Key, Value),
+    Del(Key),
+    Merge(Key, Value),
+}
+
+impl BatchOp {
+    pub(super) fn key(&self) -> &[u8] {
+        match *self {
+            BatchOp::Set(ref k, _)
+            | BatchOp::Del(ref k)
+            | BatchOp::Merge(ref k, _) => k,
+        }
+    }
+
+    // The frag for this operation in a leaf whose low bound is
+    // `lo`.
+    pub(super) fn to_frag(&self, lo: &[u8]) -> Frag {
+        match *self {
+            BatchOp::Set(ref k, ref v) => {
+                blob::set_frag(prefix_encode(lo, k), v.clone())
+            }
+            BatchOp::Del(ref k) => Frag::Del(prefix_encode(lo, k)),
+            BatchOp::Merge(ref k, ref v) => {
+                Frag::Merge(prefix_encode(lo, k), v.clone())
+            }
+        }
+    }
+
+    // The event subscribers see for this operation, given the
+    // change it made to its leaf, which must be recorded for a
+    // merge so that the event holds the value it resolved to.
+    pub(super) fn to_event(&self, change: Option<&Change>) -> Event {
+        match *self {
+            BatchOp::Set(ref k, ref v) => {
+                Event::Set(k.clone(), v.clone())
+            }
+            BatchOp::Del(ref k) => Event::Del(k.clone()),
+            BatchOp::Merge(ref k, _) => {
+                let change = change.expect(
+                    "the changes of a batch's merges are recorded",
+                );
+                match change.2 {
+                    Some(ref merged) => {
+                        Event::Merge(k.clone(), merged.clone())
+                    }
+                    None => Event::Del(k.clone()),
+                }
+            }
+        }
+    }
+}
+
+impl Batch {
+    /// Set a key to a new value.
+    pub fn set<V: Into<Value>>(&mut self, key: Key, value: V) {
+        self.ops.push(BatchOp::Set(key, value.into()));
+    }
+
+    /// Delete a key, if it exists.
+    pub fn del(&mut self, key: Key) {
+        self.ops.push(BatchOp::Del(key));
+    }
+
+    /// Merge a new value into the total state for a key, using the
+    /// tree's configured merge operator. The merge applies to what
+    /// the key holds at that point in the batch, after any earlier
+    /// operation on it in the same batch.
+    pub fn merge<V: Into<Value>>(&mut self, key: Key, value: V) {
+        self.ops.push(BatchOp::Merge(key, value.into()));
+    }
+
+    /// The number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Whether the batch merges into any key.
+    pub(super) fn has_merges(&self) -> bool {
+        self.ops.iter().any(|op| match *op {
+            BatchOp::Merge(..) => true,
+            _ => false,
+        })
+    }
+
+    // This batch with `f` applied to each value it sets.
+    pub(super) fn map_sets<F>(&self, mut f: F) -> DbResult<Batch, ()>
+    where
+        F: FnMut(Value) -> DbResult<Value, ()>,
+    {
+        let mut ops = Vec::with_capacity(self.ops.len());
+        for op in &self.ops {
+            ops.push(match *op {
+                BatchOp::Set(ref k, ref v) => {
+                    BatchOp::Set(k.clone(), f(v.clone())?)
+                }
+                ref other => other.clone(),
+            });
+        }
+        Ok(Batch { ops: ops })
+    }
+
+    // The blobs that the values this batch sets were written to.
+    pub(super) fn blobs(&self) -> Vec<PageID> {
+        self.ops
+            .iter()
+            .filter_map(|op| match *op {
+                BatchOp::Set(_, ref v) => v.blob(),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// A leaf as a batch will leave it. A leaf that already exists
+// gets `frags` linked to it, while one split off within the batch
+// is written whole as a new page before the batch is linked.
+pub(super) struct PlannedLeaf<'g> {
+    // the existing leaf this one is, or was split off of
+    pub(super) origin: PageID,
+    // the key of the existing leaf when it was read, which the
+    // batch is only linked onto if it's still current
+    pub(super) head: TreePtr<'g>,
+    // the right sibling of `origin` before the batch
+    pub(super) origin_next: Option<PageID>,
+    pub(super) is_new: bool,
+    pub(super) node: Node,
+    pub(super) frags: Vec<Frag>,
+    pub(super) split_hint: bool,
+    // the blobs an existing leaf referred to before the batch
+    pub(super) blobs: Vec<PageID>,
+}
+
+impl<'g> PlannedLeaf<'g> {
+    pub(super) fn contains(&self, key: &[u8]) -> bool {
+        self.node.lo.inner() <= key
+            && Bound::Inclusive(key.to_vec()) < self.node.hi
+    }
+
+    // One frag holding everything written to an existing leaf.
+    pub(super) fn to_frag(&self) -> Frag {
+        if self.frags.len() == 1 {
+            self.frags[0].clone()
+        } else {
+            Frag::Writes(self.frags.clone())
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Large values. When `blob_threshold` is set, a value at least that
+// long is written to a page of its own as a `Frag::Blob` before the
+// write that sets it, and is linked with a `Frag::SetBlob`. The
+// leaf record holds the whole value in memory, in an `IVec` that
+// remembers the page, but whenever the leaf is written to the log,
+// whether by the `SetBlob` or by a consolidated `Frag::Base`, only
+// the page id is written. When a leaf is read back in, the
+// pagecache reads in the pages it refers to and fills them back
+// in. So consolidating, splitting or paging in a leaf never
+// rewrites the large values it holds. A write that drops a record
+// holding a blob frees its page, after which the segments the blob
+// was written to are reclaimed like those of any other freed page.
+// A crash between writing a blob and linking the write that sets
+// it, or between that link and freeing a blob it dropped, leaves
+// the blob's page allocated.
+
+use std::collections::HashSet;
+
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::*;
+
+// Lets `Frag::SetBlob` log only the page its value was written to.
+pub fn serialize<S>(
+    value: &Value,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value.blob() {
+        Some(pid) => (pid as u64).serialize(serializer),
+        None => Err(S::Error::custom(
+            "a SetBlob value was not written to a page",
+        )),
+    }
+}
+
+pub fn deserialize<'de, D>(
+    deserializer: D,
+) -> Result<Value, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pid = u64::deserialize(deserializer)?;
+    if pid > PageID::max_value() as u64 {
+        return Err(D::Error::custom("blob page id out of range"));
+    }
+    Ok(Value::blob_ref(pid as PageID))
+}
+
+// The frag that sets the key `encoded_key` to `value`, which is a
+// SetBlob if the value was written to a blob.
+pub(super) fn set_frag(encoded_key: Key, value: Value) -> Frag {
+    if value.blob().is_some() {
+        Frag::SetBlob(encoded_key, value)
+    } else {
+        Frag::Set(encoded_key, value)
+    }
+}
+
+// The pages that the large values in `frag` were written to, in
+// the order `fill` fills them in.
+pub(super) fn references(frag: &Frag) -> Vec<PageID> {
+    let mut pids = vec![];
+    visit(frag, &mut |value| pids.extend(value.blob()));
+    pids
+}
+
+// Fills the pages listed by `references` into `frag`. A blob that
+// was freed since, or whose page now holds something else, belongs
+// to a record that a later frag replaced, so it's left empty.
+pub(super) fn fill(frag: &mut Frag, pages: Vec<Option<Frag>>) {
+    let mut pages = pages.into_iter();
+    visit_mut(frag, &mut |value| {
+        let pid = match value.blob() {
+            Some(pid) => pid,
+            None => return,
+        };
+        if let Some(Some(Frag::Blob(bytes))) = pages.next() {
+            *value = bytes.with_blob(pid);
+        }
+    });
+}
+
+// The pages written for the large values of a leaf.
+pub(super) fn blobs(node: &Node) -> Vec<PageID> {
+    match node.data {
+        Data::Leaf(ref records) => records
+            .iter()
+            .filter_map(|&(_, ref v)| v.blob())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// The pages in `before` that aren't in `after`.
+pub(super) fn dropped(
+    before: Vec<PageID>,
+    after: &[PageID],
+) -> Vec<PageID> {
+    let after: HashSet<&PageID> = after.iter().collect();
+    before.into_iter().filter(|pid| !after.contains(pid)).collect()
+}
+
+fn visit<F: FnMut(&Value)>(frag: &Frag, f: &mut F) {
+    match *frag {
+        Frag::Base(ref node, _) => visit_node(node, f),
+        Frag::ChildMerge(ref merge) => visit_node(&merge.right, f),
+        Frag::SetBlob(_, ref value) => f(value),
+        Frag::Writes(ref frags) => {
+            for frag in frags {
+                visit(frag, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn visit_node<F: FnMut(&Value)>(node: &Node, f: &mut F) {
+    if let Data::Leaf(ref records) = node.data {
+        for &(_, ref value) in records {
+            f(value);
+        }
+    }
+}
+
+fn visit_mut<F: FnMut(&mut Value)>(frag: &mut Frag, f: &mut F) {
+    match *frag {
+        Frag::Base(ref mut node, _) => visit_node_mut(node, f),
+        Frag::ChildMerge(ref mut merge) => {
+            visit_node_mut(&mut merge.right, f)
+        }
+        Frag::SetBlob(_, ref mut value) => f(value),
+        Frag::Writes(ref mut frags) => {
+            for frag in frags {
+                visit_mut(frag, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn visit_node_mut<F: FnMut(&mut Value)>(
+    node: &mut Node,
+    f: &mut F,
+) {
+    if let Data::Leaf(ref mut records) = node.data {
+        for &mut (_, ref mut value) in records {
+            f(value);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Serialization for `Data::BlockLeaf`, which stores its records
+// exactly like `Data::Leaf` in memory but writes all of its values
+// as a single block, compressed with zstd when the `zstd` feature
+// is enabled. Compressing the values together lets repetition
+// across them be shared, which usually beats compressing each one
+// alone. Blocks are decompressed when a page is read back, so the
+// cached node holds plain values and reads never pay for it.
+
+use bincode::{self, Infinite};
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(feature = "zstd")]
+use pagecache::{compress, decompress};
+
+use super::*;
+
+// matches the default `zstd_compression_factor`
+#[cfg(feature = "zstd")]
+const COMPRESSION_LEVEL: i32 = 5;
+
+#[derive(Serialize)]
+struct Encoded<'a> {
+    keys: Vec<&'a Key>,
+    compressed: bool,
+    raw_len: u64,
+    block: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Decoded {
+    keys: Vec<Key>,
+    compressed: bool,
+    raw_len: u64,
+    block: Vec<u8>,
+}
+
+pub fn serialize<S>(
+    records: &[(Key, Value)],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let values: Vec<&Value> =
+        records.iter().map(|&(_, ref v)| v).collect();
+    let raw = bincode::serialize(&values, Infinite)
+        .map_err(S::Error::custom)?;
+    let raw_len = raw.len() as u64;
+
+    #[cfg(feature = "zstd")]
+    let (compressed, block) = (
+        true,
+        compress(&*raw, COMPRESSION_LEVEL).map_err(S::Error::custom)?,
+    );
+
+    #[cfg(not(feature = "zstd"))]
+    let (compressed, block) = (false, raw);
+
+    Encoded {
+        keys: records.iter().map(|&(ref k, _)| k).collect(),
+        compressed: compressed,
+        raw_len: raw_len,
+        block: block,
+    }.serialize(serializer)
+}
+
+pub fn deserialize<'de, D>(
+    deserializer: D,
+) -> Result<Vec<(Key, Value)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let decoded = Decoded::deserialize(deserializer)?;
+
+    let raw = if decoded.compressed {
+        decompress_block(&decoded.block, decoded.raw_len)
+            .map_err(D::Error::custom)?
+    } else {
+        decoded.block
+    };
+    let values: Vec<Value> = bincode::deserialize(&*raw)
+        .map_err(D::Error::custom)?;
+
+    if values.len() != decoded.keys.len() {
+        return Err(D::Error::custom(format!(
+            "value block holds {} values for {} keys",
+            values.len(),
+            decoded.keys.len()
+        )));
+    }
+    Ok(decoded.keys.into_iter().zip(values).collect())
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_block(
+    block: &[u8],
+    raw_len: u64,
+) -> Result<Vec<u8>, String> {
+    decompress(block, raw_len as usize).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_block(
+    _block: &[u8],
+    _raw_len: u64,
+) -> Result<Vec<u8>, String> {
+    Err("value block is compressed, but zstd is not enabled"
+        .to_owned())
+}
+
+#[cfg(test)]
+fn json_ish_leaf() -> Vec<(Key, Value)> {
+    let statuses = ["active", "pending", "suspended"];
+    (0..500u32)
+        .map(|i| {
+            let k = format!("user{:05}", i).into_bytes();
+            let v = format!(
+                "{{\"id\":{},\"status\":\"{}\",\"plan\":\"basic\"}}",
+                i,
+                statuses[i as usize % statuses.len()]
+            );
+            (prefix_encode(b"", &k), v.into_bytes().into())
+        })
+        .collect()
+}
+
+#[test]
+fn test_block_leaf_round_trip() {
+    use bincode::{deserialize, serialize};
+
+    let data = Data::BlockLeaf(json_ish_leaf());
+    let bytes = serialize(&data, Infinite).unwrap();
+    assert_eq!(deserialize::<Data>(&bytes).unwrap(), data);
+
+    let empty = Data::BlockLeaf(vec![]);
+    let bytes = serialize(&empty, Infinite).unwrap();
+    assert_eq!(deserialize::<Data>(&bytes).unwrap(), empty);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_block_leaf_compression_ratio() {
+    let values: Vec<Value> =
+        json_ish_leaf().into_iter().map(|(_, v)| v).collect();
+    let raw = bincode::serialize(&values, Infinite).unwrap();
+    let block = compress(&*raw, COMPRESSION_LEVEL).unwrap().len();
+    let per_value: usize = values
+        .iter()
+        .map(|v| compress(v, COMPRESSION_LEVEL).unwrap().len())
+        .sum();
+
+    assert!(
+        block * 4 < per_value,
+        "block was {} bytes, per-value compression was {}",
+        block,
+        per_value
+    );
+}
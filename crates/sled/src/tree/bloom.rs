@@ -0,0 +1,176 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use super::*;
+
+// ten bits and seven hashes per key give about a 1% false
+// positive rate when the filter is built.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+const MAX_FALSE_POSITIVE_RATE: f64 = 0.05;
+
+/// A Bloom filter over the decoded keys of a leaf, which answers
+/// "definitely not present" for most absent keys without searching
+/// the records. Bits can't be cleared, so a deleted key keeps
+/// answering "maybe" until the filter is rebuilt, which happens
+/// once enough keys are deleted or inserted past the filter's
+/// sizing that its false positive rate passes 5%.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bloom {
+    bits: Vec<u64>,
+    // keys inserted, counting ones that have since been deleted
+    inserted: usize,
+    removed: usize,
+}
+
+impl Bloom {
+    /// Builds a filter sized for `keys`.
+    pub fn with_keys<'a, I>(keys: I, len: usize) -> Bloom
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let words = (len * BITS_PER_KEY + 63) / 64;
+        let mut bloom = Bloom {
+            bits: vec![0; std::cmp::max(words, 1)],
+            inserted: 0,
+            removed: 0,
+        };
+        for key in keys {
+            bloom.insert(key);
+        }
+        bloom
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in bit_positions(key, self.bits.len()) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.inserted += 1;
+    }
+
+    /// Records that `count` keys were deleted, which leaves their
+    /// bits set.
+    pub fn note_removed(&mut self, count: usize) {
+        self.removed += count;
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        bit_positions(key, self.bits.len())
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The chance that an absent key is reported as possibly
+    /// present, counting deleted keys that still have bits set.
+    pub fn false_positive_rate(&self) -> f64 {
+        let set: u32 = self.bits.iter().map(|w| w.count_ones()).sum();
+        let fill = set as f64 / (self.bits.len() * 64) as f64;
+        let stale = if self.inserted == 0 {
+            0.
+        } else {
+            self.removed as f64 / self.inserted as f64
+        };
+        fill.powi(HASHES as i32).max(stale)
+    }
+}
+
+// Derives every probe position into a filter of `words` 64 bit
+// words from two halves of one hash.
+fn bit_positions(
+    key: &[u8],
+    words: usize,
+) -> impl Iterator<Item = usize> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xFFFF_FFFF, hash >> 32);
+    let len = words as u64 * 64;
+    (0..HASHES).map(move |i| {
+        (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize
+    })
+}
+
+impl Node {
+    /// Returns `false` if a prefix-encoded key is definitely not in
+    /// this leaf. Without a filter, every key may be present.
+    pub fn may_contain(&self, key: KeyRef) -> bool {
+        match self.bloom {
+            Some(ref bloom) => {
+                bloom.may_contain(&prefix_decode(self.prefix(), key))
+            }
+            None => true,
+        }
+    }
+
+    /// Builds a filter for a leaf that doesn't have one, and
+    /// rebuilds one whose false positive rate has degraded past
+    /// the threshold. Index nodes never get a filter.
+    pub fn refresh_bloom(&mut self) {
+        if let Data::Index(_) = self.data {
+            return;
+        }
+        let degraded = self.bloom.as_ref().map_or(true, |bloom| {
+            bloom.false_positive_rate() > MAX_FALSE_POSITIVE_RATE
+        });
+        if degraded {
+            let keys = self.data.keys(self.prefix());
+            self.bloom = Some(Bloom::with_keys(
+                keys.iter().map(|k| &**k),
+                keys.len(),
+            ));
+        }
+    }
+
+    // Adds a prefix-encoded key to the filter, if there is one.
+    pub(super) fn bloom_insert(&mut self, key: KeyRef) {
+        if self.bloom.is_some() {
+            let decoded = prefix_decode(self.prefix(), key);
+            self.bloom.as_mut().unwrap().insert(&decoded);
+        }
+    }
+
+    pub(super) fn bloom_removed(&mut self, count: usize) {
+        if let Some(ref mut bloom) = self.bloom {
+            bloom.note_removed(count);
+        }
+    }
+}
+
+#[test]
+fn test_bloom_has_no_false_negatives() {
+    let config =
+        ConfigBuilder::new().leaf_bloom_filters(true).build();
+    let lo = b"k".to_vec();
+    let mut node = leaf(Bound::Inclusive(lo.clone()), Bound::Inf);
+    let key = |i: u32| {
+        let decoded = format!("k{:05}", i).into_bytes();
+        prefix_encode(&lo, &decoded)
+    };
+
+    for i in 0..2000 {
+        let frag = Frag::Set(key(i), vec![].into());
+        node.apply(&frag, &config).unwrap();
+        if i % 3 == 0 {
+            node.apply(&Frag::Del(key(i / 2)), &config).unwrap();
+        }
+    }
+    assert!(node.bloom.is_some());
+
+    let mut absent_hits = 0;
+    for i in 0..4000 {
+        let present = node.data.leaf_get(&key(i)).is_some();
+        let maybe = node.may_contain(&key(i));
+        assert!(maybe || !present, "false negative for key {}", i);
+        if maybe && !present {
+            absent_hits += 1;
+        }
+    }
+    assert!(
+        absent_hits < 4000 / 4,
+        "{} absent keys may have been present",
+        absent_hits
+    );
+
+    // dropping the filter, as deserializing does, is always safe
+    node.bloom = None;
+    assert!(node.may_contain(&key(1_000_000)));
+}
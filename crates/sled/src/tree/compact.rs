@@ -0,0 +1,667 @@
+// A compact binary layout for nodes, used when a `Frag::Base` is
+// written to the log. Integers are varints and byte strings are
+// length-prefixed. Keys are written as the length they share with
+// the previous key followed by the rest, since neighboring keys in
+// a node usually share much more than the prefix already elided
+// against `lo`. `IntLeaf`, `BlockLeaf` and `CompressedLeaf` carry
+// their own encodings, so their data is embedded as it serializes
+// itself.
+//
+// Node layout:
+//   varint id
+//   option next, option prev: 0, or 1 followed by a varint
+//   bound lo, bound hi: 0 inclusive or 1 exclusive followed by
+//     bytes, or 2 for Inf
+//   option prefix: 0, or 1 followed by bytes
+//   varint count of the records in the data, checked against the
+//     records read back so a short read can't go unnoticed
+//   data tag, shared with `stream`, followed by the data
+//   expirations, only if the leaf has any: EXPIRIES, a varint
+//     count, then each key followed by a varint time
+//   blobs, only if a `Leaf` has any: BLOBS, a varint count, then
+//     the varint index of each record whose value was written to
+//     a page of its own followed by that page's varint id. The
+//     values of those records are written empty
+
+use bincode::{self, Infinite};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serializer};
+
+use super::delta::{read_varint, write_varint};
+use super::stream::{
+    BLOCK_LEAF, COMPRESSED_LEAF, DICT_LEAF, INDEX, INT_LEAF, LEAF,
+    LEAF_SET,
+};
+use super::*;
+
+const INCLUSIVE: u8 = 0;
+const EXCLUSIVE: u8 = 1;
+const INF: u8 = 2;
+
+// Tags the optional section after the data. Not 0, so that a
+// stray zero byte still reads as trailing garbage.
+const EXPIRIES: u8 = 1;
+const BLOBS: u8 = 2;
+
+/// Reasons `Node::deserialize_from` may reject a buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The buffer ended partway through the node.
+    Truncated,
+    /// A tag byte didn't name a kind of bound, option or `Data`.
+    UnknownTag(u8),
+    /// A key claimed to share more bytes with the previous key
+    /// than that key has.
+    BadSharedLen { shared: usize, prev_len: usize },
+    /// The embedded encoding of an `IntLeaf`, `BlockLeaf` or
+    /// `CompressedLeaf` failed to decode.
+    Data(String),
+    /// Bytes were left over after the node.
+    TrailingBytes(usize),
+    /// The data decoded, but holds a different number of records
+    /// than the header says were written.
+    CountMismatch { expected: usize, actual: usize },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "node is truncated"),
+            DecodeError::UnknownTag(tag) => {
+                write!(f, "unknown tag {}", tag)
+            }
+            DecodeError::BadSharedLen { shared, prev_len } => write!(
+                f,
+                "key shares {} bytes with a {} byte previous key",
+                shared, prev_len
+            ),
+            DecodeError::Data(ref e) => {
+                write!(f, "failed to decode node data: {}", e)
+            }
+            DecodeError::TrailingBytes(len) => {
+                write!(f, "{} bytes follow the node", len)
+            }
+            DecodeError::CountMismatch { expected, actual } => write!(
+                f,
+                "node was written with {} records but holds {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Node {
+    /// Appends this node to `buf` in the compact layout, which is
+    /// read back by `Node::deserialize_from`.
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.id as u64);
+        write_option_id(buf, self.next);
+        write_option_id(buf, self.prev);
+        write_bound(buf, &self.lo);
+        write_bound(buf, &self.hi);
+        match self.prefix {
+            Some(ref prefix) => {
+                buf.push(1);
+                write_bytes(buf, prefix);
+            }
+            None => buf.push(0),
+        }
+        write_varint(buf, self.data.len() as u64);
+
+        match self.data {
+            Data::Index(ref ptrs) => {
+                buf.push(INDEX);
+                write_varint(buf, ptrs.len() as u64);
+                let mut prev: &[u8] = &[];
+                for &(ref k, id) in ptrs {
+                    write_key(buf, prev, k);
+                    write_varint(buf, id as u64);
+                    prev = k;
+                }
+            }
+            Data::Leaf(ref items) => {
+                buf.push(LEAF);
+                write_records(buf, items);
+            }
+            Data::DictLeaf(ref dict) => {
+                buf.push(DICT_LEAF);
+                write_varint(buf, dict.values().len() as u64);
+                for value in dict.values() {
+                    write_bytes(buf, value);
+                }
+                write_varint(buf, dict.records().len() as u64);
+                let mut prev: &[u8] = &[];
+                for &(ref k, idx) in dict.records() {
+                    write_key(buf, prev, k);
+                    write_varint(buf, idx as u64);
+                    prev = k;
+                }
+            }
+            Data::LeafSet(ref keys) => {
+                buf.push(LEAF_SET);
+                write_varint(buf, keys.len() as u64);
+                let mut prev: &[u8] = &[];
+                for &(ref k, _) in keys {
+                    write_key(buf, prev, k);
+                    prev = k;
+                }
+            }
+            Data::IntLeaf(_) => {
+                buf.push(INT_LEAF);
+                write_embedded(buf, &self.data);
+            }
+            Data::BlockLeaf(_) => {
+                buf.push(BLOCK_LEAF);
+                write_embedded(buf, &self.data);
+            }
+            Data::CompressedLeaf(..) => {
+                buf.push(COMPRESSED_LEAF);
+                write_embedded(buf, &self.data);
+            }
+        }
+
+        if !self.expiries.is_empty() {
+            buf.push(EXPIRIES);
+            write_varint(buf, self.expiries.len() as u64);
+            let mut prev: &[u8] = &[];
+            for (k, at) in self.expiries.iter() {
+                write_key(buf, prev, k);
+                write_varint(buf, at);
+                prev = k;
+            }
+        }
+
+        if let Data::Leaf(ref items) = self.data {
+            let blobs: Vec<(usize, PageID)> = items
+                .iter()
+                .enumerate()
+                .filter_map(|(i, &(_, ref v))| {
+                    v.blob().map(|pid| (i, pid))
+                })
+                .collect();
+            if !blobs.is_empty() {
+                buf.push(BLOBS);
+                write_varint(buf, blobs.len() as u64);
+                for (i, pid) in blobs {
+                    write_varint(buf, i as u64);
+                    write_varint(buf, pid as u64);
+                }
+            }
+        }
+    }
+
+    /// Reads a node written by `Node::serialize_into`, which must
+    /// take up all of `buf`.
+    pub fn deserialize_from(buf: &[u8]) -> Result<Node, DecodeError> {
+        let mut r = Reader { buf: buf };
+        let id = r.varint()? as PageID;
+        let next = r.option_id()?;
+        let prev = r.option_id()?;
+        let lo = r.bound()?;
+        let hi = r.bound()?;
+        let prefix = match r.byte()? {
+            0 => None,
+            1 => Some(r.bytes()?),
+            other => return Err(DecodeError::UnknownTag(other)),
+        };
+        let count = r.varint()? as usize;
+
+        let mut data = match r.byte()? {
+            INDEX => {
+                let len = r.len()?;
+                let mut ptrs = Vec::with_capacity(len);
+                let mut prev = vec![];
+                for _ in 0..len {
+                    let k = r.key(&prev)?;
+                    ptrs.push((k.clone(), r.varint()? as PageID));
+                    prev = k;
+                }
+                Data::Index(ptrs)
+            }
+            LEAF => Data::Leaf(r.records()?),
+            DICT_LEAF => {
+                let len = r.len()?;
+                let mut values = Vec::with_capacity(len);
+                for _ in 0..len {
+                    values.push(Value::from(r.bytes()?));
+                }
+                let len = r.len()?;
+                let mut records = Vec::with_capacity(len);
+                let mut prev = vec![];
+                for _ in 0..len {
+                    let k = r.key(&prev)?;
+                    let idx = r.varint()?;
+                    if idx >= values.len() as u64 {
+                        return Err(DecodeError::Data(format!(
+                            "record refers to value {} of {}",
+                            idx,
+                            values.len()
+                        )));
+                    }
+                    records.push((k.clone(), idx as u32));
+                    prev = k;
+                }
+                Data::DictLeaf(ValueDict::from_parts(values, records))
+            }
+            LEAF_SET => {
+                let len = r.len()?;
+                let mut keys = Vec::with_capacity(len);
+                let mut prev = vec![];
+                for _ in 0..len {
+                    let k = r.key(&prev)?;
+                    keys.push((k.clone(), ()));
+                    prev = k;
+                }
+                Data::LeafSet(keys)
+            }
+            tag @ INT_LEAF
+            | tag @ BLOCK_LEAF
+            | tag @ COMPRESSED_LEAF => {
+                let embedded = r.bytes()?;
+                let data: Data = bincode::deserialize(&embedded)
+                    .map_err(|e| DecodeError::Data(e.to_string()))?;
+                let matches = match data {
+                    Data::IntLeaf(_) => tag == INT_LEAF,
+                    Data::BlockLeaf(_) => tag == BLOCK_LEAF,
+                    Data::CompressedLeaf(..) => {
+                        tag == COMPRESSED_LEAF
+                    }
+                    _ => false,
+                };
+                if !matches {
+                    return Err(DecodeError::Data(format!(
+                        "data does not match tag {}",
+                        tag
+                    )));
+                }
+                data
+            }
+            other => return Err(DecodeError::UnknownTag(other)),
+        };
+        if data.len() != count {
+            return Err(DecodeError::CountMismatch {
+                expected: count,
+                actual: data.len(),
+            });
+        }
+
+        let mut expiries = Expiries::default();
+        if r.buf.first() == Some(&EXPIRIES) {
+            r.byte()?;
+            let len = r.len()?;
+            let mut prev = vec![];
+            for _ in 0..len {
+                let k = r.key(&prev)?;
+                expiries.set(k.clone(), r.varint()?);
+                prev = k;
+            }
+        }
+
+        if r.buf.first() == Some(&BLOBS) {
+            r.byte()?;
+            let len = r.len()?;
+            for _ in 0..len {
+                let i = r.varint()?;
+                let pid = r.varint()? as PageID;
+                let record = match data {
+                    Data::Leaf(ref mut items) => {
+                        items.get_mut(i as usize)
+                    }
+                    _ => None,
+                };
+                match record {
+                    Some(&mut (_, ref mut v)) => {
+                        *v = Value::blob_ref(pid)
+                    }
+                    None => {
+                        return Err(DecodeError::Data(format!(
+                            "blob refers to record {}, which the \
+                             node doesn't have",
+                            i
+                        )))
+                    }
+                }
+            }
+        }
+
+        if !r.buf.is_empty() {
+            return Err(DecodeError::TrailingBytes(r.buf.len()));
+        }
+
+        Ok(Node {
+            id: id,
+            data: data,
+            next: next,
+            prev: prev,
+            lo: lo,
+            hi: hi,
+            prefix: prefix,
+            bloom: None,
+            expiries: expiries,
+            recent: RecentWrites::default(),
+        })
+    }
+}
+
+// Lets `Frag::Base` write its node in the compact layout.
+pub fn serialize<S>(
+    node: &Node,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut buf = vec![];
+    node.serialize_into(&mut buf);
+    serializer.serialize_bytes(&buf)
+}
+
+pub fn deserialize<'de, D>(
+    deserializer: D,
+) -> Result<Node, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buf: Vec<u8> = Deserialize::deserialize(deserializer)?;
+    Node::deserialize_from(&buf).map_err(D::Error::custom)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_embedded(buf: &mut Vec<u8>, data: &Data) {
+    let embedded = bincode::serialize(data, Infinite)
+        .expect("failed to serialize node data");
+    write_bytes(buf, &embedded);
+}
+
+fn write_option_id(buf: &mut Vec<u8>, id: Option<PageID>) {
+    match id {
+        Some(id) => {
+            buf.push(1);
+            write_varint(buf, id as u64);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn write_bound(buf: &mut Vec<u8>, bound: &Bound) {
+    match *bound {
+        Bound::Inclusive(ref k) => {
+            buf.push(INCLUSIVE);
+            write_bytes(buf, k);
+        }
+        Bound::Exclusive(ref k) => {
+            buf.push(EXCLUSIVE);
+            write_bytes(buf, k);
+        }
+        Bound::Inf => buf.push(INF),
+    }
+}
+
+// Writes the length `key` shares with `prev`, then the rest of it.
+fn write_key(buf: &mut Vec<u8>, prev: &[u8], key: &[u8]) {
+    let shared = prev
+        .iter()
+        .zip(key.iter())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    write_varint(buf, shared as u64);
+    write_bytes(buf, &key[shared..]);
+}
+
+fn write_records(buf: &mut Vec<u8>, records: &[(Key, Value)]) {
+    write_varint(buf, records.len() as u64);
+    let mut prev: &[u8] = &[];
+    for &(ref k, ref v) in records {
+        write_key(buf, prev, k);
+        match v.blob() {
+            // written as a reference after the data
+            Some(_) => write_bytes(buf, &[]),
+            None => write_bytes(buf, v),
+        }
+        prev = k;
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&b, rest) =
+            self.buf.split_first().ok_or(DecodeError::Truncated)?;
+        self.buf = rest;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        read_varint(&mut self.buf).ok_or(DecodeError::Truncated)
+    }
+
+    // Reads a count of items, which can't be more than the bytes
+    // left, so a corrupt count can't cause a huge allocation.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.varint()?;
+        if len > self.buf.len() as u64 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(len as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.len()?;
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes.to_vec())
+    }
+
+    fn option_id(&mut self) -> Result<Option<PageID>, DecodeError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.varint()? as PageID)),
+            other => Err(DecodeError::UnknownTag(other)),
+        }
+    }
+
+    fn bound(&mut self) -> Result<Bound, DecodeError> {
+        match self.byte()? {
+            INCLUSIVE => Ok(Bound::Inclusive(self.bytes()?)),
+            EXCLUSIVE => Ok(Bound::Exclusive(self.bytes()?)),
+            INF => Ok(Bound::Inf),
+            other => Err(DecodeError::UnknownTag(other)),
+        }
+    }
+
+    fn key(&mut self, prev: &[u8]) -> Result<Key, DecodeError> {
+        let shared = self.varint()? as usize;
+        if shared > prev.len() {
+            return Err(DecodeError::BadSharedLen {
+                shared: shared,
+                prev_len: prev.len(),
+            });
+        }
+        let mut key = prev[..shared].to_vec();
+        key.extend_from_slice(&self.bytes()?);
+        Ok(key)
+    }
+
+    fn records(&mut self) -> Result<Vec<(Key, Value)>, DecodeError> {
+        let len = self.len()?;
+        let mut records = Vec::with_capacity(len);
+        let mut prev = vec![];
+        for _ in 0..len {
+            let k = self.key(&prev)?;
+            records.push((k.clone(), Value::from(self.bytes()?)));
+            prev = k;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug)]
+struct ArbNode(Node);
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for ArbNode {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> ArbNode {
+        use quickcheck::Arbitrary;
+
+        fn bound<G: quickcheck::Gen>(g: &mut G) -> Bound {
+            match g.gen_range(0, 3) {
+                0 => Bound::Inclusive(Arbitrary::arbitrary(g)),
+                1 => Bound::Exclusive(Arbitrary::arbitrary(g)),
+                _ => Bound::Inf,
+            }
+        }
+
+        let mut keys: Vec<Key> = Arbitrary::arbitrary(g);
+        keys.sort_by(|a, b| prefix_cmp(a, b));
+        keys.dedup();
+        let values: Vec<Value> =
+            keys.iter()
+                .map(|_| Value::from(Vec::<u8>::arbitrary(g)))
+                .collect();
+        let records: Vec<(Key, Value)> =
+            keys.iter().cloned().zip(values.clone()).collect();
+
+        let data = match g.gen_range(0, 7) {
+            0 => Data::Index(
+                keys.into_iter()
+                    .map(|k| (k, Arbitrary::arbitrary(g)))
+                    .collect(),
+            ),
+            1 => Data::Leaf(records),
+            2 => {
+                let mut distinct = values;
+                distinct.push(vec![].into());
+                distinct.sort();
+                distinct.dedup();
+                let records = keys
+                    .into_iter()
+                    .map(|k| {
+                        let idx = g.gen_range(0, distinct.len());
+                        (k, idx as u32)
+                    })
+                    .collect();
+                let dict = ValueDict::from_parts(distinct, records);
+                Data::DictLeaf(dict)
+            }
+            3 => Data::LeafSet(
+                keys.into_iter().map(|k| (k, ())).collect(),
+            ),
+            4 => Data::IntLeaf(records),
+            5 => {
+                let compression = ValueCompression {
+                    threshold: g.gen_range(0, 64),
+                    codec: ValueCodec::Zstd(1),
+                };
+                Data::CompressedLeaf(compression, records)
+            }
+            _ => Data::BlockLeaf(records),
+        };
+
+        ArbNode(Node {
+            id: Arbitrary::arbitrary(g),
+            data: data,
+            next: Arbitrary::arbitrary(g),
+            prev: Arbitrary::arbitrary(g),
+            lo: bound(g),
+            hi: bound(g),
+            prefix: Arbitrary::arbitrary(g),
+            bloom: None,
+            expiries: Expiries::default(),
+            recent: RecentWrites::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+fn prop_compact_round_trip(node: ArbNode) -> bool {
+    let node = node.0;
+    let mut buf = vec![];
+    node.serialize_into(&mut buf);
+    if Node::deserialize_from(&buf) != Ok(node.clone()) {
+        return false;
+    }
+
+    let frag = Frag::Base(node, Some(3));
+    let bytes = bincode::serialize(&frag, Infinite).unwrap();
+    bincode::deserialize::<Frag>(&bytes).unwrap() == frag
+}
+
+#[test]
+fn quickcheck_compact_round_trip() {
+    quickcheck::QuickCheck::new()
+        .tests(500)
+        .quickcheck(prop_compact_round_trip as fn(ArbNode) -> bool);
+}
+
+#[test]
+fn test_compact_is_smaller() {
+    let lo = b"user".to_vec();
+    let mut node = Node {
+        id: 7,
+        next: Some(8),
+        prev: Some(6),
+        ..leaf(
+            Bound::Inclusive(lo.clone()),
+            Bound::Exclusive(b"users".to_vec()),
+        )
+    };
+    for i in 0..200u32 {
+        let k = format!("user/profile/{:06}", i).into_bytes();
+        node.set_leaf(prefix_encode(&lo, &k), vec![1, 2, 3].into());
+    }
+
+    let mut buf = vec![];
+    node.serialize_into(&mut buf);
+    let serde_len =
+        bincode::serialize(&node, Infinite).unwrap().len();
+    assert!(
+        buf.len() * 3 < serde_len,
+        "compact node was {} bytes, serde's was {}",
+        buf.len(),
+        serde_len
+    );
+
+    assert_eq!(
+        Node::deserialize_from(&buf[..buf.len() - 1]),
+        Err(DecodeError::Truncated)
+    );
+    buf.push(0);
+    assert_eq!(
+        Node::deserialize_from(&buf),
+        Err(DecodeError::TrailingBytes(1))
+    );
+}
+
+#[test]
+fn test_compact_count_mismatch() {
+    let empty = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    let mut buf = vec![];
+    empty.serialize_into(&mut buf);
+    // the count is followed by the data tag and its record count
+    let count_at = buf.len() - 3;
+
+    let mut node = empty.clone();
+    for k in &[b"a", b"b", b"c"] {
+        node.set_leaf(prefix_encode(b"", *k), vec![1].into());
+    }
+    let mut buf = vec![];
+    node.serialize_into(&mut buf);
+    assert_eq!(buf[count_at], 3);
+
+    buf[count_at] = 4;
+    assert_eq!(
+        Node::deserialize_from(&buf),
+        Err(DecodeError::CountMismatch {
+            expected: 4,
+            actual: 3,
+        })
+    );
+}
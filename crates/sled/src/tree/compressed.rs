@@ -0,0 +1,238 @@
+// Serialization for `Data::CompressedLeaf`, which stores its
+// records exactly like `Data::Leaf` in memory but compresses each
+// value of at least `ValueCompression::threshold` bytes on its own
+// when written. Every value is written behind a one byte tag that
+// says how it is stored, so values that are too small to be worth
+// compressing, or that don't shrink, are kept raw. Keys are never
+// compressed. Values are decompressed when a page is read back, so
+// the cached node holds plain values and reads never pay for it.
+
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(feature = "zstd")]
+use pagecache::{compress, decompress};
+
+#[cfg(feature = "zstd")]
+use super::delta::write_varint;
+use super::delta::read_varint;
+use super::*;
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// How a `Data::CompressedLeaf` compresses its values when written.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ValueCompression {
+    /// Values shorter than this are always stored raw, as they
+    /// would gain less than the codec's framing costs.
+    pub threshold: u32,
+    pub codec: ValueCodec,
+}
+
+/// The codec applied to values at or above the threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ValueCodec {
+    /// zstd at the given level. Without the `zstd` feature, values
+    /// are written raw.
+    Zstd(i32),
+}
+
+#[derive(Serialize)]
+struct Encoded<'a> {
+    compression: ValueCompression,
+    keys: Vec<&'a Key>,
+    values: Vec<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct Decoded {
+    compression: ValueCompression,
+    keys: Vec<Key>,
+    values: Vec<Vec<u8>>,
+}
+
+pub fn serialize<S>(
+    compression: &ValueCompression,
+    records: &[(Key, Value)],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    Encoded {
+        compression: *compression,
+        keys: records.iter().map(|&(ref k, _)| k).collect(),
+        values: records
+            .iter()
+            .map(|&(_, ref v)| encode_value(compression, v))
+            .collect(),
+    }.serialize(serializer)
+}
+
+pub fn deserialize<'de, D>(
+    deserializer: D,
+) -> Result<(ValueCompression, Vec<(Key, Value)>), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let decoded = Decoded::deserialize(deserializer)?;
+    if decoded.values.len() != decoded.keys.len() {
+        return Err(D::Error::custom(format!(
+            "leaf holds {} values for {} keys",
+            decoded.values.len(),
+            decoded.keys.len()
+        )));
+    }
+
+    let mut records = Vec::with_capacity(decoded.keys.len());
+    for (k, v) in decoded.keys.into_iter().zip(decoded.values) {
+        let value = decode_value(&v).map_err(D::Error::custom)?;
+        records.push((k, value));
+    }
+    Ok((decoded.compression, records))
+}
+
+// Writes a value behind its tag, compressed if that is allowed and
+// makes it smaller.
+fn encode_value(
+    compression: &ValueCompression,
+    value: &[u8],
+) -> Vec<u8> {
+    if value.len() >= compression.threshold as usize {
+        if let Some(compressed) = compress_value(compression, value) {
+            return compressed;
+        }
+    }
+    let mut raw = Vec::with_capacity(1 + value.len());
+    raw.push(RAW);
+    raw.extend_from_slice(value);
+    raw
+}
+
+#[cfg(feature = "zstd")]
+fn compress_value(
+    compression: &ValueCompression,
+    value: &[u8],
+) -> Option<Vec<u8>> {
+    let ValueCodec::Zstd(level) = compression.codec;
+    let block = compress(value, level).ok()?;
+    let mut buf = vec![ZSTD];
+    write_varint(&mut buf, value.len() as u64);
+    buf.extend_from_slice(&block);
+    if buf.len() < 1 + value.len() {
+        Some(buf)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_value(
+    _compression: &ValueCompression,
+    _value: &[u8],
+) -> Option<Vec<u8>> {
+    None
+}
+
+fn decode_value(buf: &[u8]) -> Result<Value, String> {
+    match buf.split_first() {
+        Some((&RAW, value)) => Ok(Value::from(value)),
+        Some((&ZSTD, mut rest)) => {
+            let raw_len = read_varint(&mut rest)
+                .ok_or_else(|| "truncated value length".to_owned())?;
+            decompress_value(rest, raw_len)
+        }
+        Some((&tag, _)) => Err(format!("unknown value tag {}", tag)),
+        None => Err("value is missing its tag".to_owned()),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_value(
+    block: &[u8],
+    raw_len: u64,
+) -> Result<Value, String> {
+    decompress(block, raw_len as usize)
+        .map(Value::from)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_value(
+    _block: &[u8],
+    _raw_len: u64,
+) -> Result<Value, String> {
+    Err("value is compressed, but zstd is not enabled".to_owned())
+}
+
+#[cfg(test)]
+fn compression() -> ValueCompression {
+    ValueCompression {
+        threshold: 64,
+        codec: ValueCodec::Zstd(5),
+    }
+}
+
+#[cfg(test)]
+fn random_value(len: usize) -> Value {
+    // xorshift, so the test doesn't need a seeded rng
+    let mut x: u64 = 0x2545_F491_4F6C_DD1D;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+#[cfg(test)]
+fn json_value() -> Value {
+    b"{\"status\":\"active\",\"plan\":\"basic\"}".repeat(40).into()
+}
+
+#[test]
+fn test_compressed_leaf_round_trip() {
+    use bincode::{deserialize, serialize, Infinite};
+
+    let json = json_value();
+    let records = vec![
+        (prefix_encode(b"", b"a"), json.clone()),
+        (prefix_encode(b"", b"b"), random_value(4096)),
+        (prefix_encode(b"", b"c"), b"tiny".to_vec().into()),
+        (prefix_encode(b"", b"d"), vec![].into()),
+    ];
+    let data = Data::CompressedLeaf(compression(), records);
+    let bytes = serialize(&data, Infinite).unwrap();
+    assert_eq!(deserialize::<Data>(&bytes).unwrap(), data);
+
+    // small and incompressible values are kept raw
+    let incompressible = random_value(4096);
+    let encoded = encode_value(&compression(), &incompressible);
+    assert_eq!(encoded[0], RAW);
+    assert_eq!(&encoded[1..], &*incompressible);
+    assert_eq!(encode_value(&compression(), b"tiny"), b"\x00tiny");
+    assert_eq!(decode_value(&encoded), Ok(incompressible));
+    assert!(decode_value(&[9, 1, 2]).is_err());
+    assert!(decode_value(&[]).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compressible_values_are_compressed() {
+    let json = json_value();
+    let encoded = encode_value(&compression(), &json);
+    assert_eq!(encoded[0], ZSTD);
+    assert!(encoded.len() * 4 < json.len());
+    assert_eq!(decode_value(&encoded), Ok(json.clone()));
+
+    // unless they are below the threshold
+    let strict = ValueCompression {
+        threshold: json.len() as u32 + 1,
+        ..compression()
+    };
+    assert_eq!(encode_value(&strict, &json)[0], RAW);
+}
@@ -1,4 +1,5 @@
-use std::fmt::Debug;
+use std::cmp::Ordering;
+use std::ops::Range;
 
 use super::*;
 
@@ -6,74 +7,375 @@ use super::*;
 pub enum Data {
     Index(Vec<(Key, PageID)>),
     Leaf(Vec<(Key, Value)>),
+    /// A leaf whose values are interned in a per-node dictionary,
+    /// used when `ConfigBuilder::intern_values` is set.
+    DictLeaf(ValueDict),
+    /// A leaf that stores only keys, used when
+    /// `ConfigBuilder::key_set` is set for trees that are ordered
+    /// sets. The unit values take no space when serialized.
+    LeafSet(Vec<(Key, ())>),
+    /// A leaf that holds records just like `Leaf`, but writes keys
+    /// that are 8 byte big-endian integers as deltas from their
+    /// neighbors, used when `ConfigBuilder::int_keys` is set.
+    IntLeaf(#[serde(with = "super::delta")] Vec<(Key, Value)>),
+    /// A leaf that holds records just like `Leaf`, but writes all
+    /// of its values as one compressed block, used when
+    /// `ConfigBuilder::block_compress_values` is set.
+    BlockLeaf(#[serde(with = "super::block")] Vec<(Key, Value)>),
+    /// A leaf that holds records just like `Leaf`, but compresses
+    /// each large value on its own when written, used when
+    /// `ConfigBuilder::compress_values_above` is set.
+    #[serde(with = "super::compressed")]
+    CompressedLeaf(ValueCompression, Vec<(Key, Value)>),
 }
 
+// What a `Data::LeafSet` hands out as the value of present keys.
+static EMPTY_VALUE: Value = Value::EMPTY;
+
 impl Data {
     pub fn len(&self) -> usize {
         match *self {
             Data::Index(ref ptrs) => ptrs.len(),
-            Data::Leaf(ref items) => items.len(),
-        }
-    }
-
-    pub fn split(&self, lhs_prefix: &[u8]) -> (Key, Data) {
-        fn split_inner<T>(
-            xs: &[(Key, T)],
-            lhs_prefix: &[u8],
-        ) -> (Key, Vec<(Key, T)>)
-        where
-            T: Clone + Debug + Ord,
-        {
-            let mut decoded_xs: Vec<_> = xs.iter()
-                .map(|&(ref k, ref v)| {
-                    let decoded_k = prefix_decode(lhs_prefix, &*k);
-                    (decoded_k, v.clone())
-                })
-                .collect();
-            decoded_xs.sort();
-
-            let (_lhs, rhs) =
-                decoded_xs.split_at(decoded_xs.len() / 2 + 1);
-            let split = rhs.first()
-                .expect("rhs should contain at least one element")
-                .0
-                .clone();
-            let rhs_data: Vec<_> = rhs.iter()
-                .map(|&(ref k, ref v)| {
-                    let new_k = prefix_encode(&*split, k);
-                    (new_k, v.clone())
-                })
-                .collect();
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => items.len(),
+            Data::DictLeaf(ref dict) => dict.len(),
+            Data::LeafSet(ref keys) => keys.len(),
+        }
+    }
+
+    pub fn is_index(&self) -> bool {
+        match *self {
+            Data::Index(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The bytes taken up by keys and values, or by keys and child
+    /// ids for an index. Interned values are counted once, along
+    /// with each record's index into the dictionary.
+    pub fn byte_len(&self) -> usize {
+        let records: usize = self.record_byte_lens().iter().sum();
+        let values: usize = match *self {
+            Data::DictLeaf(ref dict) => {
+                dict.values().iter().map(|v| v.len()).sum()
+            }
+            _ => 0,
+        };
+        records + values
+    }
+
+    /// Splits `byte_len` into the bytes taken up by keys and by
+    /// everything else, in one pass.
+    pub fn key_value_bytes(&self) -> (usize, usize) {
+        let pid_len = std::mem::size_of::<PageID>();
+        let mut keys = 0;
+        let mut values = 0;
+        match *self {
+            Data::Index(ref ptrs) => {
+                for &(ref k, _) in ptrs {
+                    keys += k.len();
+                    values += pid_len;
+                }
+            }
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                for &(ref k, ref v) in items {
+                    keys += k.len();
+                    values += v.len();
+                }
+            }
+            Data::DictLeaf(ref dict) => {
+                for &(ref k, _) in dict.records() {
+                    keys += k.len();
+                    values += 4;
+                }
+                for v in dict.values() {
+                    values += v.len();
+                }
+            }
+            Data::LeafSet(ref set) => {
+                for &(ref k, _) in set {
+                    keys += k.len();
+                }
+            }
+        }
+        (keys, values)
+    }
+
+    /// Splits off the records past `ratio` of the way through, as a
+    /// right side encoded against the returned split key. A ratio
+    /// of 0.5 cuts just past the median, while one near 1.0 keeps
+    /// the left side nearly full, which suits appends. Both sides
+    /// get at least one record.
+    pub fn split(
+        &self,
+        lhs_prefix: &[u8],
+        ratio: f32,
+    ) -> (Key, Data) {
+        self.split_biased(lhs_prefix, ratio, None)
+    }
 
-            (split, rhs_data)
+    /// Splits like `split`, but cuts where the bytes of the records
+    /// rather than their count are closest to balanced, so a few
+    /// huge values don't end up on the same side. Both sides get at
+    /// least one record.
+    pub fn split_by_bytes(&self, lhs_prefix: &[u8]) -> (Key, Data) {
+        let lens = self.record_byte_lens();
+        let total: usize = lens.iter().sum();
+        let mut at = 0;
+        let mut left = 0;
+        while at < lens.len() && left + lens[at] / 2 < total / 2 {
+            left += lens[at];
+            at += 1;
         }
+        let at = std::cmp::min(std::cmp::max(at, 1), lens.len() - 1);
+        self.split_at(lhs_prefix, at)
+    }
+
+    /// Splits like `split`, but moves the cut off of `ratio` if it
+    /// would land inside `hot`, a range of record positions that
+    /// are being written to together. Keeping such a cluster in
+    /// one node avoids splitting both halves again as the hotspot
+    /// keeps growing.
+    pub fn split_biased(
+        &self,
+        lhs_prefix: &[u8],
+        ratio: f32,
+        hot: Option<Range<usize>>,
+    ) -> (Key, Data) {
+        let at = split_point(self.len(), ratio, hot);
+        self.split_at(lhs_prefix, at)
+    }
 
+    // Moves every record from position `at` onwards to a new right
+    // side.
+    fn split_at(&self, lhs_prefix: &[u8], at: usize) -> (Key, Data) {
         match *self {
             Data::Index(ref ptrs) => {
-                let (split, rhs) = split_inner(ptrs, lhs_prefix);
+                let (split, rhs) =
+                    split_inner(ptrs, lhs_prefix, at, false);
                 (split, Data::Index(rhs))
             }
             Data::Leaf(ref items) => {
-                let (split, rhs) = split_inner(items, lhs_prefix);
+                let (split, rhs) =
+                    split_inner(items, lhs_prefix, at, true);
                 (split, Data::Leaf(rhs))
             }
+            Data::IntLeaf(ref items) => {
+                let (split, rhs) =
+                    split_inner(items, lhs_prefix, at, true);
+                (split, Data::IntLeaf(rhs))
+            }
+            Data::BlockLeaf(ref items) => {
+                let (split, rhs) =
+                    split_inner(items, lhs_prefix, at, true);
+                (split, Data::BlockLeaf(rhs))
+            }
+            Data::CompressedLeaf(compression, ref items) => {
+                let (split, rhs) =
+                    split_inner(items, lhs_prefix, at, true);
+                (split, Data::CompressedLeaf(compression, rhs))
+            }
+            Data::DictLeaf(ref dict) => {
+                let records = dict.records();
+                let (split, rhs) =
+                    split_inner(records, lhs_prefix, at, true);
+                (split, Data::DictLeaf(dict.select(rhs)))
+            }
+            Data::LeafSet(ref keys) => {
+                let (split, rhs) =
+                    split_inner(keys, lhs_prefix, at, true);
+                (split, Data::LeafSet(rhs))
+            }
         }
     }
 
-    pub fn drop_gte(&mut self, at: &Bound, prefix: &[u8]) {
+    /// Appends the records of `rhs`, which must all sort after this
+    /// side's and be encoded against the same prefix. Returns
+    /// `None` if the two are different kinds of `Data`.
+    pub fn concat(self, rhs: Data) -> Option<Data> {
+        match (self, rhs) {
+            (Data::Index(mut lhs), Data::Index(rhs)) => {
+                lhs.extend(rhs);
+                Some(Data::Index(lhs))
+            }
+            (Data::Leaf(mut lhs), Data::Leaf(rhs)) => {
+                lhs.extend(rhs);
+                Some(Data::Leaf(lhs))
+            }
+            (Data::IntLeaf(mut lhs), Data::IntLeaf(rhs)) => {
+                lhs.extend(rhs);
+                Some(Data::IntLeaf(lhs))
+            }
+            (Data::BlockLeaf(mut lhs), Data::BlockLeaf(rhs)) => {
+                lhs.extend(rhs);
+                Some(Data::BlockLeaf(lhs))
+            }
+            (
+                Data::CompressedLeaf(compression, mut lhs),
+                Data::CompressedLeaf(_, rhs),
+            ) => {
+                lhs.extend(rhs);
+                Some(Data::CompressedLeaf(compression, lhs))
+            }
+            (Data::DictLeaf(mut lhs), Data::DictLeaf(rhs)) => {
+                for (k, v) in rhs.to_leaf() {
+                    lhs.set(k, v);
+                }
+                Some(Data::DictLeaf(lhs))
+            }
+            (Data::LeafSet(mut lhs), Data::LeafSet(rhs)) => {
+                lhs.extend(rhs);
+                Some(Data::LeafSet(lhs))
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes the records at or above `at`, returning them as
+    /// `Data` of the same kind, still encoded against `prefix`.
+    pub fn drop_gte(&mut self, at: &Bound, prefix: &[u8]) -> Data {
+        self.take(at, prefix, false)
+    }
+
+    /// Removes the records strictly below `at`, returning them as
+    /// `Data` of the same kind, still encoded against `prefix`.
+    /// Keys are compared exactly as in `drop_gte`, so the two
+    /// partition a node's records between them.
+    pub fn drop_lt(&mut self, at: &Bound, prefix: &[u8]) -> Data {
+        self.take(at, prefix, true)
+    }
+
+    // Removes and returns the records whose decoded keys are below
+    // `at` if `below` is set, or at or above it otherwise.
+    fn take(
+        &mut self,
+        at: &Bound,
+        prefix: &[u8],
+        below: bool,
+    ) -> Data {
         let bound = at.inner();
         match *self {
             Data::Index(ref mut ptrs) => {
-                ptrs.retain(|&(ref k, _)| {
-                    let decoded_k = prefix_decode(prefix, &*k);
-                    &*decoded_k < bound
-                })
+                Data::Index(take_where(ptrs, prefix, bound, below))
             }
             Data::Leaf(ref mut items) => {
-                items.retain(|&(ref k, _)| {
-                    let decoded_k = prefix_decode(prefix, &*k);
-                    &*decoded_k < bound
-                })
+                Data::Leaf(take_where(items, prefix, bound, below))
+            }
+            Data::IntLeaf(ref mut items) => {
+                Data::IntLeaf(take_where(items, prefix, bound, below))
+            }
+            Data::BlockLeaf(ref mut items) => Data::BlockLeaf(
+                take_where(items, prefix, bound, below),
+            ),
+            Data::CompressedLeaf(compression, ref mut items) => {
+                Data::CompressedLeaf(
+                    compression,
+                    take_where(items, prefix, bound, below),
+                )
+            }
+            Data::DictLeaf(ref mut dict) => {
+                let taken = |k: &Key| {
+                    (&*prefix_decode(prefix, k) < bound) == below
+                };
+                let moved = dict
+                    .records()
+                    .iter()
+                    .filter(|&&(ref k, _)| taken(k))
+                    .cloned()
+                    .collect();
+                let moved = dict.select(moved);
+                dict.retain(|&(ref k, _)| !taken(k));
+                Data::DictLeaf(moved)
+            }
+            Data::LeafSet(ref mut keys) => {
+                Data::LeafSet(take_where(keys, prefix, bound, below))
+            }
+        }
+    }
+
+    // The bytes of each record's key and value, or key and child
+    // id, in order.
+    fn record_byte_lens(&self) -> Vec<usize> {
+        let pid_len = std::mem::size_of::<PageID>();
+        match *self {
+            Data::Index(ref ptrs) => ptrs
+                .iter()
+                .map(|&(ref k, _)| k.len() + pid_len)
+                .collect(),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => items
+                .iter()
+                .map(|&(ref k, ref v)| k.len() + v.len())
+                .collect(),
+            Data::DictLeaf(ref dict) => dict
+                .records()
+                .iter()
+                .map(|&(ref k, _)| k.len() + 4)
+                .collect(),
+            Data::LeafSet(ref keys) => {
+                keys.iter().map(|&(ref k, _)| k.len()).collect()
+            }
+        }
+    }
+
+    /// Every decoded key, in order.
+    pub fn keys(&self, prefix: &[u8]) -> Vec<Key> {
+        match *self {
+            Data::Index(ref ptrs) => ptrs
+                .iter()
+                .map(|&(ref k, _)| prefix_decode(prefix, k))
+                .collect(),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => items
+                .iter()
+                .map(|&(ref k, _)| prefix_decode(prefix, k))
+                .collect(),
+            Data::DictLeaf(ref dict) => dict
+                .records()
+                .iter()
+                .map(|&(ref k, _)| prefix_decode(prefix, k))
+                .collect(),
+            Data::LeafSet(ref keys) => keys
+                .iter()
+                .map(|&(ref k, _)| prefix_decode(prefix, k))
+                .collect(),
+        }
+    }
+
+    /// Re-encodes every key from `from` to `to`. Both prefixes
+    /// must be at or below every key, or the order of the encoded
+    /// keys would no longer match the order of the keys.
+    pub fn reencode(&mut self, from: &[u8], to: &[u8]) {
+        let reencode =
+            |k: &Key| prefix_encode(to, &prefix_decode(from, k));
+        match *self {
+            Data::Index(ref mut ptrs) => {
+                for ptr in ptrs {
+                    ptr.0 = reencode(&ptr.0);
+                }
+            }
+            Data::Leaf(ref mut items)
+            | Data::IntLeaf(ref mut items)
+            | Data::BlockLeaf(ref mut items)
+            | Data::CompressedLeaf(_, ref mut items) => {
+                for item in items {
+                    item.0 = reencode(&item.0);
+                }
+            }
+            Data::DictLeaf(ref mut dict) => dict.map_keys(reencode),
+            Data::LeafSet(ref mut keys) => {
+                for key in keys {
+                    key.0 = reencode(&key.0);
+                }
             }
         }
     }
@@ -81,14 +383,649 @@ impl Data {
     pub fn leaf(&self) -> Option<Vec<(Key, Value)>> {
         match *self {
             Data::Index(_) => None,
-            Data::Leaf(ref items) => Some(items.clone()),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                Some(items.clone())
+            }
+            Data::DictLeaf(ref dict) => Some(dict.to_leaf()),
+            Data::LeafSet(ref keys) => Some(
+                keys.iter()
+                    .map(|&(ref k, _)| (k.clone(), Value::default()))
+                    .collect(),
+            ),
         }
     }
 
-    pub fn leaf_ref(&self) -> Option<&Vec<(Key, Value)>> {
+    /// Mutably borrows a leaf's records, or returns `None` for
+    /// interned and key-only leaves, which don't store them as
+    /// pairs, and for indexes.
+    pub fn records_mut(&mut self) -> Option<&mut Vec<(Key, Value)>> {
+        match *self {
+            Data::Leaf(ref mut items)
+            | Data::IntLeaf(ref mut items)
+            | Data::BlockLeaf(ref mut items)
+            | Data::CompressedLeaf(_, ref mut items) => Some(items),
+            Data::Index(_) | Data::DictLeaf(_) | Data::LeafSet(_) => {
+                None
+            }
+        }
+    }
+
+    /// Borrows a leaf's records in order, with their keys still
+    /// prefix-encoded. Yields nothing for an index.
+    pub fn leaf_iter<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (&'a Key, &'a Value)> + 'a {
+        let (mut records, mut dict, mut keys) = (None, None, None);
+        match *self {
+            Data::Index(_) => {}
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                records = Some(items)
+            }
+            Data::DictLeaf(ref d) => dict = Some(d),
+            Data::LeafSet(ref k) => keys = Some(k),
+        }
+
+        let records = records
+            .into_iter()
+            .flat_map(|items| items.iter())
+            .map(|&(ref k, ref v)| (k, v));
+        let dict = dict.into_iter().flat_map(|dict| {
+            dict.records().iter().map(move |&(ref k, idx)| {
+                (k, &dict.values()[idx as usize])
+            })
+        });
+        let keys = keys
+            .into_iter()
+            .flat_map(|keys| keys.iter())
+            .map(|&(ref k, _)| (k, &EMPTY_VALUE));
+        records.chain(dict).chain(keys)
+    }
+
+    /// Panics unless the keys, encoded against `prefix`, are
+    /// strictly ascending, which also rules out duplicates. They
+    /// are compared with `prefix_cmp`, which orders keys encoded
+    /// against one prefix just as their decoded forms would be
+    /// ordered, without decoding every key. `apply` runs this after
+    /// every frag in debug builds.
+    pub fn assert_sorted_unique(&self, prefix: &[u8]) {
+        match *self {
+            Data::Index(ref ptrs) => assert_ascending(ptrs, prefix),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                assert_ascending(items, prefix)
+            }
+            Data::DictLeaf(ref dict) => {
+                assert_ascending(dict.records(), prefix)
+            }
+            Data::LeafSet(ref keys) => assert_ascending(keys, prefix),
+        }
+    }
+
+    /// Sorts the records and collapses runs of the same key down
+    /// to the one that comes last, returning how many were
+    /// dropped. Records that share a key are assumed to have been
+    /// written in the order they appear.
+    pub fn dedup(&mut self) -> usize {
+        let before = self.len();
+        match *self {
+            Data::Index(ref mut ptrs) => dedup_last(ptrs),
+            Data::Leaf(ref mut items)
+            | Data::IntLeaf(ref mut items)
+            | Data::BlockLeaf(ref mut items)
+            | Data::CompressedLeaf(_, ref mut items) => {
+                dedup_last(items)
+            }
+            Data::DictLeaf(ref mut dict) => {
+                if !ascending(dict.records()) {
+                    let mut records = dict.records().to_vec();
+                    dedup_last(&mut records);
+                    *dict = dict.select(records);
+                }
+            }
+            Data::LeafSet(ref mut keys) => dedup_last(keys),
+        }
+        before - self.len()
+    }
+
+    /// Binary searches a leaf for a decoded key, as
+    /// `binary_search_by` would. Keys are decoded to be compared,
+    /// so `key` may lie anywhere, even below `prefix`. An index is
+    /// treated as an empty leaf.
+    pub fn leaf_search(
+        &self,
+        prefix: &[u8],
+        key: KeyRef,
+    ) -> Result<usize, usize> {
+        match *self {
+            Data::Index(_) => Err(0),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                search_decoded(items, prefix, key)
+            }
+            Data::DictLeaf(ref dict) => {
+                search_decoded(dict.records(), prefix, key)
+            }
+            Data::LeafSet(ref keys) => {
+                search_decoded(keys, prefix, key)
+            }
+        }
+    }
+
+    /// Returns the separator and child of an index that a decoded
+    /// key routes to, which is the one with the greatest separator
+    /// at or below the key. A key below every separator routes to
+    /// the first child, as the node's `lo` bounds it anyway.
+    /// Returns `None` for a leaf or an empty index.
+    pub fn child_for(
+        &self,
+        prefix: &[u8],
+        key: KeyRef,
+    ) -> Option<&(Key, PageID)> {
+        let ptrs = match *self {
+            Data::Index(ref ptrs) if !ptrs.is_empty() => ptrs,
+            _ => return None,
+        };
+        let idx = match search_decoded(ptrs, prefix, key) {
+            Ok(idx) => idx,
+            Err(0) => 0,
+            Err(idx) => idx - 1,
+        };
+        Some(&ptrs[idx])
+    }
+
+    /// Returns the prefix-encoded key at a position, of a record in
+    /// a leaf or of a separator in an index.
+    pub fn key_at(&self, idx: usize) -> Option<&Key> {
+        match *self {
+            Data::Index(ref ptrs) => {
+                ptrs.get(idx).map(|&(ref k, _)| k)
+            }
+            _ => self.leaf_at(idx).map(|(k, _)| k),
+        }
+    }
+
+    /// Returns the record at a position in a leaf, with its key
+    /// still prefix-encoded.
+    pub fn leaf_at(&self, idx: usize) -> Option<(&Key, &Value)> {
+        match *self {
+            Data::Index(_) => None,
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                items.get(idx).map(|&(ref k, ref v)| (k, v))
+            }
+            Data::DictLeaf(ref dict) => {
+                dict.records().get(idx).map(|&(ref k, value_idx)| {
+                    (k, &dict.values()[value_idx as usize])
+                })
+            }
+            Data::LeafSet(ref keys) => {
+                keys.get(idx).map(|&(ref k, _)| (k, &EMPTY_VALUE))
+            }
+        }
+    }
+
+    /// Returns the value a leaf holds for a decoded key, where the
+    /// records are encoded against `prefix`.
+    pub fn get(&self, key: KeyRef, prefix: &[u8]) -> Option<&Value> {
+        self.leaf_get(&prefix_encode(prefix, key))
+    }
+
+    /// Returns the value stored for a prefix-encoded key, if this
+    /// is a leaf that contains it.
+    pub fn leaf_get(&self, key: KeyRef) -> Option<&Value> {
         match *self {
             Data::Index(_) => None,
-            Data::Leaf(ref items) => Some(items),
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                let search = items.binary_search_by(
+                    |&(ref k, ref _v)| prefix_cmp(k, key),
+                );
+                search.ok().map(|idx| &items[idx].1)
+            }
+            Data::DictLeaf(ref dict) => dict.get(key),
+            Data::LeafSet(ref keys) => {
+                let search = keys.binary_search_by(|&(ref k, _)| {
+                    prefix_cmp(k, key)
+                });
+                search.ok().map(|_| &EMPTY_VALUE)
+            }
         }
     }
+
+    /// Returns whether a prefix-encoded key is present, as a record
+    /// in a leaf or as a separator in an index.
+    pub fn contains_key(&self, key: KeyRef) -> bool {
+        match *self {
+            Data::Index(ref ptrs) => ptrs
+                .binary_search_by(|&(ref k, _)| prefix_cmp(k, key))
+                .is_ok(),
+            Data::LeafSet(ref keys) => keys
+                .binary_search_by(|&(ref k, _)| prefix_cmp(k, key))
+                .is_ok(),
+            _ => self.leaf_get(key).is_some(),
+        }
+    }
+}
+
+// Splits off the records whose decoded keys are below `bound` if
+// `below` is set, or at or above it otherwise, keeping the rest in
+// `xs`.
+fn take_where<T>(
+    xs: &mut Vec<(Key, T)>,
+    prefix: &[u8],
+    bound: &[u8],
+    below: bool,
+) -> Vec<(Key, T)> {
+    let (moved, keep) = std::mem::replace(xs, vec![])
+        .into_iter()
+        .partition(|&(ref k, _)| {
+            (&*prefix_decode(prefix, k) < bound) == below
+        });
+    *xs = keep;
+    moved
+}
+
+fn search_decoded<T>(
+    xs: &[(Key, T)],
+    prefix: &[u8],
+    key: KeyRef,
+) -> Result<usize, usize> {
+    xs.binary_search_by(|&(ref k, _)| {
+        prefix_decode(prefix, k).as_slice().cmp(key)
+    })
+}
+
+fn ascending<T>(xs: &[(Key, T)]) -> bool {
+    xs.windows(2).all(|pair| {
+        prefix_cmp(&pair[0].0, &pair[1].0) == Ordering::Less
+    })
+}
+
+fn assert_ascending<T>(xs: &[(Key, T)], prefix: &[u8]) {
+    for pair in xs.windows(2) {
+        assert!(
+            prefix_cmp(&pair[0].0, &pair[1].0) == Ordering::Less,
+            "keys {:?} and {:?} are out of order or duplicated",
+            prefix_decode(prefix, &pair[0].0),
+            prefix_decode(prefix, &pair[1].0)
+        );
+    }
+}
+
+// Sorts by key and keeps only the last record of each key. The
+// sort is stable, so reversing first puts the last record of each
+// key at the front of its run, where dedup keeps it. Records that
+// are already in order, as they are outside of damaged nodes, are
+// left alone.
+fn dedup_last<T>(xs: &mut Vec<(Key, T)>) {
+    if ascending(xs) {
+        return;
+    }
+    xs.reverse();
+    xs.sort_by(|a, b| prefix_cmp(&a.0, &b.0));
+    xs.dedup_by(|a, b| a.0 == b.0);
+}
+
+/// Computes the separator a parent stores for a split between a
+/// left child whose largest key is `left_max` and a right child
+/// starting at `right_lo`. Without truncation this is exactly
+/// `right_lo`, otherwise it is the shortest key in
+/// `(left_max, right_lo]`, which routes every existing key to the
+/// same side while keeping index nodes small.
+pub fn parent_separator(
+    left_max: &[u8],
+    right_lo: &[u8],
+    truncate: bool,
+) -> Key {
+    assert!(left_max < right_lo, "split halves are out of order");
+    if !truncate {
+        return right_lo.to_vec();
+    }
+
+    let common = left_max
+        .iter()
+        .zip(right_lo.iter())
+        .take_while(|&(l, r)| l == r)
+        .count();
+
+    // right_lo is longer than the common prefix, since left_max is
+    // strictly smaller and can't share all of its bytes.
+    right_lo[..common + 1].to_vec()
+}
+
+// Returns how many records stay on the left side of a split.
+// This is just past `ratio` of the records, which for 0.5 is just
+// past the median, unless that would cut through `hot`, in which
+// case the nearest edge of `hot` that still leaves both sides
+// non-empty is used instead.
+fn split_point(
+    len: usize,
+    ratio: f32,
+    hot: Option<Range<usize>>,
+) -> usize {
+    let cut = (len as f32 * ratio).floor() as usize + 1;
+    let median = std::cmp::max(std::cmp::min(cut, len - 1), 1);
+    let hot = match hot {
+        Some(ref hot) if hot.start < median && median < hot.end => {
+            hot
+        }
+        _ => return median,
+    };
+
+    let valid = |at: usize| at > 0 && at < len;
+    let below = Some(hot.start).filter(|&at| valid(at));
+    let above = Some(hot.end).filter(|&at| valid(at));
+    match (below, above) {
+        (Some(b), Some(a)) => {
+            if median - b <= a - median {
+                b
+            } else {
+                a
+            }
+        }
+        (Some(at), None) | (None, Some(at)) => at,
+        (None, None) => median,
+    }
+}
+
+// Copies the records from `at` onwards to a right side encoded
+// against the split key. The records are sorted by `prefix_cmp`
+// under one prefix, which is the same as their decoded order, so
+// only the two keys around the cut and the moved keys are decoded,
+// and nothing is sorted. Each value that moves to the right side
+// is cloned exactly once, and the left side is never touched, so
+// values that are shared (for example behind an `Arc`) only have
+// their refcounts bumped rather than being deep-copied. Leaves
+// pass `truncate` to split at the shortest key that separates the
+// two sides, see `parent_separator`, while index nodes must split
+// at a child's lo, which their parent's separator has to match.
+fn split_inner<T>(
+    xs: &[(Key, T)],
+    lhs_prefix: &[u8],
+    at: usize,
+    truncate: bool,
+) -> (Key, Vec<(Key, T)>)
+where
+    T: Clone,
+{
+    assert!(at > 0 && at < xs.len(), "both sides need a record");
+    let left_max = prefix_decode(lhs_prefix, &xs[at - 1].0);
+    let right_lo = prefix_decode(lhs_prefix, &xs[at].0);
+    let split = parent_separator(&left_max, &right_lo, truncate);
+    let rhs_data = xs[at..]
+        .iter()
+        .map(|&(ref k, ref v)| {
+            let decoded = prefix_decode(lhs_prefix, k);
+            (prefix_encode(&split, &decoded), v.clone())
+        })
+        .collect();
+
+    (split, rhs_data)
+}
+
+#[test]
+fn test_split_preserves_sharing() {
+    use std::sync::Arc;
+
+    let shared = Arc::new(vec![0u8; 1024]);
+    let xs: Vec<(Key, Arc<Vec<u8>>)> = (0u8..8)
+        .map(|i| (prefix_encode(b"", &[i]), shared.clone()))
+        .collect();
+
+    let (split, rhs) = split_inner(&xs, b"", 5, false);
+    assert_eq!(split, vec![5]);
+    assert_eq!(rhs.len(), 3);
+
+    // only the moved values gained a reference, nothing was copied
+    assert_eq!(Arc::strong_count(&shared), 1 + xs.len() + rhs.len());
+    for &(_, ref v) in &rhs {
+        assert!(Arc::ptr_eq(v, &shared));
+    }
+}
+
+#[test]
+fn test_parent_separator() {
+    fn routes_correctly(lhs: &[&[u8]], rhs: &[&[u8]], sep: &[u8]) {
+        for k in lhs {
+            assert!(*k < sep, "{:?} should route left", k);
+        }
+        for k in rhs {
+            assert!(*k >= sep, "{:?} should route right", k);
+        }
+    }
+
+    let lhs: &[&[u8]] = &[b"a", b"apple", b"apricot"];
+    let rhs: &[&[u8]] = &[b"banana", b"bandana", b"c"];
+
+    let full = parent_separator(b"apricot", b"banana", false);
+    assert_eq!(full, b"banana".to_vec());
+    routes_correctly(lhs, rhs, &*full);
+
+    let truncated = parent_separator(b"apricot", b"banana", true);
+    assert_eq!(truncated, b"b".to_vec());
+    routes_correctly(lhs, rhs, &*truncated);
+
+    // left_max is a prefix of right_lo
+    let truncated = parent_separator(b"ban", b"banana", true);
+    assert_eq!(truncated, b"bana".to_vec());
+    routes_correctly(&[b"ban"], &[b"banana"], &*truncated);
+
+    // the separator can't be shorter than right_lo itself
+    let truncated = parent_separator(b"bananA", b"banana", true);
+    assert_eq!(truncated, b"banana".to_vec());
+}
+
+#[test]
+fn test_split_truncates_leaf_separators() {
+    let keys: Vec<Key> = (0u8..4)
+        .map(|i| {
+            let mut k = b"https://example.com/articles/".to_vec();
+            k.push(b'a' + i);
+            k.extend_from_slice(b"/comments");
+            k
+        })
+        .collect();
+    let records: Vec<(Key, Value)> = keys.iter()
+        .map(|k| (prefix_encode(b"", k), vec![].into()))
+        .collect();
+    let ptrs: Vec<(Key, PageID)> = keys.iter()
+        .enumerate()
+        .map(|(i, k)| (prefix_encode(b"", k), i))
+        .collect();
+
+    // a leaf splits at the shortest key past its left side
+    let (split, rhs) = Data::Leaf(records).split(b"", 0.5);
+    assert_eq!(split, b"https://example.com/articles/d".to_vec());
+    assert_eq!(rhs.len(), 1);
+
+    // an index node's separators have to match its children's lo
+    let (split, _) = Data::Index(ptrs).split(b"", 0.5);
+    assert_eq!(split, keys[3]);
+}
+
+#[test]
+fn test_leaf_set() {
+    use bincode::{serialize, Infinite};
+
+    let keys: Vec<(Key, ())> = (0u8..8)
+        .map(|i| (prefix_encode(b"", &[i]), ()))
+        .collect();
+    let leaf: Vec<(Key, Value)> =
+        keys.iter()
+            .map(|&(ref k, _)| (k.clone(), vec![].into()))
+            .collect();
+
+    let set = Data::LeafSet(keys.clone());
+    assert_eq!(set.leaf(), Some(leaf.clone()));
+    let present = prefix_encode(b"", &[3]);
+    let absent = prefix_encode(b"", &[9]);
+    assert_eq!(set.leaf_get(&*present), Some(&vec![].into()));
+    assert_eq!(set.leaf_get(&*absent), None);
+
+    // no bytes are spent on values, not even their length prefixes
+    let set_sz = serialize(&set, Infinite).unwrap().len();
+    let leaf_sz =
+        serialize(&Data::Leaf(leaf), Infinite).unwrap().len();
+    assert_eq!(set_sz + keys.len() * 8, leaf_sz);
+
+    let (split, rhs) = set.split(b"", 0.5);
+    assert_eq!(split, vec![5]);
+    assert_eq!(rhs.len(), 3);
+    let moved = prefix_encode(&[5], &[6]);
+    assert_eq!(rhs.leaf_get(&*moved), Some(&vec![].into()));
+}
+
+#[test]
+fn test_split_biased() {
+    let items: Vec<(Key, Value)> = (0u8..100)
+        .map(|i| (prefix_encode(b"", &[i]), vec![i].into()))
+        .collect();
+    let data = Data::Leaf(items);
+
+    assert_eq!(
+        data.split_biased(b"", 0.5, None),
+        data.split(b"", 0.5)
+    );
+    assert_eq!(data.split(b"", 0.5).0, vec![51]);
+
+    // a hotspot over the median moves the cut to its nearest edge,
+    // leaving the whole cluster on one side
+    let (split, rhs) = data.split_biased(b"", 0.5, Some(45..60));
+    assert_eq!(split, vec![45]);
+    assert_eq!(rhs.len(), 55);
+    let (split, rhs) = data.split_biased(b"", 0.5, Some(40..53));
+    assert_eq!(split, vec![53]);
+    assert_eq!(rhs.len(), 47);
+
+    // a hotspot that doesn't straddle the median changes nothing
+    let (split, _) = data.split_biased(b"", 0.5, Some(70..90));
+    assert_eq!(split, vec![51]);
+
+    // when the cluster is the whole node, fall back to the median
+    let (split, _) = data.split_biased(b"", 0.5, Some(0..100));
+    assert_eq!(split, vec![51]);
+}
+
+#[test]
+fn test_split_ratio() {
+    let items: Vec<(Key, Value)> = (0u8..10)
+        .map(|i| (prefix_encode(b"", &[i]), vec![i].into()))
+        .collect();
+    let ptrs: Vec<(Key, PageID)> = items
+        .iter()
+        .map(|&(ref k, _)| (k.clone(), 0))
+        .collect();
+
+    for data in vec![Data::Leaf(items), Data::Index(ptrs)] {
+        let (split, rhs) = data.split(b"", 0.9);
+        assert_eq!(rhs.len(), 1);
+        assert_eq!(split, vec![9]);
+        assert_eq!(rhs.keys(&split), vec![vec![9]]);
+
+        let (split, rhs) = data.split(b"", 0.5);
+        assert_eq!((split, rhs.len()), (vec![6], 4));
+
+        // neither side is ever left empty
+        assert_eq!(data.split(b"", 1.0).1.len(), 1);
+        assert_eq!(data.split(b"", 0.0).1.len(), 9);
+    }
+}
+
+#[test]
+fn test_child_for() {
+    let prefix = b"k".to_vec();
+    let ptrs: Vec<(Key, PageID)> = vec![
+        (prefix_encode(&prefix, b"k"), 1),
+        (prefix_encode(&prefix, b"kd"), 2),
+        (prefix_encode(&prefix, b"km"), 3),
+    ];
+    let index = Data::Index(ptrs);
+    let child = |key: &[u8]| {
+        index.child_for(&prefix, key).map(|&(_, id)| id)
+    };
+
+    assert_eq!(child(b"kd"), Some(2));
+    assert_eq!(child(b"k"), Some(1));
+    assert_eq!(child(b"kf"), Some(2));
+    assert_eq!(child(b"kc"), Some(1));
+    assert_eq!(child(b"a"), Some(1));
+    assert_eq!(child(b"km"), Some(3));
+    assert_eq!(child(b"zz"), Some(3));
+
+    assert_eq!(Data::Index(vec![]).child_for(&prefix, b"k"), None);
+    assert_eq!(Data::Leaf(vec![]).child_for(&prefix, b"k"), None);
+}
+
+#[test]
+fn test_get() {
+    let prefix = b"pre".to_vec();
+    let key = |i: u8| vec![b'p', b'r', b'e', i];
+    let items: Vec<(Key, Value)> = (0u8..10)
+        .map(|i| {
+            (prefix_encode(&prefix, &key(i * 2)), vec![i].into())
+        })
+        .collect();
+    let ptrs: Vec<(Key, PageID)> = items
+        .iter()
+        .map(|&(ref k, ref v)| (k.clone(), v[0] as PageID))
+        .collect();
+
+    let leaf = Data::Leaf(items);
+    assert_eq!(leaf.get(&key(6), &prefix), Some(&vec![3].into()));
+    assert_eq!(leaf.get(&key(7), &prefix), None);
+    assert_eq!(leaf.get(b"other", &prefix), None);
+    assert_eq!(Data::Index(ptrs).get(&key(8), &prefix), None);
+}
+
+#[test]
+fn test_drop_lt() {
+    let lo = b"a";
+    let leaf = Data::Leaf(
+        (0..10u8)
+            .map(|i| (prefix_encode(lo, &[b'a', i]), vec![i].into()))
+            .collect(),
+    );
+    let keys = |data: &Data| -> Vec<Key> { data.keys(lo) };
+
+    // only the bound's key matters, not whether it is inclusive
+    for at in vec![
+        Bound::Inclusive(vec![b'a', 4]),
+        Bound::Exclusive(vec![b'a', 4]),
+    ] {
+        let mut data = leaf.clone();
+        let dropped = data.drop_lt(&at, lo);
+        assert_eq!(keys(&dropped), keys(&leaf)[..4].to_vec());
+        assert_eq!(keys(&data), keys(&leaf)[4..].to_vec());
+
+        let mut gte = leaf.clone();
+        gte.drop_gte(&at, lo);
+        assert_eq!(gte, dropped);
+    }
+
+    let mut index = Data::Index(vec![
+        (prefix_encode(lo, b"a"), 1),
+        (prefix_encode(lo, b"m"), 2),
+    ]);
+    let dropped = index.drop_lt(&Bound::Inclusive(b"z".to_vec()), lo);
+    assert_eq!(dropped.len(), 2);
+    assert_eq!(index, Data::Index(vec![]));
+
+    let mut data = leaf.clone();
+    let dropped = data.drop_lt(&Bound::Inclusive(lo.to_vec()), lo);
+    assert_eq!(dropped, Data::Leaf(vec![]));
+    assert_eq!(data, leaf);
 }
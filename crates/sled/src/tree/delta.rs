@@ -0,0 +1,224 @@
+// Serialization for `Data::IntLeaf`, which stores its records
+// exactly like `Data::Leaf` in memory but writes keys that are 8
+// byte big-endian integers as varint deltas from the previous
+// key.
+//
+// Records are sorted by `prefix_cmp`, so the ones that share the
+// same number of bytes with the node prefix are contiguous, and
+// within such a group the remaining suffixes are ascending
+// integers. Each group is written as its shared length and record
+// count, followed by the first suffix and then the gaps between
+// neighbors. A leaf holding any key that isn't 8 bytes long is
+// written as plain records instead.
+
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::*;
+
+const INT_LEN: usize = 8;
+
+// The two enums below must list their variants in the same order
+// with the same shapes, since one writes what the other reads.
+#[derive(Serialize)]
+enum Encoded<'a> {
+    Deltas {
+        groups: Vec<(u8, u64)>,
+        deltas: Vec<u8>,
+        values: Vec<&'a Value>,
+    },
+    Raw(&'a [(Key, Value)]),
+}
+
+#[derive(Deserialize)]
+enum Decoded {
+    Deltas {
+        groups: Vec<(u8, u64)>,
+        deltas: Vec<u8>,
+        values: Vec<Value>,
+    },
+    Raw(Vec<(Key, Value)>),
+}
+
+pub fn serialize<S>(
+    records: &[(Key, Value)],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let fits = records.iter().all(|&(ref k, _)| {
+        !k.is_empty() && k[0] as usize + k.len() - 1 == INT_LEN
+    });
+    if !fits {
+        return Encoded::Raw(records).serialize(serializer);
+    }
+
+    let mut groups: Vec<(u8, u64)> = vec![];
+    let mut deltas = vec![];
+    let mut last = 0u64;
+    for &(ref k, _) in records {
+        let suffix = read_int(&k[1..]);
+        let same_group = groups
+            .last()
+            .map_or(false, |&(shared, _)| shared == k[0]);
+        if same_group {
+            groups.last_mut().unwrap().1 += 1;
+            write_varint(&mut deltas, suffix - last);
+        } else {
+            groups.push((k[0], 1));
+            write_varint(&mut deltas, suffix);
+        }
+        last = suffix;
+    }
+
+    Encoded::Deltas {
+        groups: groups,
+        deltas: deltas,
+        values: records.iter().map(|&(_, ref v)| v).collect(),
+    }.serialize(serializer)
+}
+
+pub fn deserialize<'de, D>(
+    deserializer: D,
+) -> Result<Vec<(Key, Value)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let (groups, deltas, values) =
+        match Decoded::deserialize(deserializer)? {
+            Decoded::Raw(records) => return Ok(records),
+            Decoded::Deltas {
+                groups,
+                deltas,
+                values,
+            } => (groups, deltas, values),
+        };
+
+    let mut values = values.into_iter();
+    let mut cursor = &*deltas;
+    let mut records = Vec::with_capacity(values.len());
+    for (shared, count) in groups {
+        if shared as usize > INT_LEN {
+            return Err(D::Error::custom(format!(
+                "shared length {} is longer than an integer key",
+                shared
+            )));
+        }
+        let mut last = 0u64;
+        for i in 0..count {
+            let delta = read_varint(&mut cursor)
+                .ok_or_else(|| D::Error::custom("truncated deltas"))?;
+            let suffix = if i == 0 {
+                delta
+            } else {
+                last.checked_add(delta).ok_or_else(|| {
+                    D::Error::custom("key delta overflows")
+                })?
+            };
+            last = suffix;
+
+            let mut key = Vec::with_capacity(1 + INT_LEN);
+            key.push(shared);
+            write_int(&mut key, suffix, INT_LEN - shared as usize);
+            let value = values.next().ok_or_else(|| {
+                D::Error::custom("more keys than values")
+            })?;
+            records.push((key, value));
+        }
+    }
+
+    if !cursor.is_empty() || values.next().is_some() {
+        return Err(D::Error::custom(
+            "key deltas do not match the values",
+        ));
+    }
+    Ok(records)
+}
+
+fn read_int(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+// Appends the low `width` bytes of `n`, most significant first.
+fn write_int(buf: &mut Vec<u8>, n: u64, width: usize) {
+    for i in (0..width).rev() {
+        buf.push((n >> (8 * i)) as u8);
+    }
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        if shift > 63 {
+            return None;
+        }
+        n |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+}
+
+#[test]
+fn test_int_leaf_round_trip() {
+    use bincode::{deserialize, serialize, Infinite};
+
+    let prefix = 1_000_000u64 << 16;
+    let mut lo = vec![];
+    write_int(&mut lo, prefix, INT_LEN);
+
+    let mut items = vec![];
+    for i in 0..1000u64 {
+        let mut k = vec![];
+        write_int(&mut k, prefix + i * i, INT_LEN);
+        let v = i.to_string().into_bytes();
+        items.push((prefix_encode(&lo, &k), v.into()));
+    }
+    items.sort_by(|a, b| prefix_cmp(&a.0, &b.0));
+
+    let data = Data::IntLeaf(items.clone());
+    let int_bytes = serialize(&data, Infinite).unwrap();
+    assert_eq!(deserialize::<Data>(&int_bytes).unwrap(), data);
+
+    let leaf_sz =
+        serialize(&Data::Leaf(items), Infinite).unwrap().len();
+    assert!(
+        int_bytes.len() * 3 < leaf_sz * 2,
+        "delta-encoded leaf was {} bytes, plain leaf was {} bytes",
+        int_bytes.len(),
+        leaf_sz
+    );
+
+    // a key that isn't 8 bytes long falls back to plain records
+    let mixed = Data::IntLeaf(vec![
+        (prefix_encode(b"", b"a"), vec![1].into()),
+        (prefix_encode(b"", &[0; 8]), vec![2].into()),
+    ]);
+    let bytes = serialize(&mixed, Infinite).unwrap();
+    assert_eq!(deserialize::<Data>(&bytes).unwrap(), mixed);
+}
+
+#[test]
+fn test_varint() {
+    for &n in &[0, 1, 127, 128, 300, 1 << 35, std::u64::MAX] {
+        let mut buf = vec![];
+        write_varint(&mut buf, n);
+        let mut cursor = &*buf;
+        assert_eq!(read_varint(&mut cursor), Some(n));
+        assert!(cursor.is_empty());
+    }
+    assert_eq!(read_varint(&mut &[0x80u8][..]), None);
+}
@@ -0,0 +1,250 @@
+use super::*;
+
+/// Leaf records whose values are stored once in a per-node
+/// dictionary, with each record holding an index into it. This
+/// saves space when many keys map to a small set of distinct
+/// values, like enum-style statuses.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValueDict {
+    values: Vec<Value>,
+    records: Vec<(Key, u32)>,
+}
+
+impl ValueDict {
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn distinct_values(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Rebuilds a dictionary from its parts, as written out by
+    /// `values` and `records`.
+    pub fn from_parts(
+        values: Vec<Value>,
+        records: Vec<(Key, u32)>,
+    ) -> ValueDict {
+        ValueDict {
+            values: values,
+            records: records,
+        }
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &*self.values
+    }
+
+    pub fn records(&self) -> &[(Key, u32)] {
+        &*self.records
+    }
+
+    /// Returns the value for a prefix-encoded key.
+    pub fn get(&self, key: KeyRef) -> Option<&Value> {
+        self.search(key)
+            .ok()
+            .map(|idx| &self.values[self.records[idx].1 as usize])
+    }
+
+    /// Sets a prefix-encoded key, returning the previous value.
+    pub fn set(&mut self, key: Key, val: Value) -> Option<Value> {
+        let value_idx = self.intern(val);
+        match self.search(&*key) {
+            Ok(idx) => {
+                let old_idx = std::mem::replace(
+                    &mut self.records[idx].1,
+                    value_idx,
+                );
+                Some(self.release(old_idx))
+            }
+            Err(idx) => {
+                self.records.insert(idx, (key, value_idx));
+                None
+            }
+        }
+    }
+
+    /// Removes a prefix-encoded key, returning the previous value.
+    pub fn del(&mut self, key: KeyRef) -> Option<Value> {
+        let idx = self.search(key).ok()?;
+        let (_, value_idx) = self.records.remove(idx);
+        Some(self.release(value_idx))
+    }
+
+    /// Keeps only the records whose encoded keys match the
+    /// predicate, dropping dictionary entries that are no longer
+    /// referenced.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&(Key, u32)) -> bool,
+    {
+        self.records.retain(f);
+        let records = std::mem::replace(&mut self.records, vec![]);
+        *self = self.select(records);
+    }
+
+    /// Replaces every record's key, which must not change the
+    /// order of the records.
+    pub fn map_keys<F>(&mut self, f: F)
+    where
+        F: Fn(&Key) -> Key,
+    {
+        for record in &mut self.records {
+            record.0 = f(&record.0);
+        }
+    }
+
+    /// Rewrites dictionary values in place with `f`, which returns
+    /// whether it changed the value. Returns how many records hold
+    /// a changed value. Rewritten values may now equal other
+    /// entries, so the dictionary is re-interned afterwards.
+    pub fn migrate_values<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&mut Value) -> bool,
+    {
+        let changed: Vec<bool> =
+            self.values.iter_mut().map(|v| f(v)).collect();
+        let count = self
+            .records
+            .iter()
+            .filter(|&&(_, idx)| changed[idx as usize])
+            .count();
+        if count > 0 {
+            let mut rebuilt = ValueDict::default();
+            for (k, v) in self.to_leaf() {
+                rebuilt.set(k, v);
+            }
+            *self = rebuilt;
+        }
+        count
+    }
+
+    /// Builds a new dictionary for a subset of this one's records,
+    /// carrying over only the values they reference.
+    pub fn select(&self, records: Vec<(Key, u32)>) -> ValueDict {
+        let mut ret = ValueDict::default();
+        let mut remap: Vec<Option<u32>> =
+            vec![None; self.values.len()];
+        for (k, old_idx) in records {
+            let new_idx = match remap[old_idx as usize] {
+                Some(new_idx) => new_idx,
+                None => {
+                    let new_idx = ret.values.len() as u32;
+                    let value = self.values[old_idx as usize].clone();
+                    ret.values.push(value);
+                    remap[old_idx as usize] = Some(new_idx);
+                    new_idx
+                }
+            };
+            ret.records.push((k, new_idx));
+        }
+        ret
+    }
+
+    /// Resolves every record to its value.
+    pub fn to_leaf(&self) -> Vec<(Key, Value)> {
+        self.records
+            .iter()
+            .map(|&(ref k, idx)| {
+                (k.clone(), self.values[idx as usize].clone())
+            })
+            .collect()
+    }
+
+    fn search(&self, key: KeyRef) -> Result<usize, usize> {
+        self.records
+            .binary_search_by(|&(ref k, _)| prefix_cmp(k, key))
+    }
+
+    fn intern(&mut self, val: Value) -> u32 {
+        if let Some(idx) = self.values.iter().position(|v| *v == val)
+        {
+            return idx as u32;
+        }
+        self.values.push(val);
+        (self.values.len() - 1) as u32
+    }
+
+    // Drops a record's reference to a dictionary entry, removing
+    // the entry once nothing else points to it.
+    fn release(&mut self, value_idx: u32) -> Value {
+        if self.records.iter().any(|&(_, idx)| idx == value_idx) {
+            return self.values[value_idx as usize].clone();
+        }
+
+        let last_idx = (self.values.len() - 1) as u32;
+        let removed = self.values.swap_remove(value_idx as usize);
+        if value_idx != last_idx {
+            for record in &mut self.records {
+                if record.1 == last_idx {
+                    record.1 = value_idx;
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[test]
+fn test_value_dict() {
+    let k = |k: &[u8]| prefix_encode(b"", k);
+    let mut dict = ValueDict::default();
+
+    assert_eq!(dict.set(k(b"a"), b"on".to_vec().into()), None);
+    assert_eq!(dict.set(k(b"b"), b"on".to_vec().into()), None);
+    assert_eq!(dict.set(k(b"c"), b"off".to_vec().into()), None);
+    assert_eq!(dict.len(), 3);
+    assert_eq!(dict.distinct_values(), 2);
+    assert_eq!(dict.get(&*k(b"b")), Some(&b"on".to_vec().into()));
+
+    // the last reference to "off" goes away, so does its entry
+    assert_eq!(
+        dict.set(k(b"c"), b"on".to_vec().into()),
+        Some(b"off".to_vec().into())
+    );
+    assert_eq!(dict.distinct_values(), 1);
+
+    assert_eq!(dict.del(&*k(b"a")), Some(b"on".to_vec().into()));
+    assert_eq!(dict.del(&*k(b"a")), None);
+    assert_eq!(
+        dict.to_leaf(),
+        vec![
+            (k(b"b"), b"on".to_vec().into()),
+            (k(b"c"), b"on".to_vec().into()),
+        ]
+    );
+}
+
+#[test]
+fn test_value_dict_space() {
+    use bincode::{serialize, Infinite};
+
+    let statuses: Vec<Value> = vec![
+        b"pending".to_vec().into(),
+        b"active".to_vec().into(),
+        b"suspended".to_vec().into(),
+    ];
+
+    let mut leaf = vec![];
+    let mut dict = ValueDict::default();
+    for i in 0..1000u16 {
+        let k = prefix_encode(b"", &[(i >> 8) as u8, i as u8]);
+        let v = statuses[i as usize % statuses.len()].clone();
+        leaf.push((k.clone(), v.clone()));
+        dict.set(k, v);
+    }
+
+    let leaf_sz =
+        serialize(&Data::Leaf(leaf), Infinite).unwrap().len();
+    let dict_sz =
+        serialize(&Data::DictLeaf(dict), Infinite).unwrap().len();
+
+    // each record carries a 4 byte index instead of a value that
+    // costs its length plus an 8 byte length prefix.
+    assert!(
+        dict_sz * 3 < leaf_sz * 2,
+        "interned leaf was {} bytes, plain leaf was {} bytes",
+        dict_sz,
+        leaf_sz
+    );
+}
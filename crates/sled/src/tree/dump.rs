@@ -0,0 +1,128 @@
+use std::fmt::Write;
+
+use super::*;
+
+/// One page of a tree, as reported by `Tree::dump_structure`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeDump {
+    /// The page holding the node.
+    pub id: PageID,
+    /// How far below the root the node is, the root being at 0.
+    pub level: usize,
+    /// Whether the node is a leaf rather than an index node.
+    pub leaf: bool,
+    /// The lowest key the node covers.
+    pub lo: Vec<u8>,
+    /// The key the node's range ends just before, or `None` if it
+    /// is the last node on its level.
+    pub hi: Option<Vec<u8>>,
+    /// The node's right sibling.
+    pub next: Option<PageID>,
+    /// How many frags make up the page: its base and the updates
+    /// stacked on it since it was last consolidated.
+    pub frags: usize,
+    /// How many records a leaf holds, or pointers an index node
+    /// holds.
+    pub len: usize,
+    /// The bytes of the node's keys and values, or of its keys and
+    /// child ids for an index node.
+    pub bytes: usize,
+    /// How many entries the node holds relative to the fanout it
+    /// splits at, capped at 1.0.
+    pub fill: f64,
+    /// The pages an index node points to, in key order, and none
+    /// for a leaf.
+    pub children: Vec<PageID>,
+    /// How the node's records are encoded.
+    pub encoding: Encoding,
+}
+
+/// Describes how a node's records are encoded, as reported by
+/// `Node::encoding`. A node written to the log already carries this
+/// in the tag of its data and its prefix, so this lets tooling report
+/// the mix of encodings in a database, such as during a migration to
+/// a different layout, without decoding the records.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct Encoding {
+    /// Keys are encoded against a prefix longer than `lo`.
+    pub long_prefix: bool,
+    /// Keys are written as integer deltas, see `Data::IntLeaf`.
+    pub int_keys: bool,
+    /// Values are interned in a per-node dictionary.
+    pub value_dict: bool,
+    /// Only keys are stored.
+    pub key_only: bool,
+    /// Values are written as one block, see `Data::BlockLeaf`.
+    pub value_block: bool,
+    /// Large values are compressed one by one, see
+    /// `Data::CompressedLeaf`.
+    pub compressed_values: bool,
+}
+
+/// The page graph of a tree, as reported by `Tree::dump_structure`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeDump {
+    /// The root page.
+    pub root: PageID,
+    /// Every node reached by walking the tree, level by level from
+    /// the root, and left to right along each level.
+    pub nodes: Vec<NodeDump>,
+}
+
+impl TreeDump {
+    /// Renders the tree as a graphviz DOT graph, with solid edges
+    /// from index nodes to their children and dashed edges from
+    /// nodes to their right siblings.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot.push_str("digraph tree {\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let hi = match node.hi {
+                Some(ref hi) => format!("{:?}", hi),
+                None => "inf".to_owned(),
+            };
+            writeln!(
+                dot,
+                "    n{} [label=\"{} {}\\n[{:?}, {})\\n{} frags, {} \
+                 {}\"];",
+                node.id,
+                if node.leaf { "leaf" } else { "index" },
+                node.id,
+                node.lo,
+                hi,
+                node.frags,
+                node.len,
+                if node.leaf { "records" } else { "children" },
+            ).unwrap();
+            for child in &node.children {
+                writeln!(dot, "    n{} -> n{};", node.id, child)
+                    .unwrap();
+            }
+            if let Some(next) = node.next {
+                writeln!(
+                    dot,
+                    "    n{} -> n{} [style=dashed];",
+                    node.id, next
+                ).unwrap();
+            }
+        }
+        let mut level = 0;
+        loop {
+            let ids: Vec<String> = self.nodes
+                .iter()
+                .filter(|node| node.level == level)
+                .map(|node| format!("n{};", node.id))
+                .collect();
+            if ids.is_empty() {
+                break;
+            }
+            writeln!(dot, "    {{ rank=same; {} }}", ids.join(" "))
+                .unwrap();
+            level += 1;
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,18 @@
+/// How durable a write must be before `Tree::set_with_durability`
+/// returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Return once the write is in the log's buffers, like `set`.
+    /// It is written out within `flush_every_ms`, or when a buffer
+    /// fills or is flushed.
+    Buffered,
+    /// Wait until the buffer holding the write has been written
+    /// out to the log's storage. A database that isn't
+    /// `temporary` fsyncs each buffer as it writes it, so there
+    /// this is as durable as `Synced`.
+    Flushed,
+    /// Wait until the log's storage has been fsynced after the
+    /// buffer holding the write was written out, even for a
+    /// `temporary` database, whose log otherwise skips fsyncs.
+    Synced,
+}
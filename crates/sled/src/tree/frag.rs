@@ -1,9 +1,6 @@
 use super::*;
 
 // TODO
-// Merged
-// LeftMerge(head: Raw, rhs: PageID, hi: Bound)
-// ParentMerge(lhs: PageID, rhs: PageID)
 // TxBegin(TxID), // in-mem
 // TxCommit(TxID), // in-mem
 // TxAbort(TxID), // in-mem
@@ -12,12 +9,40 @@ use super::*;
 pub enum Frag {
     Set(Key, Value),
     Del(Key),
+    /// Removes every record of a leaf whose decoded key falls in
+    /// `[lo, hi)`. The range may extend past the leaf's bounds.
+    DelRange(Bound, Bound),
     Merge(Key, Value),
     /// The optional page in Base means this node has replaced
     /// the specified page as a new root.
-    Base(Node, Option<PageID>),
+    Base(#[serde(with = "super::compact")] Node, Option<PageID>),
     ChildSplit(ChildSplit),
     ParentSplit(ParentSplit),
+    /// Rewrites every value in a leaf whose leading schema version
+    /// byte is the first version, using the migrator registered
+    /// under the given id, and stamps it with the second version.
+    Migrate(u8, u8, usize),
+    /// Points a node back at a new left sibling, after a split of
+    /// its old left sibling.
+    SetPrev(PageID),
+    /// Absorbs the right sibling's records and bounds, undoing a
+    /// split. Linked along with the `ParentMerge` that removes the
+    /// right sibling from the parent.
+    ChildMerge(ChildMerge),
+    ParentMerge(ParentMerge),
+    /// Everything a write batch does to one leaf, applied in order:
+    /// its Set, Del and Merge frags, and a ChildSplit for each time
+    /// the batch split the leaf to make room.
+    Writes(Vec<Frag>),
+    /// Expires a key at a time in milliseconds since the unix
+    /// epoch. Linked in a `Writes` right after the `Set` it
+    /// applies to, since any other write forgets the expiration.
+    Expire(Key, u64),
+    /// Sets a key to a value that was written to a page of its own,
+    /// which is logged as just that page's id.
+    SetBlob(Key, #[serde(with = "super::blob")] Value),
+    /// The only frag of a page holding one large value.
+    Blob(Value),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -31,3 +56,16 @@ pub struct ChildSplit {
     pub at: Bound,
     pub to: PageID,
 }
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChildMerge {
+    #[serde(with = "super::compact")]
+    pub right: Node,
+}
+
+/// Removes the child `to`, which starts at `at`, from an index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParentMerge {
+    pub at: Bound,
+    pub to: PageID,
+}
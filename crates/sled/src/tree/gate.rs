@@ -0,0 +1,118 @@
+// Keeps track of the writes in flight to a tree without making
+// them take a lock. A writer counts itself in the current epoch
+// for as long as it runs. Something that changes how writes have
+// to be made, like opening a read view or declaring an index,
+// makes its change first and then calls `quiesce`, which moves on
+// to the next epoch and waits for the writers counted in the last
+// one to finish. Writers that start after that see the change, and
+// don't wait for anything. `hold` also keeps new writers out until
+// it's dropped, for the few operations that replace the whole tree.
+
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+
+#[derive(Default)]
+pub(super) struct WriteGate {
+    epoch: AtomicUsize,
+    // the writers in flight in even and odd epochs
+    writers: [AtomicUsize; 2],
+    // taken to move on to the next epoch, so that only the
+    // writers of one epoch are ever waited for
+    turn: Mutex<()>,
+    held: AtomicBool,
+    // where writers wait for a `hold` to be dropped
+    released: Mutex<()>,
+    release: Condvar,
+}
+
+impl WriteGate {
+    // Counts a writer in until the returned guard is dropped,
+    // waiting first if the gate is held.
+    pub(super) fn enter(&self) -> Entered<'_> {
+        loop {
+            if let Some(entered) = self.try_enter() {
+                return entered;
+            }
+            self.wait_released();
+        }
+    }
+
+    // `enter`, or `None` if the gate is held.
+    pub(super) fn try_enter(&self) -> Option<Entered<'_>> {
+        loop {
+            let epoch = self.epoch.load(SeqCst);
+            let writers = &self.writers[epoch % 2];
+            writers.fetch_add(1, SeqCst);
+            if self.epoch.load(SeqCst) == epoch
+                && !self.held.load(SeqCst)
+            {
+                return Some(Entered { writers: writers });
+            }
+            writers.fetch_sub(1, SeqCst);
+            if self.held.load(SeqCst) {
+                return None;
+            }
+        }
+    }
+
+    // Waits until the gate isn't held.
+    pub(super) fn wait_released(&self) {
+        let mut released = self.released.lock().unwrap();
+        while self.held.load(SeqCst) {
+            released = self.release.wait(released).unwrap();
+        }
+    }
+
+    // Waits for every writer counted in before this was called to
+    // finish. Writers counted in afterwards go ahead meanwhile.
+    pub(super) fn quiesce(&self) {
+        let _turn = self.turn.lock().unwrap();
+        self.next_epoch();
+    }
+
+    // Keeps new writers out, and waits for the ones in flight to
+    // finish, until the returned guard is dropped.
+    pub(super) fn hold(&self) -> Held<'_> {
+        let turn = self.turn.lock().unwrap();
+        self.held.store(true, SeqCst);
+        self.next_epoch();
+        Held {
+            gate: self,
+            _turn: turn,
+        }
+    }
+
+    fn next_epoch(&self) {
+        let last = self.epoch.fetch_add(1, SeqCst);
+        while self.writers[last % 2].load(SeqCst) > 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+// A writer counted in by `WriteGate::enter`.
+pub(super) struct Entered<'a> {
+    writers: &'a AtomicUsize,
+}
+
+impl<'a> Drop for Entered<'a> {
+    fn drop(&mut self) {
+        self.writers.fetch_sub(1, SeqCst);
+    }
+}
+
+// A `WriteGate` held by `WriteGate::hold`.
+pub(super) struct Held<'a> {
+    gate: &'a WriteGate,
+    _turn: MutexGuard<'a, ()>,
+}
+
+impl<'a> Drop for Held<'a> {
+    fn drop(&mut self) {
+        self.gate.held.store(false, SeqCst);
+        let _released = self.gate.released.lock().unwrap();
+        self.gate.release.notify_all();
+    }
+}
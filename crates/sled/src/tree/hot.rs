@@ -0,0 +1,105 @@
+// Where a leaf's latest writes landed, so that a split can keep a
+// cluster of inserts around a moving hotspot on one side rather
+// than cutting through it and splitting both halves again soon
+// after.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use super::*;
+
+// How many of a leaf's latest writes are remembered.
+const RECENT_WRITES: usize = 8;
+
+/// The decoded keys of a leaf's latest writes, oldest first. Only
+/// writes applied since the node was last read from the log are
+/// remembered, so nodes compare equal whatever they remember.
+#[derive(Clone, Debug, Default)]
+pub struct RecentWrites(VecDeque<Key>);
+
+impl RecentWrites {
+    fn note(&mut self, key: Key) {
+        if self.0.len() == RECENT_WRITES {
+            self.0.pop_front();
+        }
+        self.0.push_back(key);
+    }
+}
+
+impl PartialEq for RecentWrites {
+    fn eq(&self, _other: &RecentWrites) -> bool {
+        true
+    }
+}
+
+impl Node {
+    // Remembers a write of a prefix-encoded key to a leaf.
+    pub(super) fn note_write(&mut self, key: KeyRef) {
+        let decoded = prefix_decode(self.prefix(), key);
+        self.recent.note(decoded);
+    }
+
+    pub(super) fn note_decoded_write(&mut self, key: &[u8]) {
+        self.recent.note(key.to_vec());
+    }
+
+    /// The positions of the records that most of the leaf's latest
+    /// writes went to, if those are clustered in no more than a
+    /// quarter of it. Writes spread across the leaf have no
+    /// hotspot to keep together.
+    pub fn hot_range(&self) -> Option<Range<usize>> {
+        let prefix = self.prefix();
+        let positions: Vec<usize> = self
+            .recent
+            .0
+            .iter()
+            .filter_map(|k| self.data.leaf_search(prefix, k).ok())
+            .collect();
+        if positions.len() < RECENT_WRITES / 2 {
+            return None;
+        }
+        let start = *positions.iter().min().unwrap();
+        let end = *positions.iter().max().unwrap() + 1;
+        if (end - start) * 4 > self.data.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+}
+
+#[test]
+fn test_hot_cluster_stays_in_one_half() {
+    let k = |i: u8| prefix_encode(b"", &[i]);
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    for i in 0..100u32 {
+        node.set_leaf(k((i * 37 % 100 * 2) as u8), vec![].into());
+    }
+    // spread out writes have no hotspot
+    assert_eq!(node.hot_range(), None);
+
+    // a burst of inserts around the median
+    for i in 45..55u8 {
+        node.set_leaf(k(i * 2 + 1), vec![].into());
+    }
+    let hot = node.hot_range().unwrap();
+    let rhs = node.split(1, 0.5);
+    let split = rhs.lo.inner().to_vec();
+    let cluster: Vec<u8> = (47..55u8).map(|i| i * 2 + 1).collect();
+    assert!(
+        cluster.iter().all(|&c| vec![c] < split)
+            || cluster.iter().all(|&c| vec![c] >= split),
+        "split at {:?} cut through the hot records {:?}",
+        split,
+        hot
+    );
+
+    // the same node without the writes is split at the median,
+    // which does cut through them
+    let mut cold = node.clone();
+    cold.recent = RecentWrites::default();
+    let split = cold.split(1, 0.5).lo.inner().to_vec();
+    assert!(
+        cluster.iter().any(|&c| vec![c] < split)
+            && cluster.iter().any(|&c| vec![c] >= split)
+    );
+}
@@ -0,0 +1,257 @@
+// Secondary indexes. An index lives in a second tree of the same
+// `Db`, holding an empty record for every index key an extractor
+// derives from a record of the indexed tree. The record's key
+// follows the index key, which is written with the terminated
+// encoding of `keys` so that a scan over one index key can't run
+// into the next. Every write to an indexed tree goes through
+// `commit_batches`, which works out the entries each write adds
+// and removes from the before and after images of the keys it
+// touches, and commits them in the same batch as the write, so the
+// index and the records can't disagree, even after a crash.
+
+use std::collections::VecDeque;
+use std::ops::{self, RangeBounds};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, RwLock};
+
+use keys::OrderedKey;
+
+use super::node::Change;
+use super::*;
+
+// how many index entries an `IndexIter` reads at a time, and how
+// many records each batch of a backfill indexes
+pub(super) const INDEX_CHUNK: usize = 64;
+
+pub(super) type Extractor =
+    dyn Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+// An index declared with `Tree::add_index`.
+#[derive(Clone)]
+pub(super) struct Index {
+    pub(super) name: String,
+    pub(super) tree: Tree,
+    extract: Arc<Extractor>,
+}
+
+impl Index {
+    pub(super) fn new<F>(name: &str, tree: &Tree, extract: F) -> Index
+    where
+        F: Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        Index {
+            name: name.to_owned(),
+            tree: tree.clone(),
+            extract: Arc::new(extract),
+        }
+    }
+
+    // The index keys of `key` holding `value`.
+    fn index_keys(
+        &self,
+        key: &[u8],
+        value: &Option<Value>,
+    ) -> Vec<Key> {
+        match *value {
+            Some(ref value) => (self.extract)(key, value),
+            None => vec![],
+        }
+    }
+
+    // Adds to `batch` the entries to delete and write for a change
+    // to one record of the indexed tree.
+    pub(super) fn maintain(
+        &self,
+        change: &Change,
+        batch: &mut Batch,
+    ) {
+        let (ref key, ref old, ref new) = *change;
+        let removed = self.index_keys(key, old);
+        let added = self.index_keys(key, new);
+        for index_key in &removed {
+            if !added.contains(index_key) {
+                batch.del(entry(index_key, key));
+            }
+        }
+        for index_key in &added {
+            if !removed.contains(index_key) {
+                batch.set(entry(index_key, key), vec![]);
+            }
+        }
+    }
+}
+
+// The indexes declared on a tree, shared by all of its handles.
+// Whoever declares one then waits for the writes to the tree in
+// flight to finish, so every commit that starts afterwards
+// maintains it.
+#[derive(Default)]
+pub(super) struct Indexes {
+    declared: RwLock<Vec<Index>>,
+    // set once the tree holds an index of another tree
+    is_index: AtomicBool,
+}
+
+impl Indexes {
+    pub(super) fn declared(&self) -> Vec<Index> {
+        self.declared.read().unwrap().clone()
+    }
+
+    // Adds `index` to the tree's indexes. The caller must then wait
+    // for the writes to the tree in flight to finish.
+    pub(super) fn declare(&self, index: Index) -> DbResult<(), ()> {
+        let mut declared = self.declared.write().unwrap();
+        if declared.iter().any(|i| i.name == index.name) {
+            return Err(Error::Unsupported(format!(
+                "the tree already has an index called {:?}",
+                index.name
+            )));
+        }
+        index.tree.indexes().is_index.store(true, SeqCst);
+        declared.push(index);
+        Ok(())
+    }
+
+    // The index called `name`.
+    pub(super) fn find(&self, name: &str) -> DbResult<Index, ()> {
+        let declared = self.declared.read().unwrap();
+        match declared.iter().find(|i| i.name == name) {
+            Some(index) => Ok(index.clone()),
+            None => Err(Error::Unsupported(format!(
+                "the tree has no index called {:?}",
+                name
+            ))),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.declared.read().unwrap().len()
+    }
+
+    pub(super) fn is_index(&self) -> bool {
+        self.is_index.load(SeqCst)
+    }
+}
+
+/// An iterator over the records of a `Tree` found through one of
+/// its indexes, returned by `Tree::index_range`. Yields the index
+/// key, the record's key and its value, ordered by index key and
+/// then by key. A record written after the iterator read its index
+/// entry is only returned if it still has that index key.
+pub struct IndexIter<'a> {
+    tree: &'a Tree,
+    index: Index,
+    // the index entries still to be read
+    lo: ops::Bound<Key>,
+    hi: ops::Bound<Key>,
+    read: VecDeque<(Key, Key)>,
+    done: bool,
+}
+
+impl<'a> IndexIter<'a> {
+    // Iterates over the records of `tree` that `index` lists under
+    // the index keys in `range`.
+    pub(super) fn new<K, R>(
+        tree: &'a Tree,
+        index: Index,
+        range: R,
+    ) -> IndexIter<'a>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        // every entry of an index key lies in [start, end)
+        let start = |k: &K| encode_index_key(k.as_ref());
+        let end = |k: &K| {
+            let mut end = encode_index_key(k.as_ref());
+            *end.last_mut().unwrap() = 1;
+            end
+        };
+        let lo = match range.start_bound() {
+            ops::Bound::Included(k) => ops::Bound::Included(start(k)),
+            ops::Bound::Excluded(k) => ops::Bound::Included(end(k)),
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+        let hi = match range.end_bound() {
+            ops::Bound::Included(k) => ops::Bound::Excluded(end(k)),
+            ops::Bound::Excluded(k) => ops::Bound::Excluded(start(k)),
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+        IndexIter {
+            tree: tree,
+            index: index,
+            lo: lo,
+            hi: hi,
+            read: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // Reads the next chunk of index entries.
+    fn read_entries(&mut self) -> DbResult<(), ()> {
+        let bounds = (self.lo.clone(), self.hi.clone());
+        let mut count = 0;
+        for res in self.index.tree.range(bounds).take(INDEX_CHUNK) {
+            let (entry, _) = res?;
+            self.read.push_back(decode_entry(&entry)?);
+            self.lo = ops::Bound::Excluded(entry);
+            count += 1;
+        }
+        self.done = count < INDEX_CHUNK;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for IndexIter<'a> {
+    type Item = DbResult<(Key, Key, Value), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((index_key, key)) = self.read.pop_front() {
+                let value = match self.tree.get(&key) {
+                    Ok(value) => value,
+                    Err(e) => return Some(Err(e)),
+                };
+                // skip records changed since we read their entry
+                let index_keys = self.index.index_keys(&key, &value);
+                if index_keys.contains(&index_key) {
+                    let value = value.unwrap();
+                    return Some(Ok((index_key, key, value)));
+                }
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.read_entries() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn encode_index_key(index_key: &[u8]) -> Key {
+    let mut encoded = vec![];
+    index_key.to_vec().write_ordered(&mut encoded);
+    encoded
+}
+
+// The key of an index entry for a record.
+fn entry(index_key: &[u8], key: &[u8]) -> Key {
+    let mut entry = encode_index_key(index_key);
+    entry.extend_from_slice(key);
+    entry
+}
+
+// Splits an index entry into its index key and the record's key.
+fn decode_entry(entry: &[u8]) -> DbResult<(Key, Key), ()> {
+    let mut rest = entry;
+    match Vec::read_ordered(&mut rest) {
+        Some(index_key) => Ok((index_key, rest.to_vec())),
+        None => Err(Error::ReportableBug(format!(
+            "index entry {:?} doesn't start with an index key",
+            entry
+        ))),
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use super::node::SeparatorError;
+use super::*;
+
+/// What `Tree::verify_integrity` found while walking a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeIntegrity {
+    /// The number of pages visited, both index nodes and leaves.
+    pub pages: usize,
+    /// The number of frags read back from the log and checked.
+    pub frags: usize,
+    /// The number of nodes reachable through a sibling's `next`
+    /// but not yet pointed to by their parent. A split links its
+    /// new node into the parent after the sibling, so a crash in
+    /// between leaves these behind. They are still found by
+    /// reads, and are not counted as problems.
+    pub unlinked: usize,
+    /// Everything that is wrong with the tree, in the order found.
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl TreeIntegrity {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A single inconsistency found by `Tree::verify_integrity`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntegrityProblem {
+    /// A frag of a page could not be read back from the log,
+    /// usually because it fails its checksum.
+    Checksum {
+        /// The page the frag belongs to.
+        pid: PageID,
+        /// Where the frag was written in the log.
+        lid: LogID,
+    },
+    /// A page that the index or a sibling points to is freed, or
+    /// can't be read.
+    Missing {
+        /// The page pointed to.
+        pid: PageID,
+    },
+    /// Following `next` pointers led back to a page already
+    /// visited on the same level.
+    Cycle {
+        /// The page visited twice.
+        pid: PageID,
+    },
+    /// A node's records are out of order, or fall outside of its
+    /// bounds.
+    Node {
+        /// The offending node.
+        pid: PageID,
+        /// What is wrong with it.
+        reason: String,
+    },
+    /// A level doesn't cover every key: its first node doesn't
+    /// start at the empty key, its last doesn't end at infinity,
+    /// or a node doesn't end where its right sibling starts.
+    Bounds {
+        /// The offending node.
+        pid: PageID,
+        /// What is wrong with its bounds.
+        reason: String,
+    },
+    /// A separator in an index node disagrees with the child it
+    /// points to, or the child doesn't nest within the parent's
+    /// bounds or isn't on the level below at all.
+    Separator {
+        /// The index node holding the separator.
+        parent: PageID,
+        /// The child the separator points to.
+        child: PageID,
+        /// What is wrong with the pair.
+        reason: String,
+    },
+}
+
+// Checks that the nodes of one level, in `next` order, abut each
+// other and cover every key, and that the level above points to
+// each of them with a separator matching its lo. `complete` is
+// false if the walk along the level stopped early.
+pub(super) fn check_level(
+    parents: &[Node],
+    nodes: &[Node],
+    complete: bool,
+    report: &mut TreeIntegrity,
+) {
+    if let Some(first) = nodes.first() {
+        if first.lo != Bound::Inclusive(vec![]) {
+            report.problems.push(IntegrityProblem::Bounds {
+                pid: first.id,
+                reason: format!(
+                    "first node on its level starts at {:?}",
+                    first.lo
+                ),
+            });
+        }
+    }
+    if complete {
+        if let Some(last) = nodes.last() {
+            if last.hi != Bound::Inf {
+                report.problems.push(IntegrityProblem::Bounds {
+                    pid: last.id,
+                    reason: format!(
+                        "last node on its level ends at {:?}",
+                        last.hi
+                    ),
+                });
+            }
+        }
+    }
+    for pair in nodes.windows(2) {
+        let (left, right) = (&pair[0], &pair[1]);
+        let abuts = match right.lo {
+            Bound::Inclusive(ref lo) => {
+                left.hi == Bound::Exclusive(lo.clone())
+            }
+            _ => false,
+        };
+        if !abuts {
+            report.problems.push(IntegrityProblem::Bounds {
+                pid: left.id,
+                reason: format!(
+                    "ends at {:?} but its right sibling {} starts \
+                     at {:?}",
+                    left.hi, right.id, right.lo
+                ),
+            });
+        }
+    }
+
+    if parents.is_empty() {
+        return;
+    }
+
+    let child_los: Vec<(PageID, Bound)> = nodes
+        .iter()
+        .map(|node| (node.id, node.lo.clone()))
+        .collect();
+    let mut linked = HashSet::new();
+    for parent in parents {
+        let reason = match parent.check_separators(&child_los) {
+            Ok(()) => None,
+            Err(SeparatorError::MissingChild(child)) => Some((
+                child,
+                "child is not on the level below".to_owned(),
+            )),
+            Err(SeparatorError::Mismatch {
+                child,
+                separator,
+                lo,
+            }) => Some((
+                child,
+                format!(
+                    "separator {:?} does not match the child's \
+                     lo {:?}",
+                    separator, lo
+                ),
+            )),
+        };
+        if let Some((child, reason)) = reason {
+            report.problems.push(IntegrityProblem::Separator {
+                parent: parent.id,
+                child: child,
+                reason: reason,
+            });
+        }
+
+        let ptrs = match parent.data {
+            Data::Index(ref ptrs) => ptrs,
+            _ => continue,
+        };
+        for &(_, child) in ptrs {
+            linked.insert(child);
+            let node = match nodes.iter().find(|n| n.id == child) {
+                Some(node) => node,
+                None => continue,
+            };
+            if node.hi > parent.hi {
+                report.problems.push(IntegrityProblem::Separator {
+                    parent: parent.id,
+                    child: child,
+                    reason: format!(
+                        "child ends at {:?}, past its parent's {:?}",
+                        node.hi, parent.hi
+                    ),
+                });
+            }
+        }
+    }
+    report.unlinked += nodes
+        .iter()
+        .filter(|node| !linked.contains(&node.id))
+        .count();
+}
@@ -1,23 +1,62 @@
 use super::*;
 
 use epoch::pin;
-use pagecache::PageGet;
 
 /// An iterator over keys and values in a `Tree`.
 pub struct Iter<'a> {
     pub(super) id: PageID,
-    pub(super) inner:
-        &'a PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>,
+    pub(super) tree: &'a Tree,
     pub(super) last_key: Bound,
+    // keys past this bound end the iteration
+    pub(super) hi: Bound,
+    // where the leaf at `id` should start, when we got there from
+    // its left sibling. A leaf that has since been merged away may
+    // have had its page reused for a different node.
+    pub(super) expected_lo: Option<Bound>,
     pub(super) broken: Option<Error<()>>,
     pub(super) done: bool,
-    // TODO we have to refactor this in light of pages being deleted
 }
 
 impl<'a> Iterator for Iter<'a> {
-    type Item = DbResult<(Vec<u8>, Vec<u8>), ()>;
+    type Item = DbResult<(Vec<u8>, IVec), ()>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|k, v| (k, v.clone()))
+    }
+}
+
+/// An iterator over the keys in a `Tree`, returned by
+/// `Tree::keys`, which doesn't copy their values.
+pub struct Keys<'a>(pub(super) Iter<'a>);
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = DbResult<Vec<u8>, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with(|k, _| k)
+    }
+}
+
+/// An iterator over the values in a `Tree`, in the order of their
+/// keys, returned by `Tree::values`.
+pub struct Values<'a>(pub(super) Iter<'a>);
+
+impl<'a> Iterator for Values<'a> {
+    type Item = DbResult<IVec, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with(|_, v| v.clone())
+    }
+}
+
+impl<'a> Iter<'a> {
+    // Steps to the next record, returning what `f` makes of its
+    // decoded key and borrowed value, so that callers only copy
+    // the parts of a record they need.
+    fn next_with<T, F>(&mut self, f: F) -> Option<DbResult<T, ()>>
+    where
+        F: FnOnce(Key, &Value) -> T,
+    {
         if self.done {
             return None;
         } else if let Some(broken) = self.broken.take() {
@@ -26,46 +65,204 @@ impl<'a> Iterator for Iter<'a> {
         };
 
         let guard = pin();
+        // whether we've moved on to the leaf at `id` from its left
+        // sibling during this call
+        let mut crossed = false;
         loop {
-            let res = self.inner.get(self.id, &guard);
-
-            let node = match res {
-                Ok(PageGet::Materialized(Frag::Base(base, _), _)) => {
-                    base
+            let node = match self.tree.get_node(self.id, &guard) {
+                Ok(Some(ref node))
+                    if is_leaf(node)
+                        && self.expected_lo.as_ref().map_or(
+                            true,
+                            |lo| lo.inner() == node.lo.inner(),
+                        ) =>
+                {
+                    node.clone()
+                }
+                Ok(_) => {
+                    // the leaf was merged into its left sibling, so
+                    // find where our keys live now
+                    let key = self.last_key.inner();
+                    match self.tree.leaf_for_key(key, &guard) {
+                        Ok(id) => {
+                            self.id = id;
+                            self.expected_lo = None;
+                            continue;
+                        }
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
                 }
                 Err(e) => {
-                    // TODO(when implementing merge support) this could
-                    // be None if the node was removed since the last
-                    // iteration, and we need to just get the inner
-                    // node again...
-                    error!("iteration failed: {:?}", e);
                     self.done = true;
-                    return Some(Err(e.danger_cast()));
+                    return Some(Err(e));
                 }
-                other => panic!(
-                    "the pagecache returned an unexpected value \
-                     to the Tree iterator: {:?}",
-                    other
-                ),
             };
 
-            let prefix = node.lo.inner();
-            for (ref k, ref v) in
-                node.data.leaf().expect("node should be a leaf")
-            {
-                let decoded_k = prefix_decode(prefix, k);
+            // the next leaf starts at our hi, so there is no need
+            // to read it if that is already past the end
+            let past_end = match node.hi {
+                Bound::Inf => true,
+                ref hi => !within(hi.inner(), &self.hi),
+            };
+            if crossed && !past_end {
+                if let Some(next) = node.next {
+                    self.tree.read_ahead(next, &self.hi);
+                }
+            }
+
+            // borrow the leaf's records rather than copying them
+            for (decoded_k, v) in node.iter() {
                 if Bound::Inclusive(decoded_k.clone()) > self.last_key
                 {
+                    if !within(&decoded_k, &self.hi) {
+                        self.done = true;
+                        return None;
+                    }
                     self.last_key =
                         Bound::Inclusive(decoded_k.to_vec());
-                    let ret = Ok((decoded_k, v.clone()));
-                    return Some(ret);
+                    return Some(Ok(f(decoded_k, v)));
                 }
             }
             match node.next {
-                Some(id) => self.id = id,
-                None => return None,
+                Some(id) if !past_end => {
+                    self.id = id;
+                    self.expected_lo = Some(node.hi.clone());
+                    crossed = true;
+                }
+                _ => {
+                    self.done = true;
+                    return None;
+                }
             }
         }
     }
 }
+
+/// An iterator over keys and values in a `Tree` in descending key
+/// order, which steps back between leaves through their `prev`
+/// links.
+pub struct RevIter<'a> {
+    pub(super) id: PageID,
+    pub(super) tree: &'a Tree,
+    // every key still to be returned falls within this bound
+    pub(super) hi: Bound,
+    // the `lo` of the leaf we stepped back from, which the leaf at
+    // `id` must start before. A leaf that has since been merged
+    // away may have had its page reused for a different node.
+    pub(super) right_lo: Option<Bound>,
+    pub(super) broken: Option<Error<()>>,
+    pub(super) done: bool,
+}
+
+impl<'a> Iterator for RevIter<'a> {
+    type Item = DbResult<(Vec<u8>, IVec), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        } else if let Some(broken) = self.broken.take() {
+            self.done = true;
+            return Some(Err(broken));
+        };
+
+        let guard = pin();
+        loop {
+            let node = match self.tree.get_node(self.id, &guard) {
+                Ok(Some(ref node))
+                    if is_leaf(node)
+                        && self.right_lo.as_ref().map_or(
+                            true,
+                            |lo| node.lo.inner() < lo.inner(),
+                        ) =>
+                {
+                    node.clone()
+                }
+                Ok(_) => {
+                    // the leaf was merged into its left sibling, so
+                    // find where our keys live now
+                    let found = match self.hi {
+                        Bound::Inf => self.tree.last_leaf(&guard),
+                        ref hi => {
+                            self.tree.leaf_for_key(hi.inner(), &guard)
+                        }
+                    };
+                    match found {
+                        Ok(id) => {
+                            self.id = id;
+                            self.right_lo = None;
+                            continue;
+                        }
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+            // a prev link that predates a split of the node it
+            // points to leaves us short of the keys we want, so
+            // move right until we reach the node ending past them.
+            let short = match node.hi {
+                Bound::Inf => false,
+                ref hi => within(hi.inner(), &self.hi),
+            };
+            if short {
+                self.id = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                self.right_lo = None;
+                continue;
+            }
+
+            let lo = node.lo.inner();
+            if within(lo, &self.hi) {
+                if let Some((k, v)) = node.last_within(&self.hi) {
+                    self.hi = Bound::Exclusive(k.clone());
+                    return Some(Ok((k, v.clone())));
+                }
+                self.hi = Bound::Exclusive(lo.to_vec());
+            }
+
+            if lo.is_empty() {
+                self.done = true;
+                return None;
+            }
+            match node.prev {
+                Some(id) => {
+                    self.id = id;
+                    self.right_lo = Some(node.lo.clone());
+                }
+                None => {
+                    self.done = true;
+                    return Some(Err(Error::ReportableBug(format!(
+                        "leaf {} has no prev link but isn't the \
+                         leftmost leaf",
+                        node.id
+                    ))));
+                }
+            }
+        }
+    }
+}
+
+// Whether a decoded key falls within an upper bound.
+pub(super) fn within(key: &[u8], hi: &Bound) -> bool {
+    match *hi {
+        Bound::Inclusive(ref hi) => key <= &**hi,
+        Bound::Exclusive(ref hi) => key < &**hi,
+        Bound::Inf => true,
+    }
+}
+
+fn is_leaf(node: &Node) -> bool {
+    !node.data.is_index()
+}
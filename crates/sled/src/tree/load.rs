@@ -0,0 +1,103 @@
+// Helpers for `Tree::bulk_load`, which builds a tree from records
+// that arrive sorted a level at a time, leaves first, instead of
+// inserting them one by one. Each node is filled until one more
+// entry would make it split, and is written once its right sibling
+// is known.
+
+use super::data::parent_separator;
+use super::*;
+
+// A node at `id` holding `data` and covering keys from `lo`, whose
+// `hi` and `next` are set once its right sibling is known.
+pub(super) fn loaded_node(
+    id: PageID,
+    data: Data,
+    prev: Option<PageID>,
+    lo: Key,
+) -> Node {
+    Node {
+        id: id,
+        data: data,
+        next: None,
+        prev: prev,
+        lo: Bound::Inclusive(lo),
+        hi: Bound::Inf,
+        prefix: None,
+        bloom: None,
+        expiries: Expiries::default(),
+        recent: RecentWrites::default(),
+    }
+}
+
+// Adds a record to the end of the leaf being loaded. Returns
+// `false`, leaving the leaf as it was, if the record would make the
+// leaf split, so it belongs in the next one.
+pub(super) fn load_record(
+    leaf: &mut Node,
+    key: &[u8],
+    value: Value,
+    policy: &SplitPolicy,
+    config: &Config,
+) -> DbResult<bool, ()> {
+    let encoded_key = prefix_encode(leaf.lo.inner(), key);
+    let set = blob::set_frag(encoded_key.clone(), value);
+    match leaf.check_capacity(&set, config) {
+        Ok(()) => {}
+        Err(NodeError::NodeFull(_)) => return Ok(false),
+        Err(other) => {
+            return Err(Error::Unsupported(other.to_string()))
+        }
+    }
+    leaf.apply(&set, config)
+        .map_err(|e| Error::ReportableBug(e.to_string()))?;
+    if leaf.data.len() > 1 && leaf.should_split_with(policy, config) {
+        leaf.apply(&Frag::Del(encoded_key), config)
+            .map_err(|e| Error::ReportableBug(e.to_string()))?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// The lo of the leaf after one whose largest key is `left_max`,
+// when that leaf starts with `right_lo`.
+pub(super) fn leaf_separator(
+    left_max: &[u8],
+    right_lo: &[u8],
+) -> Key {
+    parent_separator(left_max, right_lo, true)
+}
+
+// Groups the `(lo, pid)` of every node of a level into the children
+// of the index nodes above it, filling each index node until one
+// more child would make it split.
+pub(super) fn pack_index(
+    level: Vec<(Key, PageID)>,
+    policy: &SplitPolicy,
+    config: &Config,
+) -> Vec<Vec<(Key, PageID)>> {
+    let mut groups = vec![];
+    let mut group: Vec<(Key, PageID)> = vec![];
+    let mut node = loaded_node(0, Data::Index(vec![]), None, vec![]);
+    for (lo, pid) in level {
+        if group.is_empty() {
+            node.lo = Bound::Inclusive(lo.clone());
+        }
+        let encoded = prefix_encode(node.lo.inner(), &lo);
+        if let Data::Index(ref mut ptrs) = node.data {
+            ptrs.push((encoded, pid));
+        }
+        if !group.is_empty()
+            && node.should_split_with(policy, config)
+        {
+            groups.push(std::mem::replace(&mut group, vec![]));
+            node.lo = Bound::Inclusive(lo.clone());
+            let encoded = prefix_encode(&lo, &lo);
+            node.data = Data::Index(vec![(encoded, pid)]);
+        }
+        group.push((lo, pid));
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
@@ -0,0 +1,134 @@
+// Reads the writes that frags logged to a leaf made back out of
+// them, for `Db::replication_stream`. The keys in a frag are
+// encoded against the `lo` of the leaf it was linked to, which
+// never changes while the leaf's page holds it, so the caller has
+// to find that leaf.
+
+use super::*;
+
+/// The writes that a frag linked to a leaf made, in the order it
+/// made them, as the key each applies to, or starts at for a
+/// `Clear`, and what it did. `lo` is the leaf's `lo`. Frags that
+/// only restructure the tree made no writes. A `Base` only counts
+/// as one when it's an empty leaf covering every key, as a tree is
+/// started over with by `Tree::clear`.
+pub(crate) fn logged_writes(
+    frag: &Frag,
+    lo: &[u8],
+) -> Vec<(Key, ChangeOp)> {
+    let mut writes = vec![];
+    push_writes(frag, lo, &mut writes);
+    writes
+}
+
+/// The page and `lo` of the right sibling that a frag merged into
+/// a leaf, if it merged one. What was written to the sibling before
+/// then belongs to the leaf.
+pub(crate) fn merged_right(frag: &Frag) -> Option<(PageID, Key)> {
+    match *frag {
+        Frag::ChildMerge(ref merge) => {
+            let right = &merge.right;
+            Some((right.id, right.lo.inner().to_vec()))
+        }
+        _ => None,
+    }
+}
+
+fn push_writes(
+    frag: &Frag,
+    lo: &[u8],
+    writes: &mut Vec<(Key, ChangeOp)>,
+) {
+    match *frag {
+        Frag::Set(ref k, ref v) => {
+            let op = ChangeOp::Set(v.to_vec(), None);
+            writes.push((prefix_decode(lo, k), op));
+        }
+        // a blob freed before it could be read back belonged to a
+        // record that a later write replaced
+        Frag::SetBlob(_, ref v) if v.is_empty() => {}
+        Frag::SetBlob(ref k, ref v) => {
+            let op = ChangeOp::Set(v.to_vec(), None);
+            writes.push((prefix_decode(lo, k), op));
+        }
+        Frag::Del(ref k) => {
+            writes.push((prefix_decode(lo, k), ChangeOp::Del));
+        }
+        Frag::Merge(ref k, ref v) => {
+            let op = ChangeOp::Merge(v.to_vec());
+            writes.push((prefix_decode(lo, k), op));
+        }
+        Frag::DelRange(ref start, ref end) => {
+            let hi = match *end {
+                Bound::Inf => None,
+                ref end => Some(end.inner().to_vec()),
+            };
+            let start = start.inner().to_vec();
+            writes.push((start, ChangeOp::Clear(hi)));
+        }
+        // an expiration is linked right after the Set it applies to
+        Frag::Expire(ref k, at) => {
+            let key = prefix_decode(lo, k);
+            if let Some(last) = writes.last_mut() {
+                if let (ref set, ChangeOp::Set(_, ref mut expires)) =
+                    *last
+                {
+                    if *set == key {
+                        *expires = Some(at);
+                    }
+                }
+            }
+        }
+        Frag::Writes(ref frags) => {
+            for frag in frags {
+                push_writes(frag, lo, writes);
+            }
+        }
+        Frag::Base(ref node, _)
+            if !node.data.is_index()
+                && node.lo == Bound::Inclusive(vec![])
+                && node.hi == Bound::Inf
+                && node.iter().next().is_none() =>
+        {
+            writes.push((vec![], ChangeOp::Clear(None)));
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn test_logged_writes() {
+    let lo = b"ab".to_vec();
+    let k = |key: &[u8]| prefix_encode(&lo, key);
+    let frag = Frag::Writes(vec![
+        Frag::Set(k(b"abc"), vec![1].into()),
+        Frag::Expire(k(b"abc"), 9),
+        Frag::Merge(k(b"abd"), vec![2].into()),
+        Frag::Del(k(b"abe")),
+        Frag::DelRange(
+            Bound::Inclusive(b"abf".to_vec()),
+            Bound::Exclusive(b"abg".to_vec()),
+        ),
+    ]);
+    assert_eq!(
+        logged_writes(&frag, &lo),
+        vec![
+            (b"abc".to_vec(), ChangeOp::Set(vec![1], Some(9))),
+            (b"abd".to_vec(), ChangeOp::Merge(vec![2])),
+            (b"abe".to_vec(), ChangeOp::Del),
+            (b"abf".to_vec(), ChangeOp::Clear(Some(b"abg".to_vec()))),
+        ]
+    );
+
+    let split = Frag::ChildSplit(ChildSplit {
+        at: Bound::Inclusive(b"abz".to_vec()),
+        to: 7,
+    });
+    assert_eq!(logged_writes(&split, &lo), vec![]);
+    let empty = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    let cleared = Frag::Base(empty, None);
+    assert_eq!(
+        logged_writes(&cleared, &[]),
+        vec![(vec![], ChangeOp::Clear(None))]
+    );
+}
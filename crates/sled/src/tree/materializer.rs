@@ -27,17 +27,59 @@ impl Materializer for BLinkMaterializer {
         }
     }
 
-    fn merge(&self, frags: &[&Frag]) -> Frag {
+    fn merge(&self, frags: &[&Frag]) -> Result<Frag, MergeError> {
         let (mut base_node, is_root) = match frags[0].clone() {
             Frag::Base(base_node, is_root) => (base_node, is_root),
-            _ => panic!("non-Base in first element of frags slice"),
+            // a large value's page is never appended to
+            blob @ Frag::Blob(_) if frags.len() == 1 => {
+                return Ok(blob)
+            }
+            _ => {
+                return Err(MergeError {
+                    idx: 0,
+                    reason: "non-Base in first element of frags slice"
+                        .to_owned(),
+                })
+            }
         };
 
-        for &frag in &frags[1..] {
-            base_node.apply(frag, self.config.merge_operator);
+        let mut timings = if self.config.log_apply_timings {
+            Some(ApplyTimings::default())
+        } else {
+            None
+        };
+        base_node
+            .apply_batch(
+                &frags[1..],
+                &self.config,
+                None,
+                timings.as_mut(),
+            )
+            .map_err(|e| MergeError {
+                idx: e.idx + 1,
+                reason: e.error.to_string(),
+            })?;
+        if let Some(timings) = timings {
+            debug!(
+                "consolidating node {} took {:?}",
+                base_node.id, timings
+            );
+        }
+        // records that share a key or fall outside the node's
+        // bounds can only come from a damaged or partially applied
+        // chain, so keep the one applied last of each key, and only
+        // the keys the node owns
+        let dropped = base_node.clamp();
+        if dropped > 0 {
+            warn!(
+                "dropped {} duplicate or out of range keys \
+                 consolidating node {}",
+                dropped, base_node.id
+            );
         }
+        base_node.recompute_prefix();
 
-        Frag::Base(base_node, is_root)
+        Ok(Frag::Base(base_node, is_root))
     }
 
     fn recover(&self, frag: &Frag) -> Option<Vec<(PageID, PageID)>> {
@@ -59,4 +101,12 @@ impl Materializer for BLinkMaterializer {
         }
         None
     }
+
+    fn references(&self, frag: &Frag) -> Vec<PageID> {
+        blob::references(frag)
+    }
+
+    fn fill(&self, frag: &mut Frag, pages: Vec<Option<Frag>>) {
+        blob::fill(frag, pages)
+    }
 }
@@ -1,21 +1,82 @@
 use super::*;
 
+mod batch;
+mod blob;
+mod block;
+mod bloom;
 mod bound;
+mod compact;
+mod compressed;
 mod data;
+mod delta;
+mod durability;
+mod dict;
+mod dump;
 mod frag;
+mod gate;
+mod hot;
+mod index;
+mod integrity;
 mod iter;
+mod load;
+mod logged;
 mod materializer;
 mod node;
+mod policy;
+mod prefetch;
 mod prefix;
+mod stats;
+mod stream;
+mod subscription;
+mod transaction;
 mod tree;
+mod ttl;
+mod view;
 
+use self::bloom::Bloom;
+use self::batch::PlannedLeaf;
 use self::bound::Bound;
+use self::compressed::{ValueCodec, ValueCompression};
 use self::data::Data;
-use self::frag::{ChildSplit, ParentSplit};
-use self::node::Node;
+use self::dict::ValueDict;
+use self::frag::{ChildMerge, ChildSplit, ParentMerge, ParentSplit};
+use self::gate::WriteGate;
+use self::hot::RecentWrites;
+use self::index::{Index, Indexes, INDEX_CHUNK};
+use self::integrity::check_level;
+use self::load::{
+    leaf_separator, load_record, loaded_node, pack_index,
+};
+use self::node::{ApplyError, ApplyTimings, Change, Node, NodeError};
+#[cfg(test)]
+use self::node::leaf;
+use self::prefetch::Prefetcher;
 use self::prefix::{prefix_cmp, prefix_decode, prefix_encode};
+use self::subscription::Subscriptions;
+use self::ttl::{Expiries, Sweeper};
+use self::view::Views;
 
+pub(crate) use self::logged::{logged_writes, merged_right};
+pub(crate) use self::ttl::now_millis;
+pub(crate) use self::tree::{
+    first_root, quiesce_writes, start_pages, CATALOG_HEAD, IDS_HEAD,
+    NAMED_HEAD,
+};
+
+pub use self::batch::Batch;
+pub use self::dump::{Encoding, NodeDump, TreeDump};
+pub use self::durability::Durability;
 pub use self::frag::Frag;
-pub use self::iter::Iter;
+pub use self::index::IndexIter;
+pub use self::integrity::{IntegrityProblem, TreeIntegrity};
+pub use self::iter::{Iter, Keys, RevIter, Values};
 pub use self::materializer::BLinkMaterializer;
+pub use self::policy::SplitPolicy;
+pub use self::stats::{KeyBucket, TreeStats};
+pub use self::subscription::{Event, Subscriber};
+pub use self::transaction::TransactionalTree;
 pub use self::tree::Tree;
+pub use self::view::{TreeView, ViewIter};
+
+pub(crate) type TreeCache =
+    PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>;
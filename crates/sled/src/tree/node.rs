@@ -1,3 +1,9 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
 use super::*;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -5,43 +11,635 @@ pub struct Node {
     pub id: PageID,
     pub data: Data,
     pub next: Option<PageID>,
+    /// The left sibling, for stepping backwards between leaves. It
+    /// is updated after a split of the left sibling links the new
+    /// right node, so a reverse scan should check that the node it
+    /// finds ends at this node's `lo` before trusting it.
+    pub prev: Option<PageID>,
     pub lo: Bound,
     pub hi: Bound,
+    /// A longer prefix shared by every record, which records are
+    /// encoded against instead of `lo` when set. Frags are always
+    /// encoded against `lo`, so this can change during
+    /// consolidation without affecting writers.
+    pub prefix: Option<Key>,
+    /// A filter over the leaf's keys for short-circuiting lookups
+    /// of absent keys, kept when `leaf_bloom_filters` is set. It is
+    /// not serialized, and is rebuilt when the node is next
+    /// consolidated.
+    #[serde(skip)]
+    pub bloom: Option<Bloom>,
+    /// When the leaf's keys that were written with a TTL expire.
+    pub expiries: Expiries,
+    /// Where the leaf's latest writes went, which splits keep on
+    /// one side. It is not serialized.
+    #[serde(skip)]
+    pub recent: RecentWrites,
+}
+
+/// Reasons a `Node` may refuse a write before it is logged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeError {
+    /// Writing this decoded key would grow the leaf past the
+    /// configured `max_leaf_len`, so the leaf must be split before
+    /// the write is retried.
+    NodeFull(Key),
+    /// The decoded key is longer than the configured
+    /// `max_key_len`, and can never be written.
+    KeyTooLong { len: usize, max: usize },
+}
+
+impl std::fmt::Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            NodeError::NodeFull(ref key) => {
+                write!(f, "leaf is full, can't add key {:?}", key)
+            }
+            NodeError::KeyTooLong { len, max } => write!(
+                f,
+                "key of length {} exceeds max_key_len of {}",
+                len, max
+            ),
+        }
+    }
+}
+
+/// Reasons `Node::apply` could not apply a frag. These leave the
+/// node untouched, so the operation can be retried against a
+/// freshly read copy of the page.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApplyError {
+    /// A Set, Merge or Del decoded to a key at or above `hi`.
+    KeyOutOfBounds { key: Key, hi: Bound },
+    /// A Base or Blob frag showed up after the start of a chain.
+    BaseInChain,
+    /// A Merge was applied without a merge operator configured.
+    MissingMergeOperator,
+    /// A Set, Merge, Del or Migrate showed up in an index chain.
+    LeafFragInIndex,
+    /// A ParentSplit showed up in a leaf chain.
+    IndexFragInLeaf,
+    /// A Migrate refers to a migrator that wasn't registered with
+    /// `ConfigBuilder::migrator` in this run. Migrators aren't
+    /// persisted, so a database must be reopened with the same
+    /// migrators, registered in the same order.
+    UnknownMigrator(usize),
+    /// A ChildMerge's node doesn't start where this node ends, or
+    /// holds a different kind of `Data`.
+    NotMergeable { right: PageID },
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ApplyError::KeyOutOfBounds { ref key, ref hi } => write!(
+                f,
+                "key {:?} is not below the node's hi of {:?}",
+                key, hi
+            ),
+            ApplyError::BaseInChain => {
+                write!(f, "encountered base page in middle of chain")
+            }
+            ApplyError::MissingMergeOperator => {
+                write!(f, "must have a merge operator set")
+            }
+            ApplyError::LeafFragInIndex => {
+                write!(f, "encountered leaf frag in an index chain")
+            }
+            ApplyError::IndexFragInLeaf => {
+                write!(f, "encountered index frag in a leaf chain")
+            }
+            ApplyError::UnknownMigrator(id) => write!(
+                f,
+                "no migrator with id {} is registered, so a logged \
+                 migration can't be replayed",
+                id
+            ),
+            ApplyError::NotMergeable { right } => write!(
+                f,
+                "node {} can't be merged into its left sibling",
+                right
+            ),
+        }
+    }
+}
+
+/// Whether a node holds records or child pointers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeKind {
+    Leaf,
+    Index,
+}
+
+/// How full a node is, for monitoring tools that walk the tree and
+/// report histograms of page occupancy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeStats {
+    pub kind: NodeKind,
+    pub entry_count: usize,
+    /// `key_bytes + value_bytes`, the same as `Data::byte_len`.
+    pub byte_len: usize,
+    /// The bytes of the prefix-encoded keys.
+    pub key_bytes: usize,
+    /// The bytes of the values, or of the child ids for an index.
+    /// Interned values are counted once, plus a 4 byte reference
+    /// per record.
+    pub value_bytes: usize,
+    /// `entry_count` relative to the fanout the stats were taken
+    /// for, capped at 1.0 for a node that is due to split.
+    pub fill_ratio: f64,
+}
+
+/// A frag in a chain that could not be applied, which means the
+/// log was corrupted or replayed out of order. The node it was
+/// applied to may hold the frags before it, and should be thrown
+/// away.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainError {
+    /// The position of the frag in the chain.
+    pub idx: usize,
+    pub error: ApplyError,
+}
+
+/// A parent separator that disagrees with the child it points to,
+/// as found by `Node::check_separators`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeparatorError {
+    /// No lo bound was supplied for this child.
+    MissingChild(PageID),
+    /// The decoded separator for `child` is not its lo.
+    Mismatch {
+        child: PageID,
+        separator: Key,
+        lo: Bound,
+    },
+}
+
+/// The number of frags of one kind applied in a batch, and the
+/// total time spent applying them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FragTiming {
+    pub count: usize,
+    pub elapsed: Duration,
+}
+
+/// A per-kind breakdown of where `Node::apply_batch` spent its
+/// time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ApplyTimings {
+    pub set: FragTiming,
+    pub merge: FragTiming,
+    pub del: FragTiming,
+    pub split: FragTiming,
+    pub migrate: FragTiming,
+}
+
+impl ApplyTimings {
+    fn record(&mut self, frag: &Frag, elapsed: Duration) {
+        let timing = match *frag {
+            Frag::Set(..) | Frag::SetBlob(..) | Frag::Expire(..) => {
+                &mut self.set
+            }
+            Frag::Merge(..) => &mut self.merge,
+            Frag::Del(..) | Frag::DelRange(..) => &mut self.del,
+            Frag::ChildSplit(..)
+            | Frag::ParentSplit(..)
+            | Frag::ChildMerge(..)
+            | Frag::ParentMerge(..)
+            | Frag::SetPrev(..) => &mut self.split,
+            Frag::Migrate(..) => &mut self.migrate,
+            Frag::Writes(ref frags) => {
+                // spread the batch's time evenly over its frags
+                let share = elapsed / frags.len().max(1) as u32;
+                for frag in frags {
+                    self.record(frag, share);
+                }
+                return;
+            }
+            Frag::Base(..) | Frag::Blob(_) => return,
+        };
+        timing.count += 1;
+        timing.elapsed += elapsed;
+    }
+}
+
+/// How a later data frag resolved against an earlier one for the
+/// same key within one chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    /// A Set replaced what a Set or Merge wrote.
+    Overwrite,
+    /// A Merge was combined with what came before it.
+    Merge,
+    /// A Del removed what came before it.
+    Delete,
+    /// A Set brought back a key that a Del removed.
+    DeleteThenSet,
+}
+
+/// Two data frags in one chain that write the same key, given by
+/// their positions in the chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FragConflict {
+    pub key: Key,
+    pub earlier: usize,
+    pub later: usize,
+    pub resolution: Resolution,
 }
 
+/// The before and after image of a single decoded key that was
+/// touched by a data-mutating frag.
+pub type Change = (Key, Option<Value>, Option<Value>);
+
 impl Node {
+    /// Applies a single frag. Returns `true` if a
+    /// `splitting_merge_operator` asked for this node to be split
+    /// right away.
     pub fn apply(
         &mut self,
         frag: &Frag,
-        merge_operator: Option<usize>,
-    ) {
+        config: &Config,
+    ) -> Result<bool, ApplyError> {
+        self.apply_recording(frag, config, None)
+    }
+
+    /// Applies a single frag like `apply`. If `changes` is
+    /// provided, every key the frag writes is pushed to it along
+    /// with its previous and resulting value, as `apply_batch`
+    /// does.
+    pub fn apply_recording(
+        &mut self,
+        frag: &Frag,
+        config: &Config,
+        changes: Option<&mut Vec<Change>>,
+    ) -> Result<bool, ApplyError> {
+        let split_hint = self.apply_inner(frag, config, changes)?;
+        #[cfg(debug_assertions)]
+        self.data.assert_sorted_unique(self.prefix());
+        if config.leaf_bloom_filters {
+            self.refresh_bloom();
+        }
+        Ok(split_hint)
+    }
+
+    /// Applies a chain of frags in order. If `changes` is provided,
+    /// every Set, Merge and Del pushes the key along with its
+    /// previous and resulting value, giving change-data-capture
+    /// consumers the before-image of each write. If `timings` is
+    /// provided, the time spent on each kind of frag is added to
+    /// it, which helps pin down what dominates a slow
+    /// consolidation. Neither costs anything when `None`, and
+    /// consolidation only passes timings when `log_apply_timings`
+    /// is configured.
+    ///
+    /// Runs of Set and Del frags, including batches of nothing
+    /// else, are applied to a leaf of pairs by merging the last
+    /// write to each key into its records in a single pass, rather
+    /// than with a search and a shift of the records per frag, and
+    /// runs of Del frags over ascending keys are applied to other
+    /// leaves in a single pass too. Neither happens when `changes`
+    /// needs the before-image of each write. For the same reason,
+    /// Merges to a key that a later Set in the chain overwrites are
+    /// skipped without calling the merge operator.
+    ///
+    /// When `log_frag_conflicts` is configured, every pair of frags
+    /// that write the same key is logged at debug level, see
+    /// `frag_conflicts`. When `leaf_bloom_filters` is configured, a
+    /// leaf's filter is built or rebuilt afterwards if it is
+    /// missing or has degraded, see `refresh_bloom`.
+    ///
+    /// Returns the node's final `(lo, hi, next)`, which splits in
+    /// the chain may have changed, for the tree's structural
+    /// bookkeeping, or the first frag that could not be applied.
+    pub fn apply_batch(
+        &mut self,
+        frags: &[&Frag],
+        config: &Config,
+        mut changes: Option<&mut Vec<Change>>,
+        mut timings: Option<&mut ApplyTimings>,
+    ) -> Result<(Bound, Bound, Option<PageID>), ChainError> {
+        if config.log_frag_conflicts {
+            for conflict in frag_conflicts(frags, self.lo.inner()) {
+                debug!(
+                    "consolidating node {} resolved {:?}",
+                    self.id, conflict
+                );
+            }
+        }
+
+        let skip = if changes.is_none() {
+            overwritten_merges(frags)
+        } else {
+            vec![false; frags.len()]
+        };
+
+        let mut i = 0;
+        while i < frags.len() {
+            if skip[i] {
+                i += 1;
+                continue;
+            }
+
+            let run = if changes.is_none()
+                && self.data.records_mut().is_some()
+            {
+                write_run_len(&frags[i..])
+            } else {
+                0
+            };
+            if run > 1 {
+                let start = timings.as_ref().map(|_| Instant::now());
+                self.apply_write_run(&frags[i..i + run])
+                    .map_err(|mut e| {
+                        e.idx += i;
+                        e
+                    })?;
+                if let (Some(timings), Some(start)) =
+                    (timings.as_mut(), start)
+                {
+                    // spread the run's time evenly over its frags
+                    let share = start.elapsed() / run as u32;
+                    for frag in &frags[i..i + run] {
+                        timings.record(frag, share);
+                    }
+                }
+                i += run;
+                continue;
+            }
+
+            let run = if changes.is_none() {
+                del_run_len(&frags[i..])
+            } else {
+                0
+            };
+            if run > 1 {
+                let start = timings.as_ref().map(|_| Instant::now());
+                self.apply_del_run(&frags[i..i + run]).map_err(
+                    |mut e| {
+                        e.idx += i;
+                        e
+                    },
+                )?;
+                if let (Some(timings), Some(start)) =
+                    (timings.as_mut(), start)
+                {
+                    timings.del.count += run;
+                    timings.del.elapsed += start.elapsed();
+                }
+                i += run;
+                continue;
+            }
+
+            let frag = frags[i];
+            let changes = changes.as_mut().map(|c| &mut **c);
+            let start = timings.as_ref().map(|_| Instant::now());
+            if let Err(e) = self.apply_inner(frag, config, changes) {
+                return Err(ChainError { idx: i, error: e });
+            }
+            if let (Some(timings), Some(start)) =
+                (timings.as_mut(), start)
+            {
+                timings.record(frag, start.elapsed());
+            }
+            i += 1;
+        }
+
+        if config.leaf_bloom_filters {
+            self.refresh_bloom();
+        }
+
+        Ok((self.lo.clone(), self.hi.clone(), self.next))
+    }
+
+    fn apply_del_run(
+        &mut self,
+        dels: &[&Frag],
+    ) -> Result<(), ChainError> {
+        if self.data.is_index() {
+            return Err(ChainError {
+                idx: 0,
+                error: ApplyError::LeafFragInIndex,
+            });
+        }
+        let mut keys = Vec::with_capacity(dels.len());
+        for (idx, frag) in dels.iter().enumerate() {
+            let k = match **frag {
+                Frag::Del(ref k) => k,
+                _ => panic!("del run contained a non-Del frag"),
+            };
+            let decoded_k = prefix_decode(self.lo.inner(), k);
+            if Bound::Inclusive(decoded_k.clone()) >= self.hi {
+                return Err(ChainError {
+                    idx: idx,
+                    error: self.out_of_bounds(decoded_k),
+                });
+            }
+            keys.push(decoded_k);
+        }
+        let key_refs: Vec<KeyRef> =
+            keys.iter().map(|k| &**k).collect();
+        self.del_many(&key_refs);
+        Ok(())
+    }
+
+    // Applies a run of frags counted by `write_run_len` to a leaf of
+    // pairs. Only the last write to each key matters, so they're
+    // collected in key order and merged with the records into a
+    // new vector, and every other record is moved across as is.
+    fn apply_write_run(
+        &mut self,
+        frags: &[&Frag],
+    ) -> Result<(), ChainError> {
+        let mut writes: BTreeMap<Key, Option<Value>> =
+            BTreeMap::new();
+        for (idx, frag) in frags.iter().enumerate() {
+            let batched = match **frag {
+                Frag::Writes(ref batched) => batched.iter().collect(),
+                _ => vec![*frag],
+            };
+            for frag in batched {
+                let (k, v) = match *frag {
+                    Frag::Set(ref k, ref v) => {
+                        (k, Some(v.clone().unblobbed()))
+                    }
+                    // only a SetBlob's record refers to a blob
+                    Frag::SetBlob(ref k, ref v) => {
+                        (k, Some(v.clone()))
+                    }
+                    Frag::Del(ref k) => (k, None),
+                    _ => panic!("write run contained a {:?}", frag),
+                };
+                let decoded_k = prefix_decode(self.lo.inner(), k);
+                if Bound::Inclusive(decoded_k.clone()) >= self.hi {
+                    return Err(ChainError {
+                        idx: idx,
+                        error: self.out_of_bounds(decoded_k),
+                    });
+                }
+                writes.insert(decoded_k, v);
+            }
+        }
+
+        // a Set outside of a recomputed prefix moves the node back
+        // to lo, while a Del outside of it has nothing to remove
+        let outside = |prefix: &Option<Vec<u8>>, k: &[u8]| {
+            prefix.as_ref().map_or(false, |p| !k.starts_with(p))
+        };
+        let drops_prefix = writes
+            .iter()
+            .any(|(k, v)| v.is_some() && outside(&self.prefix, k));
+        if drops_prefix {
+            if let Some(prefix) = self.prefix.take() {
+                self.data.reencode(&prefix, self.lo.inner());
+            }
+        }
+        for (k, v) in &writes {
+            self.expiries.remove(k);
+            if v.is_some() {
+                if let Some(ref mut bloom) = self.bloom {
+                    bloom.insert(k);
+                }
+                self.note_decoded_write(k);
+            }
+        }
+
+        let long_prefix = self.prefix.clone();
+        let prefix = self.prefix().to_vec();
+        let mut removed = 0;
+        {
+            let records = self
+                .data
+                .records_mut()
+                .expect("write run applied to a leaf without pairs");
+            let mut old = std::mem::replace(
+                records,
+                Vec::with_capacity(records.len() + writes.len()),
+            )
+            .into_iter()
+            .peekable();
+            for (decoded_k, v) in writes {
+                if outside(&long_prefix, &decoded_k) {
+                    continue;
+                }
+                let k = prefix_encode(&prefix, &decoded_k);
+                while let Some(ord) = old
+                    .peek()
+                    .map(|&(ref old_k, _)| prefix_cmp(old_k, &k))
+                {
+                    match ord {
+                        Ordering::Less => {
+                            records.push(old.next().unwrap())
+                        }
+                        Ordering::Equal => {
+                            old.next();
+                            if v.is_none() {
+                                removed += 1;
+                            }
+                            break;
+                        }
+                        Ordering::Greater => break,
+                    }
+                }
+                if let Some(v) = v {
+                    records.push((k, v));
+                }
+            }
+            records.extend(old);
+        }
+        self.bloom_removed(removed);
+        Ok(())
+    }
+
+    pub(super) fn apply_inner(
+        &mut self,
+        frag: &Frag,
+        config: &Config,
+        changes: Option<&mut Vec<Change>>,
+    ) -> Result<bool, ApplyError> {
         use self::Frag::*;
 
+        let is_index = self.data.is_index();
+        match *frag {
+            Set(..) | SetBlob(..) | Merge(..) | Del(_)
+            | DelRange(..) | Migrate(..) | Expire(..)
+                if is_index =>
+            {
+                return Err(ApplyError::LeafFragInIndex)
+            }
+            ParentSplit(_) | ParentMerge(_) if !is_index => {
+                return Err(ApplyError::IndexFragInLeaf)
+            }
+            _ => {}
+        }
+
+        let mut split_hint = false;
         match *frag {
-            Set(ref k, ref v) => {
+            Set(ref k, ref v) | SetBlob(ref k, ref v) => {
                 let decoded_k = prefix_decode(self.lo.inner(), k);
-                if Bound::Inclusive(decoded_k) < self.hi {
-                    self.set_leaf(k.clone(), v.clone());
+                if Bound::Inclusive(decoded_k.clone()) < self.hi {
+                    // only a SetBlob's record refers to a blob, which
+                    // no other record may share
+                    let v = match *frag {
+                        SetBlob(..) => v.clone(),
+                        _ => v.clone().unblobbed(),
+                    };
+                    let k = self.encode_for_write(&decoded_k);
+                    let old = self.set_leaf(k.clone(), v);
+                    if let Some(changes) = changes {
+                        let new = self.leaf_value(&k).cloned();
+                        changes.push((decoded_k, old, new));
+                    }
                 } else {
-                    panic!("tried to consolidate set at key <= hi")
+                    return Err(self.out_of_bounds(decoded_k));
                 }
             }
             Merge(ref k, ref v) => {
                 let decoded_k = prefix_decode(self.lo.inner(), k);
-                if Bound::Inclusive(decoded_k) < self.hi {
-                    let merge_fn_ptr = merge_operator
-                        .expect("must have a merge operator set");
-                    unsafe {
-                        let merge_fn: MergeOperator =
-                            std::mem::transmute(merge_fn_ptr);
+                if Bound::Inclusive(decoded_k.clone()) < self.hi {
+                    let k = self.encode_for_write(&decoded_k);
+                    if self.is_expired(&decoded_k) {
+                        // merge into an expired key as if it were
+                        // already gone
+                        self.del_leaf(&k);
+                    }
+                    let old = if let Some(id) =
+                        config.splitting_merge_operator
+                    {
+                        let merge_fn = config
+                            .splitting_merge_operators
+                            .get(id)
+                            .ok_or(ApplyError::MissingMergeOperator)?;
+                        self.merge_leaf(
+                            k.clone(),
+                            v.clone().unblobbed(),
+                            |key, old, new| {
+                                let (merged, hint) =
+                                    merge_fn(key, old, new);
+                                split_hint = hint;
+                                merged
+                            },
+                            config.max_value_len,
+                        )
+                    } else {
+                        let merge_fn = config
+                            .merge_operator
+                            .and_then(|id| {
+                                config.merge_operators.get(id)
+                            })
+                            .ok_or(ApplyError::MissingMergeOperator)?;
                         self.merge_leaf(
                             k.clone(),
-                            v.clone(),
-                            merge_fn,
-                        );
+                            v.clone().unblobbed(),
+                            &*merge_fn,
+                            config.max_value_len,
+                        )
+                    };
+                    if let Some(changes) = changes {
+                        let new = self.leaf_value(&k).cloned();
+                        changes.push((decoded_k, old, new));
                     }
                 } else {
-                    panic!("tried to consolidate set at key <= hi")
+                    return Err(self.out_of_bounds(decoded_k));
                 }
             }
             ChildSplit(ref child_split) => {
@@ -50,119 +648,2874 @@ impl Node {
             ParentSplit(ref parent_split) => {
                 self.parent_split(parent_split);
             }
+            SetPrev(prev) => {
+                self.prev = Some(prev);
+            }
+            ChildMerge(ref child_merge) => {
+                let right = &child_merge.right;
+                *self = self.merge_with(right).ok_or(
+                    ApplyError::NotMergeable { right: right.id },
+                )?;
+            }
+            ParentMerge(ref parent_merge) => {
+                self.parent_merge(parent_merge);
+            }
+            Migrate(from, to, migrator_id) => {
+                let migrator = config
+                    .migrators
+                    .get(migrator_id)
+                    .ok_or(ApplyError::UnknownMigrator(migrator_id))?;
+                if let Some(changes) = changes {
+                    let before = self.data.leaf().unwrap_or(vec![]);
+                    self.migrate_leaf(from, to, &*migrator)?;
+                    let after = self.data.leaf().unwrap_or(vec![]);
+                    let pairs = before.into_iter().zip(after);
+                    for ((k, old), (_, new)) in pairs {
+                        if old != new {
+                            let decoded_k =
+                                prefix_decode(self.prefix(), &k);
+                            changes.push((
+                                decoded_k,
+                                Some(old),
+                                Some(new),
+                            ));
+                        }
+                    }
+                } else {
+                    self.migrate_leaf(from, to, &*migrator)?;
+                }
+            }
             Del(ref k) => {
                 let decoded_k = prefix_decode(self.lo.inner(), k);
-                if Bound::Inclusive(decoded_k) < self.hi {
-                    self.del_leaf(k);
+                if Bound::Inclusive(decoded_k.clone()) < self.hi {
+                    let k = prefix_encode(self.prefix(), &decoded_k);
+                    let old = self.del_leaf(&k);
+                    if let Some(changes) = changes {
+                        changes.push((decoded_k, old, None));
+                    }
+                } else {
+                    return Err(self.out_of_bounds(decoded_k));
+                }
+            }
+            DelRange(ref lo, ref hi) => {
+                if let Some(changes) = changes {
+                    for (k, v) in self.iter() {
+                        let decoded_k = Bound::Inclusive(k.clone());
+                        if decoded_k >= *lo && decoded_k < *hi {
+                            changes.push((k, Some(v.clone()), None));
+                        }
+                    }
+                }
+                self.del_range(lo, hi);
+            }
+            Writes(ref frags) => {
+                let mut changes = changes;
+                for frag in frags {
+                    let changes = changes.as_mut().map(|c| &mut **c);
+                    split_hint |=
+                        self.apply_inner(frag, config, changes)?;
+                }
+            }
+            Expire(ref k, at) => {
+                let decoded_k = prefix_decode(self.lo.inner(), k);
+                if Bound::Inclusive(decoded_k.clone()) < self.hi {
+                    self.expiries.set(decoded_k, at);
                 } else {
-                    panic!("tried to consolidate del at key <= hi")
+                    return Err(self.out_of_bounds(decoded_k));
                 }
             }
-            Base(_, _) => {
-                panic!("encountered base page in middle of chain")
+            Base(_, _) | Blob(_) => {
+                return Err(ApplyError::BaseInChain)
             }
         }
+        Ok(split_hint)
     }
 
-    pub fn set_leaf(&mut self, key: Key, val: Value) {
-        if let Data::Leaf(ref mut records) = self.data {
-            let search = records.binary_search_by(
-                |&(ref k, ref _v)| prefix_cmp(k, &*key),
-            );
-            if let Ok(idx) = search {
-                records.push((key, val));
-                records.swap_remove(idx);
-            } else {
-                records.push((key, val));
-                records.sort_unstable_by(|a, b| {
-                    prefix_cmp(&*a.0, &*b.0)
-                });
+    pub(super) fn out_of_bounds(&self, key: Key) -> ApplyError {
+        ApplyError::KeyOutOfBounds {
+            key: key,
+            hi: self.hi.clone(),
+        }
+    }
+
+    /// Describes how this node's records are encoded.
+    pub fn encoding(&self) -> Encoding {
+        let mut encoding = Encoding {
+            long_prefix: self.prefix.is_some(),
+            ..Encoding::default()
+        };
+        match self.data {
+            Data::IntLeaf(_) => encoding.int_keys = true,
+            Data::DictLeaf(_) => encoding.value_dict = true,
+            Data::LeafSet(_) => encoding.key_only = true,
+            Data::BlockLeaf(_) => encoding.value_block = true,
+            Data::CompressedLeaf(..) => {
+                encoding.compressed_values = true
+            }
+            Data::Index(_) | Data::Leaf(_) => {}
+        }
+        encoding
+    }
+
+    /// Captures the node's current id, bounds and data as a `Base`
+    /// frag, which can be written to start a new chain. The frag
+    /// does not mark the node as a replacement root.
+    pub fn to_base(&self) -> Frag {
+        Frag::Base(self.clone(), None)
+    }
+
+    /// The prefix that records are encoded against, which is `lo`
+    /// unless `recompute_prefix` found a longer one.
+    pub fn prefix(&self) -> &[u8] {
+        match self.prefix {
+            Some(ref prefix) => &*prefix,
+            None => self.lo.inner(),
+        }
+    }
+
+    /// Re-encodes the records of a leaf against the longest prefix
+    /// they all share, if that saves space over encoding them
+    /// against `lo`. This is run during consolidation, since a
+    /// node's actual keys often share much more than its `lo`
+    /// does. Returns the number of key bytes saved.
+    pub fn recompute_prefix(&mut self) -> usize {
+        if let Data::Index(_) = self.data {
+            return 0;
+        }
+        let decoded = self.data.keys(self.prefix());
+        let mut lcp: &[u8] = match decoded.first() {
+            Some(first) => first,
+            None => return 0,
+        };
+        for k in &decoded[1..] {
+            let common = lcp
+                .iter()
+                .zip(k.iter())
+                .take_while(|&(a, b)| a == b)
+                .count();
+            lcp = &lcp[..common];
+        }
+        // encoded keys can only elide up to 255 bytes of prefix
+        let lcp = &lcp[..std::cmp::min(lcp.len(), 255)];
+
+        let encoded_len = |prefix: &[u8]| -> usize {
+            decoded
+                .iter()
+                .map(|k| prefix_encode(prefix, k).len())
+                .sum()
+        };
+        let before = encoded_len(self.prefix());
+        let after = encoded_len(lcp);
+        if after >= before {
+            return 0;
+        }
+
+        let lcp = lcp.to_vec();
+        let old = self.prefix().to_vec();
+        self.data.reencode(&old, &lcp);
+        self.prefix = Some(lcp);
+        before - after
+    }
+
+    // Encodes a key that is about to be written. Records only stay
+    // encoded against a recomputed prefix while every key shares
+    // it, so a key that doesn't first moves the node back to `lo`.
+    fn encode_for_write(&mut self, decoded_k: &[u8]) -> Key {
+        let shares_prefix = decoded_k.starts_with(self.prefix());
+        if !shares_prefix {
+            if let Some(prefix) = self.prefix.take() {
+                self.data.reencode(&prefix, self.lo.inner());
             }
+        }
+        prefix_encode(self.prefix(), decoded_k)
+    }
+
+    /// Measures this node's occupancy against `fanout` entries, in
+    /// one pass over the records and without copying them.
+    pub fn stats(&self, fanout: usize) -> NodeStats {
+        let (key_bytes, value_bytes) = self.data.key_value_bytes();
+        let entry_count = self.data.len();
+        let fill_ratio = if fanout == 0 {
+            1.
         } else {
-            panic!("tried to Set a value to an index");
+            (entry_count as f64 / fanout as f64).min(1.)
+        };
+        NodeStats {
+            kind: match self.data {
+                Data::Index(_) => NodeKind::Index,
+                _ => NodeKind::Leaf,
+            },
+            entry_count: entry_count,
+            byte_len: key_bytes + value_bytes,
+            key_bytes: key_bytes,
+            value_bytes: value_bytes,
+            fill_ratio: fill_ratio,
+        }
+    }
+
+    /// Verifies that the bounds are ordered, that records are
+    /// sorted and unique, and that every record falls within
+    /// `[lo, hi)`.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.lo == Bound::Inf {
+            return Err("lo is Inf".to_owned());
+        }
+        if self.lo >= self.hi {
+            return Err(format!(
+                "lo {:?} is not below hi {:?}",
+                self.lo, self.hi
+            ));
+        }
+
+        let keys: Vec<&Key> = match self.data {
+            Data::Index(ref ptrs) => {
+                ptrs.iter().map(|&(ref k, _)| k).collect()
+            }
+            Data::Leaf(ref items)
+            | Data::IntLeaf(ref items)
+            | Data::BlockLeaf(ref items)
+            | Data::CompressedLeaf(_, ref items) => {
+                items.iter().map(|&(ref k, _)| k).collect()
+            }
+            Data::DictLeaf(ref dict) => {
+                let distinct = dict.distinct_values() as u32;
+                if let Some(&(_, idx)) =
+                    dict.records().iter().find(|r| r.1 >= distinct)
+                {
+                    return Err(format!(
+                        "dictionary index {} out of {} values",
+                        idx, distinct
+                    ));
+                }
+                dict.records().iter().map(|&(ref k, _)| k).collect()
+            }
+            Data::LeafSet(ref keys) => {
+                keys.iter().map(|&(ref k, _)| k).collect()
+            }
+        };
+
+        for pair in keys.windows(2) {
+            if prefix_cmp(pair[0], pair[1]) != Ordering::Less {
+                return Err(format!(
+                    "records {:?} and {:?} are out of order",
+                    pair[0], pair[1]
+                ));
+            }
+        }
+
+        for k in keys {
+            let decoded = prefix_decode(self.prefix(), k);
+            let bound = Bound::Inclusive(decoded);
+            if bound < self.lo || bound >= self.hi {
+                return Err(format!(
+                    "record {:?} is outside of [{:?}, {:?})",
+                    bound.inner(),
+                    self.lo,
+                    self.hi
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collapses records that share a key, keeping the one written
+    /// last, and returns how many were dropped. Writes never
+    /// produce duplicates, so consolidation calls this only to
+    /// recover from a damaged or misapplied chain.
+    pub fn dedup(&mut self) -> usize {
+        self.data.dedup()
+    }
+
+    /// Drops every record or separator whose decoded key falls
+    /// outside of `[lo, hi)`, which only a partially applied split
+    /// can leave behind. Keys too damaged to decode are dropped as
+    /// well, and duplicate keys are collapsed as by `dedup`, which
+    /// also restores sorted order. Returns how many entries were
+    /// discarded, so that consolidation can log it. The keys of a
+    /// node that is intact are only compared, not decoded.
+    pub fn clamp(&mut self) -> usize {
+        let before = self.data.len();
+        // nothing can be owned by a node that starts at Inf
+        let (prefix, owns_any) = match self.lo {
+            Bound::Inf => (vec![], false),
+            _ => (self.prefix().to_vec(), true),
+        };
+        let keep = |k: &Key| {
+            owns_any
+                && !k.is_empty()
+                && k[0] as usize <= prefix.len()
+        };
+        let damaged = (0..before)
+            .filter_map(|idx| self.data.key_at(idx))
+            .any(|k| !keep(k));
+        if damaged {
+            match self.data {
+                Data::Index(ref mut ptrs) => {
+                    ptrs.retain(|&(ref k, _)| keep(k))
+                }
+                Data::Leaf(ref mut records)
+                | Data::IntLeaf(ref mut records)
+                | Data::BlockLeaf(ref mut records)
+                | Data::CompressedLeaf(_, ref mut records) => {
+                    records.retain(|&(ref k, _)| keep(k))
+                }
+                Data::DictLeaf(ref mut dict) => {
+                    dict.retain(|&(ref k, _)| keep(k))
+                }
+                Data::LeafSet(ref mut keys) => {
+                    keys.retain(|&(ref k, _)| keep(k))
+                }
+            }
+        }
+
+        if owns_any {
+            let lo = self.lo.inner();
+            let hi = match self.hi {
+                Bound::Inf => None,
+                ref hi => Some(hi.inner()),
+            };
+            let outside = |k: &Key| {
+                let decoded =
+                    || prefix[..k[0] as usize].iter().chain(&k[1..]);
+                decoded().lt(lo.iter())
+                    || hi.map_or(false, |hi| decoded().ge(hi.iter()))
+            };
+            let any_outside = (0..self.data.len())
+                .filter_map(|idx| self.data.key_at(idx))
+                .any(outside);
+            if any_outside {
+                self.data.drop_lt(&self.lo, &prefix);
+                if hi.is_some() {
+                    self.data.drop_gte(&self.hi, &prefix);
+                }
+            }
+        }
+        self.dedup();
+
+        let removed = before - self.data.len();
+        self.bloom_removed(removed);
+        removed
+    }
+
+    /// Returns the value stored for a prefix-encoded key, if this
+    /// is a leaf that contains it.
+    pub fn leaf_value(&self, key: KeyRef) -> Option<&Value> {
+        if !self.may_contain(key) || self.is_expired_encoded(key) {
+            return None;
+        }
+        self.data.leaf_get(key)
+    }
+
+    /// Returns whether a prefix-encoded key is present, without
+    /// copying its value. For an index, this checks for a separator
+    /// equal to the key. A key outside of `[lo, hi)` is reported as
+    /// missing, and belongs to a sibling.
+    pub fn contains_key(&self, key: KeyRef) -> bool {
+        self.would_own(&prefix_decode(self.prefix(), key))
+            && self.may_contain(key)
+            && self.data.contains_key(key)
+            && !self.is_expired_encoded(key)
+    }
+
+    /// Returns whether a decoded key falls in `[lo, hi)`, and so
+    /// belongs in this node rather than a sibling. After a split,
+    /// exactly one of the two halves owns each key.
+    pub fn would_own(&self, key: KeyRef) -> bool {
+        let key = Bound::Inclusive(key.to_vec());
+        key >= self.lo && key < self.hi
+    }
+
+    /// Iterates over a leaf's records in order, with keys decoded
+    /// and values borrowed, skipping expired ones. Yields nothing
+    /// for an index, see `iter_index`.
+    pub fn iter<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (Key, &'a Value)> + 'a {
+        let prefix = self.prefix();
+        self.data
+            .leaf_iter()
+            .map(move |(k, v)| (prefix_decode(prefix, k), v))
+            .filter(move |&(ref k, _)| !self.is_expired(k))
+    }
+
+    /// Iterates over an index's separators in order, decoded and
+    /// paired with the child they point to. Yields nothing for a
+    /// leaf.
+    pub fn iter_index<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (Key, PageID)> + 'a {
+        let prefix = self.prefix();
+        let ptrs: &'a [(Key, PageID)] = match self.data {
+            Data::Index(ref ptrs) => ptrs,
+            _ => &[],
+        };
+        ptrs.iter()
+            .map(move |&(ref k, id)| (prefix_decode(prefix, k), id))
+    }
+
+    /// Returns the record with the smallest decoded key strictly
+    /// greater than `key`, a decoded key. `None` means the answer,
+    /// if any, is in a later node, reached through `next`. Index
+    /// nodes have no records, so they always return `None`.
+    pub fn successor(&self, key: KeyRef) -> Option<(Key, &Value)> {
+        let idx = match self.data.leaf_search(self.prefix(), key) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        };
+        self.live_record_from(idx)
+    }
+
+    /// Returns the record with the largest decoded key strictly
+    /// less than `key`, a decoded key. `None` means the answer, if
+    /// any, is in an earlier node, reached through `prev`.
+    pub fn predecessor(&self, key: KeyRef) -> Option<(Key, &Value)> {
+        let idx = match self.data.leaf_search(self.prefix(), key) {
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.live_record_before(idx)
+    }
+
+    /// Returns the record with the largest decoded key within `hi`,
+    /// an upper bound on decoded keys, for scanning backwards.
+    pub fn last_within(&self, hi: &Bound) -> Option<(Key, &Value)> {
+        let prefix = self.prefix();
+        let idx = match *hi {
+            Bound::Inclusive(ref key) => {
+                match self.data.leaf_search(prefix, key) {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                }
+            }
+            Bound::Exclusive(ref key) => {
+                match self.data.leaf_search(prefix, key) {
+                    Ok(idx) | Err(idx) => idx,
+                }
+            }
+            Bound::Inf => self.data.len(),
+        };
+        self.live_record_before(idx)
+    }
+
+    fn decoded_record(&self, idx: usize) -> Option<(Key, &Value)> {
+        self.data
+            .leaf_at(idx)
+            .map(|(k, v)| (prefix_decode(self.prefix(), k), v))
+    }
+
+    // The first unexpired record at or after `idx`.
+    fn live_record_from(
+        &self,
+        mut idx: usize,
+    ) -> Option<(Key, &Value)> {
+        loop {
+            let (k, v) = self.decoded_record(idx)?;
+            if !self.is_expired(&k) {
+                return Some((k, v));
+            }
+            idx += 1;
+        }
+    }
+
+    // The last unexpired record before `end`.
+    fn live_record_before(
+        &self,
+        mut end: usize,
+    ) -> Option<(Key, &Value)> {
+        while end > 0 {
+            end -= 1;
+            let (k, v) = self.decoded_record(end)?;
+            if !self.is_expired(&k) {
+                return Some((k, v));
+            }
+        }
+        None
+    }
+
+    /// Checks that every separator in this index node decodes to
+    /// exactly the lo of the child it points to, given the lo of
+    /// each child as `(child id, lo)` pairs. Leaves have no
+    /// separators, so they always pass.
+    pub fn check_separators(
+        &self,
+        child_los: &[(PageID, Bound)],
+    ) -> Result<(), SeparatorError> {
+        let ptrs = match self.data {
+            Data::Index(ref ptrs) => ptrs,
+            _ => return Ok(()),
+        };
+        for &(ref sep, child) in ptrs {
+            let lo = child_los
+                .iter()
+                .find(|&&(pid, _)| pid == child)
+                .map(|&(_, ref lo)| lo)
+                .ok_or(SeparatorError::MissingChild(child))?;
+            let separator = prefix_decode(self.prefix(), sep);
+            if *lo != Bound::Inclusive(separator.clone()) {
+                return Err(SeparatorError::Mismatch {
+                    child: child,
+                    separator: separator,
+                    lo: lo.clone(),
+                });
+            }
         }
+        Ok(())
     }
 
-    pub fn merge_leaf(
+    /// Sets a prefix-encoded key, returning the previous value.
+    pub fn set_leaf(
         &mut self,
         key: Key,
         val: Value,
-        merge_fn: MergeOperator,
-    ) {
-        if let Data::Leaf(ref mut records) = self.data {
-            let search = records.binary_search_by(
-                |&(ref k, ref _v)| prefix_cmp(k, &*key),
-            );
+    ) -> Option<Value> {
+        self.bloom_insert(&key);
+        self.note_write(&key);
+        self.forget_expiry(&key);
+        match self.data {
+            Data::Leaf(ref mut records)
+            | Data::IntLeaf(ref mut records)
+            | Data::BlockLeaf(ref mut records)
+            | Data::CompressedLeaf(_, ref mut records) => {
+                let search = records.binary_search_by(
+                    |&(ref k, ref _v)| prefix_cmp(k, &*key),
+                );
+                match search {
+                    Ok(idx) => {
+                        records.push((key, val));
+                        Some(records.swap_remove(idx).1)
+                    }
+                    Err(idx) => {
+                        records.insert(idx, (key, val));
+                        None
+                    }
+                }
+            }
+            Data::DictLeaf(ref mut dict) => dict.set(key, val),
+            Data::LeafSet(ref mut keys) => {
+                let search = keys.binary_search_by(|&(ref k, _)| {
+                    prefix_cmp(k, &*key)
+                });
+                match search {
+                    Ok(_) => Some(Value::default()),
+                    Err(idx) => {
+                        keys.insert(idx, (key, ()));
+                        None
+                    }
+                }
+            }
+            Data::Index(_) => {
+                panic!("tried to Set a value to an index")
+            }
+        }
+    }
 
-            let decoded_k = prefix_decode(self.lo.inner(), &key);
-            if let Ok(idx) = search {
-                let new = merge_fn(
-                    &*decoded_k,
-                    Some(&records[idx].1),
-                    &val,
+    /// Merges into a prefix-encoded key, returning the previous
+    /// value.
+    pub fn merge_leaf<F>(
+        &mut self,
+        key: Key,
+        val: Value,
+        mut merge_fn: F,
+        max_value_len: Option<usize>,
+    ) -> Option<Value>
+    where
+        F: FnMut(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>>,
+    {
+        let decoded_k = prefix_decode(self.prefix(), &key);
+        // a merge that ends up deleting the key only costs the
+        // filter a false positive
+        self.bloom_insert(&key);
+        self.note_write(&key);
+        self.forget_expiry(&key);
+        match self.data {
+            Data::Leaf(ref mut records)
+            | Data::IntLeaf(ref mut records)
+            | Data::BlockLeaf(ref mut records)
+            | Data::CompressedLeaf(_, ref mut records) => {
+                let search = records.binary_search_by(
+                    |&(ref k, ref _v)| prefix_cmp(k, &*key),
                 );
-                if let Some(new) = new {
-                    records.push((key, new));
-                    records.swap_remove(idx);
+
+                match search {
+                    Ok(idx) => {
+                        let new = merge_fn(
+                            &*decoded_k,
+                            Some(&records[idx].1),
+                            &val,
+                        ).map(Value::from);
+                        if let Some(mut new) = new {
+                            truncate_value(&mut new, max_value_len);
+                            records.push((key, new));
+                            Some(records.swap_remove(idx).1)
+                        } else {
+                            Some(records.remove(idx).1)
+                        }
+                    }
+                    Err(idx) => {
+                        let new = merge_fn(&*decoded_k, None, &val)
+                            .map(Value::from);
+                        if let Some(mut new) = new {
+                            truncate_value(&mut new, max_value_len);
+                            records.insert(idx, (key, new));
+                        }
+                        None
+                    }
+                }
+            }
+            Data::DictLeaf(ref mut dict) => {
+                let old = dict.get(&key).map(|v| &**v);
+                let new =
+                    merge_fn(&*decoded_k, old, &val).map(Value::from);
+                if let Some(mut new) = new {
+                    truncate_value(&mut new, max_value_len);
+                    dict.set(key, new)
                 } else {
-                    records.remove(idx);
+                    dict.del(&key)
                 }
-            } else {
-                let new = merge_fn(&*decoded_k, None, &val);
-                if let Some(new) = new {
-                    records.push((key, new));
-                    records.sort_unstable_by(|a, b| {
-                        prefix_cmp(&*a.0, &*b.0)
-                    });
+            }
+            Data::LeafSet(ref mut keys) => {
+                let search = keys.binary_search_by(|&(ref k, _)| {
+                    prefix_cmp(k, &*key)
+                });
+                let old: Option<&[u8]> = search.ok().map(|_| &[][..]);
+                match (search, merge_fn(&*decoded_k, old, &val)) {
+                    (Ok(_), Some(_)) => Some(Value::default()),
+                    (Ok(idx), None) => {
+                        keys.remove(idx);
+                        Some(Value::default())
+                    }
+                    (Err(idx), Some(_)) => {
+                        keys.insert(idx, (key, ()));
+                        None
+                    }
+                    (Err(_), None) => None,
                 }
             }
-        } else {
-            panic!("tried to Merge a value to an index");
+            Data::Index(_) => {
+                panic!("tried to Merge a value to an index")
+            }
         }
     }
 
-    pub fn child_split(&mut self, cs: &ChildSplit) {
-        self.data.drop_gte(&cs.at, self.lo.inner());
+    /// Narrows this node to end at the split point, returning the
+    /// records that moved out of range. They belong to the new
+    /// right sibling and are encoded against its lo, so they can be
+    /// installed there as they are.
+    pub fn child_split(&mut self, cs: &ChildSplit) -> Data {
+        let prefix = self.prefix().to_vec();
+        let mut moved = self.data.drop_gte(&cs.at, &prefix);
+        self.bloom_removed(moved.len());
+        self.expiries.split_off(cs.at.inner());
+        moved.reencode(&prefix, cs.at.inner());
         self.hi = Bound::Exclusive(cs.at.inner().to_vec());
         self.next = Some(cs.to);
+        moved
     }
 
     pub fn parent_split(&mut self, ps: &ParentSplit) {
+        let encoded_sep = prefix_encode(self.prefix(), ps.at.inner());
         if let Data::Index(ref mut ptrs) = self.data {
-            let encoded_sep =
-                prefix_encode(self.lo.inner(), ps.at.inner());
-            ptrs.push((encoded_sep, ps.to));
-            ptrs.sort_unstable_by(|a, b| prefix_cmp(&*a.0, &*b.0));
+            let search = ptrs.binary_search_by(|&(ref k, _)| {
+                prefix_cmp(k, &encoded_sep)
+            });
+            let idx = match search {
+                Ok(idx) | Err(idx) => idx,
+            };
+            ptrs.insert(idx, (encoded_sep, ps.to));
         } else {
             panic!("tried to attach a ParentSplit to a Leaf chain");
         }
     }
 
-    pub fn del_leaf(&mut self, key: KeyRef) {
-        if let Data::Leaf(ref mut records) = self.data {
-            let search = records.binary_search_by(
-                |&(ref k, ref _v)| prefix_cmp(k, &*key),
-            );
-            if let Ok(idx) = search {
-                records.remove(idx);
-            }
+    pub fn parent_merge(&mut self, pm: &ParentMerge) {
+        if let Data::Index(ref mut ptrs) = self.data {
+            ptrs.retain(|&(_, child)| child != pm.to);
         } else {
-            panic!("tried to attach a Del to an Index chain");
+            panic!("tried to attach a ParentMerge to a Leaf chain");
+        }
+    }
+
+    /// Rewrites every value whose leading schema version byte is
+    /// `from` with `migrator`, stamping the result with `to`.
+    /// Values at any other version, including `to`, are left alone.
+    /// Returns how many records were migrated.
+    pub fn migrate_leaf(
+        &mut self,
+        from: u8,
+        to: u8,
+        migrator: &MigrateFn,
+    ) -> Result<usize, ApplyError> {
+        let migrate = |v: &mut Value| {
+            if v.first() != Some(&from) {
+                return false;
+            }
+            let mut migrated = vec![to];
+            migrated.extend_from_slice(&migrator(&v[1..]));
+            *v = Value::from(migrated);
+            true
+        };
+
+        match self.data {
+            Data::Leaf(ref mut records)
+            | Data::IntLeaf(ref mut records)
+            | Data::BlockLeaf(ref mut records)
+            | Data::CompressedLeaf(_, ref mut records) => {
+                let mut count = 0;
+                for record in records {
+                    if migrate(&mut record.1) {
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            }
+            Data::DictLeaf(ref mut dict) => {
+                Ok(dict.migrate_values(migrate))
+            }
+            Data::LeafSet(_) => Ok(0),
+            Data::Index(_) => Err(ApplyError::LeafFragInIndex),
+        }
+    }
+
+    /// Removes a prefix-encoded key, returning the previous value.
+    pub fn del_leaf(&mut self, key: KeyRef) -> Option<Value> {
+        self.forget_expiry(key);
+        let old = match self.data {
+            Data::Leaf(ref mut records)
+            | Data::IntLeaf(ref mut records)
+            | Data::BlockLeaf(ref mut records)
+            | Data::CompressedLeaf(_, ref mut records) => {
+                let search = records.binary_search_by(
+                    |&(ref k, ref _v)| prefix_cmp(k, &*key),
+                );
+                search.ok().map(|idx| records.remove(idx).1)
+            }
+            Data::DictLeaf(ref mut dict) => dict.del(key),
+            Data::LeafSet(ref mut keys) => {
+                let search = keys.binary_search_by(|&(ref k, _)| {
+                    prefix_cmp(k, key)
+                });
+                search.ok().map(|idx| {
+                    keys.remove(idx);
+                    Value::default()
+                })
+            }
+            Data::Index(_) => {
+                panic!("tried to attach a Del to an Index chain")
+            }
+        };
+        if old.is_some() {
+            self.bloom_removed(1);
+        }
+        old
+    }
+
+    /// Removes every key in `sorted_keys` (decoded, ascending) in a
+    /// single linear pass over the leaf, returning how many were
+    /// present. Keys outside of `[lo, hi)` are skipped.
+    pub fn del_many(&mut self, sorted_keys: &[KeyRef]) -> usize {
+        debug_assert!(
+            sorted_keys.windows(2).all(|w| w[0] <= w[1]),
+            "del_many requires sorted keys"
+        );
+        for key in sorted_keys {
+            self.expiries.remove(key);
+        }
+
+        let encoded: Vec<Key> = sorted_keys
+            .iter()
+            .filter(|k| {
+                **k >= self.lo.inner()
+                    && Bound::Inclusive(k.to_vec()) < self.hi
+                    && self
+                        .prefix
+                        .as_ref()
+                        .map_or(true, |p| k.starts_with(p))
+            })
+            .map(|k| prefix_encode(self.prefix(), k))
+            .collect();
+
+        let mut removed = 0;
+        {
+            let mut dels = encoded.iter().peekable();
+            let mut keep = |k: &[u8]| {
+                while let Some(d) = dels.peek().cloned() {
+                    match prefix_cmp(d, k) {
+                        Ordering::Less => {
+                            dels.next();
+                        }
+                        Ordering::Equal => {
+                            removed += 1;
+                            return false;
+                        }
+                        Ordering::Greater => break,
+                    }
+                }
+                true
+            };
+
+            match self.data {
+                Data::Leaf(ref mut records)
+                | Data::IntLeaf(ref mut records)
+                | Data::BlockLeaf(ref mut records)
+                | Data::CompressedLeaf(_, ref mut records) => {
+                    records.retain(|&(ref k, _)| keep(k))
+                }
+                Data::DictLeaf(ref mut dict) => {
+                    dict.retain(|&(ref k, _)| keep(k))
+                }
+                Data::LeafSet(ref mut keys) => {
+                    keys.retain(|&(ref k, _)| keep(k))
+                }
+                Data::Index(_) => {
+                    panic!("tried to delete keys from an index")
+                }
+            }
+        }
+        self.bloom_removed(removed);
+        removed
+    }
+
+    /// Removes every record whose decoded key falls in `[lo, hi)`
+    /// in a single pass, returning how many were removed. The
+    /// range may extend past this node's own bounds. Index nodes
+    /// are left alone.
+    pub fn del_range(&mut self, lo: &Bound, hi: &Bound) -> usize {
+        self.expiries.remove_range(lo, hi);
+        let removed = self.del_range_inner(lo, hi);
+        self.bloom_removed(removed);
+        removed
+    }
+
+    fn del_range_inner(&mut self, lo: &Bound, hi: &Bound) -> usize {
+        // borrowed field by field, since `data` is borrowed mutably
+        let prefix = match self.prefix {
+            Some(ref prefix) => &**prefix,
+            None => self.lo.inner(),
+        };
+        match self.data {
+            Data::Leaf(ref mut records)
+            | Data::IntLeaf(ref mut records)
+            | Data::BlockLeaf(ref mut records)
+            | Data::CompressedLeaf(_, ref mut records) => {
+                let span = range_span(records, prefix, lo, hi);
+                records.drain(span).count()
+            }
+            Data::DictLeaf(ref mut dict) => {
+                let span = range_span(dict.records(), prefix, lo, hi);
+                let mut idx = 0;
+                dict.retain(|_| {
+                    idx += 1;
+                    !(span.start < idx && idx <= span.end)
+                });
+                span.len()
+            }
+            Data::LeafSet(ref mut keys) => {
+                let span = range_span(keys, prefix, lo, hi);
+                keys.drain(span).count()
+            }
+            Data::Index(_) => 0,
+        }
+    }
+
+    /// Walks the key sequence generated by `step`, beginning at
+    /// `start`, and returns the first key that is not present in
+    /// this node. This supports dense id allocation where used ids
+    /// are stored as keys. If every key up to `hi` is taken, returns
+    /// the first key of the sequence at or past `hi` as an error,
+    /// which the caller continues from in the next node.
+    pub fn first_gap<F>(
+        &self,
+        start: &[u8],
+        step: F,
+    ) -> Result<Key, Key>
+    where
+        F: Fn(&[u8]) -> Key,
+    {
+        debug_assert!(
+            start >= self.lo.inner(),
+            "first_gap must start within the node's bounds"
+        );
+
+        let mut candidate = start.to_vec();
+        while Bound::Inclusive(candidate.clone()) < self.hi {
+            let encoded = prefix_encode(self.prefix(), &*candidate);
+            if self.leaf_value(&*encoded).is_none() {
+                return Ok(candidate);
+            }
+            let next = step(&*candidate);
+            debug_assert!(next > candidate, "step must increase");
+            candidate = next;
+        }
+        Err(candidate)
+    }
+
+    /// Refuses a Set or Merge whose full key is longer than
+    /// `max_key_len`, or that would add a new key to a leaf that
+    /// already holds `max_leaf_len` records. Unlike
+    /// `should_split`, which is checked after a write lands, this
+    /// runs before a frag is linked so the leaf never transiently
+    /// exceeds the cap.
+    pub fn check_capacity(
+        &self,
+        frag: &Frag,
+        config: &Config,
+    ) -> Result<(), NodeError> {
+        match *frag {
+            Frag::Set(ref k, _)
+            | Frag::SetBlob(ref k, _)
+            | Frag::Merge(ref k, _) => {
+                let decoded_k = prefix_decode(self.lo.inner(), k);
+                if let Some(max) = config.max_key_len {
+                    if decoded_k.len() > max {
+                        return Err(NodeError::KeyTooLong {
+                            len: decoded_k.len(),
+                            max: max,
+                        });
+                    }
+                }
+
+                let cap = match config.max_leaf_len {
+                    Some(cap) => cap,
+                    None => return Ok(()),
+                };
+                let stored_k =
+                    prefix_encode(self.prefix(), &decoded_k);
+                let is_new = self.leaf_value(&stored_k).is_none();
+                if self.data.len() >= cap && is_new {
+                    Err(NodeError::NodeFull(decoded_k))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
         }
     }
 
-    pub fn should_split(&self, fanout: u8) -> bool {
-        self.data.len() > fanout as usize
+    /// Estimates how many distinct values this leaf holds using a
+    /// small HyperLogLog sketch, for judging whether value
+    /// interning would pay off. The sketch hashes with fixed keys,
+    /// so repeated calls on the same node agree. Interned leaves
+    /// already know their exact count, and index nodes hold no
+    /// values at all.
+    pub fn distinct_value_estimate(&self) -> usize {
+        match self.data {
+            Data::Leaf(ref records)
+            | Data::IntLeaf(ref records)
+            | Data::BlockLeaf(ref records)
+            | Data::CompressedLeaf(_, ref records) => {
+                let mut sketch = [0u8; HLL_REGISTERS];
+                for &(_, ref v) in records {
+                    let mut hasher = DefaultHasher::new();
+                    v.hash(&mut hasher);
+                    let hash = hasher.finish();
+
+                    let register =
+                        (hash as usize) & (HLL_REGISTERS - 1);
+                    let rest = hash >> HLL_BITS;
+                    let rank = (rest.leading_zeros() as u8 + 1)
+                        - HLL_BITS as u8;
+                    if rank > sketch[register] {
+                        sketch[register] = rank;
+                    }
+                }
+                hll_estimate(&sketch)
+            }
+            Data::DictLeaf(ref dict) => dict.distinct_values(),
+            Data::LeafSet(ref keys) => {
+                if keys.is_empty() {
+                    0
+                } else {
+                    1
+                }
+            }
+            Data::Index(_) => 0,
+        }
+    }
+
+    /// Whether this node has more entries than `blink_fanout`, is
+    /// a leaf at `max_leaf_len`, or takes up more bytes than
+    /// `max_node_size`.
+    pub fn should_split(&self, config: &Config) -> bool {
+        let policy = SplitPolicy::from_config(config);
+        self.should_split_with(&policy, config)
+    }
+
+    /// Like `should_split`, with the fanout and `max_node_size` of
+    /// `policy` in place of those of `config`.
+    pub fn should_split_with(
+        &self,
+        policy: &SplitPolicy,
+        config: &Config,
+    ) -> bool {
+        let len = self.data.len();
+        let at_cap = match self.data {
+            Data::Index(_) => false,
+            _ => config.max_leaf_len.map_or(false, |cap| len >= cap),
+        };
+        let too_big = policy
+            .max_node_size
+            .map_or(false, |max| self.should_split_bytes(max));
+        len > policy.fanout as usize || at_cap || too_big
+    }
+
+    /// Whether this node has fewer entries than `merge_threshold`,
+    /// and should be merged with a sibling.
+    pub fn should_merge(&self, config: &Config) -> bool {
+        config
+            .merge_threshold
+            .map_or(false, |min| self.data.len() < min)
     }
 
-    pub fn split(&self, id: PageID) -> Node {
-        let (split, right_data) = self.data.split(self.lo.inner());
+    /// Splits off a right side with id `id` the way `config` asks
+    /// for, by bytes if this node is over `max_node_size`, and at
+    /// `split_ratio` of its records otherwise.
+    pub fn split_for(&self, id: PageID, config: &Config) -> Node {
+        let policy = SplitPolicy::from_config(config);
+        self.split_with(id, &policy, config)
+    }
+
+    /// Like `split_for`, with the `max_node_size` of `policy` in
+    /// place of that of `config`.
+    pub fn split_with(
+        &self,
+        id: PageID,
+        policy: &SplitPolicy,
+        config: &Config,
+    ) -> Node {
+        match policy.max_node_size {
+            Some(max) if self.should_split_bytes(max) => {
+                self.split_by_bytes(id)
+            }
+            _ => self.split(id, config.split_ratio),
+        }
+    }
+
+    /// Whether this node's records take up more than `max_bytes`,
+    /// as measured by `Data::byte_len`. This suits trees whose
+    /// values vary wildly in size better than `should_split`, and
+    /// pairs with `split_by_bytes`. A node with a single record
+    /// can't be split, so it never needs to be.
+    pub fn should_split_bytes(&self, max_bytes: usize) -> bool {
+        self.data.len() > 1 && self.data.byte_len() > max_bytes
+    }
+
+    /// Splits off a right side with id `id`, holding the records
+    /// past `ratio` of the way through this node, unless that would
+    /// cut through the `hot_range` of its latest writes, see
+    /// `Data::split_biased`.
+    pub fn split(&self, id: PageID, ratio: f32) -> Node {
+        let split = match self.hot_range() {
+            Some(hot) => {
+                self.data.split_biased(self.prefix(), ratio, Some(hot))
+            }
+            None => self.data.split(self.prefix(), ratio),
+        };
+        self.split_to(id, split)
+    }
+
+    /// Splits like `split`, balancing the two halves by bytes
+    /// rather than by record count.
+    pub fn split_by_bytes(&self, id: PageID) -> Node {
+        let split = self.data.split_by_bytes(self.prefix());
+        self.split_to(id, split)
+    }
+
+    /// Combines this node with its right sibling, undoing a split
+    /// so that underfull neighbors can share a page again. Both
+    /// sides are re-encoded against this node's `lo`. Returns
+    /// `None` if `right` doesn't start where this node ends, or
+    /// holds a different kind of `Data`.
+    pub fn merge_with(&self, right: &Node) -> Option<Node> {
+        let adjacent = match self.hi {
+            Bound::Inf => false,
+            ref hi => hi.inner() == right.lo.inner(),
+        };
+        let same_kind = std::mem::discriminant(&self.data)
+            == std::mem::discriminant(&right.data);
+        if !adjacent || !same_kind {
+            return None;
+        }
+
+        let lo = self.lo.inner();
+        let mut lhs = self.data.clone();
+        lhs.reencode(self.prefix(), lo);
+        let mut rhs = right.data.clone();
+        rhs.reencode(right.prefix(), lo);
+
+        Some(Node {
+            id: self.id,
+            data: lhs.concat(rhs)?,
+            next: right.next,
+            prev: self.prev,
+            lo: self.lo.clone(),
+            hi: right.hi.clone(),
+            prefix: None,
+            bloom: None,
+            expiries: self.expiries.merged(&right.expiries),
+            recent: RecentWrites::default(),
+        })
+    }
+
+    fn split_to(&self, id: PageID, split: (Key, Data)) -> Node {
+        let (split, right_data) = split;
+        let expiries = self.expiries.above(&split);
         Node {
             id: id,
             data: right_data,
             next: self.next,
+            prev: Some(self.id),
             lo: Bound::Inclusive(split),
             hi: self.hi.clone(),
+            prefix: None,
+            bloom: None,
+            expiries: expiries,
+            recent: RecentWrites::default(),
+        }
+    }
+}
+
+const HLL_BITS: usize = 6;
+const HLL_REGISTERS: usize = 1 << HLL_BITS;
+
+fn hll_estimate(sketch: &[u8; HLL_REGISTERS]) -> usize {
+    let m = HLL_REGISTERS as f64;
+    let sum: f64 =
+        sketch.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+    let raw = 0.709 * m * m / sum;
+
+    // small cardinalities are far more accurate with linear
+    // counting over the untouched registers.
+    let zeros = sketch.iter().filter(|&&r| r == 0).count();
+    if raw <= 2.5 * m && zeros > 0 {
+        (m * (m / zeros as f64).ln()).round() as usize
+    } else {
+        raw.round() as usize
+    }
+}
+
+/// Marks each Merge whose key is overwritten by a later Set in
+/// `frags`, since applying it can't affect the result.
+fn overwritten_merges(frags: &[&Frag]) -> Vec<bool> {
+    let mut skip = vec![false; frags.len()];
+    let mut set_later: HashSet<&Key> = HashSet::new();
+    for (idx, frag) in frags.iter().enumerate().rev() {
+        match **frag {
+            Frag::Set(ref k, _) | Frag::SetBlob(ref k, _) => {
+                set_later.insert(k);
+            }
+            Frag::Merge(ref k, _) => {
+                skip[idx] = set_later.contains(k);
+            }
+            _ => {}
+        }
+    }
+    skip
+}
+
+/// Finds every data frag in `frags` that writes a key an earlier
+/// data frag already wrote, pairing it with the latest such frag
+/// and saying how the two resolved. Keys are decoded against `lo`.
+/// This shows which write superseded which when tracking down a
+/// lost update.
+pub fn frag_conflicts(
+    frags: &[&Frag],
+    lo: &[u8],
+) -> Vec<FragConflict> {
+    let mut last_write: HashMap<&Key, usize> = HashMap::new();
+    let mut conflicts = vec![];
+    for (idx, frag) in frags.iter().enumerate() {
+        let k = match **frag {
+            Frag::Set(ref k, _)
+            | Frag::SetBlob(ref k, _)
+            | Frag::Merge(ref k, _) => k,
+            Frag::Del(ref k) => k,
+            _ => continue,
+        };
+        if let Some(earlier) = last_write.insert(k, idx) {
+            let resolution = match (frags[earlier], *frag) {
+                (&Frag::Del(_), &Frag::Set(..))
+                | (&Frag::Del(_), &Frag::SetBlob(..)) => {
+                    Resolution::DeleteThenSet
+                }
+                (_, &Frag::Set(..)) | (_, &Frag::SetBlob(..)) => {
+                    Resolution::Overwrite
+                }
+                (_, &Frag::Merge(..)) => Resolution::Merge,
+                _ => Resolution::Delete,
+            };
+            conflicts.push(FragConflict {
+                key: prefix_decode(lo, k),
+                earlier: earlier,
+                later: idx,
+                resolution: resolution,
+            });
+        }
+    }
+    conflicts
+}
+
+/// Returns how many frags at the start of `frags` are Sets, Dels,
+/// or batches of nothing else.
+fn write_run_len(frags: &[&Frag]) -> usize {
+    let is_write = |frag: &Frag| match *frag {
+        Frag::Set(..) | Frag::SetBlob(..) | Frag::Del(_) => true,
+        _ => false,
+    };
+    frags
+        .iter()
+        .take_while(|frag| match ***frag {
+            Frag::Writes(ref batched) => batched.iter().all(is_write),
+            ref frag => is_write(frag),
+        })
+        .count()
+}
+
+/// Returns how many frags at the start of `frags` are Dels over
+/// strictly ascending keys.
+fn del_run_len(frags: &[&Frag]) -> usize {
+    let mut last: Option<&Key> = None;
+    let mut len = 0;
+    for frag in frags {
+        match **frag {
+            Frag::Del(ref k) => {
+                if let Some(last) = last {
+                    if prefix_cmp(last, k) != Ordering::Less {
+                        break;
+                    }
+                }
+                last = Some(k);
+                len += 1;
+            }
+            _ => break,
+        }
+    }
+    len
+}
+
+// Finds the positions of the sorted records whose decoded keys
+// fall in `[lo, hi)`, by binary searching for the first one and
+// walking forward from there.
+fn range_span<T>(
+    records: &[(Key, T)],
+    prefix: &[u8],
+    lo: &Bound,
+    hi: &Bound,
+) -> std::ops::Range<usize> {
+    let decoded =
+        |k: &Key| Bound::Inclusive(prefix_decode(prefix, k));
+    let start = records
+        .binary_search_by(|&(ref k, _)| {
+            if decoded(k) < *lo {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+        .unwrap_err();
+    let len = records[start..]
+        .iter()
+        .take_while(|&&(ref k, _)| decoded(k) < *hi)
+        .count();
+    start..start + len
+}
+
+/// Enforces the configured `max_value_len` policy, returning
+/// whether the value was cut short.
+pub(super) fn truncate_value(
+    value: &mut Value,
+    max_len: Option<usize>,
+) -> bool {
+    match max_len {
+        Some(max_len) if value.len() > max_len => {
+            debug!(
+                "truncating value of length {} to {}",
+                value.len(),
+                max_len
+            );
+            *value = Value::from(&value[..max_len]);
+            true
+        }
+        _ => false,
+    }
+}
+
+// An empty leaf covering `lo..hi`, for tests to fill in.
+#[cfg(test)]
+pub(super) fn leaf(lo: Bound, hi: Bound) -> Node {
+    Node {
+        id: 0,
+        data: Data::Leaf(vec![]),
+        next: None,
+        prev: None,
+        lo: lo,
+        hi: hi,
+        prefix: None,
+        bloom: None,
+        expiries: Expiries::default(),
+        recent: RecentWrites::default(),
+    }
+}
+
+#[test]
+fn test_apply_batch_changes() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .merge_operator(concatenate_merge)
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    let frags = vec![
+        Frag::Set(prefix_encode(b"", b"a"), vec![1].into()),
+        Frag::Set(prefix_encode(b"", b"a"), vec![2].into()),
+        Frag::Merge(prefix_encode(b"", b"a"), vec![3].into()),
+        Frag::Del(prefix_encode(b"", b"a")),
+        Frag::Del(prefix_encode(b"", b"b")),
+    ];
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let mut changes = vec![];
+    node.apply_batch(&frag_refs, &config, Some(&mut changes), None)
+        .unwrap();
+
+    assert_eq!(
+        changes,
+        vec![
+            (b"a".to_vec(), None, Some(vec![1].into())),
+            (
+                b"a".to_vec(),
+                Some(vec![1].into()),
+                Some(vec![2].into()),
+            ),
+            (
+                b"a".to_vec(),
+                Some(vec![2].into()),
+                Some(vec![2, 3].into()),
+            ),
+            (b"a".to_vec(), Some(vec![2, 3].into()), None),
+            (b"b".to_vec(), None, None),
+        ]
+    );
+    assert_eq!(node.data, Data::Leaf(vec![]));
+}
+
+#[test]
+fn test_check_capacity() {
+    let config = ConfigBuilder::new()
+        .blink_fanout(32)
+        .max_leaf_len(Some(4))
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    for i in 0..4u8 {
+        let frag =
+            Frag::Set(prefix_encode(b"", &[i]), vec![i].into());
+        assert_eq!(node.check_capacity(&frag, &config), Ok(()));
+        node.apply(&frag, &config).unwrap();
+    }
+    assert!(node.should_split(&config));
+
+    // the 5th distinct key is refused, overwrites are not
+    let full = Frag::Set(prefix_encode(b"", &[4]), vec![4].into());
+    assert_eq!(
+        node.check_capacity(&full, &config),
+        Err(NodeError::NodeFull(vec![4]))
+    );
+    let overwrite =
+        Frag::Set(prefix_encode(b"", &[0]), vec![9].into());
+    assert_eq!(node.check_capacity(&overwrite, &config), Ok(()));
+
+    let mut rhs = node.split(1, 0.5);
+    node.apply(
+        &Frag::ChildSplit(ChildSplit {
+            at: rhs.lo.clone(),
+            to: rhs.id,
+        }),
+        &config,
+    ).unwrap();
+    assert!(node.data.len() < 4);
+    assert!(rhs.data.len() < 4);
+
+    let encoded = prefix_encode(rhs.lo.inner(), &[4]);
+    let full = Frag::Set(encoded.clone(), vec![4].into());
+    assert_eq!(rhs.check_capacity(&full, &config), Ok(()));
+    rhs.apply(&full, &config).unwrap();
+    assert_eq!(rhs.leaf_value(&*encoded), Some(&vec![4].into()));
+}
+
+#[test]
+fn test_max_key_len() {
+    let config = ConfigBuilder::new().max_key_len(Some(4)).build();
+    // the limit applies to the full key, not the encoded suffix
+    let node = leaf(Bound::Inclusive(b"abc".to_vec()), Bound::Inf);
+
+    let at_limit =
+        Frag::Set(prefix_encode(b"abc", b"abcd"), vec![].into());
+    assert_eq!(node.check_capacity(&at_limit, &config), Ok(()));
+
+    let over =
+        Frag::Merge(prefix_encode(b"abc", b"abcde"), vec![].into());
+    assert_eq!(
+        node.check_capacity(&over, &config),
+        Err(NodeError::KeyTooLong { len: 5, max: 4 })
+    );
+
+    // deletes of any key are allowed
+    let del = Frag::Del(prefix_encode(b"abc", b"abcde"));
+    assert_eq!(node.check_capacity(&del, &config), Ok(()));
+}
+
+#[test]
+fn test_del_many() {
+    let config = ConfigBuilder::new().build();
+    let lo = b"b".to_vec();
+    let mut node = leaf(
+        Bound::Inclusive(lo.clone()),
+        Bound::Exclusive(b"x".to_vec()),
+    );
+    let keys: Vec<Key> = (b'b'..b'x')
+        .map(|c| vec![c, c])
+        .collect();
+    for k in &keys {
+        let frag =
+            Frag::Set(prefix_encode(&*lo, k), k.clone().into());
+        node.apply(&frag, &config).unwrap();
+    }
+
+    let mut individually = node.clone();
+
+    let to_delete: Vec<&[u8]> = vec![
+        b"a",      // below lo
+        b"bb",     // present
+        b"bc",     // absent
+        b"dd",     // present
+        b"mm",     // present
+        b"zz",     // at or above hi
+    ];
+    assert_eq!(node.del_many(&to_delete), 3);
+
+    for k in &to_delete {
+        if *k >= &*lo && *k < &b"x"[..] {
+            individually.del_leaf(&*prefix_encode(&*lo, k));
+        }
+    }
+    assert_eq!(node, individually);
+    assert_eq!(node.data.len(), keys.len() - 3);
+}
+
+#[test]
+fn test_first_gap() {
+    fn be(i: u64) -> Key {
+        (0..8).rev().map(|shift| (i >> (shift * 8)) as u8).collect()
+    }
+    fn incr(k: &[u8]) -> Key {
+        let mut i = 0u64;
+        for b in k {
+            i = (i << 8) | *b as u64;
+        }
+        be(i + 1)
+    }
+
+    let config = ConfigBuilder::new().build();
+    let mut node =
+        leaf(Bound::Inclusive(be(0)), Bound::Exclusive(be(10)));
+    for i in (0..5).chain(7..10) {
+        let frag =
+            Frag::Set(prefix_encode(&*be(0), &*be(i)), vec![].into());
+        node.apply(&frag, &config).unwrap();
+    }
+
+    assert_eq!(node.first_gap(&*be(0), incr), Ok(be(5)));
+    assert_eq!(node.first_gap(&*be(6), incr), Ok(be(6)));
+
+    // fully dense up to hi, so the next node must be consulted
+    assert_eq!(node.first_gap(&*be(7), incr), Err(be(10)));
+}
+
+#[test]
+fn test_apply_batch_timings() {
+    let config = ConfigBuilder::new().build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    let mut frags = vec![];
+    for i in 0..100u8 {
+        let frag =
+            Frag::Set(prefix_encode(b"", &[i]), vec![i].into());
+        frags.push(frag);
+    }
+    for i in 0..10u8 {
+        frags.push(Frag::Del(prefix_encode(b"", &[i])));
+    }
+    frags.push(Frag::ChildSplit(ChildSplit {
+        at: Bound::Inclusive(vec![50]),
+        to: 1,
+    }));
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let mut timings = ApplyTimings::default();
+    let bounds = node.apply_batch(
+        &frag_refs,
+        &config,
+        None,
+        Some(&mut timings),
+    );
+
+    assert_eq!(timings.set.count, 100);
+    assert_eq!(timings.del.count, 10);
+    assert_eq!(timings.merge, FragTiming::default());
+    assert_eq!(timings.split.count, 1);
+    assert!(timings.set.elapsed > Duration::default());
+    assert_eq!(
+        bounds,
+        Ok((
+            Bound::Inclusive(vec![]),
+            Bound::Exclusive(vec![50]),
+            Some(1)
+        ))
+    );
+    assert_eq!(node.data.len(), 40);
+}
+
+#[test]
+fn test_apply_batch_del_run() {
+    let config = ConfigBuilder::new().build();
+    let k = |i: u16| prefix_encode(b"", &[(i >> 8) as u8, i as u8]);
+    let mut base = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    for i in 0..10_000 {
+        base.set_leaf(k(i), vec![1].into());
+    }
+
+    let mut frags: Vec<Frag> =
+        (2000..7000).map(|i| Frag::Del(k(i))).collect();
+    // out of order, so a leaf without pairs applies these three
+    // one at a time
+    frags.push(Frag::Del(k(9001)));
+    frags.push(Frag::Set(k(8000), vec![2].into()));
+    frags.push(Frag::Del(k(20_000)));
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let mut one_by_one = base.clone();
+    for frag in &frag_refs {
+        one_by_one.apply(frag, &config).unwrap();
+    }
+
+    let mut coalesced = base.clone();
+    let mut timings = ApplyTimings::default();
+    coalesced
+        .apply_batch(&frag_refs, &config, None, Some(&mut timings))
+        .unwrap();
+    assert_eq!(coalesced, one_by_one);
+    assert_eq!(coalesced.data.len(), 4999);
+    assert_eq!(timings.del.count, 5002);
+    assert_eq!(timings.set.count, 1);
+}
+
+#[test]
+fn test_apply_batch_write_run() {
+    let config = ConfigBuilder::new().build();
+    let key = |i: u32| format!("hot/{:04}", i).into_bytes();
+    let lo = key(0);
+    let set = |i: u32, v: u8| {
+        Frag::Set(prefix_encode(&lo, &key(i)), vec![v].into())
+    };
+    let del = |i: u32| Frag::Del(prefix_encode(&lo, &key(i)));
+    let mut base = leaf(Bound::Inclusive(lo.clone()), Bound::Inf);
+    for i in 0..1000 {
+        base.apply(&set(i, 0), &config).unwrap();
+    }
+    base.recompute_prefix();
+
+    // many writes to a few hot keys, in and out of batches, with
+    // an expiration and a key outside of the prefix in between
+    let mut frags = vec![];
+    for round in 0..50u8 {
+        for &i in &[3, 500, 999] {
+            frags.push(set(i, round));
+        }
+        frags.push(del(round as u32));
+        frags.push(Frag::Writes(vec![set(7, round), del(8)]));
+    }
+    frags.push(Frag::Expire(prefix_encode(&lo, &key(500)), 1));
+    frags.push(del(4));
+    frags.push(Frag::Del(prefix_encode(&lo, b"other/elsewhere")));
+    let cold = prefix_encode(&lo, b"other/cold");
+    frags.push(Frag::Set(cold, vec![1].into()));
+    frags.push(set(1001, 1));
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let mut one_by_one = base.clone();
+    for frag in &frag_refs {
+        one_by_one.apply(frag, &config).unwrap();
+    }
+    let mut merged = base.clone();
+    merged.apply_batch(&frag_refs, &config, None, None).unwrap();
+
+    assert_eq!(merged, one_by_one);
+    assert!(merged.check_invariants().is_ok());
+    assert_eq!(merged.prefix, None);
+    // keys 3 and 7 are set again after they're deleted
+    assert_eq!(merged.data.len(), 1000 - 48 + 2);
+    let hot = prefix_encode(merged.prefix(), &key(999));
+    assert_eq!(merged.leaf_value(&hot), Some(&vec![49].into()));
+
+    // without a recomputed prefix, records are encoded against lo,
+    // which most of the keys don't start with
+    let more: Vec<Frag> = (990..1010).map(|i| set(i, 50)).collect();
+    let more_refs: Vec<&Frag> = more.iter().collect();
+    for frag in &more_refs {
+        one_by_one.apply(frag, &config).unwrap();
+    }
+    merged.apply_batch(&more_refs, &config, None, None).unwrap();
+    assert_eq!(merged, one_by_one);
+    assert_eq!(merged.leaf_value(&hot), Some(&vec![50].into()));
+}
+
+#[test]
+fn test_distinct_value_estimate() {
+    let leaf = |distinct: usize| {
+        let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+        for i in 0..4096u16 {
+            let k = prefix_encode(b"", &[(i >> 8) as u8, i as u8]);
+            let v = (i as usize % distinct).to_string().into_bytes();
+            node.set_leaf(k, v.into());
+        }
+        node
+    };
+
+    let few = leaf(3);
+    assert_eq!(few.distinct_value_estimate(), 3);
+    assert_eq!(
+        few.distinct_value_estimate(),
+        few.distinct_value_estimate()
+    );
+
+    // 64 registers give roughly 13% standard error
+    let many = leaf(4096).distinct_value_estimate();
+    assert!(many > 2048 && many < 8192, "estimated {}", many);
+}
+
+#[test]
+fn test_splitting_merge_hint() {
+    fn append_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> (Option<Vec<u8>>, bool) {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        let split = ret.len() >= 3;
+        (Some(ret), split)
+    }
+
+    let config = ConfigBuilder::new()
+        .splitting_merge_operator(append_merge)
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    let merge = Frag::Merge(prefix_encode(b"", b"a"), vec![1].into());
+    assert!(!node.apply(&merge, &config).unwrap());
+    assert!(!node.apply(&merge, &config).unwrap());
+    assert!(node.apply(&merge, &config).unwrap());
+    assert_eq!(
+        node.leaf_value(&prefix_encode(b"", b"a")),
+        Some(&vec![1, 1, 1].into())
+    );
+
+    // plain sets never ask for a split
+    let set = Frag::Set(prefix_encode(b"", b"a"), vec![0; 10].into());
+    assert!(!node.apply(&set, &config).unwrap());
+}
+
+#[test]
+fn test_recompute_prefix() {
+    let config = ConfigBuilder::new().build();
+    let key =
+        |i: u32| format!("tenant/0042/user/{:08}", i).into_bytes();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    for i in 0..1000 {
+        let frag =
+            Frag::Set(prefix_encode(b"", &key(i)), vec![1].into());
+        node.apply(&frag, &config).unwrap();
+    }
+
+    let before = bincode::serialized_size(&node.data);
+    let saved = node.recompute_prefix();
+    let after = bincode::serialized_size(&node.data);
+    assert_eq!(node.prefix(), &b"tenant/0042/user/00000"[..]);
+    assert_eq!(before - after, saved as u64);
+    assert_eq!(node.recompute_prefix(), 0);
+    assert!(node.check_invariants().is_ok());
+
+    // frags are still encoded against lo
+    let set =
+        Frag::Set(prefix_encode(b"", &key(500)), vec![2].into());
+    node.apply(&set, &config).unwrap();
+    let del = Frag::Del(prefix_encode(b"", &key(7)));
+    node.apply(&del, &config).unwrap();
+    assert_eq!(node.prefix(), &b"tenant/0042/user/00000"[..]);
+    let stored = prefix_encode(node.prefix(), &key(500));
+    assert_eq!(node.leaf_value(&stored), Some(&vec![2].into()));
+    assert_eq!(node.data.len(), 999);
+
+    // a key outside of the shared prefix moves the node back to lo
+    let set =
+        Frag::Set(prefix_encode(b"", &key(5000)), vec![3].into());
+    node.apply(&set, &config).unwrap();
+    assert_eq!(node.prefix, None);
+    assert_eq!(node.data.len(), 1000);
+    assert!(node.check_invariants().is_ok());
+    let keys = node.data.keys(node.prefix());
+    assert_eq!(keys.last(), Some(&key(5000)));
+
+    // splitting a recomputed node hands the right side its own lo
+    node.recompute_prefix();
+    let rhs = node.split(1, 0.5);
+    assert!(rhs.check_invariants().is_ok());
+}
+
+#[test]
+fn test_to_base() {
+    let config = ConfigBuilder::new().build();
+    let mut node = Node {
+        id: 3,
+        ..leaf(Bound::Inclusive(b"a".to_vec()), Bound::Inf)
+    };
+    let frags = vec![
+        Frag::Set(prefix_encode(b"a", b"b"), vec![1].into()),
+        Frag::Set(prefix_encode(b"a", b"c"), vec![2].into()),
+        Frag::ChildSplit(ChildSplit {
+            at: Bound::Inclusive(b"c".to_vec()),
+            to: 4,
+        }),
+    ];
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+    node.apply_batch(&frag_refs, &config, None, None).unwrap();
+
+    match node.to_base() {
+        Frag::Base(base, None) => assert_eq!(base, node),
+        other => panic!("expected a Base, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_apply_batch_reports_bad_frag() {
+    let config = ConfigBuilder::new().build();
+    let leaf = leaf(
+        Bound::Inclusive(vec![]),
+        Bound::Exclusive(b"m".to_vec()),
+    );
+    let index = Node {
+        data: Data::Index(vec![(prefix_encode(b"", b""), 1)]),
+        ..leaf.clone()
+    };
+    let k = |k: &[u8]| prefix_encode(b"", k);
+    let set = Frag::Set(k(b"a"), vec![1].into());
+    let dels = [Frag::Del(k(b"b")), Frag::Del(k(b"z"))];
+    let ps = Frag::ParentSplit(ParentSplit {
+        at: Bound::Inclusive(b"c".to_vec()),
+        to: 2,
+    });
+
+    // the second del of the run is past hi
+    let chain = [&set, &dels[0], &dels[1]];
+    let err = leaf.clone().apply_batch(&chain, &config, None, None);
+    assert_eq!(err.map_err(|e| e.idx), Err(2));
+
+    let chain = [&set, &ps];
+    assert_eq!(
+        leaf.clone().apply_batch(&chain, &config, None, None),
+        Err(ChainError {
+            idx: 1,
+            error: ApplyError::IndexFragInLeaf,
+        })
+    );
+    let chain = [&ps, &set];
+    assert_eq!(
+        index.clone().apply_batch(&chain, &config, None, None),
+        Err(ChainError {
+            idx: 1,
+            error: ApplyError::LeafFragInIndex,
+        })
+    );
+}
+
+#[test]
+fn test_apply_batch_skips_overwritten_merges() {
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    static MERGES: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        MERGES.fetch_add(1, SeqCst);
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .merge_operator(counting_merge)
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    let a = prefix_encode(b"", b"a");
+    let b = prefix_encode(b"", b"b");
+    let frags = vec![
+        Frag::Merge(a.clone(), vec![1].into()),
+        Frag::Merge(a.clone(), vec![2].into()),
+        Frag::Set(a.clone(), vec![3].into()),
+        Frag::Merge(b.clone(), vec![4].into()),
+    ];
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let mut individually = node.clone();
+    node.apply_batch(&frag_refs, &config, None, None).unwrap();
+    // only the merge to b, which is never overwritten, runs
+    assert_eq!(MERGES.load(SeqCst), 1);
+
+    for frag in &frag_refs {
+        individually.apply(frag, &config).unwrap();
+    }
+    assert_eq!(MERGES.load(SeqCst), 4);
+    assert_eq!(node, individually);
+    assert_eq!(node.leaf_value(&a), Some(&vec![3].into()));
+}
+
+#[test]
+fn test_child_split_returns_moved_records() {
+    let mut node = Node {
+        data: Data::DictLeaf(ValueDict::default()),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    for i in 0..10u8 {
+        node.set_leaf(
+            prefix_encode(b"", &[1, i]),
+            vec![i % 3].into(),
+        );
+    }
+
+    let rhs = node.split(7, 0.5);
+    let moved = node.child_split(&ChildSplit {
+        at: rhs.lo.clone(),
+        to: 7,
+    });
+    assert_eq!(moved, rhs.data);
+    assert_eq!(node.data.len() + moved.len(), 10);
+    assert_eq!(node.check_invariants(), Ok(()));
+}
+
+#[test]
+fn test_check_separators() {
+    let parent = Node {
+        data: Data::Index(vec![
+            (prefix_encode(b"", b""), 1),
+            (prefix_encode(b"", b"m"), 2),
+        ]),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+
+    let mut child_los = vec![
+        (1, Bound::Inclusive(vec![])),
+        (2, Bound::Inclusive(b"m".to_vec())),
+    ];
+    assert_eq!(parent.check_separators(&child_los), Ok(()));
+
+    child_los[1].1 = Bound::Inclusive(b"n".to_vec());
+    assert_eq!(
+        parent.check_separators(&child_los),
+        Err(SeparatorError::Mismatch {
+            child: 2,
+            separator: b"m".to_vec(),
+            lo: Bound::Inclusive(b"n".to_vec()),
+        })
+    );
+
+    assert_eq!(
+        parent.check_separators(&child_los[..1]),
+        Err(SeparatorError::MissingChild(2))
+    );
+}
+
+#[test]
+fn test_frag_conflicts() {
+    let k = |k: &[u8]| prefix_encode(b"", k);
+    let frags = vec![
+        Frag::Set(k(b"a"), vec![1].into()),
+        Frag::Merge(k(b"a"), vec![2].into()),
+        Frag::Del(k(b"b")),
+        Frag::Set(k(b"c"), vec![3].into()),
+        Frag::Set(k(b"a"), vec![4].into()),
+        Frag::Set(k(b"b"), vec![5].into()),
+        Frag::Del(k(b"a")),
+    ];
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+
+    let conflict = |key: &[u8], earlier, later, resolution| {
+        FragConflict {
+            key: key.to_vec(),
+            earlier: earlier,
+            later: later,
+            resolution: resolution,
+        }
+    };
+    assert_eq!(
+        frag_conflicts(&frag_refs, b""),
+        vec![
+            conflict(b"a", 0, 1, Resolution::Merge),
+            conflict(b"a", 1, 4, Resolution::Overwrite),
+            conflict(b"b", 2, 5, Resolution::DeleteThenSet),
+            conflict(b"a", 4, 6, Resolution::Delete),
+        ]
+    );
+}
+
+#[test]
+fn test_migrate() {
+    fn v1_to_v2(value: &[u8]) -> Vec<u8> {
+        // v2 values are v1 values with a trailing checksum byte
+        let mut ret = value.to_vec();
+        ret.push(value.iter().fold(0u8, |a, b| a.wrapping_add(*b)));
+        ret
+    }
+
+    let config = ConfigBuilder::new().migrator(v1_to_v2).build();
+    for data in vec![
+        Data::Leaf(vec![]),
+        Data::DictLeaf(ValueDict::default()),
+    ] {
+        let mut node = Node {
+            data: data,
+            ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+        };
+        node.set_leaf(prefix_encode(b"", b"a"), vec![1, 3, 4].into());
+        node.set_leaf(
+            prefix_encode(b"", b"b"),
+            vec![2, 3, 4, 7].into(),
+        );
+        node.set_leaf(prefix_encode(b"", b"c"), vec![1, 5].into());
+
+        let migrate = Frag::Migrate(1, 2, 0);
+        let mut changes = vec![];
+        let frags = [&migrate];
+        node.apply_batch(&frags, &config, Some(&mut changes), None)
+            .unwrap();
+
+        assert_eq!(
+            node.data.leaf().unwrap(),
+            vec![
+                (prefix_encode(b"", b"a"), vec![2, 3, 4, 7].into()),
+                (prefix_encode(b"", b"b"), vec![2, 3, 4, 7].into()),
+                (prefix_encode(b"", b"c"), vec![2, 5, 5].into()),
+            ]
+        );
+        assert_eq!(changes.len(), 2);
+
+        // nothing is left at v1, so migrating again is a no-op
+        assert_eq!(node.migrate_leaf(1, 2, &v1_to_v2), Ok(0));
+
+        // a database reopened without its migrators can't replay
+        // the migration, but that isn't a reason to crash
+        let unknown = Frag::Migrate(1, 2, 1);
+        let err = node.clone().apply_batch(
+            &[&unknown],
+            &config,
+            None,
+            None,
+        );
+        assert_eq!(
+            err.map_err(|e| e.error),
+            Err(ApplyError::UnknownMigrator(1))
+        );
+    }
+
+    let mut index = Node {
+        data: Data::Index(vec![(prefix_encode(b"", b""), 1)]),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    assert_eq!(
+        index.migrate_leaf(1, 2, &v1_to_v2),
+        Err(ApplyError::LeafFragInIndex)
+    );
+}
+
+#[test]
+fn test_prev_links_across_splits() {
+    use std::collections::BTreeMap;
+
+    let config = ConfigBuilder::new().build();
+    let mut pages = BTreeMap::new();
+    let mut root = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    for i in 0..64u8 {
+        root.set_leaf(prefix_encode(b"", &[i]), vec![i].into());
+    }
+    pages.insert(0, root);
+
+    // split the largest node each time, the way the tree does
+    for new_id in 1..8 {
+        let id = *pages
+            .iter()
+            .max_by_key(|&(_, node)| node.data.len())
+            .unwrap()
+            .0;
+        let node = pages[&id].clone();
+        let rhs = node.split(new_id, 0.5);
+        let split = Frag::ChildSplit(ChildSplit {
+            at: rhs.lo.clone(),
+            to: new_id,
+        });
+        let left = pages.get_mut(&id).unwrap();
+        left.apply(&split, &config).unwrap();
+        if let Some(next) = node.next {
+            let set_prev = Frag::SetPrev(new_id);
+            let next = pages.get_mut(&next).unwrap();
+            next.apply(&set_prev, &config).unwrap();
+        }
+        pages.insert(new_id, rhs);
+    }
+
+    let mut forward = vec![0];
+    while let Some(next) = pages[forward.last().unwrap()].next {
+        forward.push(next);
+    }
+    let last = *forward.last().unwrap();
+    let mut backward = vec![last];
+    while let Some(prev) = pages[backward.last().unwrap()].prev {
+        backward.push(prev);
+    }
+    backward.reverse();
+
+    assert_eq!(forward.len(), 8);
+    assert_eq!(forward, backward);
+    for pair in forward.windows(2) {
+        let (left, right) = (&pages[&pair[0]], &pages[&pair[1]]);
+        let right_lo = right.lo.inner().to_vec();
+        assert_eq!(left.hi, Bound::Exclusive(right_lo));
+    }
+    assert_eq!(pages[&0].prev, None);
+}
+
+#[test]
+fn test_split_by_bytes() {
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    node.set_leaf(prefix_encode(b"", b"a"), vec![0; 4 << 20].into());
+    assert!(!node.should_split_bytes(1 << 20));
+
+    node.set_leaf(prefix_encode(b"", b"b"), vec![1; 4 << 20].into());
+    node.set_leaf(prefix_encode(b"", b"c"), vec![2; 10].into());
+    assert_eq!(node.data.len(), 3);
+    assert!(node.data.byte_len() > 8 << 20);
+    assert!(node.should_split_bytes(1 << 20));
+    assert!(!node.should_split(&ConfigBuilder::new().build()));
+
+    // by count the two large values would stay together
+    let rhs = node.split_by_bytes(1);
+    assert_eq!(rhs.lo, Bound::Inclusive(b"b".to_vec()));
+    assert_eq!(rhs.data.len(), 2);
+    let by_count = node.split(1, 0.5);
+    assert_eq!(by_count.lo, Bound::Inclusive(b"c".to_vec()));
+
+    let mut index = Node {
+        data: Data::Index(vec![]),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    if let Data::Index(ref mut ptrs) = index.data {
+        ptrs.push((prefix_encode(b"", b"k"), 1));
+    }
+    assert_eq!(index.data.byte_len(), 2 + 8);
+}
+
+#[test]
+fn test_should_split_max_node_size() {
+    let config = ConfigBuilder::new()
+        .max_node_size(Some(1 << 20))
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    node.set_leaf(prefix_encode(b"", b"a"), vec![0; 2 << 20].into());
+    assert!(!node.should_split(&config));
+
+    node.set_leaf(prefix_encode(b"", b"b"), vec![1; 10].into());
+    node.set_leaf(prefix_encode(b"", b"c"), vec![2; 10].into());
+    assert!(node.should_split(&config));
+    assert!(!node.should_split(&ConfigBuilder::new().build()));
+
+    // over the size limit the large value gets a node to itself
+    let rhs = node.split_for(1, &config);
+    assert_eq!(rhs.lo, Bound::Inclusive(b"b".to_vec()));
+
+    let small = ConfigBuilder::new()
+        .max_node_size(Some(1 << 30))
+        .split_ratio(0.9)
+        .build();
+    assert!(!node.should_split(&small));
+    let rhs = node.split_for(1, &small);
+    assert_eq!(rhs.lo, Bound::Inclusive(b"c".to_vec()));
+}
+
+#[test]
+fn test_apply_errors() {
+    let config = ConfigBuilder::new().build();
+    let mut node = Node {
+        next: Some(1),
+        ..leaf(
+            Bound::Inclusive(b"a".to_vec()),
+            Bound::Exclusive(b"m".to_vec()),
+        )
+    };
+    node.set_leaf(prefix_encode(b"a", b"b"), vec![1].into());
+    let before = node.clone();
+
+    let out_of_range =
+        Frag::Set(prefix_encode(b"a", b"x"), vec![2].into());
+    assert_eq!(
+        node.apply(&out_of_range, &config),
+        Err(ApplyError::KeyOutOfBounds {
+            key: b"x".to_vec(),
+            hi: Bound::Exclusive(b"m".to_vec()),
+        })
+    );
+    let at_hi = Frag::Del(prefix_encode(b"a", b"m"));
+    match node.apply(&at_hi, &config) {
+        Err(ApplyError::KeyOutOfBounds { .. }) => {}
+        other => panic!("del at hi returned {:?}", other),
+    }
+
+    let merge =
+        Frag::Merge(prefix_encode(b"a", b"b"), vec![3].into());
+    assert_eq!(
+        node.apply(&merge, &config),
+        Err(ApplyError::MissingMergeOperator)
+    );
+    let base = Frag::Base(before.clone(), None);
+    assert_eq!(
+        node.apply(&base, &config),
+        Err(ApplyError::BaseInChain)
+    );
+    assert_eq!(node, before);
+}
+
+#[test]
+fn test_apply_merge_fn_closure() {
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::sync::Arc;
+
+    let merges = Arc::new(AtomicUsize::new(0));
+    let counter = merges.clone();
+    let sep = 0xFF;
+    let config = ConfigBuilder::new()
+        .merge_fn(move |_key, old, new| {
+            counter.fetch_add(1, SeqCst);
+            let mut ret = old.map(|o| o.to_vec()).unwrap_or(vec![]);
+            if !ret.is_empty() {
+                ret.push(sep);
+            }
+            ret.extend_from_slice(new);
+            Some(ret)
+        })
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+
+    let k = prefix_encode(b"", b"k");
+    for v in 1..4 {
+        node.apply(&Frag::Merge(k.clone(), vec![v].into()), &config)
+            .unwrap();
+    }
+    assert_eq!(merges.load(SeqCst), 3);
+    assert_eq!(
+        node.leaf_value(&k),
+        Some(&vec![1, sep, 2, sep, 3].into())
+    );
+}
+
+#[test]
+fn test_expiry_merge_drops_expired_values() {
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::sync::Arc;
+
+    let clock = Arc::new(AtomicUsize::new(1000));
+    let now = clock.clone();
+    let config = ConfigBuilder::new()
+        .merge_fn(::expiry_merge(move || now.load(SeqCst) as u64))
+        .build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    let expiring = |at: u64, payload: &[u8]| {
+        let mut value: Vec<u8> =
+            (0..8).map(|i| (at >> (56 - i * 8)) as u8).collect();
+        value.extend_from_slice(payload);
+        value
+    };
+    let merge = |node: &mut Node, k: &[u8], v: Vec<u8>| {
+        let k = prefix_encode(b"", k);
+        node.apply(&Frag::Merge(k, v.into()), &config).unwrap();
+    };
+    let get = |node: &Node, k: &[u8]| {
+        node.leaf_value(&prefix_encode(b"", k)).cloned()
+    };
+
+    merge(&mut node, b"a", expiring(2000, b"x"));
+    merge(&mut node, b"b", expiring(2000, b"y"));
+    // a bare expiry refreshes a live entry and keeps its payload
+    merge(&mut node, b"b", expiring(5000, b""));
+    assert_eq!(get(&node, b"b"), Some(expiring(5000, b"y").into()));
+    // and does nothing for a missing one
+    merge(&mut node, b"c", expiring(5000, b""));
+    assert_eq!(get(&node, b"c"), None);
+
+    clock.store(3000, SeqCst);
+    // "a" has expired, but stays until its key is next touched
+    assert_eq!(get(&node, b"a"), Some(expiring(2000, b"x").into()));
+    merge(&mut node, b"a", expiring(9000, b""));
+    assert_eq!(get(&node, b"a"), None);
+    assert_eq!(get(&node, b"b"), Some(expiring(5000, b"y").into()));
+
+    // merging in a value that has already expired removes the key
+    merge(&mut node, b"b", expiring(2500, b"z"));
+    assert_eq!(get(&node, b"b"), None);
+    assert_eq!(node.data.len(), 0);
+}
+
+#[test]
+fn test_merge_with() {
+    let mut node = Node {
+        next: Some(9),
+        ..leaf(
+            Bound::Inclusive(b"k".to_vec()),
+            Bound::Exclusive(b"z".to_vec()),
+        )
+    };
+    for i in 0..20u8 {
+        let k = [b'k', b'x', i];
+        node.set_leaf(prefix_encode(b"k", &k), vec![i].into());
+    }
+    let original = node.clone();
+
+    let rhs = node.split(1, 0.5);
+    node.child_split(&ChildSplit {
+        at: rhs.lo.clone(),
+        to: 1,
+    });
+    // prefixes longer than `lo` must be undone by the merge
+    assert!(node.recompute_prefix() > 0);
+
+    assert_eq!(rhs.merge_with(&node), None);
+    let mut set = rhs.clone();
+    set.data = Data::LeafSet(vec![]);
+    assert_eq!(node.merge_with(&set), None);
+
+    let merged = node.merge_with(&rhs).unwrap();
+    assert_eq!(merged, original);
+    assert_eq!(merged.check_invariants(), Ok(()));
+}
+
+#[test]
+fn test_apply_child_and_parent_merge() {
+    let config =
+        ConfigBuilder::new().merge_threshold(Some(2)).build();
+    let mut lhs = Node {
+        id: 1,
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    for i in 0..6u8 {
+        lhs.set_leaf(prefix_encode(b"", &[i]), vec![i].into());
+    }
+    let original = lhs.clone();
+    let rhs = lhs.split(2, 0.5);
+    lhs.child_split(&ChildSplit {
+        at: rhs.lo.clone(),
+        to: 2,
+    });
+    assert!(!lhs.should_merge(&config));
+
+    let mut parent = Node {
+        data: Data::Index(vec![(vec![0], 1)]),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    parent.parent_split(&ParentSplit {
+        at: rhs.lo.clone(),
+        to: 2,
+    });
+    assert_eq!(parent.data.len(), 2);
+
+    // a right side that doesn't start where we end is refused
+    let mut stray = rhs.clone();
+    stray.lo = Bound::Inclusive(vec![5]);
+    let stray = Frag::ChildMerge(ChildMerge { right: stray });
+    assert_eq!(
+        lhs.clone().apply(&stray, &config),
+        Err(ApplyError::NotMergeable { right: 2 })
+    );
+
+    let merge = Frag::ChildMerge(ChildMerge { right: rhs.clone() });
+    lhs.apply(&merge, &config).unwrap();
+    assert_eq!(lhs, original);
+
+    let unlink = Frag::ParentMerge(ParentMerge {
+        at: rhs.lo.clone(),
+        to: 2,
+    });
+    assert_eq!(
+        lhs.clone().apply(&unlink, &config),
+        Err(ApplyError::IndexFragInLeaf)
+    );
+    parent.apply(&unlink, &config).unwrap();
+    assert_eq!(parent.data, Data::Index(vec![(vec![0], 1)]));
+    assert!(parent.should_merge(&config));
+}
+
+#[test]
+fn test_apply_del_range() {
+    let config = ConfigBuilder::new().build();
+    let mut node =
+        leaf(Bound::Inclusive(vec![2]), Bound::Exclusive(vec![8]));
+    for i in 2..8u8 {
+        node.set_leaf(prefix_encode(&[2], &[i]), vec![i].into());
+    }
+
+    // the range may start before the node and end after it
+    let frags = vec![
+        Frag::DelRange(
+            Bound::Inclusive(vec![0]),
+            Bound::Exclusive(vec![4]),
+        ),
+        Frag::DelRange(Bound::Inclusive(vec![7]), Bound::Inf),
+    ];
+    let frag_refs: Vec<&Frag> = frags.iter().collect();
+    let mut changes = vec![];
+    node.apply_batch(&frag_refs, &config, Some(&mut changes), None)
+        .unwrap();
+
+    assert_eq!(
+        changes,
+        vec![
+            (vec![2], Some(vec![2].into()), None),
+            (vec![3], Some(vec![3].into()), None),
+            (vec![7], Some(vec![7].into()), None),
+        ]
+    );
+    let keys: Vec<Key> = node.iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec![vec![4], vec![5], vec![6]]);
+
+    let mut index = node.clone();
+    index.data = Data::Index(vec![(prefix_encode(&[2], &[2]), 1)]);
+    assert_eq!(
+        index.apply(&frags[0], &config),
+        Err(ApplyError::LeafFragInIndex)
+    );
+}
+
+#[test]
+fn test_del_range() {
+    let lo = b"b".to_vec();
+    let mut node = leaf(
+        Bound::Inclusive(lo.clone()),
+        Bound::Exclusive(b"x".to_vec()),
+    );
+    for c in b'b'..b'x' {
+        node.set_leaf(prefix_encode(&*lo, &[c, c]), vec![c].into());
+    }
+    let original = node.clone();
+    let range = |lo: &[u8], hi: &[u8]| {
+        (Bound::Inclusive(lo.to_vec()), Bound::Exclusive(hi.to_vec()))
+    };
+
+    // entirely below, entirely above and empty ranges are no-ops
+    for &(ref lo, ref hi) in &[
+        range(b"a", b"b"),
+        range(b"y", b"z"),
+        range(b"mm", b"mm"),
+        (Bound::Inclusive(b"y".to_vec()), Bound::Inf),
+    ] {
+        assert_eq!(node.del_range(lo, hi), 0);
+        assert_eq!(node, original);
+    }
+
+    // overlaps the start of the node, ending on a present key
+    let (a, dd) = range(b"a", b"dd");
+    assert_eq!(node.del_range(&a, &dd), 2);
+    assert_eq!(node.data.keys(&lo)[0], b"dd".to_vec());
+
+    // overlaps the end of the node
+    let (v, inf) = (Bound::Inclusive(b"v".to_vec()), Bound::Inf);
+    assert_eq!(node.del_range(&v, &inf), 2);
+    assert_eq!(node.data.keys(&lo).last(), Some(&b"uu".to_vec()));
+
+    let mut dict = original.clone();
+    dict.data = Data::DictLeaf(ValueDict::default());
+    for (k, v) in original.data.leaf().unwrap() {
+        dict.set_leaf(k, v);
+    }
+    let (ee, hh) = range(b"ee", b"hh");
+    assert_eq!(dict.del_range(&ee, &hh), 3);
+    assert_eq!(dict.data.len(), original.data.len() - 3);
+    let get = |k: &[u8]| dict.leaf_value(&prefix_encode(&lo, k));
+    assert_eq!(get(b"dd"), Some(&vec![b'd'].into()));
+    assert_eq!(get(b"ee"), None);
+    assert_eq!(get(b"hh"), Some(&vec![b'h'].into()));
+
+    let mut index = original.clone();
+    index.data = Data::Index(vec![(prefix_encode(&lo, b"b"), 1)]);
+    assert_eq!(index.del_range(&a, &inf), 0);
+    assert_eq!(index.data.len(), 1);
+}
+
+#[test]
+fn test_iter() {
+    let lo = b"k".to_vec();
+    let originals: Vec<(Key, Value)> = (0..20u8)
+        .map(|i| (vec![b'k', b'x', i], vec![i].into()))
+        .collect();
+
+    for data in vec![
+        Data::Leaf(vec![]),
+        Data::DictLeaf(ValueDict::default()),
+    ] {
+        let mut node = Node {
+            data: data,
+            ..leaf(Bound::Inclusive(lo.clone()), Bound::Inf)
+        };
+        for &(ref k, ref v) in originals.iter().rev() {
+            node.set_leaf(prefix_encode(&lo, k), v.clone());
+        }
+
+        let rhs = node.split(1, 0.5);
+        node.child_split(&ChildSplit {
+            at: rhs.lo.clone(),
+            to: 1,
+        });
+        node.recompute_prefix();
+
+        let iterated: Vec<(Key, Value)> = node
+            .iter()
+            .chain(rhs.iter())
+            .map(|(k, v)| (k, v.clone()))
+            .collect();
+        assert_eq!(iterated, originals);
+        assert_eq!(node.iter_index().count(), 0);
+    }
+
+    let index = Node {
+        data: Data::Index(vec![
+            (prefix_encode(&lo, b"k"), 1),
+            (prefix_encode(&lo, b"km"), 2),
+        ]),
+        ..leaf(Bound::Inclusive(lo.clone()), Bound::Inf)
+    };
+    let seps: Vec<(Key, PageID)> = index.iter_index().collect();
+    assert_eq!(seps, vec![(b"k".to_vec(), 1), (b"km".to_vec(), 2)]);
+    assert_eq!(index.iter().count(), 0);
+}
+
+#[test]
+fn test_dedup() {
+    let k = |c: u8| prefix_encode(b"", &[c]);
+    let mut node = Node {
+        data: Data::Leaf(vec![
+            (k(1), vec![1].into()),
+            (k(2), vec![2].into()),
+            (k(2), vec![3].into()),
+            (k(3), vec![4].into()),
+        ]),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+    assert!(node.check_invariants().is_err());
+
+    assert_eq!(node.dedup(), 1);
+    assert_eq!(node.leaf_value(&k(2)), Some(&vec![3].into()));
+    assert_eq!(node.data.len(), 3);
+    assert_eq!(node.check_invariants(), Ok(()));
+    node.data.assert_sorted_unique(node.prefix());
+    assert_eq!(node.dedup(), 0);
+
+    let mut dict = ValueDict::from_parts(
+        vec![vec![1].into(), vec![2].into()],
+        vec![(k(5), 0), (k(5), 1), (k(4), 0)],
+    );
+    let mut data = Data::DictLeaf(dict.clone());
+    assert_eq!(data.dedup(), 1);
+    dict = ValueDict::from_parts(
+        vec![vec![1].into(), vec![2].into()],
+        vec![(k(4), 0), (k(5), 1)],
+    );
+    assert_eq!(data.leaf(), Data::DictLeaf(dict).leaf());
+}
+
+#[test]
+#[should_panic]
+fn test_assert_sorted_unique() {
+    let k = prefix_encode(b"", b"k");
+    Data::LeafSet(vec![(k.clone(), ()), (k, ())])
+        .assert_sorted_unique(b"");
+}
+
+#[test]
+fn test_contains_key() {
+    let lo = b"b".to_vec();
+    for data in vec![
+        Data::Leaf(vec![]),
+        Data::DictLeaf(ValueDict::default()),
+        Data::LeafSet(vec![]),
+    ] {
+        let mut node = Node {
+            data: data,
+            ..leaf(
+                Bound::Inclusive(lo.clone()),
+                Bound::Inclusive(b"d".to_vec()),
+            )
+        };
+        for k in &[&b"bb"[..], b"bd", b"c"] {
+            node.set_leaf(prefix_encode(&lo, k), vec![].into());
+        }
+        let contains =
+            |k: &[u8]| node.contains_key(&prefix_encode(&lo, k));
+
+        assert!(contains(b"bb"));
+        assert!(contains(b"c"));
+        assert!(!contains(b"bc"));
+        assert!(!contains(b"b"));
+        // outside of the node's bounds
+        assert!(!contains(b"a"));
+        assert!(!contains(b"d"));
+        assert!(!contains(b"zz"));
+    }
+
+    let index = Node {
+        data: Data::Index(vec![
+            (prefix_encode(&lo, b"b"), 1),
+            (prefix_encode(&lo, b"bm"), 2),
+        ]),
+        ..leaf(
+            Bound::Inclusive(lo.clone()),
+            Bound::Inclusive(b"c".to_vec()),
+        )
+    };
+    assert!(index.contains_key(&prefix_encode(&lo, b"bm")));
+    assert!(!index.contains_key(&prefix_encode(&lo, b"bn")));
+    assert!(!index.contains_key(&prefix_encode(&lo, b"c")));
+}
+
+#[test]
+fn test_merges_routed_across_split() {
+    fn concat(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret = old.map(|o| o.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(new);
+        Some(ret)
+    }
+    let config = ConfigBuilder::new().merge_operator(concat).build();
+    let lo = b"m".to_vec();
+    let mut lhs = leaf(Bound::Inclusive(lo.clone()), Bound::Inf);
+    let key = |i: u8| vec![b'm', b'a' + i];
+    for i in 0..10 {
+        lhs.set_leaf(prefix_encode(&lo, &key(i)), vec![i].into());
+    }
+
+    // merges that were in flight for the node before it split
+    let pending: Vec<(Key, Value)> =
+        (0..12).map(|i| (key(i), vec![100 + i].into())).collect();
+
+    let mut rhs = lhs.split(1, 0.5);
+    let split_key = rhs.lo.inner().to_vec();
+    lhs.child_split(&ChildSplit {
+        at: rhs.lo.clone(),
+        to: 1,
+    });
+
+    for &(ref k, ref v) in &pending {
+        assert!(lhs.would_own(k) != rhs.would_own(k));
+        assert_eq!(rhs.would_own(k), *k >= split_key);
+        let owner = if lhs.would_own(k) {
+            &mut lhs
+        } else {
+            &mut rhs
+        };
+        let encoded = prefix_encode(owner.lo.inner(), k);
+        owner
+            .apply(&Frag::Merge(encoded, v.clone()), &config)
+            .unwrap();
+    }
+
+    for i in 0..12 {
+        let k = key(i);
+        let expected = if i < 10 {
+            vec![i, 100 + i]
+        } else {
+            vec![100 + i]
+        };
+        let (owner, other) = if k < split_key {
+            (&lhs, &rhs)
+        } else {
+            (&rhs, &lhs)
+        };
+        let found = owner
+            .iter()
+            .find(|&(ref found, _)| *found == k)
+            .map(|(_, v)| v.clone());
+        assert_eq!(found, Some(expected.into()));
+        assert!(other.iter().all(|(found, _)| found != k));
+    }
+
+    // the left half refuses a merge for a key it gave away
+    let moved =
+        Frag::Merge(prefix_encode(&lo, &key(11)), vec![0].into());
+    assert!(lhs.apply(&moved, &config).is_err());
+}
+
+#[test]
+fn test_clamp() {
+    let lo = b"f".to_vec();
+    let k = |k: &[u8]| prefix_encode(&lo, k);
+    let mut node = Node {
+        id: 0,
+        // as left behind by a split that was only partly applied
+        data: Data::Leaf(vec![
+            (k(b"a"), vec![1].into()),
+            (k(b"f"), vec![2].into()),
+            (k(b"g"), vec![3].into()),
+            (k(b"m"), vec![4].into()),
+            (k(b"z"), vec![5].into()),
+        ]),
+        next: None,
+        prev: None,
+        lo: Bound::Inclusive(lo.clone()),
+        hi: Bound::Inclusive(b"m".to_vec()),
+        prefix: None,
+        bloom: None,
+        expiries: Expiries::default(),
+        recent: RecentWrites::default(),
+    };
+
+    assert_eq!(node.clamp(), 3);
+    let kept: Vec<Key> = node.iter().map(|(k, _)| k).collect();
+    assert_eq!(kept, vec![b"f".to_vec(), b"g".to_vec()]);
+    assert_eq!(node.clamp(), 0);
+
+    // out of order and repeated records are fixed up too
+    node.data = Data::Index(vec![
+        (k(b"h"), 3),
+        (k(b"f"), 1),
+        (k(b"q"), 9),
+        (k(b"g"), 2),
+        (k(b"g"), 4),
+    ]);
+    assert_eq!(node.clamp(), 2);
+    let kept: Vec<(Key, PageID)> = node.iter_index().collect();
+    let expected: Vec<(Key, PageID)> = vec![
+        (b"f".to_vec(), 1),
+        (b"g".to_vec(), 4),
+        (b"h".to_vec(), 3),
+    ];
+    assert_eq!(kept, expected);
+
+    // as are keys that can't be decoded at all
+    node.data = Data::Leaf(vec![
+        (vec![], vec![].into()),
+        (vec![200, b'x'], vec![].into()),
+        (k(b"g"), vec![].into()),
+    ]);
+    assert_eq!(node.clamp(), 2);
+    assert_eq!(node.data.len(), 1);
+
+    node.lo = Bound::Inf;
+    assert_eq!(node.clamp(), 1);
+    assert_eq!(node.data.len(), 0);
+}
+
+#[test]
+fn test_set_leaf_inserts_in_place() {
+    fn concat(
+        _key: &[u8],
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret = old.map(|o| o.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(new);
+        Some(ret)
+    }
+    let empty = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    let k = |i: u32| {
+        let bytes: Vec<u8> =
+            (0..4).map(|s| (i >> (24 - s * 8)) as u8).collect();
+        prefix_encode(b"", &bytes)
+    };
+
+    // ascending, descending, and scattered into the middle
+    let orders: Vec<Vec<u32>> = vec![
+        (0..500).collect(),
+        (0..500).rev().collect(),
+        (0..500).map(|i| (i * 7919) % 500).collect(),
+    ];
+    for order in &orders {
+        let mut node = empty.clone();
+        let mut merged = empty.clone();
+        // the old path pushed and then sorted the whole leaf
+        let mut reference: Vec<(Key, Value)> = vec![];
+        for &i in order {
+            let v = vec![i as u8];
+            node.set_leaf(k(i), v.clone().into());
+            merged.merge_leaf(k(i), v.clone().into(), concat, None);
+            reference.push((k(i), v.into()));
+            reference.sort_unstable_by(|a, b| prefix_cmp(&a.0, &b.0));
+        }
+        assert_eq!(node.data, Data::Leaf(reference.clone()));
+        assert_eq!(merged.data, Data::Leaf(reference));
+    }
+}
+
+#[test]
+fn test_stats() {
+    let records: Vec<(Key, Value)> = vec![
+        (prefix_encode(b"", b"a"), vec![0; 10].into()),
+        (prefix_encode(b"", b"bcd"), vec![0; 3].into()),
+        (prefix_encode(b"", b"ef"), vec![].into()),
+    ];
+    let mut node = Node {
+        data: Data::Leaf(records),
+        ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+    };
+
+    // each encoded key has a one byte shared length in front
+    let stats = node.stats(4);
+    assert_eq!(stats.kind, NodeKind::Leaf);
+    assert_eq!(stats.entry_count, 3);
+    assert_eq!(stats.key_bytes, 2 + 4 + 3);
+    assert_eq!(stats.value_bytes, 13);
+    assert_eq!(stats.byte_len, node.data.byte_len());
+    assert_eq!(stats.fill_ratio, 0.75);
+    assert_eq!(node.stats(2).fill_ratio, 1.);
+
+    node.data = Data::Index(vec![
+        (prefix_encode(b"", b""), 1),
+        (prefix_encode(b"", b"m"), 2),
+    ]);
+    let stats = node.stats(16);
+    assert_eq!(stats.kind, NodeKind::Index);
+    assert_eq!(stats.key_bytes, 1 + 2);
+    assert_eq!(stats.value_bytes, 2 * std::mem::size_of::<PageID>());
+    assert_eq!(stats.byte_len, node.data.byte_len());
+    assert!(stats.fill_ratio > 0. && stats.fill_ratio < 1.);
+}
+
+#[test]
+fn test_successor_and_predecessor() {
+    let lo = b"b".to_vec();
+    let mut node = Node {
+        data: Data::DictLeaf(ValueDict::default()),
+        ..leaf(
+            Bound::Inclusive(lo.clone()),
+            Bound::Exclusive(b"y".to_vec()),
+        )
+    };
+    for k in &[&b"bb"[..], b"dd", b"ff", b"hh"] {
+        node.set_leaf(prefix_encode(&lo, k), k[..1].to_vec().into());
+    }
+    let entry = |k: &[u8]| Some((k.to_vec(), Value::from(&k[..1])));
+    fn owned(found: Option<(Key, &Value)>) -> Option<(Key, Value)> {
+        found.map(|(k, v)| (k, v.clone()))
+    }
+
+    // below the first entry, and below lo entirely
+    assert_eq!(owned(node.successor(b"ba")), entry(b"bb"));
+    assert_eq!(owned(node.successor(b"a")), entry(b"bb"));
+    assert_eq!(owned(node.predecessor(b"ba")), None);
+    assert_eq!(owned(node.predecessor(b"a")), None);
+
+    // exact matches are skipped in both directions
+    assert_eq!(owned(node.successor(b"bb")), entry(b"dd"));
+    assert_eq!(owned(node.predecessor(b"bb")), None);
+    assert_eq!(owned(node.successor(b"dd")), entry(b"ff"));
+    assert_eq!(owned(node.predecessor(b"dd")), entry(b"bb"));
+
+    // between entries
+    assert_eq!(owned(node.successor(b"e")), entry(b"ff"));
+    assert_eq!(owned(node.predecessor(b"e")), entry(b"dd"));
+
+    // at and above the last entry
+    assert_eq!(owned(node.successor(b"hh")), None);
+    assert_eq!(owned(node.predecessor(b"hh")), entry(b"ff"));
+    assert_eq!(owned(node.successor(b"x")), None);
+    assert_eq!(owned(node.predecessor(b"zz")), entry(b"hh"));
+
+    // last_within treats its bound as inclusive or exclusive
+    let within = |hi: Bound| owned(node.last_within(&hi));
+    let dd = b"dd".to_vec();
+    assert_eq!(within(Bound::Inclusive(dd.clone())), entry(b"dd"));
+    assert_eq!(within(Bound::Exclusive(dd)), entry(b"bb"));
+    assert_eq!(within(Bound::Exclusive(b"bb".to_vec())), None);
+    assert_eq!(within(Bound::Inf), entry(b"hh"));
+
+    // a recomputed prefix doesn't change the answers
+    node.data = Data::Leaf(node.data.leaf().unwrap());
+    node.set_leaf(prefix_encode(&lo, b"d"), b"d".to_vec().into());
+    node.del_leaf(&prefix_encode(&lo, b"bb"));
+    node.del_leaf(&prefix_encode(&lo, b"ff"));
+    node.del_leaf(&prefix_encode(&lo, b"hh"));
+    assert!(node.recompute_prefix() > 0);
+    assert_eq!(owned(node.successor(b"c")), entry(b"d"));
+    assert_eq!(owned(node.successor(b"d")), entry(b"dd"));
+    assert_eq!(owned(node.predecessor(b"e")), entry(b"dd"));
+}
+
+#[test]
+fn test_inserts_keep_order() {
+    fn index() -> Node {
+        Node {
+            data: Data::Index(vec![(prefix_encode(b"", b""), 1)]),
+            ..leaf(Bound::Inclusive(vec![]), Bound::Inf)
+        }
+    }
+
+    let n = 2_000u32;
+    let sequential: Vec<Key> =
+        (1..n).map(|i| i.to_be_bytes().to_vec()).collect();
+    // 7919 is prime, so stepping by it visits every key once
+    let random: Vec<Key> = (1..n)
+        .map(|i| (1 + i * 7919 % (n - 1)).to_be_bytes().to_vec())
+        .collect();
+
+    for keys in &[sequential, random] {
+        let mut node = index();
+        for (i, key) in keys.iter().enumerate() {
+            node.parent_split(&ParentSplit {
+                at: Bound::Inclusive(key.clone()),
+                to: i + 2,
+            });
+        }
+        assert_eq!(node.check_invariants(), Ok(()));
+        assert_eq!(node.data.len(), n as usize);
+
+        // what parent_split used to do for each separator
+        let mut sorted = vec![(prefix_encode(b"", b""), 1)];
+        for (i, key) in keys.iter().enumerate() {
+            sorted.push((prefix_encode(b"", key), i + 2));
+            sorted.sort_unstable_by(|a, b| prefix_cmp(&*a.0, &*b.0));
+        }
+        assert_eq!(node.data, Data::Index(sorted));
+
+        let mut leaf = Node {
+            data: Data::Leaf(vec![]),
+            ..index()
+        };
+        for key in keys.iter() {
+            let encoded = prefix_encode(b"", key);
+            leaf.set_leaf(encoded, key.clone().into());
         }
+        assert_eq!(leaf.check_invariants(), Ok(()));
+        assert_eq!(leaf.data.len(), n as usize - 1);
     }
 }
@@ -0,0 +1,62 @@
+// When a tree splits its nodes. Every tree starts out with the
+// `blink_fanout` and `max_node_size` of its config, and each can be
+// given its own policy while it's open with `Tree::set_split_policy`.
+
+use super::*;
+
+/// When the nodes of a `Tree` are split, as returned by
+/// `Tree::split_policy`.
+///
+/// # Examples
+///
+/// ```
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let db = sled::Db::start(config).unwrap();
+/// let blobs = db.open_tree(b"blobs").unwrap();
+///
+/// let mut policy = blobs.split_policy();
+/// policy.fanout = 8;
+/// policy.max_node_size = Some(1 << 20);
+/// blobs.set_split_policy(policy).unwrap();
+/// assert_eq!(db.open_tree(b"blobs").unwrap().split_policy(), policy);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SplitPolicy {
+    /// Nodes with more entries than this are split. At least 2.
+    pub fanout: u8,
+    /// Nodes whose keys and values take up more than this many
+    /// bytes are split, evenly by bytes, in addition to the
+    /// `fanout` limit on their number of entries.
+    pub max_node_size: Option<usize>,
+}
+
+impl SplitPolicy {
+    /// The policy that trees using `config` start out with.
+    pub fn from_config(config: &Config) -> SplitPolicy {
+        SplitPolicy {
+            fanout: config.blink_fanout,
+            max_node_size: config.max_node_size,
+        }
+    }
+
+    // Refuses a policy that `config` would refuse as a
+    // `blink_fanout`.
+    pub(super) fn check(&self, config: &Config) -> DbResult<(), ()> {
+        if self.fanout < 2 {
+            return Err(Error::Unsupported(
+                "a split policy's fanout must be at least 2".to_owned(),
+            ));
+        }
+        let too_small = config
+            .merge_threshold
+            .map_or(false, |min| min > self.fanout as usize / 2);
+        if too_small {
+            return Err(Error::Unsupported(
+                "merge_threshold must be at most half of a split \
+                 policy's fanout"
+                    .to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
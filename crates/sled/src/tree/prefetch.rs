@@ -0,0 +1,124 @@
+// Read-ahead for scans. When an iterator moves on to a leaf through
+// its left sibling's `next` link, it asks a background thread to
+// page in the `read_ahead` leaves after it, so that the log is read
+// while the iterator is still working through the leaf it's on.
+
+use std::sync::mpsc::{
+    sync_channel, Receiver, SyncSender, TrySendError,
+};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use epoch::pin;
+
+use super::iter::within;
+use super::*;
+
+// How many requests may wait for the thread before more are
+// dropped. A scan that outruns its read-ahead reads its leaves
+// itself.
+const QUEUE: usize = 4;
+
+// Leaves to page in, starting at `id` and following `next` links
+// for up to `depth` leaves, or until one ends past `hi`.
+struct ReadAhead {
+    id: PageID,
+    hi: Bound,
+    depth: usize,
+}
+
+// Pages in leaves on a background thread until it is dropped.
+pub(super) struct Prefetcher {
+    // dropping this wakes the thread up to exit
+    requests: Option<SyncSender<ReadAhead>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    // Starts a thread that reads leaves of `pages`. It only holds
+    // the pagecache, so it doesn't keep any tree alive.
+    pub(super) fn start(pages: Arc<TreeCache>) -> Prefetcher {
+        let (tx, rx) = sync_channel(QUEUE);
+        let handle = thread::Builder::new()
+            .name("sled_read_ahead".to_owned())
+            .spawn(move || run(&pages, &rx))
+            .unwrap();
+        Prefetcher {
+            requests: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    // Asks for `depth` leaves starting at `id` to be paged in,
+    // without waiting on the thread.
+    pub(super) fn read_ahead(
+        &self,
+        id: PageID,
+        hi: &Bound,
+        depth: usize,
+    ) {
+        let request = ReadAhead {
+            id: id,
+            hi: hi.clone(),
+            depth: depth,
+        };
+        if let Some(ref requests) = self.requests {
+            match requests.try_send(request) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("the read-ahead thread stopped early");
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("error joining read-ahead thread: {:?}", e);
+            }
+        }
+    }
+}
+
+fn run(pages: &TreeCache, requests: &Receiver<ReadAhead>) {
+    while let Ok(request) = requests.recv() {
+        let mut id = request.id;
+        for _ in 0..request.depth {
+            let guard = pin();
+            // the leaf may have been merged away and its page
+            // reused since the link to it was read, which only
+            // costs us a wasted read
+            let next = match pages.get(id, &guard) {
+                Ok(PageGet::Materialized(Frag::Base(ref node, _), _))
+                    if !node.data.is_index() =>
+                {
+                    let past_end = match node.hi {
+                        Bound::Inf => true,
+                        ref hi => !within(hi.inner(), &request.hi),
+                    };
+                    if past_end {
+                        None
+                    } else {
+                        node.next
+                    }
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    debug!(
+                        "failed to read ahead pid {}: {:?}",
+                        id, e
+                    );
+                    None
+                }
+            };
+            match next {
+                Some(next) => id = next,
+                None => break,
+            }
+        }
+    }
+}
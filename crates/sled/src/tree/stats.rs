@@ -0,0 +1,45 @@
+/// The shape of a `Tree` and the space its records take up, as
+/// reported by `Tree::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// The number of levels, counting the leaves.
+    pub height: usize,
+    /// The number of pages, both index nodes and leaves.
+    pub pages: usize,
+    /// The number of leaves.
+    pub leaves: usize,
+    /// The number of records stored in the leaves, including
+    /// expired ones that haven't been swept yet.
+    pub records: usize,
+    /// The total length of the records' keys.
+    pub key_bytes: usize,
+    /// The total length of the records' values.
+    pub value_bytes: usize,
+    /// The number of distinct values in each leaf, estimated with
+    /// a small sketch and summed over the leaves. Far fewer than
+    /// `records` means `intern_values` would store most values only
+    /// once per leaf.
+    pub distinct_values: usize,
+    /// The average number of records in a leaf, as a proportion of
+    /// the number at which leaves are split: the fanout of the
+    /// tree's `SplitPolicy`, or `max_leaf_len` if that is lower.
+    pub avg_leaf_fill: f64,
+}
+
+/// The keys between two adjacent separators in the lowest level of
+/// a `Tree`'s index, which are the keys of one leaf, as returned by
+/// `Tree::key_histogram`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBucket {
+    /// The lowest key that can fall in this bucket, which is empty
+    /// for the first.
+    pub lo: Vec<u8>,
+    /// The first key past this bucket, or `None` for the last.
+    pub hi: Option<Vec<u8>>,
+    /// Roughly how many keys the bucket holds, counting expired
+    /// ones that haven't been swept yet.
+    pub keys: usize,
+    /// Whether the bucket's leaf was read to count `keys`, rather
+    /// than assumed to hold as many as the leaves that were.
+    pub counted: bool,
+}
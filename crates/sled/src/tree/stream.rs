@@ -0,0 +1,9 @@
+// Tags identifying which kind of `Data` follows in the compact
+// encoding in compact.rs.
+pub const INDEX: u8 = 0;
+pub const LEAF: u8 = 1;
+pub const DICT_LEAF: u8 = 2;
+pub const LEAF_SET: u8 = 3;
+pub const INT_LEAF: u8 = 4;
+pub const BLOCK_LEAF: u8 = 5;
+pub const COMPRESSED_LEAF: u8 = 6;
@@ -0,0 +1,203 @@
+// Delivers the writes made to a tree to the `Subscriber`s watching
+// a prefix of their keys.
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, RwLock, Weak};
+
+use super::*;
+
+/// A write to a key of a watched `Tree`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The key was set to a value.
+    Set(Key, Value),
+    /// A value was merged into the key with the tree's merge
+    /// operator. Holds the value the key was left with. A merge
+    /// that removes the key is a `Del` instead.
+    Merge(Key, Value),
+    /// The key was removed.
+    Del(Key),
+}
+
+impl Event {
+    /// The key that was written.
+    pub fn key(&self) -> &[u8] {
+        match *self {
+            Event::Set(ref k, _)
+            | Event::Merge(ref k, _)
+            | Event::Del(ref k) => k,
+        }
+    }
+}
+
+/// The writes to keys starting with a prefix, returned by
+/// `Tree::watch_prefix`. Iterating blocks until the next write,
+/// and only returns it once it is durable. Writes to a key come
+/// out in the order they were applied, but concurrent writes to
+/// different keys may come out in either order. The iterator ends
+/// when the tree is dropped, and dropping the `Subscriber` stops
+/// the tree from sending it any more events.
+///
+/// # Examples
+///
+/// ```
+/// use sled::Event;
+///
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// let mut events = t.watch_prefix(b"user/");
+///
+/// t.set(b"user/1".to_vec(), vec![1]).unwrap();
+/// t.set(b"item/1".to_vec(), vec![1]).unwrap();
+/// t.del(b"user/1").unwrap();
+///
+/// assert_eq!(
+///     events.next().unwrap(),
+///     Ok(Event::Set(b"user/1".to_vec(), vec![1].into()))
+/// );
+/// assert_eq!(
+///     events.next().unwrap(),
+///     Ok(Event::Del(b"user/1".to_vec()))
+/// );
+/// ```
+pub struct Subscriber {
+    rx: Receiver<Event>,
+    // events already received that are known to be durable
+    ready: VecDeque<Event>,
+    pages: Arc<TreeCache>,
+    // the tree stops sending events once this is dropped
+    _alive: Arc<()>,
+}
+
+impl Iterator for Subscriber {
+    type Item = DbResult<Event, ()>;
+
+    fn next(&mut self) -> Option<DbResult<Event, ()>> {
+        if self.ready.is_empty() {
+            match self.rx.recv() {
+                Ok(event) => self.ready.push_back(event),
+                Err(_) => return None,
+            }
+            while let Ok(event) = self.rx.try_recv() {
+                self.ready.push_back(event);
+            }
+            // an event is sent once its frag is linked, so one
+            // flush makes every event received so far durable
+            if let Err(e) = self.pages.flush() {
+                return Some(Err(e.danger_cast()));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+struct Watcher {
+    prefix: Key,
+    tx: Sender<Event>,
+    alive: Weak<()>,
+}
+
+// The subscribers of one tree, shared by all of its handles.
+#[derive(Default)]
+pub(super) struct Subscriptions {
+    watchers: RwLock<Vec<Watcher>>,
+    // how many watchers there are, so that writes to a tree nobody
+    // watches don't need to take the lock
+    count: AtomicUsize,
+}
+
+impl Subscriptions {
+    pub(super) fn subscribe(
+        &self,
+        prefix: &[u8],
+        pages: Arc<TreeCache>,
+    ) -> Subscriber {
+        let (tx, rx) = channel();
+        let alive = Arc::new(());
+        let mut watchers = self.watchers.write().unwrap();
+        watchers.push(Watcher {
+            prefix: prefix.to_vec(),
+            tx: tx,
+            alive: Arc::downgrade(&alive),
+        });
+        self.count.store(watchers.len(), SeqCst);
+        Subscriber {
+            rx: rx,
+            ready: VecDeque::new(),
+            pages: pages,
+            _alive: alive,
+        }
+    }
+
+    // Whether nobody is subscribed, in which case writes needn't
+    // gather the keys they remove.
+    pub(super) fn is_empty(&self) -> bool {
+        self.count.load(SeqCst) == 0
+    }
+
+    // Sends the event made by `f` to everyone watching its key.
+    // `f` is only called if the tree has subscribers.
+    pub(super) fn publish<F>(&self, f: F)
+    where
+        F: FnOnce() -> Event,
+    {
+        if self.is_empty() {
+            return;
+        }
+        let event = f();
+        let mut closed = false;
+        {
+            let watchers = self.watchers.read().unwrap();
+            for w in watchers.iter() {
+                if !event.key().starts_with(&w.prefix) {
+                    continue;
+                }
+                if w.alive.upgrade().is_none()
+                    || w.tx.send(event.clone()).is_err()
+                {
+                    closed = true;
+                }
+            }
+        }
+        if closed {
+            let mut watchers = self.watchers.write().unwrap();
+            watchers.retain(|w| w.alive.upgrade().is_some());
+            self.count.store(watchers.len(), SeqCst);
+        }
+    }
+}
+
+#[test]
+fn test_publish() {
+    let subscriptions = Subscriptions::default();
+    let mut called = false;
+    subscriptions.publish(|| {
+        called = true;
+        Event::Del(vec![1])
+    });
+    assert!(!called);
+
+    let config = ConfigBuilder::new().temporary(true).build();
+    let pages = Arc::new(PageCache::start(config).unwrap());
+    let mut a = subscriptions.subscribe(b"a", pages.clone());
+    let b = subscriptions.subscribe(b"", pages);
+    assert!(!subscriptions.is_empty());
+
+    subscriptions.publish(|| Event::Del(b"b".to_vec()));
+    subscriptions
+        .publish(|| Event::Set(b"ab".to_vec(), vec![1].into()));
+    assert_eq!(
+        a.next().unwrap(),
+        Ok(Event::Set(b"ab".to_vec(), vec![1].into()))
+    );
+
+    drop(b);
+    subscriptions.publish(|| Event::Del(b"b".to_vec()));
+    assert_eq!(subscriptions.count.load(SeqCst), 1);
+
+    drop(subscriptions);
+    assert_eq!(a.next(), None);
+}
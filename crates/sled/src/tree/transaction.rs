@@ -0,0 +1,164 @@
+// Optimistic transactions over the trees of one pagecache. Reads
+// go straight to the trees and are remembered along with what they
+// saw, while writes are staged. Commit checks that each read would
+// still see the same thing, and links all of the staged writes as
+// one batch, but only if none of the leaves read or written have
+// changed since that check, so the transaction takes effect at a
+// single point in time.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use super::tree::commit_batches;
+use super::*;
+
+/// A handle to a tree within a transaction started by
+/// `Tree::transaction`. Writes are staged until the transaction
+/// commits, and reads see the transaction's own writes.
+pub struct TransactionalTree {
+    idx: usize,
+    state: Rc<RefCell<Vec<Enlisted>>>,
+}
+
+// A tree taking part in a transaction, with what each key read
+// from it held, and the writes staged for it, `None` being a
+// deletion.
+struct Enlisted {
+    tree: Tree,
+    reads: HashMap<Key, Option<Value>>,
+    writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl TransactionalTree {
+    pub(super) fn new(tree: Tree) -> TransactionalTree {
+        TransactionalTree {
+            idx: 0,
+            state: Rc::new(RefCell::new(vec![Enlisted {
+                tree: tree,
+                reads: HashMap::new(),
+                writes: BTreeMap::new(),
+            }])),
+        }
+    }
+
+    /// Joins `tree` to this transaction, returning a handle that
+    /// commits along with this one. `tree` must belong to the same
+    /// `Db` as the tree the transaction was started on.
+    pub fn tree(
+        &self,
+        tree: &Tree,
+    ) -> DbResult<TransactionalTree, ()> {
+        let mut state = self.state.borrow_mut();
+        if !state[0].tree.shares_pages(tree) {
+            return Err(Error::Unsupported(
+                "a transaction can't span databases".to_owned(),
+            ));
+        }
+
+        let joined = state.iter().position(|e| e.tree.is_same(tree));
+        let idx = match joined {
+            Some(idx) => idx,
+            None => {
+                state.push(Enlisted {
+                    tree: tree.clone(),
+                    reads: HashMap::new(),
+                    writes: BTreeMap::new(),
+                });
+                state.len() - 1
+            }
+        };
+        Ok(TransactionalTree {
+            idx: idx,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Retrieve a value from the tree, as written earlier in this
+    /// transaction if it was.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
+        let mut state = self.state.borrow_mut();
+        let enlisted = &mut state[self.idx];
+        if let Some(written) = enlisted.writes.get(key) {
+            return Ok(written.clone());
+        }
+        if let Some(read) = enlisted.reads.get(key) {
+            return Ok(read.clone());
+        }
+
+        let value = enlisted.tree.get(key)?;
+        enlisted.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    /// Set a key to a new value when the transaction commits.
+    pub fn set<V: Into<Value>>(&self, key: Key, value: V) {
+        let mut state = self.state.borrow_mut();
+        state[self.idx].writes.insert(key, Some(value.into()));
+    }
+
+    /// Merge a value into a key with the tree's merge operator when
+    /// the transaction commits. The merge applies to what the key
+    /// holds at that point in the transaction, after any earlier
+    /// `set`, `del` or `merge` of it, and `get` sees its result.
+    /// Like `get`, merging a key the transaction hasn't written
+    /// reads it, so the transaction retries if it changes before
+    /// the commit.
+    pub fn merge<V: Into<Value>>(
+        &self,
+        key: Key,
+        value: V,
+    ) -> DbResult<(), ()> {
+        let old = self.get(&key)?;
+        let mut state = self.state.borrow_mut();
+        let enlisted = &mut state[self.idx];
+        let new = enlisted.tree.merge_value(
+            &key,
+            old.as_ref().map(|v| &**v),
+            &value.into(),
+        )?;
+        enlisted.writes.insert(key, new);
+        Ok(())
+    }
+
+    /// Delete a value when the transaction commits, returning the
+    /// value it has now if it exists.
+    pub fn del(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
+        let old = self.get(key)?;
+        let mut state = self.state.borrow_mut();
+        state[self.idx].writes.insert(key.to_vec(), None);
+        Ok(old)
+    }
+
+    // Commits the writes of every tree in the transaction, returning
+    // `false` if something read has changed since.
+    pub(super) fn commit(&self) -> DbResult<bool, ()> {
+        let state = self.state.borrow();
+        let trees: Vec<&Tree> =
+            state.iter().map(|e| &e.tree).collect();
+        let reads: Vec<Vec<(Key, Option<Value>)>> = state
+            .iter()
+            .map(|e| {
+                e.reads
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            })
+            .collect();
+        let batches: Vec<Batch> = state
+            .iter()
+            .map(|e| {
+                let mut batch = Batch::default();
+                for (k, v) in &e.writes {
+                    let k = k.clone();
+                    match *v {
+                        Some(ref v) => batch.set(k, v.clone()),
+                        None => batch.del(k),
+                    }
+                }
+                batch
+            })
+            .collect();
+        commit_batches(&trees, &reads, &batches)
+    }
+}
@@ -1,14 +1,19 @@
 use std::fmt::{self, Debug};
+use std::io;
+use std::ops::{self, RangeBounds};
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use epoch::{pin, Guard, Shared};
 
+use super::gate::Entered;
+use super::node::truncate_value;
 use super::*;
 
 impl<'a> IntoIterator for &'a Tree {
-    type Item = DbResult<(Vec<u8>, Vec<u8>), ()>;
+    type Item = DbResult<(Vec<u8>, IVec), ()>;
     type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Iter<'a> {
@@ -16,15 +21,45 @@ impl<'a> IntoIterator for &'a Tree {
     }
 }
 
+// The previous root recorded in the first root of a tree, where
+// recovery starts following the tree's chain of root hoists. Named
+// trees are found through the catalog instead, so they share one
+// marker.
+pub(crate) const DEFAULT_HEAD: PageID = std::usize::MAX;
+pub(crate) const CATALOG_HEAD: PageID = std::usize::MAX - 1;
+pub(crate) const NAMED_HEAD: PageID = std::usize::MAX - 2;
+pub(crate) const IDS_HEAD: PageID = std::usize::MAX - 3;
+
+// What `Tree::len` holds until the first call counts the keys.
+const UNKNOWN_LEN: usize = std::usize::MAX;
+
+// How many leaves `Tree::key_histogram` reads to count their keys.
+const SAMPLED_LEAVES: usize = 16;
+
 /// A flash-sympathetic persistent lock-free B+ tree
 #[derive(Clone)]
 pub struct Tree {
-    pages: Arc<
-        PageCache<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>,
-    >,
+    pages: Arc<TreeCache>,
     config: Config,
     root: Arc<AtomicUsize>,
-    merge_operator: Option<MergeOperator>,
+    // entered by every write, batches included. Reads never use it.
+    gate: Arc<WriteGate>,
+    // taken by whatever is merging nodes, since merges aren't
+    // linked in one record with the collapse of the root that may
+    // follow them
+    merging: Arc<Mutex<()>>,
+    // the number of keys, or UNKNOWN_LEN until it's first asked for.
+    // Writers update it before they leave the gate, so a count
+    // taken while the gate is held can't miss or double count one.
+    len: Arc<AtomicUsize>,
+    subscriptions: Arc<Subscriptions>,
+    indexes: Arc<Indexes>,
+    views: Arc<Views>,
+    // started by the first write with a TTL
+    sweeper: Arc<Mutex<Option<Sweeper>>>,
+    split_policy: Arc<RwLock<SplitPolicy>>,
+    // started by the first scan to move between leaves
+    prefetcher: Arc<Mutex<Option<Prefetcher>>>,
 }
 
 unsafe impl Send for Tree {}
@@ -33,112 +68,402 @@ unsafe impl Sync for Tree {}
 impl Tree {
     /// Load existing or create a new `Tree`.
     pub fn start(config: Config) -> DbResult<Tree, ()> {
-        #[cfg(any(test, feature = "check_snapshot_integrity"))]
-        match config
-            .verify_snapshot::<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>(
-            ) {
-            Ok(_) => {}
-            #[cfg(feature = "failpoints")]
-            Err(Error::FailPoint) => {}
-            other => panic!("failed to verify snapshot: {:?}", other),
+        let pages = start_pages(&config)?;
+        let roots = pages.recovered_state().unwrap_or_default();
+        Tree::start_default(pages, config, &roots)
+    }
+
+    // Opens the default tree of a pagecache, or creates it if this
+    // is a new database.
+    pub(crate) fn start_default(
+        pages: Arc<TreeCache>,
+        config: Config,
+        roots: &[(PageID, PageID)],
+    ) -> DbResult<Tree, ()> {
+        if let Some(first) = first_root(roots, DEFAULT_HEAD) {
+            return Ok(Tree::open(pages, config, roots, first));
+        }
+        if config.read_only {
+            return Err(Error::Unsupported(
+                "can't create a database in read-only mode"
+                    .to_owned(),
+            ));
         }
 
-        let pages = PageCache::start(config.clone())?;
+        let tree = Tree::create(pages, config, DEFAULT_HEAD)?;
+        assert_eq!(
+            tree.root_id(),
+            0,
+            "we expect that this is the first page ever allocated"
+        );
+        Ok(tree)
+    }
 
-        let roots_opt = pages.recovered_state().clone().and_then(
-            |mut roots: Vec<(PageID, PageID)>| {
-                if roots.is_empty() {
-                    None
-                } else {
-                    let mut last = std::usize::MAX;
-                    let mut last_idx = std::usize::MAX;
-                    while !roots.is_empty() {
-                        // find the root that links to the last one
-                        for (i, &(root, prev_root)) in
-                            roots.iter().enumerate()
-                        {
-                            if prev_root == last {
-                                last = root;
-                                last_idx = i;
-                                break;
-                            }
-                            assert_ne!(
-                                i + 1,
-                                roots.len(),
-                                "encountered gap in root chain"
-                            );
-                        }
-                        roots.remove(last_idx);
-                    }
-                    assert_ne!(last, std::usize::MAX);
-                    Some(last)
-                }
+    // Opens the tree whose first root was `first`, following
+    // `roots` to the root it has been hoisted to since.
+    pub(crate) fn open(
+        pages: Arc<TreeCache>,
+        config: Config,
+        roots: &[(PageID, PageID)],
+        first: PageID,
+    ) -> Tree {
+        let root_id = last_root(roots, first);
+        debug!("recovered root {} while starting tree", root_id);
+        let split_policy = SplitPolicy::from_config(&config);
+        Tree {
+            pages: pages,
+            config: config,
+            root: Arc::new(AtomicUsize::new(root_id)),
+            gate: Arc::new(WriteGate::default()),
+            merging: Arc::new(Mutex::new(())),
+            len: Arc::new(AtomicUsize::new(UNKNOWN_LEN)),
+            subscriptions: Arc::new(Subscriptions::default()),
+            indexes: Arc::new(Indexes::default()),
+            views: Arc::new(Views::default()),
+            sweeper: Arc::new(Mutex::new(None)),
+            split_policy: Arc::new(RwLock::new(split_policy)),
+            prefetcher: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Creates an empty tree with a root and a single leaf. Its root
+    // records `head` as its previous root, which is how recovery
+    // tells the chains of different trees apart.
+    pub(crate) fn create(
+        pages: Arc<TreeCache>,
+        config: Config,
+        head: PageID,
+    ) -> DbResult<Tree, ()> {
+        let guard = pin();
+        let root_id = pages.allocate(&guard)?;
+        debug!("allocated pid {} for root of new tree", root_id);
+
+        let leaf_id = pages.allocate(&guard)?;
+        trace!("allocated pid {} for leaf in new", leaf_id);
+
+        let leaf = Frag::Base(
+            Node {
+                id: leaf_id,
+                data: empty_leaf(&config, head),
+                next: None,
+                prev: None,
+                lo: Bound::Inclusive(vec![]),
+                hi: Bound::Inf,
+                prefix: None,
+                bloom: None,
+                expiries: Expiries::default(),
+                recent: RecentWrites::default(),
             },
+            None,
         );
 
-        let root_id = if let Some(root_id) = roots_opt {
-            debug!("recovered root {} while starting tree", root_id);
-            root_id
-        } else {
-            let guard = pin();
-            let root_id = pages.allocate(&guard)?;
-            assert_eq!(
-                root_id,
-                0,
-                "we expect that this is the first page ever allocated"
-            );
-            debug!("allocated pid {} for root of new tree", root_id);
-
-            let leaf_id = pages.allocate(&guard)?;
-            trace!("allocated pid {} for leaf in new", leaf_id);
-
-            let leaf = Frag::Base(
-                Node {
-                    id: leaf_id,
-                    data: Data::Leaf(vec![]),
-                    next: None,
-                    lo: Bound::Inclusive(vec![]),
-                    hi: Bound::Inf,
-                },
-                None,
-            );
+        // vec![0] represents a prefix-encoded empty prefix
+        let root_index_vec = vec![(vec![0], leaf_id)];
 
-            // vec![0] represents a prefix-encoded empty prefix
-            let root_index_vec = vec![(vec![0], leaf_id)];
-
-            let root = Frag::Base(
-                Node {
-                    id: root_id,
-                    data: Data::Index(root_index_vec),
-                    next: None,
-                    lo: Bound::Inclusive(vec![]),
-                    hi: Bound::Inf,
-                },
-                Some(std::usize::MAX),
-            );
+        let root = Frag::Base(
+            Node {
+                id: root_id,
+                data: Data::Index(root_index_vec),
+                next: None,
+                prev: None,
+                lo: Bound::Inclusive(vec![]),
+                hi: Bound::Inf,
+                prefix: None,
+                bloom: None,
+                expiries: Expiries::default(),
+                recent: RecentWrites::default(),
+            },
+            Some(head),
+        );
 
-            pages
-                .replace(root_id, Shared::null(), root, &guard)
-                .map_err(|e| e.danger_cast())?;
-            pages
-                .replace(leaf_id, Shared::null(), leaf, &guard)
-                .map_err(|e| e.danger_cast())?;
-            root_id
-        };
+        pages
+            .replace(root_id, Shared::null(), root, &guard)
+            .map_err(|e| e.danger_cast())?;
+        pages
+            .replace(leaf_id, Shared::null(), leaf, &guard)
+            .map_err(|e| e.danger_cast())?;
 
+        let split_policy = SplitPolicy::from_config(&config);
         Ok(Tree {
-            pages: Arc::new(pages),
+            pages: pages,
             config: config,
             root: Arc::new(AtomicUsize::new(root_id)),
-            merge_operator: None,
+            gate: Arc::new(WriteGate::default()),
+            merging: Arc::new(Mutex::new(())),
+            len: Arc::new(AtomicUsize::new(0)),
+            subscriptions: Arc::new(Subscriptions::default()),
+            indexes: Arc::new(Indexes::default()),
+            views: Arc::new(Views::default()),
+            sweeper: Arc::new(Mutex::new(None)),
+            split_policy: Arc::new(RwLock::new(split_policy)),
+            prefetcher: Arc::new(Mutex::new(None)),
         })
     }
 
+    // The current root of this tree.
+    pub(crate) fn root_id(&self) -> PageID {
+        self.root.load(SeqCst)
+    }
+
+    // Adds `delta` to the number of keys, unless they haven't been
+    // counted yet. Callers must have entered the gate.
+    fn adjust_len(&self, delta: isize) {
+        let mut len = self.len.load(SeqCst);
+        while len != UNKNOWN_LEN && delta != 0 {
+            let new = (len as isize + delta) as usize;
+            match self.len.compare_exchange(len, new, SeqCst, SeqCst)
+            {
+                Ok(_) => return,
+                Err(actual) => len = actual,
+            }
+        }
+    }
+
+    // Replaces the root with an index node over `ptrs`, returning
+    // the first child of the root it replaced. The root keeps its
+    // page and its link to the previous root, so the chain of
+    // roots recovery follows is intact. Callers must hold the
+    // gate.
+    fn install_root(
+        &self,
+        ptrs: Vec<(Key, PageID)>,
+        guard: &Guard,
+    ) -> DbResult<Option<(Key, PageID)>, ()> {
+        let root_id = self.root_id();
+        loop {
+            let get = self.pages
+                .get(root_id, guard)
+                .map_err(|e| e.danger_cast())?;
+            let (old_root, prev_root, cas_key) = match get {
+                PageGet::Materialized(
+                    Frag::Base(node, prev_root),
+                    cas_key,
+                ) => (node, prev_root, cas_key),
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base root while replacing it: {:?}",
+                        broken
+                    )))
+                }
+            };
+            let root = Node {
+                id: root_id,
+                data: Data::Index(ptrs.clone()),
+                next: None,
+                prev: None,
+                lo: Bound::Inclusive(vec![]),
+                hi: Bound::Inf,
+                prefix: None,
+                bloom: None,
+                expiries: Expiries::default(),
+                recent: RecentWrites::default(),
+            };
+            let replace = self.pages.replace(
+                root_id,
+                cas_key,
+                Frag::Base(root, prev_root),
+                guard,
+            );
+            match replace {
+                Ok(_) => return Ok(old_root.iter_index().next()),
+                // a split below installed itself in the root
+                Err(Error::CasFailed(_)) => M.tree_looped(),
+                Err(other) => return Err(other.danger_cast()),
+            }
+        }
+    }
+
+    // Writes a node built by `bulk_load` to its freshly allocated
+    // page.
+    fn write_loaded(
+        &self,
+        node: Node,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let id = node.id;
+        let base = Frag::Base(node, None);
+        self.pages
+            .replace(id, Shared::null(), base, guard)
+            .map_err(|e| e.danger_cast())?;
+        Ok(())
+    }
+
+    // `value` as it should be set: truncated to `max_value_len`,
+    // and written to a blob first if it's at least `blob_threshold`
+    // long, in which case the value refers to the blob's page. This
+    // is the only place a set value is truncated, so each one that
+    // is cut short is counted once in `values_truncated`.
+    fn store_value(
+        &self,
+        value: Value,
+        guard: &Guard,
+    ) -> DbResult<Value, ()> {
+        let mut value = value.unblobbed();
+        if truncate_value(&mut value, self.config.max_value_len) {
+            M.value_truncated();
+        }
+        match self.config.blob_threshold {
+            Some(threshold) if value.len() >= threshold => {}
+            _ => return Ok(value),
+        }
+        let pid = self.pages.allocate(guard)?;
+        self.pages
+            .replace(
+                pid,
+                Shared::null(),
+                Frag::Blob(value.clone()),
+                guard,
+            )
+            .map_err(|e| e.danger_cast())?;
+        Ok(value.with_blob(pid))
+    }
+
+    // The blobs that the records of `node` refer to. Only a tree
+    // that writes blobs looks for them.
+    fn blobs_of(&self, node: &Node) -> Vec<PageID> {
+        if self.config.blob_threshold.is_some() {
+            blob::blobs(node)
+        } else {
+            vec![]
+        }
+    }
+
+    // `batch` with the values it sets prepared by `store_value`, or
+    // `None` if this tree doesn't write blobs.
+    fn store_batch(
+        &self,
+        batch: &Batch,
+        guard: &Guard,
+    ) -> DbResult<Option<Batch>, ()> {
+        if self.config.blob_threshold.is_none() {
+            return Ok(None);
+        }
+        batch.map_sets(|v| self.store_value(v, guard)).map(Some)
+    }
+
+    // Frees the pages of blobs that no record refers to any more.
+    fn free_blobs<I>(
+        &self,
+        pids: I,
+        guard: &Guard,
+    ) -> DbResult<(), ()>
+    where
+        I: IntoIterator<Item = PageID>,
+    {
+        for pid in pids {
+            self.pages
+                .free(pid, guard)
+                .map_err(|e| e.danger_cast())?;
+        }
+        Ok(())
+    }
+
+    // Frees every page of this tree except its past and present
+    // roots, whose records recovery still follows when it rebuilds
+    // the root chains of other trees. The tree must not be used
+    // afterwards.
+    pub(crate) fn free_pages(&self) -> DbResult<(), ()> {
+        let guard = pin();
+        self.free_levels(self.root_id(), &guard)
+    }
+
+    // Frees the pages of the level starting at `first` and of every
+    // level below it, except for past and present roots.
+    fn free_levels(
+        &self,
+        first: PageID,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let mut level = Some(first);
+        while let Some(first) = level.take() {
+            let mut cursor = Some(first);
+            while let Some(pid) = cursor {
+                let get = self.pages
+                    .get(pid, guard)
+                    .map_err(|e| e.danger_cast())?;
+                let (node, prev_root) = match get {
+                    PageGet::Materialized(
+                        Frag::Base(node, prev_root),
+                        _,
+                    ) => (node, prev_root),
+                    broken => {
+                        return Err(Error::ReportableBug(format!(
+                            "got non-base node while freeing tree: \
+                             {:?}",
+                            broken
+                        )))
+                    }
+                };
+                if pid == first {
+                    if let Data::Index(ref ptrs) = node.data {
+                        level = ptrs.first().map(|&(_, child)| child);
+                    }
+                }
+                if prev_root.is_none() {
+                    self.pages
+                        .free(pid, guard)
+                        .map_err(|e| e.danger_cast())?;
+                }
+                self.free_blobs(self.blobs_of(&node), guard)?;
+                cursor = node.next;
+            }
+        }
+        Ok(())
+    }
+
     /// Flushes any pending IO buffers to disk to ensure durability.
     pub fn flush(&self) -> CacheResult<(), ()> {
         self.pages.flush()
     }
 
+    /// Returns a handle that is done once every write made so far
+    /// is durable, leaving the caller free to carry on meanwhile.
+    /// The writes are flushed within `flush_every_ms` by the
+    /// background flusher, or when `FlushHandle::wait` is called,
+    /// whichever comes first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .flush_every_ms(Some(500))
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![1]).unwrap();
+    /// let flushed = t.flush_async();
+    /// t.set(vec![2], vec![2]).unwrap();
+    /// flushed.wait().unwrap();
+    /// ```
+    pub fn flush_async(&self) -> FlushHandle {
+        self.pages.flush_async()
+    }
+
+    /// Returns how far writes are ahead of the disk. Once more
+    /// than `max_unflushed_bytes` of them aren't durable, or more
+    /// than `max_resident_bytes` of pages are resident, writes wait
+    /// for the log to catch up or for pages to be paged out, and
+    /// the `try_` writes refuse to. The pressure is shared by every
+    /// tree of a `Db`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .max_unflushed_bytes(Some(1 << 20))
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![1]).unwrap();
+    /// let pressure = t.pressure();
+    /// assert!(pressure.level() < 1.0);
+    /// t.flush().unwrap();
+    /// assert_eq!(t.pressure().unflushed_bytes, 0);
+    /// ```
+    pub fn pressure(&self) -> Pressure {
+        self.pages.pressure()
+    }
+
     /// Retrieve a value from the `Tree` if it exists.
     pub fn get(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
         let guard = pin();
@@ -146,11 +471,265 @@ impl Tree {
         Ok(ret)
     }
 
+    /// Returns `true` if the `Tree` contains a value for the
+    /// specified key. This is the primary read for trees opened
+    /// with `key_set`, which store no values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .key_set(true)
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![]);
+    /// assert_eq!(t.contains_key(&[1]), Ok(true));
+    /// assert_eq!(t.contains_key(&[2]), Ok(false));
+    /// ```
+    pub fn contains_key(&self, key: &[u8]) -> DbResult<bool, ()> {
+        let guard = pin();
+        let path = self.path_for_key(key, &guard)?;
+        let &(ref leaf, _) = path.last().expect(
+            "path_for_key should always return a path \
+             of length >= 2 (root + leaf)",
+        );
+        let encoded_key = prefix_encode(leaf.prefix(), key);
+        Ok(leaf.contains_key(&*encoded_key))
+    }
+
+    /// Walks the key sequence generated by `step`, beginning at
+    /// `start`, and returns the first key that isn't in the tree,
+    /// for allocating dense ids stored as keys. `step` must return
+    /// a key greater than the one it's given. Another thread may
+    /// take the key before it's written, so claim it with
+    /// `cas(key, None, ..)` and search again if that fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![0], vec![]).unwrap();
+    /// t.set(vec![1], vec![]).unwrap();
+    /// t.set(vec![3], vec![]).unwrap();
+    /// let next = |k: &[u8]| vec![k[0] + 1];
+    /// assert_eq!(t.first_gap(&[0], next), Ok(vec![2]));
+    /// assert_eq!(t.first_gap(&[3], next), Ok(vec![4]));
+    /// ```
+    pub fn first_gap<F>(
+        &self,
+        start: &[u8],
+        step: F,
+    ) -> DbResult<Key, ()>
+    where
+        F: Fn(&[u8]) -> Key,
+    {
+        let guard = pin();
+        let mut candidate = start.to_vec();
+        loop {
+            let path = self.path_for_key(&*candidate, &guard)?;
+            let &(ref leaf, _) = path.last().expect(
+                "path_for_key should always return a path \
+                 of length >= 2 (root + leaf)",
+            );
+            match leaf.first_gap(&*candidate, &step) {
+                Ok(gap) => return Ok(gap),
+                Err(next) => candidate = next,
+            }
+        }
+    }
+
+    /// Retrieves the values of many keys at once, returned in the
+    /// order the keys were given. The keys are looked up in sorted
+    /// order, so the index is descended once for each distinct leaf
+    /// they fall in rather than once per key, under a single pin
+    /// and read lock. Keys in the same leaf are read from the same
+    /// version of it, but writes may land between leaves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// let values = t.get_many(&[&[2], &[3], &[1]]).unwrap();
+    /// assert_eq!(
+    ///     values,
+    ///     vec![Some(vec![20].into()), None, Some(vec![10].into())]
+    /// );
+    /// ```
+    pub fn get_many(
+        &self,
+        keys: &[&[u8]],
+    ) -> DbResult<Vec<Option<Value>>, ()> {
+        let guard = pin();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| keys[i]);
+
+        let mut ret = vec![None; keys.len()];
+        let mut leaf: Option<Node> = None;
+        for i in order {
+            let key = keys[i];
+            // keys come in ascending order, so the leaf covers this
+            // one unless it lies past the leaf's hi
+            let covered = leaf.as_ref().map_or(false, |node| {
+                match node.hi {
+                    Bound::Inf => true,
+                    ref hi => key < hi.inner(),
+                }
+            });
+            if !covered {
+                let mut path = self.path_for_key(key, &guard)?;
+                let (node, _) = path.pop().expect(
+                    "path_for_key should always return a path \
+                     of length >= 2 (root + leaf)",
+                );
+                leaf = Some(node);
+            }
+            let node = leaf.as_ref().unwrap();
+            let encoded_key = prefix_encode(node.prefix(), key);
+            ret[i] = node.leaf_value(&*encoded_key).cloned();
+        }
+        Ok(ret)
+    }
+
+    /// Returns the key and value with the greatest key strictly
+    /// less than `key`, if there is one. Only the leaf `key` falls
+    /// in and, if that holds nothing smaller, the leaves to its
+    /// left are read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![3], vec![30]);
+    /// assert_eq!(t.get_lt(&[3]), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.get_lt(&[4]), Ok(Some((vec![3], vec![30].into()))));
+    /// assert_eq!(t.get_lt(&[1]), Ok(None));
+    /// ```
+    pub fn get_lt(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<(Key, Value)>, ()> {
+        let guard = pin();
+        // every key at or above `hi` has been ruled out
+        let mut hi = key.to_vec();
+        let mut cursor = self.leaf_for_key(&hi, &guard)?;
+        // whether `cursor` is the left sibling of a leaf starting
+        // at `hi`
+        let mut stepped_left = false;
+        loop {
+            let node = match self.get_node(cursor, &guard)? {
+                Some(node)
+                    if !node.data.is_index()
+                        && (!stepped_left
+                            || node.lo.inner() < &*hi) =>
+                {
+                    node
+                }
+                _ => {
+                    // the leaf was merged into its left sibling, so
+                    // find where the keys below `hi` live now
+                    cursor = self.leaf_for_key(&hi, &guard)?;
+                    stepped_left = false;
+                    continue;
+                }
+            };
+            let short = match node.hi {
+                Bound::Inf => false,
+                ref node_hi => node_hi.inner() < &*hi,
+            };
+            if short {
+                // a split moved some keys below `hi` to the right
+                cursor = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                stepped_left = false;
+                continue;
+            }
+            if let Some((k, v)) = node.predecessor(&hi) {
+                return Ok(Some((k, v.clone())));
+            }
+            match node.prev {
+                Some(prev) => {
+                    cursor = prev;
+                    hi = node.lo.inner().to_vec();
+                    stepped_left = true;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the key and value with the smallest key strictly
+    /// greater than `key`, if there is one. Only the leaf `key`
+    /// falls in and, if that holds nothing greater, the leaves to
+    /// its right are read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![3], vec![30]);
+    /// assert_eq!(t.get_gt(&[1]), Ok(Some((vec![3], vec![30].into()))));
+    /// assert_eq!(t.get_gt(&[0]), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.get_gt(&[3]), Ok(None));
+    /// ```
+    pub fn get_gt(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<(Key, Value)>, ()> {
+        let guard = pin();
+        let mut cursor = self.leaf_for_key(key, &guard)?;
+        // where the leaf at `cursor` should start, when we got there
+        // from its left sibling
+        let mut expected_lo: Option<Key> = None;
+        loop {
+            let node = match self.get_node(cursor, &guard)? {
+                Some(node)
+                    if !node.data.is_index()
+                        && expected_lo.as_ref().map_or(true, |lo| {
+                            &**lo == node.lo.inner()
+                        }) =>
+                {
+                    node
+                }
+                _ => {
+                    // the leaf was merged into its left sibling, so
+                    // start over from the leaf `key` falls in
+                    cursor = self.leaf_for_key(key, &guard)?;
+                    expected_lo = None;
+                    continue;
+                }
+            };
+            if let Some((k, v)) = node.successor(key) {
+                return Ok(Some((k, v.clone())));
+            }
+            match node.next {
+                Some(next) => {
+                    cursor = next;
+                    expected_lo = Some(node.hi.inner().to_vec());
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
     /// Compare and swap. Capable of unique creation, conditional modification,
     /// or deletion. If old is None, this will only set the value if it doesn't
     /// exist yet. If new is None, will delete the value if old is correct.
     /// If both old and new are Some, will modify the value if old is correct.
-    /// If Tree is read-only, will do nothing.
+    /// If Tree is read-only, fails with `Error::Unsupported`.
+    /// On a mismatch, returns `Error::CasFailed` holding the value
+    /// that was actually present, or `None` if the key was absent,
+    /// so a retry loop can try again without reading the key.
     ///
     /// # Examples
     ///
@@ -160,55 +739,108 @@ impl Tree {
     /// let t = sled::Tree::start(config).unwrap();
     ///
     /// // unique creation
-    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Ok(()));
-    /// assert_eq!(t.cas(vec![1], None, Some(vec![1])), Err(Error::CasFailed(Some(vec![1]))));
+    /// let absent: Option<&[u8]> = None;
+    /// assert_eq!(t.cas(vec![1], absent, Some(vec![1])), Ok(()));
+    /// assert_eq!(t.cas(vec![1], absent, Some(vec![1])), Err(Error::CasFailed(Some(vec![1].into()))));
     ///
     /// // conditional modification
     /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Ok(()));
-    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Err(Error::CasFailed(Some(vec![2]))));
+    /// assert_eq!(t.cas(vec![1], Some(vec![1]), Some(vec![2])), Err(Error::CasFailed(Some(vec![2].into()))));
     ///
     /// // conditional deletion
-    /// assert_eq!(t.cas(vec![1], Some(vec![2]), None), Ok(()));
+    /// assert_eq!(t.cas(vec![1], Some(vec![2]), absent), Ok(()));
     /// assert_eq!(t.get(&*vec![1]), Ok(None));
     /// ```
-    pub fn cas(
+    pub fn cas<OV, NV>(
         &self,
         key: Key,
-        old: Option<Value>,
-        new: Option<Value>,
-    ) -> DbResult<(), Option<Value>> {
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> DbResult<(), Option<Value>>
+    where
+        OV: AsRef<[u8]>,
+        NV: Into<Value>,
+    {
+        let old = old.as_ref().map(|o| o.as_ref());
+        let new = new.map(|n| n.into());
         if self.config.read_only {
-            return Err(Error::CasFailed(None));
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let _writes = self.gate.enter();
+        // a tree that isn't batched now stays so until we're done
+        if self.is_batched() {
+            drop(_writes);
+            return self.cas_batched(key, old, new);
         }
         // we need to retry caps until old != cur, since just because
         // cap fails it doesn't mean our value was changed.
         let guard = pin();
+        let new = match new {
+            Some(n) => Some(
+                self.store_value(n, &guard)
+                    .map_err(|e| e.danger_cast())?,
+            ),
+            None => None,
+        };
+        let written = new.as_ref().and_then(|n| n.blob());
         loop {
-            let (mut path, cur) = self.get_internal(&*key, &guard)
+            let (path, cur) = self.get_internal(&*key, &guard)
                 .map_err(|e| e.danger_cast())?;
 
-            if old != cur {
+            if old != cur.as_ref().map(|c| &**c) {
+                self.free_blobs(written, &guard)
+                    .map_err(|e| e.danger_cast())?;
                 return Err(Error::CasFailed(cur));
             }
 
-            let &mut (ref node, ref cas_key) = path.last_mut()
-                .expect(
+            let &(ref node, ref cas_key) = path.last().expect(
                 "get_internal somehow returned a path of length zero",
             );
             let encoded_key = prefix_encode(node.lo.inner(), &*key);
             let frag = if let Some(ref n) = new {
-                Frag::Set(encoded_key, n.clone())
+                blob::set_frag(encoded_key, n.clone())
             } else {
                 Frag::Del(encoded_key)
             };
-            let link = self.pages.link(
+            match node.check_capacity(&frag, &self.config) {
+                Ok(()) => {}
+                Err(NodeError::NodeFull(_)) => {
+                    // split the full leaf before retrying the write
+                    self.recursive_split(&path, false, &guard)
+                        .map_err(|e| e.danger_cast())?;
+                    M.tree_looped();
+                    continue;
+                }
+                Err(other) => {
+                    self.free_blobs(written, &guard)
+                        .map_err(|e| e.danger_cast())?;
+                    return Err(Error::Unsupported(other.to_string()))
+                }
+            }
+            let link = self.pages.link(
                 node.id,
                 cas_key.clone(),
                 frag.clone(),
                 &guard,
             );
             match link {
-                Ok(_) => return Ok(()),
+                Ok(_) => {
+                    self.adjust_len(match (&cur, &new) {
+                        (&None, &Some(_)) => 1,
+                        (&Some(_), &None) => -1,
+                        _ => 0,
+                    });
+                    let dropped = cur.and_then(|c| c.blob());
+                    self.free_blobs(dropped, &guard)
+                        .map_err(|e| e.danger_cast())?;
+                    self.subscriptions.publish(|| match new {
+                        Some(v) => Event::Set(key, v),
+                        None => Event::Del(key),
+                    });
+                    return Ok(());
+                }
                 Err(Error::CasFailed(_)) => {}
                 Err(other) => return Err(other.danger_cast()),
             }
@@ -216,14 +848,226 @@ impl Tree {
         }
     }
 
+    /// Compare and swap, unless writes are being held back by
+    /// `max_unflushed_bytes` or `max_resident_bytes`, in which case
+    /// it fails with an `Error::Io` of kind `WouldBlock` rather
+    /// than waiting, and writes nothing.
+    pub fn try_cas<OV, NV>(
+        &self,
+        key: Key,
+        old: Option<OV>,
+        new: Option<NV>,
+    ) -> DbResult<(), Option<Value>>
+    where
+        OV: AsRef<[u8]>,
+        NV: Into<Value>,
+    {
+        self.refuse_if_throttling().map_err(|e| e.danger_cast())?;
+        self.cas(key, old, new)
+    }
+
+    /// Replaces the value of a key with the result of calling `f`
+    /// on the current one, returning the value it replaced. `None`
+    /// in either place means the key is absent, so returning
+    /// `None` deletes the key. The write is a `cas` against the
+    /// value `f` saw, and when another writer gets there first `f`
+    /// is called again with the value the failed `cas` returned,
+    /// so it may run more than once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// let incr = |old: Option<&[u8]>| {
+    ///     let n = old.map_or(0, |v| v[0]);
+    ///     Some(vec![n + 1])
+    /// };
+    /// assert_eq!(t.fetch_and_update(b"counter", incr), Ok(None));
+    /// assert_eq!(
+    ///     t.fetch_and_update(b"counter", incr),
+    ///     Ok(Some(vec![1].into()))
+    /// );
+    /// assert_eq!(t.get(b"counter"), Ok(Some(vec![2].into())));
+    /// ```
+    pub fn fetch_and_update<F, V>(
+        &self,
+        key: &[u8],
+        mut f: F,
+    ) -> DbResult<Option<Value>, ()>
+    where
+        F: FnMut(Option<&[u8]>) -> Option<V>,
+        V: Into<Value>,
+    {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let mut current = self.get(key)?;
+        loop {
+            let new = f(current.as_ref().map(|v| &**v));
+            match self.cas(key.to_vec(), current.clone(), new) {
+                Ok(()) => return Ok(current),
+                Err(Error::CasFailed(actual)) => {
+                    current = actual;
+                    M.tree_looped();
+                }
+                Err(other) => return Err(other.danger_cast()),
+            }
+        }
+    }
+
+    /// Deletes a key, returning the value it held, with the same
+    /// `cas` loop as `fetch_and_update`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// assert_eq!(t.fetch_and_del(&[1]), Ok(Some(vec![10].into())));
+    /// assert_eq!(t.fetch_and_del(&[1]), Ok(None));
+    /// ```
+    pub fn fetch_and_del(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<Value>, ()> {
+        self.fetch_and_update(key, |_| None::<Value>)
+    }
+
     /// Set a key to a new value.
-    pub fn set(&self, key: Key, value: Value) -> DbResult<(), ()> {
+    pub fn set<V>(&self, key: Key, value: V) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        self.set_inner(key, value.into(), None)
+    }
+
+    /// Set a key to a new value, unless writes are being held back
+    /// by `max_unflushed_bytes` or `max_resident_bytes`, in which
+    /// case it fails with an `Error::Io` of kind `WouldBlock`
+    /// rather than waiting, and writes nothing.
+    pub fn try_set<V>(&self, key: Key, value: V) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        self.refuse_if_throttling()?;
+        self.set(key, value)
+    }
+
+    /// Set a key to a new value, returning once the write is as
+    /// durable as `durability` asks. Waiting on a flush makes the
+    /// writes made before this one just as durable, but leaves
+    /// writes in flight on other threads to be flushed later.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::Durability;
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set_with_durability(vec![1], vec![1], Durability::Buffered)
+    ///     .unwrap();
+    /// t.set_with_durability(vec![2], vec![2], Durability::Synced)
+    ///     .unwrap();
+    /// assert_eq!(t.get(&[1]), Ok(Some(vec![1].into())));
+    /// ```
+    pub fn set_with_durability<V>(
+        &self,
+        key: Key,
+        value: V,
+        durability: Durability,
+    ) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        self.set_inner(key, value.into(), None)?;
+        match durability {
+            Durability::Buffered => Ok(()),
+            Durability::Flushed => self.flush_async().wait(),
+            Durability::Synced => {
+                self.flush_async().wait()?;
+                if self.config.temporary {
+                    self.config.file()?.sync()?;
+                    M.fsynced();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Set a key to a new value that expires after `ttl`. Once it
+    /// has, reads treat the key as absent, and a merge into it
+    /// starts from nothing. Any later write to the key replaces
+    /// the expiration, so a plain `set` makes it permanent again.
+    ///
+    /// The first call starts a background thread that deletes
+    /// expired keys every `expire_every_ms`, see `sweep_expired`.
+    /// Until a key is swept, it still counts towards `len` and
+    /// takes up space in its leaf.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// let ttl = Duration::from_millis(10);
+    /// t.set_with_ttl(b"session".to_vec(), vec![1], ttl).unwrap();
+    /// assert_eq!(t.get(b"session"), Ok(Some(vec![1].into())));
+    ///
+    /// std::thread::sleep(Duration::from_millis(20));
+    /// assert_eq!(t.get(b"session"), Ok(None));
+    /// ```
+    pub fn set_with_ttl<V>(
+        &self,
+        key: Key,
+        value: V,
+        ttl: Duration,
+    ) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        let ttl_ms = ttl.as_secs() * 1000
+            + u64::from(ttl.subsec_millis());
+        let at = now_millis().saturating_add(ttl_ms);
+        self.set_inner(key, value.into(), Some(at))?;
+        self.start_sweeper();
+        Ok(())
+    }
+
+    // Sets a key, expiring it at `expires` if given.
+    pub(crate) fn set_inner(
+        &self,
+        key: Key,
+        value: Value,
+        expires: Option<u64>,
+    ) -> DbResult<(), ()> {
         if self.config.read_only {
             return Err(Error::Unsupported(
                 "the database is in read-only mode".to_owned(),
             ));
         }
+        let _writes = self.gate.enter();
+        if self.is_batched() {
+            drop(_writes);
+            if expires.is_some() {
+                return Err(Error::Unsupported(
+                    "keys with a TTL can't be written to an indexed \
+                     tree, or while a read view of it is open"
+                        .to_owned(),
+                ));
+            }
+            let mut batch = Batch::default();
+            batch.set(key, value);
+            return self.apply_batch(batch);
+        }
         let guard = pin();
+        let value = self.store_value(value, &guard)?;
         loop {
             let mut path = self.path_for_key(&*key, &guard)?;
             let (mut last_node, last_cas_key) = path.pop().expect(
@@ -232,7 +1076,29 @@ impl Tree {
             );
             let encoded_key =
                 prefix_encode(last_node.lo.inner(), &*key);
-            let frag = Frag::Set(encoded_key, value.clone());
+            let set =
+                blob::set_frag(encoded_key.clone(), value.clone());
+            match last_node.check_capacity(&set, &self.config) {
+                Ok(()) => {}
+                Err(NodeError::NodeFull(_)) => {
+                    // split the full leaf before retrying the write
+                    path.push((last_node, last_cas_key));
+                    self.recursive_split(&path, false, &guard)?;
+                    M.tree_looped();
+                    continue;
+                }
+                Err(other) => {
+                    self.free_blobs(value.blob(), &guard)?;
+                    return Err(Error::Unsupported(other.to_string()))
+                }
+            }
+            let frag = match expires {
+                Some(at) => Frag::Writes(vec![
+                    set,
+                    Frag::Expire(encoded_key, at),
+                ]),
+                None => set,
+            };
             let link = self.pages.link(
                 last_node.id,
                 last_cas_key,
@@ -241,14 +1107,25 @@ impl Tree {
             );
             match link {
                 Ok(new_cas_key) => {
+                    let before = last_node.data.len();
+                    let blobs = self.blobs_of(&last_node);
                     last_node
-                        .apply(&frag, self.config.merge_operator);
-                    let should_split = last_node
-                        .should_split(self.config.blink_fanout);
+                        .apply(&frag, &self.config)
+                        .map_err(|e| {
+                            Error::ReportableBug(e.to_string())
+                        })?;
+                    let after = last_node.data.len();
+                    self.adjust_len(after as isize - before as isize);
+                    let left = self.blobs_of(&last_node);
+                    let dropped = blob::dropped(blobs, &left);
+                    self.free_blobs(dropped, &guard)?;
+                    let should_split = self.should_split(&last_node);
                     path.push((last_node.clone(), new_cas_key));
+                    self.subscriptions
+                        .publish(|| Event::Set(key.clone(), value));
                     // success
                     if should_split {
-                        self.recursive_split(&path, &guard)?;
+                        self.recursive_split(&path, false, &guard)?;
                     }
                     return Ok(());
                 }
@@ -290,24 +1167,35 @@ impl Tree {
     /// tree.set(k.clone(), vec![0]);
     /// tree.merge(k.clone(), vec![1]);
     /// tree.merge(k.clone(), vec![2]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![0, 1, 2])));
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![0, 1, 2].into())));
     ///
     /// // sets replace previously merged data,
     /// // bypassing the merge function.
     /// tree.set(k.clone(), vec![3]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![3])));
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![3].into())));
     ///
     /// // merges on non-present values will add them
     /// tree.del(&k);
     /// tree.merge(k.clone(), vec![4]);
-    /// assert_eq!(tree.get(&k), Ok(Some(vec![4])));
+    /// assert_eq!(tree.get(&k), Ok(Some(vec![4].into())));
     /// ```
-    pub fn merge(&self, key: Key, value: Value) -> DbResult<(), ()> {
+    pub fn merge<V>(&self, key: Key, value: V) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        let value = value.into();
         if self.config.read_only {
             return Err(Error::Unsupported(
                 "the database is in read-only mode".to_owned(),
             ));
         }
+        let _writes = self.gate.enter();
+        if self.is_batched() {
+            drop(_writes);
+            let mut batch = Batch::default();
+            batch.merge(key, value);
+            return self.apply_batch(batch);
+        }
         let guard = pin();
         loop {
             let mut path = self.path_for_key(&*key, &guard)?;
@@ -319,6 +1207,19 @@ impl Tree {
             let encoded_key =
                 prefix_encode(last_node.lo.inner(), &*key);
             let frag = Frag::Merge(encoded_key, value.clone());
+            match last_node.check_capacity(&frag, &self.config) {
+                Ok(()) => {}
+                Err(NodeError::NodeFull(_)) => {
+                    // split the full leaf before retrying the write
+                    path.push((last_node, last_cas_key));
+                    self.recursive_split(&path, false, &guard)?;
+                    M.tree_looped();
+                    continue;
+                }
+                Err(other) => {
+                    return Err(Error::Unsupported(other.to_string()))
+                }
+            }
 
             let link = self.pages.link(
                 last_node.id,
@@ -328,14 +1229,38 @@ impl Tree {
             );
             match link {
                 Ok(new_cas_key) => {
-                    last_node
-                        .apply(&frag, self.config.merge_operator);
-                    let should_split = last_node
-                        .should_split(self.config.blink_fanout);
+                    let before = last_node.data.len();
+                    let blobs = self.blobs_of(&last_node);
+                    let split_hint = last_node
+                        .apply(&frag, &self.config)
+                        .map_err(|e| {
+                            Error::ReportableBug(e.to_string())
+                        })?;
+                    let after = last_node.data.len();
+                    self.adjust_len(after as isize - before as isize);
+                    let left = self.blobs_of(&last_node);
+                    let dropped = blob::dropped(blobs, &left);
+                    self.free_blobs(dropped, &guard)?;
+                    let should_split =
+                        split_hint || self.should_split(&last_node);
+                    let merged = last_node
+                        .leaf_value(&prefix_encode(
+                            last_node.prefix(),
+                            &key,
+                        ))
+                        .cloned();
                     path.push((last_node.clone(), new_cas_key));
+                    self.subscriptions.publish(|| match merged {
+                        Some(v) => Event::Merge(key.clone(), v),
+                        None => Event::Del(key.clone()),
+                    });
                     // success
                     if should_split {
-                        self.recursive_split(&path, &guard)?;
+                        self.recursive_split(
+                            &path,
+                            split_hint,
+                            &guard,
+                        )?;
                     }
                     return Ok(());
                 }
@@ -346,6 +1271,19 @@ impl Tree {
         }
     }
 
+    /// Merge a new value into the total state for a key, unless
+    /// writes are being held back by `max_unflushed_bytes` or
+    /// `max_resident_bytes`, in which case it fails with an
+    /// `Error::Io` of kind `WouldBlock` rather than waiting, and
+    /// writes nothing.
+    pub fn try_merge<V>(&self, key: Key, value: V) -> DbResult<(), ()>
+    where
+        V: Into<Value>,
+    {
+        self.refuse_if_throttling()?;
+        self.merge(key, value)
+    }
+
     /// Delete a value, returning the last result if it existed.
     ///
     /// # Examples
@@ -354,50 +1292,59 @@ impl Tree {
     /// let config = sled::ConfigBuilder::new().temporary(true).build();
     /// let t = sled::Tree::start(config).unwrap();
     /// t.set(vec![1], vec![1]);
-    /// assert_eq!(t.del(&*vec![1]), Ok(Some(vec![1])));
+    /// assert_eq!(t.del(&*vec![1]), Ok(Some(vec![1].into())));
     /// assert_eq!(t.del(&*vec![1]), Ok(None));
     /// ```
     pub fn del(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
         if self.config.read_only {
-            return Ok(None);
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let _writes = self.gate.enter();
+        if self.is_batched() {
+            drop(_writes);
+            return self.del_batched(key);
         }
         let guard = pin();
         let mut ret: Option<Value>;
+        let mut underfull = false;
         loop {
             let mut path = self.path_for_key(&*key, &guard)?;
-            let (leaf_node, leaf_cas_key) = path.pop().expect(
+            let (mut leaf_node, leaf_cas_key) = path.pop().expect(
                 "path_for_key should always return a path \
                  of length >= 2 (root + leaf)",
             );
-            let encoded_key =
-                prefix_encode(leaf_node.lo.inner(), key);
-            match leaf_node.data {
-                Data::Leaf(ref items) => {
-                    let search =
-                        items.binary_search_by(|&(ref k, ref _v)| {
-                            prefix_cmp(k, &*encoded_key)
-                        });
-                    if let Ok(idx) = search {
-                        ret = Some(items[idx].1.clone());
-                    } else {
-                        ret = None;
-                        break;
-                    }
-                }
-                _ => panic!("last node in path is not leaf"),
+            let stored_key = prefix_encode(leaf_node.prefix(), key);
+            if let Some(v) = leaf_node.leaf_value(&*stored_key) {
+                ret = Some(v.clone());
+            } else {
+                ret = None;
+                break;
             }
 
+            let encoded_key =
+                prefix_encode(leaf_node.lo.inner(), key);
             let frag = Frag::Del(encoded_key);
             let link = self.pages.link(
                 leaf_node.id,
                 leaf_cas_key,
-                frag,
+                frag.clone(),
                 &guard,
             );
 
             match link {
                 Ok(_) => {
                     // success
+                    self.adjust_len(-1);
+                    let blob = ret.as_ref().and_then(|v| v.blob());
+                    self.free_blobs(blob, &guard)?;
+                    self.subscriptions
+                        .publish(|| Event::Del(key.to_vec()));
+                    let config = &self.config;
+                    underfull = config.merge_threshold.is_some()
+                        && leaf_node.apply(&frag, config).is_ok()
+                        && leaf_node.should_merge(config);
                     break;
                 }
                 Err(Error::CasFailed(_)) => {
@@ -407,76 +1354,2103 @@ impl Tree {
                 Err(other) => return Err(other.danger_cast()),
             }
         }
+        drop(_writes);
+        if underfull {
+            self.merge_underfull(key, &guard)?;
+        }
         Ok(ret)
     }
 
-    /// Iterate over tuples of keys and values, starting at the provided key.
+    /// Delete a value, returning the last result if it existed,
+    /// unless writes are being held back by `max_unflushed_bytes`
+    /// or `max_resident_bytes`, in which case it fails with an
+    /// `Error::Io` of kind `WouldBlock` rather than waiting, and
+    /// deletes nothing.
+    pub fn try_del(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
+        self.refuse_if_throttling()?;
+        self.del(key)
+    }
+
+    /// Delete every key in `range`, returning how many were
+    /// removed. Rather than a Del per key, each leaf the range
+    /// touches gets a single frag that drops all of its records in
+    /// the range. Leaves are visited one after another, so the
+    /// removal is not atomic: a concurrent reader may see part of
+    /// it, and a key written into the range while it runs may
+    /// survive.
     ///
     /// # Examples
     ///
     /// ```
     /// let config = sled::ConfigBuilder::new().temporary(true).build();
     /// let t = sled::Tree::start(config).unwrap();
-    /// t.set(vec![1], vec![10]);
-    /// t.set(vec![2], vec![20]);
-    /// t.set(vec![3], vec![30]);
-    /// let mut iter = t.scan(&*vec![2]);
-    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30]))));
-    /// assert_eq!(iter.next(), None);
+    /// for i in 0..10 {
+    ///     t.set(vec![i], vec![i]);
+    /// }
+    /// assert_eq!(t.remove_range(vec![2]..vec![5]), Ok(3));
+    /// assert_eq!(t.remove_range(vec![8]..), Ok(2));
+    /// let keys: Vec<_> =
+    ///     t.iter().map(|r| r.unwrap().0[0]).collect();
+    /// assert_eq!(keys, vec![0, 1, 5, 6, 7]);
     /// ```
-    pub fn scan(&self, key: &[u8]) -> Iter {
+    pub fn remove_range<K, R>(&self, range: R) -> DbResult<usize, ()>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        // a key's immediate successor is the key with a zero byte
+        // appended, which lets every range become `[lo, hi)`
+        let successor = |k: &K| {
+            let mut k = k.as_ref().to_vec();
+            k.push(0);
+            k
+        };
+        let lo = match range.start_bound() {
+            ops::Bound::Included(k) => k.as_ref().to_vec(),
+            ops::Bound::Excluded(k) => successor(k),
+            ops::Bound::Unbounded => vec![],
+        };
+        let hi = match range.end_bound() {
+            ops::Bound::Included(k) => Bound::Exclusive(successor(k)),
+            ops::Bound::Excluded(k) => {
+                Bound::Exclusive(k.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => Bound::Inf,
+        };
+        let lo = Bound::Inclusive(lo);
+        if lo >= hi {
+            return Ok(0);
+        }
+
+        let _writes = self.gate.enter();
+        if self.is_indexed() {
+            return Err(Error::Unsupported(
+                "remove_range can't maintain the tree's indexes"
+                    .to_owned(),
+            ));
+        }
+        if self.is_viewed() {
+            return Err(Error::Unsupported(
+                "remove_range can't be used while a read view of the \
+                 tree is open"
+                    .to_owned(),
+            ));
+        }
         let guard = pin();
-        let mut broken = None;
-        let id = match self.get_internal(key, &guard) {
-            Ok((ref path, _)) if !path.is_empty() => {
-                let &(ref last_node, ref _last_cas_key) =
-                    path.last().expect("path is not empty");
-                last_node.id
-            }
-            Ok(_) => {
-                broken = Some(Error::ReportableBug(
-                    "failed to get path for key".to_owned(),
-                ));
-                0
+        let mut removed = 0;
+        let mut underfull = vec![];
+        let mut cursor = self.leaf_for_key(lo.inner(), &guard)?;
+        // where the leaf at `cursor` should start, when we got there
+        // from its left sibling, which tells us if it has been
+        // merged away since
+        let mut expected_lo: Option<Key> = None;
+        loop {
+            let get = self.pages
+                .get(cursor, &guard)
+                .map_err(|e| e.danger_cast())?;
+            let (mut node, cas_key) = match get {
+                PageGet::Materialized(Frag::Base(node, _), cas_key)
+                    if !node.data.is_index()
+                        && expected_lo.as_ref().map_or(true, |lo| {
+                            &**lo == node.lo.inner()
+                        }) =>
+                {
+                    (node, cas_key)
+                }
+                _ => {
+                    // the leaf was merged into its left sibling, so
+                    // find where the rest of the range lives now
+                    let resume = expected_lo
+                        .take()
+                        .unwrap_or_else(|| lo.inner().to_vec());
+                    cursor = self.leaf_for_key(&resume, &guard)?;
+                    continue;
+                }
+            };
+
+            // the keys to send a Del for, gathered before del_range
+            // drops them
+            let mut watched_keys = vec![];
+            if !self.subscriptions.is_empty() {
+                watched_keys.extend(
+                    node.iter()
+                        .map(|(k, _)| k)
+                        .filter(|k| in_range(k, &lo, &hi)),
+                );
             }
-            Err(e) => {
-                broken = Some(e.danger_cast());
-                0
+            let blobs = self.blobs_of(&node);
+            let count = node.del_range(&lo, &hi);
+            if count > 0 {
+                let frag = Frag::DelRange(lo.clone(), hi.clone());
+                match self.pages.link(cursor, cas_key, frag, &guard) {
+                    Ok(_) => {}
+                    Err(Error::CasFailed(_)) => {
+                        M.tree_looped();
+                        continue;
+                    }
+                    Err(other) => return Err(other.danger_cast()),
+                }
+                removed += count;
+                let left = self.blobs_of(&node);
+                self.free_blobs(blob::dropped(blobs, &left), &guard)?;
+                for key in watched_keys {
+                    self.subscriptions.publish(|| Event::Del(key));
+                }
+                if node.should_merge(&self.config) {
+                    underfull.push(node.lo.inner().to_vec());
+                }
             }
-        };
-        Iter {
-            id: id,
-            inner: &self.pages,
-            last_key: Bound::Exclusive(key.to_vec()),
-            broken: broken,
-            done: false,
+
+            let past_end = match node.hi {
+                Bound::Inf => true,
+                ref node_hi => {
+                    Bound::Inclusive(node_hi.inner().to_vec()) >= hi
+                }
+            };
+            match node.next {
+                Some(next) if !past_end => {
+                    cursor = next;
+                    expected_lo = Some(node.hi.inner().to_vec());
+                }
+                _ => break,
+            }
+        }
+        self.adjust_len(-(removed as isize));
+        drop(_writes);
+        for key in underfull {
+            self.merge_underfull(&key, &guard)?;
         }
+        Ok(removed)
     }
 
-    /// Iterate over the tuples of keys and values in this tree.
+    /// Returns the number of keys in the tree. The first call
+    /// counts them by reading every leaf, holding up writes to the
+    /// tree until it's done, and every write keeps the count up to
+    /// date from then on, so later calls are free. A write still
+    /// in flight may or may not be counted yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![1]);
+    /// t.set(vec![2], vec![2]);
+    /// t.set(vec![2], vec![3]);
+    /// assert_eq!(t.len(), Ok(2));
+    /// t.del(&[1]);
+    /// assert_eq!(t.len(), Ok(1));
+    /// ```
+    pub fn len(&self) -> DbResult<usize, ()> {
+        let len = self.len.load(SeqCst);
+        if len != UNKNOWN_LEN {
+            return Ok(len);
+        }
+
+        let _held = self.gate.hold();
+        let len = self.len.load(SeqCst);
+        if len != UNKNOWN_LEN {
+            return Ok(len);
+        }
+        let guard = pin();
+        let mut len = 0;
+        let mut cursor = Some(self.leaf_for_key(b"", &guard)?);
+        while let Some(pid) = cursor {
+            let node = self.get_node(pid, &guard)?.ok_or_else(|| {
+                Error::ReportableBug(format!(
+                    "leaf {} was freed while counting keys",
+                    pid
+                ))
+            })?;
+            len += node.data.len();
+            cursor = node.next;
+        }
+        self.len.store(len, SeqCst);
+        Ok(len)
+    }
+
+    /// When this tree splits its nodes, which starts out as the
+    /// `blink_fanout` and `max_node_size` of its config.
+    pub fn split_policy(&self) -> SplitPolicy {
+        *self.split_policy.read().unwrap()
+    }
+
+    /// Changes when this tree splits its nodes, for every handle
+    /// to it, so that trees of the same `Db` holding very
+    /// different records can have nodes of different shapes.
+    /// Nodes already over a new, smaller limit are split the next
+    /// time they are written to, rather than right away. The
+    /// policy lasts until the tree is closed, so it has to be set
+    /// again each time the database is started. Fails with
+    /// `Error::Unsupported` for a fanout below 2, or less than
+    /// twice the `merge_threshold`. See `SplitPolicy` for an
+    /// example.
+    pub fn set_split_policy(
+        &self,
+        policy: SplitPolicy,
+    ) -> DbResult<(), ()> {
+        policy.check(&self.config)?;
+        *self.split_policy.write().unwrap() = policy;
+        Ok(())
+    }
+
+    /// Walks every level of the tree to report its shape and the
+    /// space its records take up. Writes carry on during the walk,
+    /// so on a tree being written to the result is approximate: a
+    /// node may be counted before or after a write to it, and one
+    /// that splits or merges while it's read may be counted twice.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"a".to_vec(), vec![1, 2, 3]);
+    /// t.set(b"b".to_vec(), vec![4]);
+    /// let stats = t.stats().unwrap();
+    /// assert_eq!(stats.records, 2);
+    /// assert_eq!(stats.key_bytes, 2);
+    /// assert_eq!(stats.value_bytes, 4);
+    /// assert_eq!(stats.distinct_values, 2);
+    /// ```
+    pub fn stats(&self) -> DbResult<TreeStats, ()> {
+        let guard = pin();
+        let mut stats = TreeStats::default();
+        // the first node of each level, from the root down
+        let firsts = self.path_for_key(b"", &guard)?;
+        stats.height = firsts.len();
+        for (depth, (first, _)) in firsts.into_iter().enumerate() {
+            let mut cursor = Some(first);
+            while let Some(node) = cursor {
+                stats.pages += 1;
+                if !node.data.is_index() {
+                    stats.leaves += 1;
+                    stats.distinct_values +=
+                        node.distinct_value_estimate();
+                    for (k, v) in node.iter() {
+                        stats.records += 1;
+                        stats.key_bytes += k.len();
+                        stats.value_bytes += v.len();
+                    }
+                }
+                cursor = self.next_on_level(&node, depth, &guard)?;
+            }
+        }
+
+        let fanout = self.split_policy().fanout as usize;
+        let split_len = self.config
+            .max_leaf_len
+            .map_or(fanout, |cap| cap.min(fanout));
+        if stats.leaves > 0 {
+            stats.avg_leaf_fill = stats.records as f64
+                / stats.leaves as f64
+                / split_len as f64;
+        }
+        Ok(stats)
+    }
+
+    /// Divides the tree's keys into a bucket per leaf, bounded by
+    /// the separators in the lowest level of its index, with
+    /// roughly how many keys each holds, so that how the keys of a
+    /// large tree are distributed can be judged without scanning
+    /// it. Only the index and up to 16 evenly spaced leaves are
+    /// read: their keys are counted, and every other leaf is
+    /// assumed to hold their average. Leaves split as they fill
+    /// up, so buckets are narrower where keys are denser. Writes
+    /// carry on meanwhile, so the counts of a tree being written to
+    /// are approximate. The keys of a leaf that has split but isn't
+    /// linked into its parent yet are counted with its left
+    /// sibling's.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"a".to_vec(), vec![1]).unwrap();
+    /// t.set(b"b".to_vec(), vec![2]).unwrap();
+    /// let buckets = t.key_histogram().unwrap();
+    /// assert_eq!(buckets.len(), 1);
+    /// assert_eq!(buckets[0].keys, 2);
+    /// ```
+    pub fn key_histogram(&self) -> DbResult<Vec<KeyBucket>, ()> {
+        let guard = pin();
+        // the first node of each level, from the root down
+        let mut firsts = self.path_for_key(b"", &guard)?;
+        if firsts.len() < 2 {
+            let root = firsts.pop().expect("a path has a root").0;
+            return Ok(vec![KeyBucket {
+                lo: vec![],
+                hi: None,
+                keys: root.data.len(),
+                counted: true,
+            }]);
+        }
+
+        // the children of the lowest level of the index are leaves
+        let depth = firsts.len() - 2;
+        let mut cursor = Some(firsts.swap_remove(depth).0);
+        let mut leaves: Vec<(Key, PageID)> = vec![];
+        while let Some(node) = cursor {
+            for (lo, pid) in node.iter_index() {
+                // a node read after a merge may repeat separators
+                let after = leaves
+                    .last()
+                    .map_or(true, |&(ref last, _)| lo > *last);
+                if after {
+                    leaves.push((lo, pid));
+                }
+            }
+            cursor = self.next_on_level(&node, depth, &guard)?;
+        }
+        let step =
+            (leaves.len() + SAMPLED_LEAVES - 1) / SAMPLED_LEAVES;
+        let mut counts = vec![None; leaves.len()];
+        for i in (0..leaves.len()).step_by(step) {
+            // a leaf merged away since is left to the average
+            counts[i] = self
+                .get_node(leaves[i].1, &guard)?
+                .map(|leaf| leaf.data.len());
+        }
+        let sampled: Vec<usize> =
+            counts.iter().filter_map(|&count| count).collect();
+        let average = (sampled.iter().sum::<usize>()
+            + sampled.len() / 2)
+            / sampled.len().max(1);
+
+        let mut buckets = Vec::with_capacity(leaves.len());
+        for (i, &(ref lo, _)) in leaves.iter().enumerate() {
+            buckets.push(KeyBucket {
+                lo: lo.clone(),
+                hi: leaves.get(i + 1).map(|&(ref hi, _)| hi.clone()),
+                keys: counts[i].unwrap_or(average),
+                counted: counts[i].is_some(),
+            });
+        }
+        Ok(buckets)
+    }
+
+    /// Returns up to `n` keys, in order, that divide the tree into
+    /// `n + 1` ranges holding about the same number of keys, as
+    /// split points for sharding it. They are worked out from the
+    /// buckets of `key_histogram`, so the tree isn't scanned. The
+    /// keys are separators from the index, which fall between keys
+    /// of the tree but needn't be keys of it, and no range is
+    /// narrower than a leaf, so a small tree gives fewer than `n`,
+    /// and one that fits in a single leaf gives none.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .blink_fanout(8)
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..100u8 {
+    ///     t.set(vec![i], vec![]).unwrap();
+    /// }
+    /// let splits = t.key_sample(3).unwrap();
+    /// assert_eq!(splits.len(), 3);
+    /// assert!(splits[0] < splits[1] && splits[1] < splits[2]);
+    /// ```
+    pub fn key_sample(&self, n: usize) -> DbResult<Vec<Key>, ()> {
+        let buckets = self.key_histogram()?;
+        let total: usize = buckets.iter().map(|b| b.keys).sum();
+        let mut sample: Vec<Key> = vec![];
+        let mut before = 0;
+        let mut i = 1;
+        for bucket in &buckets {
+            let after = before + bucket.keys;
+            // the i-th split point falls in this bucket, so split
+            // at whichever end of it is closer
+            while i <= n && total * i / (n + 1) < after {
+                let target = total * i / (n + 1);
+                let closer_to_hi = after - target < target - before;
+                let split = match bucket.hi {
+                    Some(ref hi) if closer_to_hi => hi,
+                    _ => &bucket.lo,
+                };
+                let repeated = sample.last() == Some(split);
+                if !split.is_empty() && !repeated {
+                    sample.push(split.clone());
+                }
+                i += 1;
+            }
+            before = after;
+        }
+        Ok(sample)
+    }
+
+    /// Walks the tree level by level, checking that the nodes on
+    /// each level abut and cover every key, that each node's
+    /// records are sorted and within its bounds, that each
+    /// separator in an index node matches the lo of the child it
+    /// points to, and that every frag of every page reads back from
+    /// the log with a valid checksum. Problems are collected rather
+    /// than returned as errors, so one bad page doesn't hide the
+    /// rest.
+    ///
+    /// A split or merge in flight would look like a broken level,
+    /// so this holds the tree's writes off for the whole walk:
+    /// writes in flight are waited for, and every write to the
+    /// tree that starts before the walk is done stalls until it
+    /// is, including the time taken to read every frag back from
+    /// the log. On a large tree that's a long stall, so this is
+    /// meant for maintenance windows and tests rather than for a
+    /// tree serving writes. Other trees of the same `Db` aren't
+    /// held up.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"a".to_vec(), vec![1]).unwrap();
+    /// let report = t.verify_integrity().unwrap();
+    /// assert!(report.is_ok(), "{:?}", report.problems);
+    /// ```
+    pub fn verify_integrity(&self) -> DbResult<TreeIntegrity, ()> {
+        let _held = self.gate.hold();
+        // frags still in the log's buffers can't be read back
+        self.pages.flush()?;
+        let guard = pin();
+        let mut report = TreeIntegrity::default();
+        let mut parents = vec![];
+        let mut level = Some(self.root_id());
+        while let Some(first) = level.take() {
+            let mut nodes: Vec<Node> = vec![];
+            let mut seen = std::collections::HashSet::new();
+            let mut complete = true;
+            let mut cursor = Some(first);
+            while let Some(pid) = cursor.take() {
+                let problems = &mut report.problems;
+                if !seen.insert(pid) {
+                    problems.push(IntegrityProblem::Cycle {
+                        pid: pid,
+                    });
+                    complete = false;
+                    break;
+                }
+                report.pages += 1;
+                let (frags, failed) = self.pages.verify_page(pid)?;
+                report.frags += frags;
+                for lid in failed {
+                    problems.push(IntegrityProblem::Checksum {
+                        pid: pid,
+                        lid: lid,
+                    });
+                }
+                match self.get_node(pid, &guard) {
+                    Ok(Some(node)) => {
+                        if let Err(reason) = node.check_invariants() {
+                            problems.push(IntegrityProblem::Node {
+                                pid: pid,
+                                reason: reason,
+                            });
+                        }
+                        cursor = node.next;
+                        nodes.push(node);
+                    }
+                    Ok(None) | Err(Error::Corruption { .. }) => {
+                        problems.push(IntegrityProblem::Missing {
+                            pid: pid,
+                        });
+                        complete = false;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            check_level(&parents, &nodes, complete, &mut report);
+
+            level = match nodes.first().map(|node| &node.data) {
+                Some(&Data::Index(ref ptrs)) => {
+                    ptrs.first().map(|&(_, child)| child)
+                }
+                _ => None,
+            };
+            parents = nodes;
+        }
+        Ok(report)
+    }
+
+    /// Returns the page graph of the tree: each node's bounds,
+    /// sibling, children and how many frags make up its page, for
+    /// debugging splits and merges. `TreeDump::to_dot` renders it
+    /// for graphviz. Writes carry on meanwhile, so the graph of a
+    /// tree being written to may mix nodes read before and after a
+    /// split or merge.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"a".to_vec(), vec![1]).unwrap();
+    /// let dump = t.dump_structure().unwrap();
+    /// assert_eq!(dump.nodes[0].id, dump.root);
+    /// assert!(dump.to_dot().starts_with("digraph"));
+    /// ```
+    pub fn dump_structure(&self) -> DbResult<TreeDump, ()> {
+        let guard = pin();
+        // the first node of each level, from the root down
+        let firsts = self.path_for_key(b"", &guard)?;
+        let mut dump = TreeDump {
+            root: firsts[0].0.id,
+            nodes: vec![],
+        };
+        let fanout = self.split_policy().fanout as usize;
+        let split_len = self.config
+            .max_leaf_len
+            .map_or(fanout, |cap| cap.min(fanout));
+        for (depth, (first, _)) in firsts.into_iter().enumerate() {
+            let mut cursor = Some(first);
+            while let Some(node) = cursor {
+                // a node that absorbed its right sibling while we
+                // read it is read again, and its later state kept
+                if dump.nodes.last().map(|n| n.id) == Some(node.id) {
+                    dump.nodes.pop();
+                }
+                let children: Vec<PageID> = match node.data {
+                    Data::Index(ref ptrs) => {
+                        ptrs.iter().map(|&(_, child)| child).collect()
+                    }
+                    _ => vec![],
+                };
+                let stats = if node.data.is_index() {
+                    node.stats(fanout)
+                } else {
+                    node.stats(split_len)
+                };
+                dump.nodes.push(NodeDump {
+                    id: node.id,
+                    level: depth,
+                    leaf: !node.data.is_index(),
+                    lo: node.lo.inner().to_vec(),
+                    hi: match node.hi {
+                        Bound::Inf => None,
+                        ref hi => Some(hi.inner().to_vec()),
+                    },
+                    next: node.next,
+                    frags: self.pages.chain_len(node.id),
+                    len: stats.entry_count,
+                    bytes: stats.byte_len,
+                    fill: stats.fill_ratio,
+                    children: children,
+                    encoding: node.encoding(),
+                });
+                cursor = self.next_on_level(&node, depth, &guard)?;
+            }
+        }
+        Ok(dump)
+    }
+
+    // The `lo` of the leaf at `pid`, which the keys of the frags
+    // linked to it are encoded against, if it is one of this
+    // tree's leaves.
+    pub(crate) fn leaf_lo(
+        &self,
+        pid: PageID,
+    ) -> DbResult<Option<Key>, ()> {
+        let guard = pin();
+        let node = match self.get_node(pid, &guard)? {
+            Some(ref node) if node.data.is_index() => return Ok(None),
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        if self.leaf_for_key(node.lo.inner(), &guard)? != pid {
+            return Ok(None);
+        }
+        Ok(Some(node.lo.inner().to_vec()))
+    }
+
+    /// Removes every key, leaving the root pointing at a single
+    /// empty leaf, and frees the pages that held them. The new
+    /// root is written as one log record, so after a crash the
+    /// tree is either cleared or untouched, though a crash before
+    /// the old pages are freed leaks them. Waits for writes in
+    /// flight to finish, but an iterator running alongside may
+    /// still return keys that were removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// for i in 0..100 {
+    ///     t.set(vec![i], vec![i]);
+    /// }
+    /// t.clear().unwrap();
+    /// assert_eq!(t.len(), Ok(0));
+    /// assert_eq!(t.get(&[1]), Ok(None));
+    /// ```
+    pub fn clear(&self) -> DbResult<(), ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let _held = self.gate.hold();
+        if self.is_indexed() {
+            return Err(Error::Unsupported(
+                "clear can't maintain the tree's indexes".to_owned(),
+            ));
+        }
+        if self.is_viewed() {
+            return Err(Error::Unsupported(
+                "clear can't be used while a read view of the tree \
+                 is open"
+                    .to_owned(),
+            ));
+        }
+        let guard = pin();
+
+        let mut watched_keys = vec![];
+        if !self.subscriptions.is_empty() {
+            let mut cursor = Some(self.leaf_for_key(b"", &guard)?);
+            while let Some(pid) = cursor {
+                let node =
+                    self.get_node(pid, &guard)?.ok_or_else(|| {
+                        Error::ReportableBug(format!(
+                            "leaf {} was freed while clearing tree",
+                            pid
+                        ))
+                    })?;
+                watched_keys.extend(node.iter().map(|(k, _)| k));
+                cursor = node.next;
+            }
+        }
+
+        // the catalog and the id tree are the only trees with a
+        // different kind of leaf, and they are never cleared
+        let leaf_id = self.pages.allocate(&guard)?;
+        let leaf = Node {
+            id: leaf_id,
+            data: empty_leaf(&self.config, DEFAULT_HEAD),
+            next: None,
+            prev: None,
+            lo: Bound::Inclusive(vec![]),
+            hi: Bound::Inf,
+            prefix: None,
+            bloom: None,
+            expiries: Expiries::default(),
+            recent: RecentWrites::default(),
+        };
+        self.pages
+            .replace(
+                leaf_id,
+                Shared::null(),
+                Frag::Base(leaf, None),
+                &guard,
+            )
+            .map_err(|e| e.danger_cast())?;
+
+        // vec![0] represents a prefix-encoded empty prefix
+        let old_first =
+            self.install_root(vec![(vec![0], leaf_id)], &guard)?;
+
+        self.len.store(0, SeqCst);
+        for key in watched_keys {
+            self.subscriptions.publish(|| Event::Del(key));
+        }
+        match old_first {
+            Some((_, first)) => self.free_levels(first, &guard),
+            None => Ok(()),
+        }
+    }
+
+    /// Loads records into an empty tree from an iterator yielding
+    /// them in strictly ascending key order, returning how many
+    /// were loaded. Rather than inserting each record with its own
+    /// CAS loop and splitting leaves as they fill, this fills each
+    /// leaf up to the point where it would split, writes it as a
+    /// new page, and then builds the index nodes above the leaves
+    /// a level at a time. The new leaves and index nodes can't be
+    /// reached until the root is replaced at the end, in one log
+    /// record, so after a crash the tree is either fully loaded or
+    /// still empty, though a crash before then leaks the new
+    /// pages.
+    ///
+    /// Fails without loading anything if the tree holds any keys,
+    /// has indexes, or is given a key that isn't greater than the
+    /// one before it or is longer than `max_key_len`. Writes wait
+    /// for the load to finish. Subscribers see a `Set` for every
+    /// record once it is done.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// let records = (0..1000u32).map(|i| {
+    ///     (i.to_be_bytes().to_vec(), vec![0; 10])
+    /// });
+    /// assert_eq!(t.bulk_load(records), Ok(1000));
+    /// assert_eq!(t.len(), Ok(1000));
+    /// let value = t.get(&7u32.to_be_bytes()).unwrap();
+    /// assert_eq!(value, Some(vec![0; 10].into()));
+    /// ```
+    pub fn bulk_load<I, V>(&self, records: I) -> DbResult<usize, ()>
+    where
+        I: IntoIterator<Item = (Key, V)>,
+        V: Into<Value>,
+    {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let _held = self.gate.hold();
+        if self.is_indexed() {
+            return Err(Error::Unsupported(
+                "bulk_load can't maintain the tree's indexes"
+                    .to_owned(),
+            ));
+        }
+        if self.is_viewed() {
+            return Err(Error::Unsupported(
+                "bulk_load can't be used while a read view of the \
+                 tree is open"
+                    .to_owned(),
+            ));
+        }
+        if let Some(res) = self.iter().next() {
+            res?;
+            return Err(Error::Unsupported(
+                "bulk_load needs an empty tree".to_owned(),
+            ));
+        }
+        let guard = pin();
+
+        let mut written = vec![];
+        let mut events = vec![];
+        let loaded = self.load_levels(
+            records.into_iter().map(|(k, v)| (k, v.into())),
+            &mut written,
+            &mut events,
+            &guard,
+        );
+        let (top, count) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                for pid in written {
+                    self.pages
+                        .free(pid, &guard)
+                        .map_err(|e| e.danger_cast())?;
+                }
+                return Err(e);
+            }
+        };
+
+        let ptrs = top
+            .into_iter()
+            .map(|(lo, pid)| (prefix_encode(b"", &lo), pid))
+            .collect();
+        let old_first = self.install_root(ptrs, &guard)?;
+
+        self.len.store(count, SeqCst);
+        for (key, value) in events {
+            self.subscriptions.publish(|| Event::Set(key, value));
+        }
+        if let Some((_, first)) = old_first {
+            self.free_levels(first, &guard)?;
+        }
+        Ok(count)
+    }
+
+    // Writes the leaves holding `records`, and the index nodes
+    // above them up to the level that fits in the root, pushing
+    // the id of every page it allocates, blobs included, to
+    // `written`. Returns the lo and id of each node of that level,
+    // and how many records were loaded. `events` gets each record
+    // if anyone is subscribed to the tree.
+    fn load_levels<I>(
+        &self,
+        records: I,
+        written: &mut Vec<PageID>,
+        events: &mut Vec<(Key, Value)>,
+        guard: &Guard,
+    ) -> DbResult<(Vec<(Key, PageID)>, usize), ()>
+    where
+        I: Iterator<Item = (Key, Value)>,
+    {
+        let config = &self.config;
+        let policy = self.split_policy();
+        let publish = !self.subscriptions.is_empty();
+        let mut level = vec![];
+        let mut count = 0;
+
+        let leaf_id = self.pages.allocate(guard)?;
+        written.push(leaf_id);
+        let data = empty_leaf(config, DEFAULT_HEAD);
+        let mut leaf = loaded_node(leaf_id, data, None, vec![]);
+        let mut last: Option<Key> = None;
+        for (key, value) in records {
+            if let Some(ref last) = last {
+                if key <= *last {
+                    return Err(Error::Unsupported(format!(
+                        "bulk_load was given key {:?} after {:?}, \
+                         but keys must be strictly ascending",
+                        key, last
+                    )));
+                }
+            }
+            let value = self.store_value(value, guard)?;
+            written.extend(value.blob());
+            if publish {
+                events.push((key.clone(), value.clone()));
+            }
+            let loaded = load_record(
+                &mut leaf,
+                &key,
+                value.clone(),
+                &policy,
+                config,
+            )?;
+            if !loaded {
+                // the leaf is full, so start its right sibling
+                let next_id = self.pages.allocate(guard)?;
+                written.push(next_id);
+                let lo = leaf_separator(last.as_ref().unwrap(), &key);
+                let data = empty_leaf(config, DEFAULT_HEAD);
+                let next =
+                    loaded_node(next_id, data, Some(leaf.id), lo);
+                leaf.hi = Bound::Exclusive(next.lo.inner().to_vec());
+                leaf.next = Some(next_id);
+                let full = std::mem::replace(&mut leaf, next);
+                level.push((full.lo.inner().to_vec(), full.id));
+                self.write_loaded(full, guard)?;
+                load_record(&mut leaf, &key, value, &policy, config)?;
+            }
+            last = Some(key);
+            count += 1;
+        }
+        level.push((leaf.lo.inner().to_vec(), leaf.id));
+        self.write_loaded(leaf, guard)?;
+
+        loop {
+            let mut groups = pack_index(level, &policy, config);
+            if groups.len() == 1 {
+                return Ok((groups.pop().unwrap(), count));
+            }
+            let mut ids = vec![];
+            for _ in 0..groups.len() {
+                let id = self.pages.allocate(guard)?;
+                written.push(id);
+                ids.push(id);
+            }
+            let los: Vec<Key> =
+                groups.iter().map(|g| g[0].0.clone()).collect();
+            level = vec![];
+            for (i, children) in groups.into_iter().enumerate() {
+                let lo = los[i].clone();
+                let ptrs = children
+                    .into_iter()
+                    .map(|(k, pid)| (prefix_encode(&lo, &k), pid))
+                    .collect();
+                let prev = if i > 0 {
+                    Some(ids[i - 1])
+                } else {
+                    None
+                };
+                let mut node =
+                    loaded_node(ids[i], Data::Index(ptrs), prev, lo);
+                if let Some(next_lo) = los.get(i + 1) {
+                    node.hi = Bound::Exclusive(next_lo.clone());
+                    node.next = Some(ids[i + 1]);
+                }
+                level.push((los[i].clone(), node.id));
+                self.write_loaded(node, guard)?;
+            }
+        }
+    }
+
+    /// Deletes every key whose TTL has passed, returning how many
+    /// were removed. Each leaf holding expired keys gets a single
+    /// frag deleting all of them, so sweeping many keys pushes
+    /// their leaves towards consolidation, which reclaims their
+    /// space. Subscribers see a `Del` for each key. This runs in
+    /// the background every `expire_every_ms` once a key has been
+    /// written with a TTL, but can be called directly, for
+    /// instance when `expire_every_ms` is `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let config = sled::ConfigBuilder::new()
+    ///     .temporary(true)
+    ///     .expire_every_ms(None)
+    ///     .build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set_with_ttl(vec![1], vec![1], Duration::from_millis(1));
+    /// t.set(vec![2], vec![2]);
+    /// std::thread::sleep(Duration::from_millis(10));
+    /// assert_eq!(t.len(), Ok(2));
+    /// assert_eq!(t.sweep_expired(), Ok(1));
+    /// assert_eq!(t.len(), Ok(1));
+    /// ```
+    pub fn sweep_expired(&self) -> DbResult<usize, ()> {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        let _writes = self.gate.enter();
+        let guard = pin();
+        let mut removed = 0;
+        let mut underfull = vec![];
+        let mut cursor = self.leaf_for_key(b"", &guard)?;
+        // where the leaf at `cursor` should start, when we got there
+        // from its left sibling
+        let mut expected_lo: Option<Key> = None;
+        loop {
+            let get = self.pages
+                .get(cursor, &guard)
+                .map_err(|e| e.danger_cast())?;
+            let (mut node, cas_key) = match get {
+                PageGet::Materialized(Frag::Base(node, _), cas_key)
+                    if !node.data.is_index()
+                        && expected_lo.as_ref().map_or(true, |lo| {
+                            &**lo == node.lo.inner()
+                        }) =>
+                {
+                    (node, cas_key)
+                }
+                _ => {
+                    // the leaf was merged into its left sibling, so
+                    // find where the rest of the tree lives now
+                    let resume =
+                        expected_lo.take().unwrap_or_default();
+                    cursor = self.leaf_for_key(&resume, &guard)?;
+                    continue;
+                }
+            };
+
+            let expired = node.expired_keys();
+            if !expired.is_empty() {
+                let lo = node.lo.inner().to_vec();
+                let dels = expired
+                    .iter()
+                    .map(|k| Frag::Del(prefix_encode(&lo, k)))
+                    .collect();
+                let frag = Frag::Writes(dels);
+                let link = self.pages.link(
+                    cursor,
+                    cas_key,
+                    frag.clone(),
+                    &guard,
+                );
+                match link {
+                    Ok(_) => {}
+                    Err(Error::CasFailed(_)) => {
+                        M.tree_looped();
+                        continue;
+                    }
+                    Err(other) => return Err(other.danger_cast()),
+                }
+                removed += expired.len();
+                self.adjust_len(-(expired.len() as isize));
+                for key in expired {
+                    self.subscriptions.publish(|| Event::Del(key));
+                }
+                let blobs = self.blobs_of(&node);
+                node.apply(&frag, &self.config).map_err(|e| {
+                    Error::ReportableBug(e.to_string())
+                })?;
+                let left = self.blobs_of(&node);
+                self.free_blobs(blob::dropped(blobs, &left), &guard)?;
+                if node.should_merge(&self.config) {
+                    underfull.push(lo);
+                }
+            }
+
+            match node.next {
+                Some(next) => {
+                    cursor = next;
+                    expected_lo = Some(node.hi.inner().to_vec());
+                }
+                None => break,
+            }
+        }
+        drop(_writes);
+        for key in underfull {
+            self.merge_underfull(&key, &guard)?;
+        }
+        Ok(removed)
+    }
+
+    // Starts sweeping expired keys in the background, unless that
+    // is already happening or `expire_every_ms` is `None`.
+    fn start_sweeper(&self) {
+        let every_ms = match self.config.expire_every_ms {
+            Some(every_ms) => every_ms,
+            None => return,
+        };
+        let mut sweeper = self.sweeper.lock().unwrap();
+        if sweeper.is_none() {
+            // the sweeper's handle doesn't share this slot, so
+            // dropping every other handle stops the sweeper
+            let tree = Tree {
+                sweeper: Arc::new(Mutex::new(None)),
+                ..self.clone()
+            };
+            *sweeper = Some(Sweeper::start(tree, every_ms));
+        }
+    }
+
+    // Has up to `read_ahead` leaves, starting at `id`, paged in on
+    // a background thread for an iterator that will go on to keys
+    // within `hi`.
+    pub(super) fn read_ahead(&self, id: PageID, hi: &Bound) {
+        let depth = self.config.read_ahead;
+        if depth == 0 {
+            return;
+        }
+        let mut prefetcher = self.prefetcher.lock().unwrap();
+        let pages = &self.pages;
+        prefetcher
+            .get_or_insert_with(|| Prefetcher::start(pages.clone()))
+            .read_ahead(id, hi, depth);
+    }
+
+    /// Subscribes to every write to a key starting with `prefix`,
+    /// returning a `Subscriber` that yields them as `Event`s once
+    /// they are durable. An empty prefix watches the whole tree.
+    /// `remove_range` and `clear` send a `Del` for each key they
+    /// remove, and a batch sends one event per operation, including
+    /// deletions of keys that weren't there. See `Subscriber` for
+    /// an example.
+    pub fn watch_prefix(&self, prefix: &[u8]) -> Subscriber {
+        self.subscriptions.subscribe(prefix, self.pages.clone())
+    }
+
+    /// Declares a secondary index called `name`, stored in `index`,
+    /// another tree of the same `Db` that nothing else writes to.
+    /// `extract` returns the index keys of a record from its key
+    /// and value, and every write to this tree updates the index
+    /// in the same atomic batch as the write, including writes
+    /// made by transactions. Records already in the tree are
+    /// indexed before this returns.
+    ///
+    /// Indexes aren't persisted, so like a merge operator they
+    /// must be declared again each time the `Db` is started,
+    /// before anything writes to the tree. Writes with a TTL,
+    /// `remove_range` and `clear` fail with `Error::Unsupported`
+    /// on an indexed tree, and a tree that holds an index can't
+    /// have indexes of its own.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users").unwrap();
+    /// let by_city = db.open_tree(b"users by city").unwrap();
+    ///
+    /// // values are "name,city"
+    /// users
+    ///     .add_index("city", &by_city, |_key, value| {
+    ///         let city = value.splitn(2, |&b| b == b',').nth(1);
+    ///         city.map(|c| c.to_vec()).into_iter().collect()
+    ///     })
+    ///     .unwrap();
+    ///
+    /// users.set(b"1".to_vec(), b"ann,Oslo".to_vec()).unwrap();
+    /// users.set(b"2".to_vec(), b"bo,Lima".to_vec()).unwrap();
+    /// users.set(b"3".to_vec(), b"cy,Oslo".to_vec()).unwrap();
+    /// users.set(b"3".to_vec(), b"cy,Rome".to_vec()).unwrap();
+    ///
+    /// let oslo = users.get_by_index("city", b"Oslo").unwrap();
+    /// let ann = (b"1".to_vec(), b"ann,Oslo".to_vec().into());
+    /// assert_eq!(oslo, vec![ann]);
+    ///
+    /// let keys: Vec<Vec<u8>> = users
+    ///     .index_range("city", &b"M"[..]..)
+    ///     .unwrap()
+    ///     .map(|res| res.unwrap().1)
+    ///     .collect();
+    /// assert_eq!(keys, vec![b"1".to_vec(), b"3".to_vec()]);
+    /// ```
+    pub fn add_index<F>(
+        &self,
+        name: &str,
+        index: &Tree,
+        extract: F,
+    ) -> DbResult<(), ()>
+    where
+        F: Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        } else if !self.shares_pages(index) || self.is_same(index) {
+            return Err(Error::Unsupported(
+                "an index must be a different tree of the same Db"
+                    .to_owned(),
+            ));
+        } else if self.indexes().is_index() {
+            return Err(Error::Unsupported(
+                "a tree holding an index can't be indexed".to_owned(),
+            ));
+        } else if index.indexes().is_index() || index.is_indexed() {
+            return Err(Error::Unsupported(
+                "an index must be held by a tree of its own"
+                    .to_owned(),
+            ));
+        }
+
+        let index = Index::new(name, index, extract);
+        self.indexes().declare(index.clone())?;
+        self.gate.quiesce();
+
+        // writes from here on maintain the index, so only records
+        // that haven't changed since we read them need entries
+        let mut records = self.iter();
+        loop {
+            let mut chunk = vec![];
+            for res in records.by_ref().take(INDEX_CHUNK) {
+                let (key, value) = res?;
+                chunk.push((key, Some(value)));
+            }
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            loop {
+                let mut entries = Batch::default();
+                for &(ref key, ref value) in &chunk {
+                    let added = (key.clone(), None, value.clone());
+                    index.maintain(&added, &mut entries);
+                }
+                let committed = commit_batches(
+                    &[self, &index.tree],
+                    &[chunk.clone(), vec![]],
+                    &[Batch::default(), entries],
+                )?;
+                if committed {
+                    break;
+                }
+                for &mut (ref key, ref mut value) in &mut chunk {
+                    *value = self.get(key)?;
+                }
+                M.tree_looped();
+            }
+        }
+    }
+
+    /// Returns every record that the index called `name` lists
+    /// under `index_key`, in key order.
+    pub fn get_by_index(
+        &self,
+        name: &str,
+        index_key: &[u8],
+    ) -> DbResult<Vec<(Key, Value)>, ()> {
+        self.index_range(name, index_key..=index_key)?
+            .map(|res| res.map(|(_, key, value)| (key, value)))
+            .collect()
+    }
+
+    /// Iterates over the records that the index called `name`
+    /// lists under the index keys in `range`.
+    pub fn index_range<K, R>(
+        &self,
+        name: &str,
+        range: R,
+    ) -> DbResult<IndexIter<'_>, ()>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let index = self.indexes().find(name)?;
+        Ok(IndexIter::new(self, index, range))
+    }
+
+    // The indexes declared on this tree.
+    pub(super) fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    // The read views open on this tree.
+    pub(super) fn views(&self) -> &Views {
+        &self.views
+    }
+
+    // Whether writes to this tree have indexes to maintain.
+    pub(super) fn is_indexed(&self) -> bool {
+        self.indexes().len() > 0
+    }
+
+    // Whether writes to this tree must go through `commit_batches`,
+    // to maintain its indexes or to preserve what they overwrite
+    // for its read views. Declaring an index and opening a view
+    // wait for the writes in flight to finish, so a write that
+    // found this `false` after entering the gate is done before
+    // either of them returns.
+    pub(super) fn is_batched(&self) -> bool {
+        self.is_indexed() || self.is_viewed()
+    }
+
+    // Whether a read view of this tree is open.
+    pub(crate) fn is_viewed(&self) -> bool {
+        self.views().is_open()
+    }
+
+    // `cas` on a batched tree, committed as a batch along with the
+    // index entries it changes.
+    pub(super) fn cas_batched(
+        &self,
+        key: Key,
+        old: Option<&[u8]>,
+        new: Option<Value>,
+    ) -> DbResult<(), Option<Value>> {
+        let mut batch = Batch::default();
+        match new {
+            Some(new) => batch.set(key.clone(), new),
+            None => batch.del(key.clone()),
+        }
+        loop {
+            let read = vec![(key.clone(), old.map(Value::from))];
+            let committed =
+                commit_batches(&[self], &[read], &[batch.clone()])
+                    .map_err(|e| e.danger_cast())?;
+            if committed {
+                return Ok(());
+            }
+            let cur = self.get(&key).map_err(|e| e.danger_cast())?;
+            if cur.as_ref().map(|c| &**c) != old {
+                return Err(Error::CasFailed(cur));
+            }
+            M.tree_looped();
+        }
+    }
+
+    // What the configured merge operator makes of merging
+    // `operand` into `old`, the value of `key`, with `None` meaning
+    // the key is removed.
+    pub(super) fn merge_value(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        operand: &[u8],
+    ) -> DbResult<Option<Value>, ()> {
+        let config = &self.config;
+        let splitting = config.splitting_merge_operator;
+        let merged = if let Some(id) = splitting {
+            config
+                .splitting_merge_operators
+                .get(id)
+                .map(|merge_fn| merge_fn(key, old, operand).0)
+        } else {
+            config
+                .merge_operator
+                .and_then(|id| config.merge_operators.get(id))
+                .map(|merge_fn| merge_fn(key, old, operand))
+        };
+        match merged {
+            Some(merged) => Ok(merged.map(Value::from)),
+            None => Err(Error::Unsupported(
+                ApplyError::MissingMergeOperator.to_string(),
+            )),
+        }
+    }
+
+    // `del` on a batched tree.
+    pub(super) fn del_batched(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<Value>, ()> {
+        let mut batch = Batch::default();
+        batch.del(key.to_vec());
+        loop {
+            let cur = self.get(key)?;
+            if cur.is_none() {
+                return Ok(None);
+            }
+            let read = vec![(key.to_vec(), cur.clone())];
+            if commit_batches(&[self], &[read], &[batch.clone()])? {
+                return Ok(cur);
+            }
+            M.tree_looped();
+        }
+    }
+
+    /// Atomically applies every operation in `batch`, in order.
+    /// The batch is logged as a single record, so after a crash
+    /// either all of it is recovered or none of it is. A batch too
+    /// large for one IO buffer fails with `Error::Unsupported`
+    /// without writing anything. Nothing waits for a batch, and
+    /// `get` and the other single-key operations see all of it or
+    /// none of it, but an iterator running alongside one may see
+    /// part of it.
+    pub fn apply_batch(&self, batch: Batch) -> DbResult<(), ()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        commit_batches(&[self], &[vec![]], &[batch]).map(|_| ())
+    }
+
+    /// Atomically applies every operation in `batch`, unless writes
+    /// are being held back by `max_unflushed_bytes` or
+    /// `max_resident_bytes`, in which case it fails with an
+    /// `Error::Io` of kind `WouldBlock` rather than waiting, and
+    /// applies none of them.
+    pub fn try_apply_batch(&self, batch: Batch) -> DbResult<(), ()> {
+        self.refuse_if_throttling()?;
+        self.apply_batch(batch)
+    }
+
+    // Fails with `WouldBlock` if a write would have to wait for
+    // the log to catch up or for pages to be paged out.
+    fn refuse_if_throttling(&self) -> DbResult<(), ()> {
+        if self.pressure().is_throttling() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "writes are waiting for the log to be flushed \
+                 or for pages to be paged out",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs `f` as a serializable transaction, retrying it until
+    /// it commits or returns an error. `f` reads and stages writes
+    /// through a `TransactionalTree`, which sees its own writes, and
+    /// can join other trees of the same `Db` to the transaction
+    /// with `TransactionalTree::tree`. The writes to every tree are
+    /// committed atomically, as one batch, and only if nothing `f`
+    /// read has changed since. An error returned by `f` aborts the
+    /// transaction without writing anything. Since `f` may run
+    /// more than once, it should have no other side effects.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let db = sled::Db::start(config).unwrap();
+    /// let balances = db.open_tree(b"balances").unwrap();
+    /// let history = db.open_tree(b"history").unwrap();
+    /// balances.set(b"alice".to_vec(), vec![10]).unwrap();
+    ///
+    /// balances
+    ///     .transaction(|tx| {
+    ///         let history = tx.tree(&history)?;
+    ///         let alice = tx.get(b"alice")?.unwrap();
+    ///         tx.set(b"alice".to_vec(), vec![alice[0] - 3]);
+    ///         tx.set(b"bob".to_vec(), vec![3]);
+    ///         history.set(b"1".to_vec(), b"alice->bob".to_vec());
+    ///         assert_eq!(tx.get(b"bob"), Ok(Some(vec![3].into())));
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert_eq!(balances.get(b"alice"), Ok(Some(vec![7].into())));
+    /// assert_eq!(balances.get(b"bob"), Ok(Some(vec![3].into())));
+    /// let entry = history.get(b"1").unwrap();
+    /// assert_eq!(entry, Some(b"alice->bob".to_vec().into()));
+    /// ```
+    pub fn transaction<F, R>(&self, f: F) -> DbResult<R, ()>
+    where
+        F: Fn(&TransactionalTree) -> DbResult<R, ()>,
+    {
+        loop {
+            let tx = TransactionalTree::new(self.clone());
+            let ret = f(&tx)?;
+            if tx.commit()? {
+                return Ok(ret);
+            }
+            M.tree_looped();
+        }
+    }
+
+    // Whether `other` is a handle to this same tree.
+    pub(super) fn is_same(&self, other: &Tree) -> bool {
+        Arc::ptr_eq(&self.root, &other.root)
+    }
+
+    // Whether `other` lives in the same pagecache as this tree.
+    pub(super) fn shares_pages(&self, other: &Tree) -> bool {
+        Arc::ptr_eq(&self.pages, &other.pages)
+    }
+
+    // Reads `key` for a batch that depends on its value, planning
+    // its leaf with no writes if the batch doesn't write to it, so
+    // that the batch is only linked if the leaf hasn't changed
+    // since.
+    fn plan_read<'g>(
+        &self,
+        key: &[u8],
+        planned: &mut Vec<PlannedLeaf<'g>>,
+        guard: &'g Guard,
+    ) -> DbResult<Option<Value>, ()> {
+        let (mut path, value) = self.get_internal(key, guard)?;
+        let (leaf, head) = path.pop().expect(
+            "path_for_key should always return a path \
+             of length >= 2 (root + leaf)",
+        );
+        self.plan_leaf(&leaf, head, planned);
+        Ok(value)
+    }
+
+    // Adds an existing leaf, read at `head`, to `planned` unless
+    // it's already there.
+    fn plan_leaf<'g>(
+        &self,
+        leaf: &Node,
+        head: TreePtr<'g>,
+        planned: &mut Vec<PlannedLeaf<'g>>,
+    ) {
+        if !planned.iter().any(|l| l.origin == leaf.id) {
+            planned.push(PlannedLeaf {
+                origin: leaf.id,
+                head: head,
+                origin_next: leaf.next,
+                is_new: false,
+                node: leaf.clone(),
+                frags: vec![],
+                split_hint: false,
+                blobs: self.blobs_of(leaf),
+            });
+        }
+    }
+
+    // Works out what each operation of a batch does to its leaf by
+    // applying it to a copy of the leaf. A leaf that would grow
+    // past `max_leaf_len` is split within the batch, onto a page
+    // that nothing points at until the batch is linked. Returns by
+    // how much the batch changes the number of keys. If `changes`
+    // is provided, each key written is pushed to it with its
+    // previous and resulting value. Fails with `CasFailed` if a
+    // leaf changed while it was being planned, after which the
+    // batch has to be planned again.
+    fn plan_batch<'g>(
+        &self,
+        batch: &Batch,
+        planned: &mut Vec<PlannedLeaf<'g>>,
+        mut changes: Option<&mut Vec<Change>>,
+        guard: &'g Guard,
+    ) -> DbResult<isize, ()> {
+        let mut delta = 0;
+        for op in &batch.ops {
+            let key = op.key();
+            let mut path = self.path_for_key(key, guard)?;
+            let (leaf, head) = path.pop().expect(
+                "path_for_key should always return a path \
+                 of length >= 2 (root + leaf)",
+            );
+            self.plan_leaf(&leaf, head, planned);
+
+            loop {
+                // a leaf planned earlier may have taken over more
+                // keys since, from a sibling merged into it
+                let idx = match planned.iter().position(|l| {
+                    l.origin == leaf.id && l.contains(key)
+                }) {
+                    Some(idx) => idx,
+                    None => return Err(Error::CasFailed(())),
+                };
+                let frag = op.to_frag(planned[idx].node.lo.inner());
+                let fits = {
+                    let node = &planned[idx].node;
+                    node.check_capacity(&frag, &self.config)
+                };
+                match fits {
+                    Ok(()) => {}
+                    Err(NodeError::NodeFull(_)) => {
+                        self.plan_split(planned, idx, guard)?;
+                        continue;
+                    }
+                    Err(other) => {
+                        let e = other.to_string();
+                        return Err(Error::Unsupported(e));
+                    }
+                }
+
+                let leaf = &mut planned[idx];
+                let before = leaf.node.data.len() as isize;
+                let changes = changes.as_mut().map(|c| &mut **c);
+                match leaf.node.apply_recording(
+                    &frag,
+                    &self.config,
+                    changes,
+                ) {
+                    Ok(hint) => leaf.split_hint |= hint,
+                    Err(e @ ApplyError::MissingMergeOperator) => {
+                        return Err(Error::Unsupported(e.to_string()))
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        return Err(Error::ReportableBug(e));
+                    }
+                }
+                delta += leaf.node.data.len() as isize - before;
+                if !leaf.is_new {
+                    leaf.frags.push(frag);
+                }
+                break;
+            }
+        }
+        Ok(delta)
+    }
+
+    // Splits a full planned leaf like `child_split`, except that
+    // the new right side is only written with the batch.
+    fn plan_split<'g>(
+        &self,
+        planned: &mut Vec<PlannedLeaf<'g>>,
+        idx: usize,
+        guard: &'g Guard,
+    ) -> DbResult<(), ()> {
+        let new_pid = self.pages.allocate(guard)?;
+        trace!("allocated pid {} in plan_split", new_pid);
+
+        let (origin, rhs) = {
+            let leaf = &mut planned[idx];
+            let mut rhs = self.split_node(&leaf.node, new_pid);
+            let split = ChildSplit {
+                at: rhs.lo.clone(),
+                to: new_pid,
+            };
+
+            // the right side takes the records that the left side
+            // drops, so the two can't disagree about where they went
+            rhs.data = leaf.node.child_split(&split);
+            if self.config.leaf_bloom_filters {
+                leaf.node.refresh_bloom();
+            }
+            let child_split = Frag::ChildSplit(split);
+            if !leaf.is_new {
+                leaf.frags.push(child_split);
+            }
+            (leaf.origin, rhs)
+        };
+
+        // a right sibling split off earlier in the batch can be
+        // pointed back at the new page directly
+        if let Some(next) = rhs.next {
+            let right = planned
+                .iter_mut()
+                .find(|l| l.is_new && l.node.id == next);
+            if let Some(right) = right {
+                right.node.prev = Some(new_pid);
+            }
+        }
+
+        planned.push(PlannedLeaf {
+            origin: origin,
+            head: Shared::null(),
+            origin_next: None,
+            is_new: true,
+            node: rhs,
+            frags: vec![],
+            split_hint: false,
+            blobs: vec![],
+        });
+        Ok(())
+    }
+
+    // Writes the leaves that batches split off, then links the
+    // frags for the leaves that already existed as one log record.
+    // Fails with `CasFailed`, linking nothing, if one of those
+    // leaves has changed since it was planned.
+    fn link_batches<'g>(
+        &self,
+        planned: &[Vec<PlannedLeaf<'g>>],
+        guard: &'g Guard,
+    ) -> DbResult<(), ()> {
+        let mut frags = vec![];
+        for leaf in planned.iter().flat_map(|p| p) {
+            if leaf.is_new {
+                self.pages
+                    .replace(
+                        leaf.node.id,
+                        Shared::null(),
+                        leaf.node.to_base(),
+                        guard,
+                    )
+                    .map_err(|e| e.danger_cast())?;
+                M.split();
+            } else {
+                frags.push((leaf.node.id, leaf.head, leaf.to_frag()));
+            }
+        }
+        self.pages.link_all(frags, guard)
+    }
+
+    // Repairs the structure around the leaves a batch was linked
+    // to: points old right siblings back at the leaves split off
+    // in the batch, installs those in their parents, and splits
+    // whatever has grown too big.
+    fn finish_batch<'g>(
+        &self,
+        planned: &[PlannedLeaf<'g>],
+        guard: &'g Guard,
+    ) -> DbResult<(), ()> {
+        for leaf in planned.iter().filter(|l| !l.is_new) {
+            let next = match leaf.origin_next {
+                Some(next) => next,
+                None => continue,
+            };
+            let rightmost = planned.iter().find(|l| {
+                l.is_new
+                    && l.origin == leaf.origin
+                    && l.node.next == Some(next)
+            });
+            if let Some(rightmost) = rightmost {
+                self.relink_prev(
+                    next,
+                    leaf.origin,
+                    rightmost.node.id,
+                    guard,
+                )?;
+            }
+        }
+
+        // walking to each new leaf installs it in its parent, and
+        // only then do the paths show which parents are too big
+        for leaf in planned.iter().filter(|l| l.is_new) {
+            self.path_for_key(leaf.node.lo.inner(), guard)?;
+        }
+        for leaf in planned {
+            let lo = leaf.node.lo.inner();
+            let path = self.path_for_key(lo, guard)?;
+            self.recursive_split(&path, leaf.split_hint, guard)?;
+        }
+        Ok(())
+    }
+
+    /// Iterate over tuples of keys and values, starting at the provided key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// let mut iter = t.scan(&*vec![2]);
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn scan(&self, key: &[u8]) -> Iter {
+        let guard = pin();
+        let (id, broken) = match self.leaf_for_key(key, &guard) {
+            Ok(id) => (id, None),
+            Err(e) => (0, Some(e)),
+        };
+        Iter {
+            id: id,
+            tree: self,
+            last_key: Bound::Exclusive(key.to_vec()),
+            hi: Bound::Inf,
+            expected_lo: None,
+            broken: broken,
+            done: false,
+        }
+    }
+
+    /// Iterate over the tuples of keys and values in this tree.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// let mut iter = t.iter();
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn iter(&self) -> Iter {
+        self.scan(b"")
+    }
+
+    /// Iterate over tuples of keys and values whose keys fall in
+    /// `range`, in order. The iteration ends at the first leaf
+    /// that starts past the range, without reading further. Keys
+    /// are compared as bytes, so numbers and other typed keys
+    /// should be written with the encodings in `sled::keys`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// let mut iter = t.range(vec![1]..vec![3]);
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), None);
+    ///
+    /// assert_eq!(t.range::<Vec<u8>, _>(..).count(), 3);
+    ///
+    /// use sled::keys;
+    /// for n in &[-1.5f64, 300.0, 2.0] {
+    ///     t.set(keys::encode(n), vec![]).unwrap();
+    /// }
+    /// let lo = keys::encode(&-10.0f64);
+    /// let hi = keys::encode(&10.0f64);
+    /// let found: Vec<f64> = t
+    ///     .range(lo..hi)
+    ///     .map(|res| keys::decode(&res.unwrap().0).unwrap())
+    ///     .collect();
+    /// assert_eq!(found, vec![-1.5, 2.0]);
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> Iter<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (start, last_key) = match range.start_bound() {
+            ops::Bound::Included(k) => {
+                (k.as_ref(), Bound::Exclusive(k.as_ref().to_vec()))
+            }
+            ops::Bound::Excluded(k) => {
+                (k.as_ref(), Bound::Inclusive(k.as_ref().to_vec()))
+            }
+            ops::Bound::Unbounded => {
+                (&[][..], Bound::Exclusive(vec![]))
+            }
+        };
+        let hi = match range.end_bound() {
+            ops::Bound::Included(k) => {
+                Bound::Inclusive(k.as_ref().to_vec())
+            }
+            ops::Bound::Excluded(k) => {
+                Bound::Exclusive(k.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => Bound::Inf,
+        };
+
+        let mut iter = self.scan(start);
+        iter.last_key = last_key;
+        iter.hi = hi;
+        iter
+    }
+
+    /// Iterate over the keys in `range`, in order, like `range`
+    /// but without copying any values out of the tree's leaves,
+    /// which suits existence checks and exporting keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![0; 4096]).unwrap();
+    /// t.set(vec![2], vec![0; 4096]).unwrap();
+    /// t.set(vec![3], vec![0; 4096]).unwrap();
+    ///
+    /// let keys: Vec<Vec<u8>> =
+    ///     t.keys(vec![2]..).map(|res| res.unwrap()).collect();
+    /// assert_eq!(keys, vec![vec![2], vec![3]]);
+    /// ```
+    pub fn keys<K, R>(&self, range: R) -> Keys<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        Keys(self.range(range))
+    }
+
+    /// Iterate over the values of the keys in `range`, in the order
+    /// of their keys, like `range` but without returning the keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]).unwrap();
+    /// t.set(vec![2], vec![20]).unwrap();
+    ///
+    /// let values: Vec<sled::IVec> =
+    ///     t.values::<Vec<u8>, _>(..).map(|res| res.unwrap()).collect();
+    /// assert_eq!(values, vec![vec![10u8], vec![20u8]]);
+    /// ```
+    pub fn values<K, R>(&self, range: R) -> Values<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        Values(self.range(range))
+    }
+
+    /// Iterate over tuples of keys and values whose keys start
+    /// with `prefix`, in order. The scan seeks to the leaf holding
+    /// the first such key and ends with the first key that doesn't
+    /// share the prefix, without reading any leaf after it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(b"a".to_vec(), vec![1]);
+    /// t.set(b"ba".to_vec(), vec![2]);
+    /// t.set(b"bb".to_vec(), vec![3]);
+    /// t.set(b"c".to_vec(), vec![4]);
+    /// let mut iter = t.scan_prefix(b"b");
+    /// assert_eq!(iter.next(), Some(Ok((b"ba".to_vec(), vec![2].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((b"bb".to_vec(), vec![3].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Iter<'_> {
+        let mut iter = self.scan(prefix);
+        iter.hi = prefix_end(prefix);
+        iter
+    }
+
+    /// Iterate over tuples of keys and values in descending key
+    /// order, starting at the provided key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// let mut iter = t.scan_rev(&*vec![2]);
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn scan_rev(&self, key: &[u8]) -> RevIter<'_> {
+        let guard = pin();
+        let (id, broken) = match self.leaf_for_key(key, &guard) {
+            Ok(id) => (id, None),
+            Err(e) => (0, Some(e)),
+        };
+        RevIter {
+            id: id,
+            tree: self,
+            hi: Bound::Inclusive(key.to_vec()),
+            right_lo: None,
+            broken: broken,
+            done: false,
+        }
+    }
+
+    /// Iterate over the tuples of keys and values in this tree in
+    /// descending key order, starting from the last leaf rather
+    /// than walking the whole tree.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![1], vec![10]);
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![3], vec![30]);
+    /// let mut iter = t.iter_rev();
+    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20].into()))));
+    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10].into()))));
+    /// assert_eq!(iter.next(), None);
+    /// ```
+    pub fn iter_rev(&self) -> RevIter<'_> {
+        let guard = pin();
+        let (id, broken) = match self.last_leaf(&guard) {
+            Ok(id) => (id, None),
+            Err(e) => (0, Some(e)),
+        };
+        RevIter {
+            id: id,
+            tree: self,
+            hi: Bound::Inf,
+            right_lo: None,
+            broken: broken,
+            done: false,
+        }
+    }
+
+    /// Returns the key and value with the smallest key, or `None`
+    /// if the tree is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert_eq!(t.first(), Ok(None));
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![1], vec![10]);
+    /// assert_eq!(t.first(), Ok(Some((vec![1], vec![10].into()))));
+    /// ```
+    pub fn first(&self) -> DbResult<Option<(Key, Value)>, ()> {
+        self.iter().next().map_or(Ok(None), |res| res.map(Some))
+    }
+
+    /// Returns the key and value with the greatest key, or `None`
+    /// if the tree is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// assert_eq!(t.last(), Ok(None));
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![1], vec![10]);
+    /// assert_eq!(t.last(), Ok(Some((vec![2], vec![20].into()))));
+    /// ```
+    pub fn last(&self) -> DbResult<Option<(Key, Value)>, ()> {
+        self.iter_rev().next().map_or(Ok(None), |res| res.map(Some))
+    }
+
+    /// Atomically removes and returns the key and value with the
+    /// smallest key, or `None` if the tree is empty. The removal
+    /// is a `cas` against the value that was read, retried until
+    /// it succeeds, so when several threads pop at once each
+    /// record goes to exactly one of them. This makes the tree
+    /// usable as a durable priority queue.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Tree::start(config).unwrap();
+    /// t.set(vec![2], vec![20]);
+    /// t.set(vec![1], vec![10]);
+    /// assert_eq!(t.pop_min(), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.pop_min(), Ok(Some((vec![2], vec![20].into()))));
+    /// assert_eq!(t.pop_min(), Ok(None));
+    /// ```
+    pub fn pop_min(&self) -> DbResult<Option<(Key, Value)>, ()> {
+        self.pop_with(|| self.first())
+    }
+
+    /// Atomically removes and returns the key and value with the
+    /// greatest key, or `None` if the tree is empty. See
+    /// `pop_min`.
     ///
     /// # Examples
     ///
     /// ```
     /// let config = sled::ConfigBuilder::new().temporary(true).build();
     /// let t = sled::Tree::start(config).unwrap();
-    /// t.set(vec![1], vec![10]);
     /// t.set(vec![2], vec![20]);
-    /// t.set(vec![3], vec![30]);
-    /// let mut iter = t.iter();
-    /// assert_eq!(iter.next(), Some(Ok((vec![1], vec![10]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![2], vec![20]))));
-    /// assert_eq!(iter.next(), Some(Ok((vec![3], vec![30]))));
-    /// assert_eq!(iter.next(), None);
+    /// t.set(vec![1], vec![10]);
+    /// assert_eq!(t.pop_max(), Ok(Some((vec![2], vec![20].into()))));
+    /// assert_eq!(t.pop_max(), Ok(Some((vec![1], vec![10].into()))));
+    /// assert_eq!(t.pop_max(), Ok(None));
     /// ```
-    pub fn iter(&self) -> Iter {
-        self.scan(b"")
+    pub fn pop_max(&self) -> DbResult<Option<(Key, Value)>, ()> {
+        self.pop_with(|| self.last())
+    }
+
+    // Removes the record that `find` returns with a cas, finding
+    // it again whenever another writer changes it first.
+    fn pop_with<F>(
+        &self,
+        find: F,
+    ) -> DbResult<Option<(Key, Value)>, ()>
+    where
+        F: Fn() -> DbResult<Option<(Key, Value)>, ()>,
+    {
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+        loop {
+            let (k, v) = match find()? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            match self.cas(k.clone(), Some(&v), None::<Value>) {
+                Ok(()) => return Ok(Some((k, v))),
+                Err(Error::CasFailed(_)) => M.tree_looped(),
+                Err(other) => return Err(other.danger_cast()),
+            }
+        }
+    }
+
+    // The leaf that `key` belongs in.
+    pub(super) fn leaf_for_key(
+        &self,
+        key: &[u8],
+        guard: &Guard,
+    ) -> DbResult<PageID, ()> {
+        let (path, _) = self.get_internal(key, guard)?;
+        match path.last() {
+            Some(&(ref last_node, ref _last_cas_key)) => {
+                Ok(last_node.id)
+            }
+            None => Err(Error::ReportableBug(
+                "failed to get path for key".to_owned(),
+            )),
+        }
+    }
+
+    // Descends along the last child of each index to the leaf
+    // holding the greatest keys, moving right past any split that
+    // hasn't reached its parent yet.
+    pub(super) fn last_leaf(
+        &self,
+        guard: &Guard,
+    ) -> DbResult<PageID, ()> {
+        let mut cursor = self.root.load(SeqCst);
+        loop {
+            let get_cursor = self.pages
+                .get(cursor, guard)
+                .map_err(|e| e.danger_cast())?;
+            if get_cursor.is_free() || get_cursor.is_allocated() {
+                cursor = self.root.load(SeqCst);
+                continue;
+            }
+            let node = match get_cursor {
+                PageGet::Materialized(Frag::Base(base, _), _) => base,
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base node in last_leaf: {:?}",
+                        broken
+                    )))
+                }
+            };
+
+            if node.hi != Bound::Inf {
+                cursor = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                continue;
+            }
+            match node.data {
+                Data::Index(ref ptrs) => {
+                    cursor = ptrs.last()
+                        .expect("index nodes are never empty")
+                        .1;
+                }
+                _ => return Ok(node.id),
+            }
+        }
     }
 
+    // `split_leaf` forces the last node in the path to split even
+    // if it is below the configured limits, as requested by a
+    // `splitting_merge_operator`.
     fn recursive_split<'g>(
         &self,
         path: &[(Node, TreePtr<'g>)],
+        split_leaf: bool,
         guard: &'g Guard,
     ) -> DbResult<(), ()> {
         // to split, we pop the path, see if it's in need of split, recurse up
@@ -503,8 +3477,13 @@ impl Tree {
         let mut all_page_views = path.to_vec();
         let mut root_and_key = all_page_views.remove(0);
 
+        let mut force = split_leaf;
         while let Some((node, cas_key)) = all_page_views.pop() {
-            if node.should_split(self.config.blink_fanout) {
+            // a node needs at least 3 records to split into two
+            // non-empty halves
+            let forced = force && node.data.len() >= 3;
+            force = false;
+            if forced || self.should_split(&node) {
                 // try to child split
                 if let Ok(parent_split) =
                     self.child_split(&node, cas_key, guard)
@@ -526,10 +3505,13 @@ impl Tree {
 
                     match res {
                         Ok(res) => {
-                            parent_node.apply(
-                                &Frag::ParentSplit(parent_split),
-                                self.config.merge_operator,
-                            );
+                            let ps = Frag::ParentSplit(parent_split);
+                            if let Err(e) =
+                                parent_node.apply(&ps, &self.config)
+                            {
+                                let e = e.to_string();
+                                return Err(Error::ReportableBug(e));
+                            }
                             *parent_cas_key = res;
                         }
                         Err(Error::CasFailed(_)) => continue,
@@ -545,7 +3527,7 @@ impl Tree {
 
         let (root_node, root_cas_key) = root_and_key;
 
-        if root_node.should_split(self.config.blink_fanout) {
+        if self.should_split(&root_node) {
             if let Ok(parent_split) =
                 self.child_split(&root_node, root_cas_key, guard)
             {
@@ -561,6 +3543,17 @@ impl Tree {
         Ok(())
     }
 
+    // Whether `node` should be split under this tree's policy.
+    fn should_split(&self, node: &Node) -> bool {
+        node.should_split_with(&self.split_policy(), &self.config)
+    }
+
+    // Splits off the right side of `node` under this tree's policy,
+    // giving it the id `id`.
+    fn split_node(&self, node: &Node, id: PageID) -> Node {
+        node.split_with(id, &self.split_policy(), &self.config)
+    }
+
     fn child_split<'g>(
         &self,
         node: &Node,
@@ -570,8 +3563,9 @@ impl Tree {
         let new_pid = self.pages.allocate(guard)?;
         trace!("allocated pid {} in child_split", new_pid);
 
-        // split the node in half
-        let rhs = node.split(new_pid);
+        // split the node by bytes if it's too big, and otherwise at
+        // the configured ratio, in half by default
+        let rhs = self.split_node(node, new_pid);
 
         let child_split = Frag::ChildSplit(ChildSplit {
             at: rhs.lo.clone(),
@@ -602,7 +3596,7 @@ impl Tree {
         );
 
         match link {
-            Ok(_) => {}
+            Ok(_) => M.split(),
             Err(Error::CasFailed(_)) => {
                 // if we failed, don't follow through with the parent split
                 self.pages
@@ -613,9 +3607,299 @@ impl Tree {
             Err(other) => return Err(other.danger_cast()),
         }
 
+        if let Some(next) = node.next {
+            self.relink_prev(next, node.id, new_pid, guard)?;
+        }
+
         Ok(parent_split)
     }
 
+    // Points `pid` back at `new_prev`, which was split off of
+    // `old_prev`. Gives up if `pid` no longer points at `old_prev`,
+    // since a later split has already moved the pointer on.
+    fn relink_prev(
+        &self,
+        pid: PageID,
+        old_prev: PageID,
+        new_prev: PageID,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        loop {
+            let get = self.pages
+                .get(pid, guard)
+                .map_err(|e| e.danger_cast())?;
+            let (node, cas_key) = match get {
+                PageGet::Materialized(
+                    Frag::Base(node, _),
+                    cas_key,
+                ) => (node, cas_key),
+                _ => return Ok(()),
+            };
+            if node.prev != Some(old_prev) {
+                return Ok(());
+            }
+
+            let link = self.pages.link(
+                pid,
+                cas_key,
+                Frag::SetPrev(new_prev),
+                guard,
+            );
+            match link {
+                Ok(_) => return Ok(()),
+                Err(Error::CasFailed(_)) => continue,
+                Err(other) => return Err(other.danger_cast()),
+            }
+        }
+    }
+
+    // Merges the underfull nodes on the path to `key` with a
+    // sibling, from the leaf up, and then collapses a root left with
+    // a single index child. Writes go on meanwhile, and a merge
+    // that one of them gets in the way of is given up on, as is
+    // the whole call if another merge is running or the gate is
+    // held, leaving the merge to a later delete.
+    fn merge_underfull(
+        &self,
+        key: &[u8],
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let _writes = match self.gate.try_enter() {
+            Some(writes) => writes,
+            None => return Ok(()),
+        };
+        let _merging = match self.merging.try_lock() {
+            Ok(merging) => merging,
+            Err(_) => return Ok(()),
+        };
+
+        let mut depth = self.path_for_key(key, guard)?.len() - 1;
+        while depth > 0 {
+            let mut path = self.path_for_key(key, guard)?;
+            path.truncate(depth + 1);
+            let (node, _) = path.pop().unwrap();
+            let (parent, parent_key) = path.pop().unwrap();
+            if !node.should_merge(&self.config)
+                || !self.merge_child(
+                    &parent,
+                    parent_key,
+                    &node,
+                    guard,
+                )?
+            {
+                break;
+            }
+            depth -= 1;
+        }
+        self.collapse_root(guard)
+    }
+
+    // Merges `node` with its right sibling, or with its left one if
+    // it's the last child of `parent`. The left side absorbs the
+    // right one in the same log record that removes the right side
+    // from the parent and splits it at its own low key, handing
+    // everything it held to the left side, after which its page is
+    // freed. A write that read the right side before then fails to
+    // link to it, and a search that reaches it is sent on to the
+    // left side, as after any split. Returns
+    // `false` if the two can't be merged, either because they
+    // aren't adjacent children of `parent` or because the result
+    // would have to be split again.
+    fn merge_child<'g>(
+        &self,
+        parent: &Node,
+        parent_key: TreePtr<'g>,
+        node: &Node,
+        guard: &'g Guard,
+    ) -> DbResult<bool, ()> {
+        let (lhs_id, rhs_id) = match parent.data {
+            Data::Index(ref ptrs) => {
+                let pos = ptrs
+                    .iter()
+                    .position(|&(_, child)| child == node.id);
+                match pos {
+                    Some(pos) if pos + 1 < ptrs.len() => {
+                        (node.id, ptrs[pos + 1].1)
+                    }
+                    Some(pos) if pos > 0 => {
+                        (ptrs[pos - 1].1, node.id)
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            _ => {
+                return Err(Error::ReportableBug(
+                    "merging a node whose parent is a leaf"
+                        .to_owned(),
+                ))
+            }
+        };
+        let ((lhs, lhs_key), (rhs, rhs_key)) = match (
+            self.get_keyed_node(lhs_id, guard)?,
+            self.get_keyed_node(rhs_id, guard)?,
+        ) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => return Ok(false),
+        };
+        // a split of the left side that hasn't reached the parent
+        // yet puts another node between the two
+        if lhs.next != Some(rhs.id) {
+            return Ok(false);
+        }
+        let fits = lhs
+            .merge_with(&rhs)
+            .map_or(false, |merged| !self.should_split(&merged));
+        if !fits {
+            return Ok(false);
+        }
+
+        let mut frags = vec![
+            (
+                parent.id,
+                parent_key,
+                Frag::ParentMerge(ParentMerge {
+                    at: rhs.lo.clone(),
+                    to: rhs.id,
+                }),
+            ),
+            (
+                lhs.id,
+                lhs_key,
+                Frag::ChildMerge(ChildMerge { right: rhs.clone() }),
+            ),
+            (
+                rhs.id,
+                rhs_key,
+                Frag::ChildSplit(ChildSplit {
+                    at: rhs.lo.clone(),
+                    to: lhs.id,
+                }),
+            ),
+        ];
+        if let Some(next) = rhs.next {
+            let next_key = match self.get_keyed_node(next, guard)? {
+                Some((_, next_key)) => next_key,
+                None => return Ok(false),
+            };
+            frags.push((next, next_key, Frag::SetPrev(lhs.id)));
+        }
+        match self.pages.link_all(frags, guard) {
+            Ok(()) => {}
+            Err(Error::CasFailed(())) => return Ok(false),
+            Err(other) => return Err(other),
+        }
+        self.pages.free(rhs.id, guard).map_err(|e| e.danger_cast())?;
+        Ok(true)
+    }
+
+    // Copies the only child of the root into the root's page, when
+    // that child is an index, removing a level from the tree. The
+    // root keeps its page so that the chain of roots recovery
+    // follows is left alone. A split of the child racing with this
+    // leaves the node split off of it unreachable, but everything
+    // below it is still reached through the copy. Merges, which
+    // remove children, can't race with it.
+    fn collapse_root(
+        &self,
+        guard: &Guard,
+    ) -> DbResult<(), ()> {
+        let root_id = self.root_id();
+        let get = self.pages
+            .get(root_id, guard)
+            .map_err(|e| e.danger_cast())?;
+        let (root, prev_root, cas_key) = match get {
+            PageGet::Materialized(
+                Frag::Base(node, prev_root),
+                cas_key,
+            ) => (node, prev_root, cas_key),
+            _ => return Ok(()),
+        };
+        let child_id = match root.data {
+            Data::Index(ref ptrs) if ptrs.len() == 1 => ptrs[0].1,
+            _ => return Ok(()),
+        };
+        let mut child = match self.get_node(child_id, guard)? {
+            Some(child) => child,
+            None => return Ok(()),
+        };
+        if !child.data.is_index() || child.next.is_some() {
+            return Ok(());
+        }
+
+        child.id = root_id;
+        let replace = self.pages.replace(
+            root_id,
+            cas_key,
+            Frag::Base(child, prev_root),
+            guard,
+        );
+        match replace {
+            Ok(_) => {}
+            Err(Error::CasFailed(_)) => return Ok(()),
+            Err(other) => return Err(other.danger_cast()),
+        }
+        self.pages.free(child_id, guard).map_err(|e| e.danger_cast())
+    }
+
+    // The node at `pid`, or `None` if the page has been freed.
+    pub(super) fn get_node<'g>(
+        &self,
+        pid: PageID,
+        guard: &'g Guard,
+    ) -> DbResult<Option<Node>, ()> {
+        let node = self.get_keyed_node(pid, guard)?;
+        Ok(node.map(|(node, _)| node))
+    }
+
+    // `get_node`, along with the key to link to the node's page.
+    fn get_keyed_node<'g>(
+        &self,
+        pid: PageID,
+        guard: &'g Guard,
+    ) -> DbResult<Option<(Node, TreePtr<'g>)>, ()> {
+        let get = self.pages
+            .get(pid, guard)
+            .map_err(|e| e.danger_cast())?;
+        match get {
+            PageGet::Materialized(Frag::Base(node, _), key) => {
+                Ok(Some((node, key)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // The right sibling of `node`, which is `depth` levels below the
+    // root, or `None` at the end of its level, for walks that run
+    // alongside writes. If the sibling has been merged away since
+    // `node` was read, the node now holding the keys after `node`
+    // is found from the root instead, and may start before them.
+    fn next_on_level(
+        &self,
+        node: &Node,
+        depth: usize,
+        guard: &Guard,
+    ) -> DbResult<Option<Node>, ()> {
+        let next = match node.next {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        match self.get_node(next, guard)? {
+            Some(ref next)
+                if next.lo.inner() == node.hi.inner()
+                    && next.hi > next.lo =>
+            {
+                return Ok(Some(next.clone()))
+            }
+            _ => {}
+        }
+        let mut path = self.path_for_key(node.hi.inner(), guard)?;
+        if depth < path.len() {
+            Ok(Some(path.swap_remove(depth).0))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn parent_split<'g>(
         &self,
         parent_node: Node,
@@ -632,12 +3916,12 @@ impl Tree {
         )
     }
 
-    fn root_hoist<'g>(
+    fn root_hoist(
         &self,
         from: PageID,
         to: PageID,
         at: Key,
-        guard: &'g Guard,
+        guard: &Guard,
     ) -> DbResult<(), ()> {
         // hoist new root, pointing to lhs & rhs
         let new_root_pid = self.pages.allocate(guard)?;
@@ -654,8 +3938,13 @@ impl Tree {
                 id: new_root_pid,
                 data: Data::Index(new_root_vec),
                 next: None,
+                prev: None,
                 lo: Bound::Inclusive(vec![]),
                 hi: Bound::Inf,
+                prefix: None,
+                bloom: None,
+                expiries: Expiries::default(),
+                recent: RecentWrites::default(),
             },
             Some(from),
         );
@@ -697,27 +3986,27 @@ impl Tree {
 
         let ret = path.last().and_then(
             |&(ref last_node, ref _last_cas_key)| {
-                let data = &last_node.data;
-                let items = data.leaf_ref()
-                    .expect("last_node should be a leaf");
                 let encoded_key =
-                    prefix_encode(last_node.lo.inner(), key);
-                let search = items.binary_search_by(
-                    |&(ref k, ref _v)| prefix_cmp(k, &*encoded_key),
-                );
-                if let Ok(idx) = search {
-                    // cap a del frag below
-                    Some(items[idx].1.clone())
-                } else {
-                    // key does not exist
-                    None
-                }
+                    prefix_encode(last_node.prefix(), key);
+                last_node.leaf_value(&*encoded_key).cloned()
             },
         );
 
         Ok((path, ret))
     }
 
+    // When `key` expires, if it was written with a TTL.
+    pub(crate) fn expiry_of(
+        &self,
+        key: &[u8],
+    ) -> DbResult<Option<u64>, ()> {
+        let guard = pin();
+        let path = self.path_for_key(key, &guard)?;
+        Ok(path
+            .last()
+            .and_then(|&(ref leaf, _)| leaf.expiries.get(key)))
+    }
+
     #[doc(hidden)]
     pub fn key_debug_str(&self, key: &[u8]) -> String {
         let guard = pin();
@@ -814,7 +4103,7 @@ impl Tree {
                 }
             }
 
-            let prefix = node.lo.inner().to_vec();
+            let prefix = node.prefix().to_vec();
             path.push((node, cas_key));
 
             match path.last()
@@ -822,22 +4111,22 @@ impl Tree {
                 .0
                 .data
             {
-                Data::Index(ref ptrs) => {
+                ref index @ Data::Index(_) => {
                     let old_cursor = cursor;
-                    for &(ref sep_k, ref ptr) in ptrs {
-                        let decoded_sep_k =
-                            prefix_decode(&*prefix, sep_k);
-                        if &*decoded_sep_k <= &*key {
-                            cursor = *ptr;
-                        } else {
-                            break; // we've found our next cursor
-                        }
+                    let child = index.child_for(&prefix, key);
+                    if let Some(&(_, ptr)) = child {
+                        cursor = ptr;
                     }
                     if cursor == old_cursor {
                         panic!("stuck in page traversal loop");
                     }
                 }
-                Data::Leaf(_) => {
+                Data::Leaf(_)
+                | Data::DictLeaf(_)
+                | Data::LeafSet(_)
+                | Data::IntLeaf(_)
+                | Data::BlockLeaf(_)
+                | Data::CompressedLeaf(..) => {
                     break;
                 }
             }
@@ -906,7 +4195,12 @@ impl Debug for Tree {
                             panic!("trying to debug print empty index node");
                         }
                     }
-                    Data::Leaf(_items) => {
+                    Data::Leaf(_)
+                    | Data::DictLeaf(_)
+                    | Data::LeafSet(_)
+                    | Data::IntLeaf(_)
+                    | Data::BlockLeaf(_)
+                    | Data::CompressedLeaf(..) => {
                         // we've reached the end of our tree, all leafs are on
                         // the lowest level.
                         break;
@@ -918,3 +4212,423 @@ impl Debug for Tree {
         Ok(())
     }
 }
+
+// The gate of each tree in `trees`, once each, in a fixed order so
+// that callers holding overlapping sets of trees can't deadlock.
+fn gates<'a>(trees: &[&'a Tree]) -> Vec<&'a WriteGate> {
+    let mut gates: Vec<&WriteGate> =
+        trees.iter().map(|tree| &*tree.gate).collect();
+    gates.sort_by_key(|gate| *gate as *const WriteGate as usize);
+    gates.dedup_by_key(|gate| *gate as *const WriteGate as usize);
+    gates
+}
+
+// Waits for the writes in flight to every tree in `trees` to
+// finish, without holding off new ones.
+pub(crate) fn quiesce_writes(trees: &[&Tree]) {
+    for gate in gates(trees) {
+        gate.quiesce();
+    }
+}
+
+// Enters the gate of every tree in `trees`. A gate that's held is
+// waited on without having entered any of the others, so that a
+// `hold_writes` waiting for us to leave one of them can finish.
+fn enter_all<'a>(trees: &[&'a Tree]) -> Vec<Entered<'a>> {
+    let gates = gates(trees);
+    'retry: loop {
+        let mut entered = Vec::with_capacity(gates.len());
+        for gate in &gates {
+            match gate.try_enter() {
+                Some(writes) => entered.push(writes),
+                None => {
+                    drop(entered);
+                    gate.wait_released();
+                    continue 'retry;
+                }
+            }
+        }
+        return entered;
+    }
+}
+
+// Commits a batch to each of `trees`, which must share a pagecache,
+// as one log record, but only if each key in `reads` still holds
+// the value read for it. Returns `false` without writing anything
+// if one doesn't. The entries that the batches add to and remove
+// from the indexes of their trees are committed in the same
+// record. The batches are linked with `PageCache::link_all`, which
+// only links them if none of their leaves changed since they were
+// planned, so a concurrent write to one of those leaves makes the
+// batches be planned again rather than waiting for a lock.
+pub(super) fn commit_batches(
+    trees: &[&Tree],
+    reads: &[Vec<(Key, Option<Value>)>],
+    batches: &[Batch],
+) -> DbResult<bool, ()> {
+    let writes_to_read_only = trees
+        .iter()
+        .zip(batches)
+        .any(|(tree, batch)| {
+            tree.config.read_only && !batch.is_empty()
+        });
+    if writes_to_read_only {
+        return Err(Error::Unsupported(
+            "the database is in read-only mode".to_owned(),
+        ));
+    }
+
+    loop {
+        let declared: Vec<Vec<Index>> = trees
+            .iter()
+            .map(|tree| tree.indexes().declared())
+            .collect();
+        let mut all = trees.to_vec();
+        for index in declared.iter().flat_map(|d| d) {
+            if !all.iter().any(|tree| tree.is_same(&index.tree)) {
+                all.push(&index.tree);
+            }
+        }
+
+        let _writes = enter_all(&all);
+        // an index declared before we entered the gates must be
+        // maintained too, so start over with it
+        let stale = trees.iter().zip(&declared).any(|(tree, d)| {
+            tree.indexes().len() != d.len()
+        });
+        if stale {
+            M.tree_looped();
+            continue;
+        }
+        match try_commit(&all, reads, batches, &declared) {
+            Err(Error::CasFailed(())) => M.tree_looped(),
+            other => return other,
+        }
+    }
+}
+
+// `commit_batches`, once the gate of every tree is entered.
+// `trees` starts with the trees that `reads`, `batches` and
+// `declared` belong to, followed by any tree holding one of their
+// indexes that isn't written to otherwise. Fails with
+// `CasFailed`, without writing anything, if a leaf changed while
+// the batches were planned.
+fn try_commit(
+    trees: &[&Tree],
+    reads: &[Vec<(Key, Option<Value>)>],
+    batches: &[Batch],
+    declared: &[Vec<Index>],
+) -> DbResult<bool, ()> {
+    let guard = pin();
+    // the leaves of the keys read are planned first, so that a
+    // write to one of them after it's read stops the batches from
+    // being linked
+    let mut read_leaves = vec![];
+    for (tree, reads) in trees.iter().zip(reads) {
+        let mut leaves = vec![];
+        for &(ref key, ref read) in reads {
+            let current = tree.plan_read(key, &mut leaves, &guard)?;
+            if current != *read {
+                return Ok(false);
+            }
+        }
+        read_leaves.push(leaves);
+    }
+    read_leaves.resize_with(trees.len(), Vec::new);
+
+    // large values are written to blobs once the batches are sure
+    // to be applied, and freed again if linking them fails
+    let mut stored = Vec::with_capacity(batches.len());
+    for (tree, batch) in trees.iter().zip(batches) {
+        stored.push(tree.store_batch(batch, &guard)?);
+    }
+    let written: Vec<PageID> = stored
+        .iter()
+        .flat_map(|stored| stored)
+        .flat_map(|batch| batch.blobs())
+        .collect();
+    let batches: Vec<&Batch> = batches
+        .iter()
+        .zip(&stored)
+        .map(|(batch, stored)| stored.as_ref().unwrap_or(batch))
+        .collect();
+
+    // a tree's index entries are only known once its own batch is
+    // planned, so the trees holding indexes are planned last
+    let mut order: Vec<usize> = (0..trees.len()).collect();
+    order.sort_by_key(|&i| trees[i].indexes().is_index());
+    let ordered: Vec<&Tree> =
+        order.iter().map(|&i| trees[i]).collect();
+    let mut entries = vec![Batch::default(); trees.len()];
+    // the changes each batch made, where they were recorded
+    let mut recorded = vec![vec![]; trees.len()];
+
+    let mut planned: Vec<Vec<PlannedLeaf>> = vec![];
+    let mut deltas = vec![];
+    let mut linked = Ok(());
+    for &i in &order {
+        let tree = trees[i];
+        let indexes = declared.get(i).map_or(&[][..], |d| &d[..]);
+        let viewed = tree.is_viewed();
+        let mut changes = vec![];
+        let mut entry_changes = vec![];
+        let mut leaves = std::mem::take(&mut read_leaves[i]);
+        let plan = batches
+            .get(i)
+            .map_or(Ok(0), |batch| {
+                let recording = if indexes.is_empty()
+                    && !viewed
+                    && !batch.has_merges()
+                {
+                    None
+                } else {
+                    Some(&mut changes)
+                };
+                tree.plan_batch(batch, &mut leaves, recording, &guard)
+            })
+            .and_then(|delta| {
+                let batch = &entries[i];
+                let recording = if viewed {
+                    Some(&mut entry_changes)
+                } else {
+                    None
+                };
+                tree.plan_batch(batch, &mut leaves, recording, &guard)
+                    .map(|entries_delta| delta + entries_delta)
+            });
+        // nothing is linked until every tree is planned
+        let expiry_of = |key: &[u8]| tree.expiry_of(key);
+        let plan = plan.and_then(|delta| {
+            tree.views().preserve(&changes, &expiry_of)?;
+            tree.views().preserve(&entry_changes, &expiry_of)?;
+            Ok(delta)
+        });
+        planned.push(leaves);
+        match plan {
+            Ok(delta) => deltas.push(delta),
+            Err(e) => {
+                linked = Err(e);
+                break;
+            }
+        }
+        for index in indexes {
+            let held = trees
+                .iter()
+                .position(|tree| tree.is_same(&index.tree))
+                .expect(
+                    "the tree holding an index should be committed \
+                     along with the tree it indexes",
+                );
+            for change in &changes {
+                index.maintain(change, &mut entries[held]);
+            }
+        }
+        recorded[i] = changes;
+    }
+    let linked =
+        linked.and_then(|()| trees[0].link_batches(&planned, &guard));
+    if let Err(e) = linked {
+        // nothing points at the leaves split off yet
+        let new_leaves =
+            planned.iter().flat_map(|p| p).filter(|l| l.is_new);
+        for leaf in new_leaves {
+            trees[0]
+                .pages
+                .free(leaf.node.id, &guard)
+                .map_err(|e| e.danger_cast())?;
+        }
+        trees[0].free_blobs(written, &guard)?;
+        return Err(e);
+    }
+
+    // the blobs of the records that the batches overwrote or
+    // removed, and of values a later write in a batch replaced
+    let mut before = written;
+    let mut after = vec![];
+    for leaf in planned.iter().flat_map(|p| p) {
+        before.extend_from_slice(&leaf.blobs);
+        after.extend(trees[0].blobs_of(&leaf.node));
+    }
+    trees[0].free_blobs(blob::dropped(before, &after), &guard)?;
+
+    for (tree, delta) in ordered.iter().zip(deltas) {
+        tree.adjust_len(delta);
+    }
+    for (i, tree) in trees.iter().enumerate() {
+        // each operation of a recorded batch made one change
+        let ops = batches.get(i).map_or(&[][..], |b| &b.ops[..]);
+        for (j, op) in ops.iter().enumerate() {
+            tree.subscriptions
+                .publish(|| op.to_event(recorded[i].get(j)));
+        }
+        for op in &entries[i].ops {
+            tree.subscriptions.publish(|| op.to_event(None));
+        }
+    }
+    for (tree, planned) in ordered.iter().zip(&planned) {
+        tree.finish_batch(planned, &guard)?;
+    }
+    Ok(true)
+}
+
+// The data of a new tree's first leaf, whose kind every later leaf
+// keeps. The catalog of named trees maps names to page ids, and
+// the id tree holds the ids reserved by `Db::generate_id`, so they
+// always store plain records. Values are only compressed as a
+// block when the log doesn't already compress whole pages, which
+// shares repetition across values just the same.
+fn empty_leaf(config: &Config, head: PageID) -> Data {
+    let block =
+        config.block_compress_values && !log_compresses(config);
+    if head == CATALOG_HEAD || head == IDS_HEAD {
+        Data::Leaf(vec![])
+    } else if config.key_set {
+        Data::LeafSet(vec![])
+    } else if config.intern_values {
+        Data::DictLeaf(ValueDict::default())
+    } else if config.int_keys {
+        Data::IntLeaf(vec![])
+    } else if block {
+        Data::BlockLeaf(vec![])
+    } else if let Some(threshold) = config.compress_values_above {
+        let level = config.zstd_compression_factor;
+        let compression = ValueCompression {
+            threshold: threshold as u32,
+            codec: ValueCodec::Zstd(level),
+        };
+        Data::CompressedLeaf(compression, vec![])
+    } else {
+        Data::Leaf(vec![])
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn log_compresses(config: &Config) -> bool {
+    config.use_compression
+}
+
+#[cfg(not(feature = "zstd"))]
+fn log_compresses(_config: &Config) -> bool {
+    false
+}
+
+// Whether `key` lies in `[lo, hi)`.
+fn in_range(key: &[u8], lo: &Bound, hi: &Bound) -> bool {
+    key >= lo.inner()
+        && match *hi {
+            Bound::Inf => true,
+            ref hi => key < hi.inner(),
+        }
+}
+
+// Starts the pagecache that one or more trees live in.
+pub(crate) fn start_pages(
+    config: &Config,
+) -> DbResult<Arc<TreeCache>, ()> {
+    #[cfg(any(test, feature = "check_snapshot_integrity"))]
+    match config
+        .verify_snapshot::<BLinkMaterializer, Frag, Vec<(PageID, PageID)>>(
+        ) {
+        Ok(_) => {}
+        #[cfg(feature = "failpoints")]
+        Err(Error::FailPoint) => {}
+        // a configuration the pagecache refuses to open with
+        Err(e @ Error::Unsupported(_)) => return Err(e),
+        other => panic!("failed to verify snapshot: {:?}", other),
+    }
+
+    Ok(Arc::new(PageCache::start(config.clone())?))
+}
+
+// The first root of the tree whose chain starts at `head`.
+pub(crate) fn first_root(
+    roots: &[(PageID, PageID)],
+    head: PageID,
+) -> Option<PageID> {
+    roots
+        .iter()
+        .find(|&&(_, prev_root)| prev_root == head)
+        .map(|&(root, _)| root)
+}
+
+// Follows the root hoists recorded in `roots` from `first` to the
+// current root of its tree.
+fn last_root(roots: &[(PageID, PageID)], first: PageID) -> PageID {
+    let mut root = first;
+    while let Some(&(next, _)) =
+        roots.iter().find(|&&(_, prev_root)| prev_root == root)
+    {
+        root = next;
+    }
+    root
+}
+
+// The exclusive upper bound of the keys starting with `prefix`,
+// which is the prefix with trailing 0xFF bytes dropped and its last
+// remaining byte incremented. A prefix of only 0xFF bytes, or an
+// empty one, has no upper bound.
+fn prefix_end(prefix: &[u8]) -> Bound {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return Bound::Exclusive(end);
+        }
+    }
+    Bound::Inf
+}
+
+#[test]
+fn test_prefix_end() {
+    assert_eq!(prefix_end(b""), Bound::Inf);
+    assert_eq!(prefix_end(b"ab"), Bound::Exclusive(b"ac".to_vec()));
+    assert_eq!(
+        prefix_end(&[1, 0xFF, 0xFF]),
+        Bound::Exclusive(vec![2])
+    );
+    assert_eq!(prefix_end(&[0xFF, 0xFF]), Bound::Inf);
+}
+
+#[test]
+fn test_del_merges_underfull_nodes() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .merge_threshold(Some(2))
+        .build();
+    let t = Tree::start(config).unwrap();
+    let guard = pin();
+    let shape = |t: &Tree| {
+        let depth = t.path_for_key(b"", &guard).unwrap().len();
+        let mut leaves = 0;
+        let mut cursor = Some(t.leaf_for_key(b"", &guard).unwrap());
+        while let Some(pid) = cursor {
+            leaves += 1;
+            cursor = t.get_node(pid, &guard).unwrap().unwrap().next;
+        }
+        (depth, leaves)
+    };
+
+    for i in 0..64u8 {
+        t.set(vec![i], vec![i]).unwrap();
+    }
+    let (depth, leaves) = shape(&t);
+    assert!(depth > 3 && leaves > 16);
+
+    for i in 1..64u8 {
+        assert_eq!(t.del(&[i]), Ok(Some(vec![i].into())));
+    }
+    assert_eq!(shape(&t), (2, 1));
+    let items: Vec<_> = t.iter().map(|r| r.unwrap()).collect();
+    assert_eq!(items, vec![(vec![0], vec![0].into())]);
+
+    // deleting from the right merges nodes into their left siblings
+    for i in 1..64u8 {
+        t.set(vec![i], vec![i]).unwrap();
+    }
+    for i in (1..64u8).rev() {
+        assert_eq!(t.del(&[i]), Ok(Some(vec![i].into())));
+    }
+    assert_eq!(shape(&t), (2, 1));
+    assert_eq!(t.iter_rev().count(), 1);
+}
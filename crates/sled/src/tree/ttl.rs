@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::*;
+
+/// When the keys of a leaf that were written with a TTL expire, as
+/// milliseconds since the unix epoch, keyed by decoded key. Any
+/// other write to a key forgets its expiration. Expired records
+/// stay in the leaf, hidden from reads, until a sweep deletes them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Expiries(BTreeMap<Key, u64>);
+
+impl Expiries {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn get(&self, key: KeyRef) -> Option<u64> {
+        self.0.get(key).cloned()
+    }
+
+    pub fn set(&mut self, key: Key, at: u64) {
+        self.0.insert(key, at);
+    }
+
+    pub fn remove(&mut self, key: KeyRef) {
+        self.0.remove(key);
+    }
+
+    /// Forgets every key in `[lo, hi)`.
+    pub fn remove_range(&mut self, lo: &Bound, hi: &Bound) {
+        if self.is_empty() {
+            return;
+        }
+        self.0.retain(|k, _| {
+            let k = Bound::Inclusive(k.clone());
+            k < *lo || k >= *hi
+        });
+    }
+
+    /// Removes and returns the expirations of keys at or above
+    /// `at`.
+    pub fn split_off(&mut self, at: KeyRef) -> Expiries {
+        Expiries(self.0.split_off(at))
+    }
+
+    /// Copies the expirations of keys at or above `at`.
+    pub fn above(&self, at: KeyRef) -> Expiries {
+        Expiries(
+            self.0
+                .range(at.to_vec()..)
+                .map(|(k, &at)| (k.clone(), at))
+                .collect(),
+        )
+    }
+
+    /// Combines these expirations with a right sibling's.
+    pub fn merged(&self, right: &Expiries) -> Expiries {
+        let mut ret = self.clone();
+        ret.0.extend(right.iter().map(|(k, at)| (k.clone(), at)));
+        ret
+    }
+
+    pub fn iter<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (&'a Key, u64)> {
+        self.0.iter().map(|(k, &at)| (k, at))
+    }
+
+    pub fn is_expired(&self, key: KeyRef, now: u64) -> bool {
+        self.get(key).map_or(false, |at| at <= now)
+    }
+}
+
+/// The current time in milliseconds since the unix epoch, the
+/// clock that expirations are measured against.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+impl Node {
+    /// Returns `true` if a decoded key was written with a TTL that
+    /// has passed. Reads treat such a key as absent.
+    pub fn is_expired(&self, key: KeyRef) -> bool {
+        !self.expiries.is_empty()
+            && self.expiries.is_expired(key, now_millis())
+    }
+
+    // `is_expired` for a key encoded against the node's prefix.
+    pub(super) fn is_expired_encoded(&self, key: KeyRef) -> bool {
+        !self.expiries.is_empty()
+            && self.is_expired(&prefix_decode(self.prefix(), key))
+    }
+
+    /// The decoded keys of the records whose TTL has passed, in
+    /// order.
+    pub fn expired_keys(&self) -> Vec<Key> {
+        let now = now_millis();
+        self.expiries
+            .iter()
+            .filter(|&(_, at)| at <= now)
+            .map(|(k, _)| k.clone())
+            .filter(|k| self.data.get(k, self.prefix()).is_some())
+            .collect()
+    }
+
+    // Forgets the expiration of a key encoded against the node's
+    // prefix, which every write but a TTL'd set does.
+    pub(super) fn forget_expiry(&mut self, key: KeyRef) {
+        if !self.expiries.is_empty() {
+            let decoded = prefix_decode(self.prefix(), key);
+            self.expiries.remove(&decoded);
+        }
+    }
+}
+
+// Sweeps a tree's expired keys on a background thread until it is
+// dropped.
+pub(super) struct Sweeper {
+    // dropping this wakes the thread up to exit
+    shutdown: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    // Starts sweeping `tree` every `every_ms`. The thread holds its
+    // own handle to the tree, which must not own this sweeper, or
+    // the tree would never be dropped.
+    pub(super) fn start(tree: Tree, every_ms: u64) -> Sweeper {
+        let (tx, rx) = channel();
+        let handle = thread::Builder::new()
+            .name("sled_expiry_sweeper".to_owned())
+            .spawn(move || loop {
+                match rx.recv_timeout(Duration::from_millis(every_ms))
+                {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                if let Err(e) = tree.sweep_expired() {
+                    error!("failed to sweep expired keys: {:?}", e);
+                }
+            })
+            .unwrap();
+        Sweeper {
+            shutdown: Some(tx),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.shutdown.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("error joining expiry sweeper: {:?}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_expiries() {
+    let mut expiries = Expiries::default();
+    for (i, at) in [5u64, 10, 15, 20].iter().enumerate() {
+        expiries.set(vec![i as u8], *at);
+    }
+    assert!(expiries.is_expired(&[0], 5));
+    assert!(!expiries.is_expired(&[1], 5));
+    assert!(!expiries.is_expired(&[9], 100));
+
+    let right = expiries.split_off(&[2]);
+    assert_eq!(expiries.len(), 2);
+    assert_eq!(right.get(&[3]), Some(20));
+    assert_eq!(expiries.merged(&right).len(), 4);
+    assert_eq!(right.above(&[3]).len(), 1);
+
+    let mut all = expiries.merged(&right);
+    all.remove_range(
+        &Bound::Inclusive(vec![1]),
+        &Bound::Exclusive(vec![3]),
+    );
+    let keys: Vec<&Key> = all.iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec![&vec![0], &vec![3]]);
+}
+
+#[test]
+fn test_expired_records_are_hidden() {
+    let config = ConfigBuilder::new().build();
+    let mut node = leaf(Bound::Inclusive(vec![]), Bound::Inf);
+    for i in 0..6u8 {
+        let k = prefix_encode(b"", &[i]);
+        let set = Frag::Set(k.clone(), vec![i].into());
+        // odd keys expired long ago
+        let frag = if i % 2 == 1 {
+            Frag::Writes(vec![set, Frag::Expire(k, 1)])
+        } else {
+            set
+        };
+        node.apply(&frag, &config).unwrap();
+    }
+
+    let keys: Vec<Key> = node.iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec![vec![0], vec![2], vec![4]]);
+    assert_eq!(node.leaf_value(&prefix_encode(b"", &[1])), None);
+    assert_eq!(node.successor(&[0]).unwrap().0, vec![2]);
+    assert_eq!(node.predecessor(&[4]).unwrap().0, vec![2]);
+    assert_eq!(node.last_within(&Bound::Inf).unwrap().0, vec![4]);
+    assert_eq!(node.expired_keys(), vec![vec![1], vec![3], vec![5]]);
+
+    // expirations survive serialization and follow their keys
+    // through a split
+    let mut buf = vec![];
+    node.serialize_into(&mut buf);
+    assert_eq!(Node::deserialize_from(&buf), Ok(node.clone()));
+    let right = node.split(1, 0.5);
+    assert_eq!(right.lo, Bound::Inclusive(vec![4]));
+    node.child_split(&ChildSplit {
+        at: right.lo.clone(),
+        to: 1,
+    });
+    assert_eq!(node.expired_keys(), vec![vec![1], vec![3]]);
+    assert_eq!(right.expired_keys(), vec![vec![5]]);
+
+    // a plain write makes a key permanent again
+    let frag = Frag::Set(prefix_encode(b"", &[1]), vec![9].into());
+    node.apply(&frag, &config).unwrap();
+    assert_eq!(node.expired_keys(), vec![vec![3]]);
+    assert!(node.leaf_value(&prefix_encode(b"", &[1])).is_some());
+}
@@ -0,0 +1,272 @@
+// Read views. A view reads a tree as it was when the view was
+// taken, while writers carry on. Rather than keeping old versions
+// of pages around, every write to a tree with an open view goes
+// through `commit_batches`, which works out the value each key
+// held before the write, and saves it in every open view that
+// hasn't saved one for that key yet before the write is linked.
+// A view reads the live tree first and its saved values second,
+// so a write that lands in between is never seen without the
+// value it replaced.
+
+use std::collections::BTreeMap;
+use std::ops::{self, RangeBounds};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::node::Change;
+use super::*;
+
+// The values that keys written since a view was taken held when
+// it was taken, with when they expire if they were written with a
+// TTL, and `None` for keys that didn't exist yet.
+pub(super) type Preserved =
+    Mutex<BTreeMap<Key, Option<(Value, Option<u64>)>>>;
+
+// The views open on a tree, shared by all of its handles. Whoever
+// opens one then waits for the writes to the tree in flight to
+// finish, after which every write knows it has to save what it
+// overwrites.
+#[derive(Default)]
+pub(super) struct Views {
+    open: Mutex<Vec<Weak<Preserved>>>,
+}
+
+impl Views {
+    // Opens a view of the tree. The caller must wait for the
+    // writes in flight to finish before reading through it.
+    fn open(&self) -> Arc<Preserved> {
+        let preserved = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut open = self.open.lock().unwrap();
+        open.retain(|view| view.upgrade().is_some());
+        open.push(Arc::downgrade(&preserved));
+        preserved
+    }
+
+    pub(super) fn is_open(&self) -> bool {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|view| view.upgrade().is_some());
+        !open.is_empty()
+    }
+
+    // Saves the previous value of each changed key in every open
+    // view that doesn't have one for it yet, along with when it
+    // expires as `expiry_of` finds in the live tree. This must run
+    // before the changes are linked.
+    pub(super) fn preserve<F>(
+        &self,
+        changes: &[Change],
+        expiry_of: F,
+    ) -> DbResult<(), ()>
+    where
+        F: Fn(&[u8]) -> DbResult<Option<u64>, ()>,
+    {
+        let open = self.open.lock().unwrap();
+        for view in open.iter().filter_map(|view| view.upgrade()) {
+            let mut preserved = view.lock().unwrap();
+            for &(ref key, ref old, _) in changes {
+                if preserved.contains_key(key) {
+                    continue;
+                }
+                let old = match *old {
+                    Some(ref v) => Some((v.clone(), expiry_of(key)?)),
+                    None => None,
+                };
+                preserved.insert(key.clone(), old);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A read-only view of a `Tree` as it was when `Db::read_view`
+/// was called. Writes to the tree carry on meanwhile, but aren't
+/// seen through the view. The values that writes replace are kept
+/// in memory until every view taken before them is dropped, so
+/// views are meant to be short-lived. Keys written with a TTL are
+/// read as of now rather than as of the view.
+#[derive(Clone)]
+pub struct TreeView {
+    tree: Tree,
+    preserved: Arc<Preserved>,
+}
+
+impl TreeView {
+    // A view of `tree`, which can't be read from until the writes
+    // to it in flight have finished.
+    pub(crate) fn new(tree: &Tree) -> TreeView {
+        TreeView {
+            tree: tree.clone(),
+            preserved: tree.views().open(),
+        }
+    }
+
+    /// Retrieve the value `key` held when the view was taken.
+    pub fn get(&self, key: &[u8]) -> DbResult<Option<Value>, ()> {
+        let live = self.tree.get(key)?;
+        match self.preserved.lock().unwrap().get(key) {
+            Some(old) => Ok(old.clone().map(|(v, _)| v)),
+            None => Ok(live),
+        }
+    }
+
+    /// Returns `true` if `key` had a value when the view was taken.
+    pub fn contains_key(&self, key: &[u8]) -> DbResult<bool, ()> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    // Calls `f` with every record of the view in order, with when
+    // it expires if it was written with a TTL.
+    pub(crate) fn visit_records<F>(
+        &self,
+        mut f: F,
+    ) -> DbResult<(), ()>
+    where
+        F: FnMut(Key, &Value, Option<u64>) -> DbResult<(), ()>,
+    {
+        for record in self.iter() {
+            let (k, v) = record?;
+            // the live expiry is read before the preserved one, so
+            // a write landing in between has saved the old one
+            let live = self.tree.expiry_of(&k)?;
+            let preserved = self.preserved.lock().unwrap();
+            let expires = match preserved.get(&k) {
+                Some(&Some((_, expires))) => expires,
+                _ => live,
+            };
+            drop(preserved);
+            f(k, &v, expires)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates over the keys and values of the view, in order.
+    pub fn iter(&self) -> ViewIter<'_> {
+        self.range::<Vec<u8>, _>(..)
+    }
+
+    /// Iterates over the keys and values of the view, starting
+    /// from `key`.
+    pub fn scan(&self, key: &[u8]) -> ViewIter<'_> {
+        self.range(key..)
+    }
+
+    /// Iterates over the keys and values of the view that fall in
+    /// `range`.
+    pub fn range<K, R>(&self, range: R) -> ViewIter<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let owned = |bound: ops::Bound<&K>| match bound {
+            ops::Bound::Included(k) => {
+                ops::Bound::Included(k.as_ref().to_vec())
+            }
+            ops::Bound::Excluded(k) => {
+                ops::Bound::Excluded(k.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+        let lo = owned(range.start_bound());
+        let hi = owned(range.end_bound());
+        ViewIter {
+            live: self.tree.range((lo.clone(), hi.clone())),
+            preserved: &self.preserved,
+            lo: lo,
+            hi: hi,
+            next_live: None,
+            live_done: false,
+        }
+    }
+}
+
+/// An iterator over the keys and values of a `TreeView`, returned
+/// by `TreeView::iter`, `scan` and `range`.
+pub struct ViewIter<'a> {
+    live: Iter<'a>,
+    preserved: &'a Preserved,
+    // the keys still to be returned
+    lo: ops::Bound<Key>,
+    hi: ops::Bound<Key>,
+    // the live record after the last key returned
+    next_live: Option<(Key, Value)>,
+    live_done: bool,
+}
+
+impl<'a> ViewIter<'a> {
+    // The first preserved key after the last one returned, up to
+    // and including the next live key.
+    fn next_preserved(&self) -> Option<(Key, Option<Value>)> {
+        let hi = match self.next_live {
+            Some((ref k, _)) => ops::Bound::Included(&**k),
+            None => as_slice(&self.hi),
+        };
+        let lo = as_slice(&self.lo);
+        if is_empty(lo, hi) {
+            return None;
+        }
+        let preserved = self.preserved.lock().unwrap();
+        preserved
+            .range::<[u8], _>((lo, hi))
+            .next()
+            .map(|(k, old)| (k.clone(), old.clone().map(|(v, _)| v)))
+    }
+}
+
+impl<'a> Iterator for ViewIter<'a> {
+    type Item = DbResult<(Key, Value), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next_live.is_none() && !self.live_done {
+                match self.live.next() {
+                    Some(Ok(record)) => self.next_live = Some(record),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.live_done = true,
+                }
+            }
+            // the live record is read before the preserved values,
+            // so one written since the view was taken has had its
+            // previous value saved by now
+            let (key, value) = match self.next_preserved() {
+                Some((key, old)) => {
+                    let replaced = self
+                        .next_live
+                        .as_ref()
+                        .map_or(false, |&(ref k, _)| *k == key);
+                    if replaced {
+                        self.next_live = None;
+                    }
+                    (key, old)
+                }
+                None => match self.next_live.take() {
+                    Some((key, value)) => (key, Some(value)),
+                    None => return None,
+                },
+            };
+            self.lo = ops::Bound::Excluded(key.clone());
+            if let Some(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+fn as_slice(bound: &ops::Bound<Key>) -> ops::Bound<&[u8]> {
+    match *bound {
+        ops::Bound::Included(ref k) => ops::Bound::Included(&**k),
+        ops::Bound::Excluded(ref k) => ops::Bound::Excluded(&**k),
+        ops::Bound::Unbounded => ops::Bound::Unbounded,
+    }
+}
+
+// Whether no key lies between `lo` and `hi`, which `BTreeMap::range`
+// panics on when they cross.
+fn is_empty(lo: ops::Bound<&[u8]>, hi: ops::Bound<&[u8]>) -> bool {
+    use std::ops::Bound::*;
+    match (lo, hi) {
+        (Included(lo), Included(hi)) => lo > hi,
+        (Included(lo), Excluded(hi))
+        | (Excluded(lo), Included(hi))
+        | (Excluded(lo), Excluded(hi)) => lo >= hi,
+        _ => false,
+    }
+}
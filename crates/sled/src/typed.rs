@@ -0,0 +1,283 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use bincode::{deserialize, serialize, Infinite};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::*;
+use keys::OrderedKey;
+
+/// Converts values of type `T` to and from the bytes stored in a
+/// `Tree`, for use by `TypedTree`.
+pub trait Codec<T> {
+    /// Names this encoding, so that `Db::open_typed_tree` can
+    /// refuse to read a tree written with a different one.
+    fn name() -> String;
+
+    /// Encodes `value` as bytes.
+    fn encode(value: &T) -> Vec<u8>;
+
+    /// Decodes bytes written by `encode`, returning `None` if they
+    /// don't describe a `T`.
+    fn decode(bytes: &[u8]) -> Option<T>;
+}
+
+/// A `Codec` for any serde type, using bincode. Bincode writes
+/// integers little-endian, so the encoded bytes don't sort in the
+/// same order as the values they encode, which makes this suited
+/// to values but not to keys that will be iterated over in order.
+/// Encoding panics if `T`'s `Serialize` implementation fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bincode;
+
+impl<T> Codec<T> for Bincode
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn name() -> String {
+        "bincode".to_owned()
+    }
+
+    fn encode(value: &T) -> Vec<u8> {
+        serialize(value, Infinite).expect("failed to serialize value")
+    }
+
+    fn decode(bytes: &[u8]) -> Option<T> {
+        deserialize(bytes).ok()
+    }
+}
+
+/// A `Codec` whose encoded bytes sort in the same order as the
+/// values they encode, for any `OrderedKey`, using the encodings
+/// in the `keys` module.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ordered;
+
+impl<T> Codec<T> for Ordered
+where
+    T: OrderedKey,
+{
+    fn name() -> String {
+        "ordered".to_owned()
+    }
+
+    fn encode(value: &T) -> Vec<u8> {
+        keys::encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<T> {
+        keys::decode(bytes)
+    }
+}
+
+/// A `Codec` that sorts keys in the opposite order to the codec
+/// `C`, by inverting every bit of its encoding. This only reverses
+/// the order if no encoding written by `C` is a prefix of another,
+/// which holds for `Ordered` and `CaseInsensitive`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reversed<C>(PhantomData<C>);
+
+impl<T, C> Codec<T> for Reversed<C>
+where
+    C: Codec<T>,
+{
+    fn name() -> String {
+        format!("reversed {}", C::name())
+    }
+
+    fn encode(value: &T) -> Vec<u8> {
+        C::encode(value).into_iter().map(|b| !b).collect()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<T> {
+        let inverted: Vec<u8> = bytes.iter().map(|b| !b).collect();
+        C::decode(&inverted)
+    }
+}
+
+/// A `Codec` for `String` keys that sorts them ignoring case, by
+/// storing them in lowercase. Keys that differ only in case are
+/// the same key, and are read back in lowercase.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CaseInsensitive;
+
+impl Codec<String> for CaseInsensitive {
+    fn name() -> String {
+        "case insensitive".to_owned()
+    }
+
+    fn encode(value: &String) -> Vec<u8> {
+        keys::encode(&value.to_lowercase())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<String> {
+        keys::decode(bytes)
+    }
+}
+
+/// A view of a `Tree` whose keys and values are of types `K` and
+/// `V`, converted to and from bytes by the codecs `KC` and `VC`.
+/// Keys default to the order-preserving `Ordered` codec, so that
+/// iteration and `range` follow the natural order of `K`, and
+/// values default to `Bincode`. Other orders, such as `Reversed`
+/// or `CaseInsensitive`, or a collation, come from choosing or
+/// implementing a different key codec. Reading a key or value that
+/// the codec can't decode fails with `Error::Unsupported`.
+///
+/// # Examples
+///
+/// ```
+/// use sled::TypedTree;
+///
+/// let config = sled::ConfigBuilder::new().temporary(true).build();
+/// let t = sled::Tree::start(config).unwrap();
+/// let typed: TypedTree<u64, String> = TypedTree::new(t);
+///
+/// typed.set(&256, &"b".to_owned()).unwrap();
+/// typed.set(&1, &"a".to_owned()).unwrap();
+/// assert_eq!(typed.get(&1), Ok(Some("a".to_owned())));
+///
+/// let keys: Vec<u64> =
+///     typed.iter().map(|res| res.unwrap().0).collect();
+/// assert_eq!(keys, vec![1, 256]);
+/// ```
+pub struct TypedTree<K, V, KC = Ordered, VC = Bincode> {
+    tree: Tree,
+    marker: PhantomData<fn() -> (K, V, KC, VC)>,
+}
+
+impl<K, V, KC, VC> Clone for TypedTree<K, V, KC, VC> {
+    fn clone(&self) -> TypedTree<K, V, KC, VC> {
+        TypedTree {
+            tree: self.tree.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, KC, VC> TypedTree<K, V, KC, VC>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    /// Wraps `tree`, which should only hold keys and values written
+    /// with the same codecs. `Db::open_typed_tree` also checks that
+    /// a named tree's key codec hasn't changed since it was
+    /// created.
+    pub fn new(tree: Tree) -> TypedTree<K, V, KC, VC> {
+        TypedTree {
+            tree: tree,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the underlying `Tree`.
+    pub fn inner(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Retrieve the value for `key`, if it exists.
+    pub fn get(&self, key: &K) -> DbResult<Option<V>, ()> {
+        match self.tree.get(&KC::encode(key))? {
+            Some(v) => decode_value::<V, VC>(&v).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `true` if the tree holds a value for `key`.
+    pub fn contains_key(&self, key: &K) -> DbResult<bool, ()> {
+        self.tree.contains_key(&KC::encode(key))
+    }
+
+    /// Set `key` to `value`.
+    pub fn set(&self, key: &K, value: &V) -> DbResult<(), ()> {
+        self.tree.set(KC::encode(key), VC::encode(value))
+    }
+
+    /// Delete `key`, returning the value it had, if any.
+    pub fn del(&self, key: &K) -> DbResult<Option<V>, ()> {
+        match self.tree.del(&KC::encode(key))? {
+            Some(v) => decode_value::<V, VC>(&v).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterates over every record, in the order of the encoded
+    /// keys.
+    pub fn iter(&self) -> TypedIter<'_, K, V, KC, VC> {
+        TypedIter::new(self.tree.iter())
+    }
+
+    /// Iterates over the records starting at `key`.
+    pub fn scan(&self, key: &K) -> TypedIter<'_, K, V, KC, VC> {
+        TypedIter::new(self.tree.scan(&KC::encode(key)))
+    }
+
+    /// Iterates over the records whose keys fall within `range`,
+    /// which is only meaningful for an order-preserving key codec.
+    pub fn range<R>(&self, range: R) -> TypedIter<'_, K, V, KC, VC>
+    where
+        R: RangeBounds<K>,
+    {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(k) => Bound::Included(KC::encode(k)),
+            Bound::Excluded(k) => Bound::Excluded(KC::encode(k)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let bounds =
+            (encode(range.start_bound()), encode(range.end_bound()));
+        TypedIter::new(self.tree.range(bounds))
+    }
+}
+
+fn decode_value<V, VC>(bytes: &[u8]) -> DbResult<V, ()>
+where
+    VC: Codec<V>,
+{
+    VC::decode(bytes).ok_or_else(|| {
+        Error::Unsupported(format!(
+            "value {:?} can't be decoded by this tree's codec",
+            bytes
+        ))
+    })
+}
+
+/// An iterator over the decoded keys and values of a `TypedTree`.
+pub struct TypedIter<'a, K, V, KC, VC> {
+    inner: Iter<'a>,
+    marker: PhantomData<fn() -> (K, V, KC, VC)>,
+}
+
+impl<'a, K, V, KC, VC> TypedIter<'a, K, V, KC, VC> {
+    fn new(inner: Iter<'a>) -> TypedIter<'a, K, V, KC, VC> {
+        TypedIter {
+            inner: inner,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V, KC, VC> Iterator for TypedIter<'a, K, V, KC, VC>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    type Item = DbResult<(K, V), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = match self.inner.next()? {
+            Ok(kv) => kv,
+            Err(e) => return Some(Err(e)),
+        };
+        let key = KC::decode(&k).ok_or_else(|| {
+            Error::Unsupported(format!(
+                "key {:?} can't be decoded by this tree's codec",
+                k
+            ))
+        });
+        Some(key.and_then(|key| {
+            decode_value::<V, VC>(&v).map(|value| (key, value))
+        }))
+    }
+}
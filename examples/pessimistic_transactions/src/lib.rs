@@ -212,7 +212,7 @@ impl<'a> Tx<'a> {
 
         // perform predicate matches
         for &Predicate(ref k, ref p) in &self.predicates {
-            let current = self.db.tree.get(k)?;
+            let current = self.db.tree.get(k)?.map(Vec::from);
             if !p(&k, &current) {
                 return Ok(TxRet::PredicateFailure);
             }
@@ -231,7 +231,8 @@ impl<'a> Tx<'a> {
         // perform gets
         let mut ret_gets = vec![];
         for &Read(ref k) in &self.gets {
-            ret_gets.push((k.clone(), self.db.tree.get(k)?));
+            let value = self.db.tree.get(k)?.map(Vec::from);
+            ret_gets.push((k.clone(), value));
         }
 
         Ok(TxRet::Committed(ret_gets))
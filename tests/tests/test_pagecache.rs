@@ -9,10 +9,15 @@ use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use epoch::{pin, Shared};
 use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen};
 
-use pagecache::{ConfigBuilder, Materializer, PageCache, PageGet};
+use pagecache::{
+    ConfigBuilder, Materializer, MergeError, PageCache, PageGet,
+};
 
 type PageID = usize;
 
+// a frag value that TestMaterializer refuses to merge
+const CORRUPT: usize = std::usize::MAX;
+
 #[derive(Clone)]
 pub struct TestMaterializer;
 
@@ -27,14 +32,23 @@ impl Materializer for TestMaterializer {
         TestMaterializer
     }
 
-    fn merge(&self, frags: &[&Vec<usize>]) -> Vec<usize> {
+    fn merge(
+        &self,
+        frags: &[&Vec<usize>],
+    ) -> Result<Vec<usize>, MergeError> {
         let mut consolidated = vec![];
-        for &frag in frags.iter() {
+        for (idx, &frag) in frags.iter().enumerate() {
+            if frag.contains(&CORRUPT) {
+                return Err(MergeError {
+                    idx: idx,
+                    reason: "found a corrupt frag".to_owned(),
+                });
+            }
             let mut frag = frag.clone();
             consolidated.append(&mut frag);
         }
 
-        consolidated
+        Ok(consolidated)
     }
 
     fn recover(&self, _: &Vec<usize>) -> Option<()> {
@@ -74,6 +88,32 @@ fn pagecache_caching() {
     }
 }
 
+#[test]
+fn pagecache_chain_len_is_bounded() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .page_consolidation_threshold(4)
+        .build();
+
+    let pc: PageCache<TestMaterializer, _, _> =
+        PageCache::start(config.clone()).unwrap();
+
+    let guard = pin();
+    let id = pc.allocate(&guard).unwrap();
+    let mut key =
+        pc.replace(id, Shared::null(), vec![0], &guard).unwrap();
+
+    // appending without ever reading the page still consolidates
+    // it once its chain grows past the threshold
+    for i in 1..100 {
+        key = pc.link(id, key, vec![i], &guard).unwrap();
+        assert!(pc.chain_len(id) <= 4);
+    }
+    let (page, _) = pc.get(id, &guard).unwrap().unwrap();
+    assert_eq!(page, (0..100).collect::<Vec<_>>());
+}
+
 #[test]
 fn pagecache_strange_crash_1() {
     let config = ConfigBuilder::new()
@@ -202,6 +242,81 @@ fn basic_pagecache_recovery() {
     assert!(res.is_free());
 }
 
+#[test]
+fn pagecache_link_all_recovery() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .io_buf_size(1000)
+        .build();
+
+    let pc: PageCache<TestMaterializer, _, _> =
+        PageCache::start(config.clone()).unwrap();
+
+    let guard = pin();
+    let a = pc.allocate(&guard).unwrap();
+    let b = pc.allocate(&guard).unwrap();
+    let a_key =
+        pc.replace(a, Shared::null(), vec![1], &guard).unwrap();
+    let b_key =
+        pc.replace(b, Shared::null(), vec![10], &guard).unwrap();
+    let b_key = pc.link(b, b_key, vec![11], &guard).unwrap();
+
+    // nothing is linked if one page has moved on
+    match pc.link_all(
+        vec![(b, b_key, vec![20]), (a, Shared::null(), vec![2])],
+        &guard,
+    ) {
+        Err(pagecache::Error::CasFailed(())) => {}
+        other => panic!("expected CasFailed, got {:?}", other),
+    }
+    // reading a page may consolidate it, giving it a new key
+    let (b_page, b_key) = pc.get(b, &guard).unwrap().unwrap();
+    assert_eq!(b_page, vec![10, 11]);
+
+    let frags = vec![(a, a_key, vec![2]), (b, b_key, vec![20])];
+    pc.link_all(frags, &guard).unwrap();
+
+    // a page may only appear once in a batch
+    let (_, a_key) = pc.get(a, &guard).unwrap().unwrap();
+    assert!(
+        pc.link_all(
+            vec![(a, a_key, vec![3]), (a, a_key, vec![4])],
+            &guard
+        ).is_err()
+    );
+    drop(pc);
+
+    let pc2: PageCache<TestMaterializer, _, _> =
+        PageCache::start(config.clone()).unwrap();
+    let (a_page, _) = pc2.get(a, &guard).unwrap().unwrap();
+    let (b_page, _) = pc2.get(b, &guard).unwrap().unwrap();
+    assert_eq!(a_page, vec![1, 2]);
+    assert_eq!(b_page, vec![10, 11, 20]);
+}
+
+#[test]
+fn pagecache_merge_error_is_corruption() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .io_buf_size(1000)
+        .build();
+
+    let pc: PageCache<TestMaterializer, _, _> =
+        PageCache::start(config.clone()).unwrap();
+
+    let guard = pin();
+    let id = pc.allocate(&guard).unwrap();
+    let key =
+        pc.replace(id, Shared::null(), vec![1], &guard).unwrap();
+    pc.link(id, key, vec![CORRUPT], &guard).unwrap();
+    match pc.get(id, &guard) {
+        Err(pagecache::Error::Corruption { .. }) => {}
+        other => panic!("expected Corruption, got {:?}", other),
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Op {
     Replace(PageID, usize),
@@ -4,6 +4,9 @@ extern crate rand;
 extern crate sled;
 
 use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
 use std::thread;
 
@@ -56,12 +59,12 @@ fn parallel_tree_ops() {
     par!{t, |tree: &Tree, k: Vec<u8>| {
         assert_eq!(tree.get(&*k), Ok(None));
         tree.set(k.clone(), k.clone()).unwrap();
-        assert_eq!(tree.get(&*k), Ok(Some(k)));
+        assert_eq!(tree.get(&*k), Ok(Some(k.into())));
     }};
 
     println!("========== reading sets ==========");
     par!{t, |tree: &Tree, k: Vec<u8>| {
-        if tree.get(&*k.clone()) != Ok(Some(k.clone())) {
+        if tree.get(&*k.clone()) != Ok(Some(k.clone().into())) {
             println!("{}", tree.key_debug_str(&*k.clone()));
             panic!("expected key {:?} not found", k);
         }
@@ -79,7 +82,7 @@ fn parallel_tree_ops() {
         let k1 = k.clone();
         let mut k2 = k.clone();
         k2.reverse();
-        assert_eq!(tree.get(&*k1), Ok(Some(k2)));
+        assert_eq!(tree.get(&*k1), Ok(Some(k2.into())));
     }};
 
     println!("========== deleting ==========");
@@ -114,7 +117,7 @@ fn tree_subdir() {
 
     std::fs::remove_dir_all("/tmp/test_tree_subdir").unwrap();
 
-    assert_eq!(res, Ok(Some(vec![1])));
+    assert_eq!(res, Ok(Some(vec![1].into())));
 }
 
 #[test]
@@ -149,55 +152,3391 @@ fn tree_iterator() {
     let mut tree_scan = t.scan(&*half_key);
     assert_eq!(
         tree_scan.next(),
-        Some(Ok((half_key.clone(), half_key)))
+        Some(Ok((half_key.clone(), half_key.clone().into())))
     );
 
     let first_key = kv(0);
     let mut tree_scan = t.scan(&*first_key);
     assert_eq!(
         tree_scan.next(),
-        Some(Ok((first_key.clone(), first_key)))
+        Some(Ok((first_key.clone(), first_key.clone().into())))
     );
 
     let last_key = kv(N_PER_THREAD - 1);
     let mut tree_scan = t.scan(&*last_key);
     assert_eq!(
         tree_scan.next(),
-        Some(Ok((last_key.clone(), last_key)))
+        Some(Ok((last_key.clone(), last_key.into())))
     );
     assert_eq!(tree_scan.next(), None);
+
+    let reversed: Vec<Vec<u8>> =
+        t.iter_rev().map(|res| res.unwrap().0).collect();
+    let expected: Vec<Vec<u8>> =
+        (0..N_PER_THREAD).rev().map(kv).collect();
+    assert_eq!(reversed, expected);
+
+    let mut tree_scan = t.scan_rev(&*half_key);
+    assert_eq!(
+        tree_scan.next(),
+        Some(Ok((half_key.clone(), half_key.clone().into())))
+    );
+    let prior = kv(half_way - 1);
+    assert_eq!(
+        tree_scan.next(),
+        Some(Ok((prior.clone(), prior.into())))
+    );
+
+    let mut tree_scan = t.scan_rev(&*first_key);
+    assert_eq!(
+        tree_scan.next(),
+        Some(Ok((first_key.clone(), first_key.into())))
+    );
+    assert_eq!(tree_scan.next(), None);
+}
+
+#[test]
+fn tree_range() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![]).unwrap();
+    }
+    let keys = |iter: sled::Iter| -> Vec<Vec<u8>> {
+        iter.map(|res| res.unwrap().0).collect()
+    };
+    let expected = |range: std::ops::Range<usize>| -> Vec<Vec<u8>> {
+        range.map(kv).collect()
+    };
+
+    assert_eq!(keys(t.range(kv(10)..kv(20))), expected(10..20));
+    assert_eq!(keys(t.range(kv(10)..=kv(20))), expected(10..21));
+    assert_eq!(keys(t.range(..kv(5))), expected(0..5));
+    assert_eq!(
+        keys(t.range(kv(N_PER_THREAD - 3)..)),
+        expected(N_PER_THREAD - 3..N_PER_THREAD)
+    );
+    assert_eq!(
+        keys(t.range::<Vec<u8>, _>(..)),
+        expected(0..N_PER_THREAD)
+    );
+
+    let excluded = (
+        std::ops::Bound::Excluded(kv(10)),
+        std::ops::Bound::Included(kv(12)),
+    );
+    assert_eq!(keys(t.range(excluded)), expected(11..13));
+    assert_eq!(keys(t.range(kv(20)..kv(20))), expected(0..0));
+    assert_eq!(keys(t.range(kv(20)..kv(10))), expected(0..0));
+}
+
+#[test]
+fn tree_keys_and_values() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .expire_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let value = |i: usize| vec![i as u8; 1024];
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), value(i)).unwrap();
+    }
+    // expired keys are skipped like they are by `range`
+    let ttl = std::time::Duration::from_millis(1);
+    t.set_with_ttl(kv(N_PER_THREAD), vec![1], ttl).unwrap();
+    thread::sleep(std::time::Duration::from_millis(5));
+
+    let keys: Vec<Vec<u8>> =
+        t.keys(kv(10)..kv(20)).map(|res| res.unwrap()).collect();
+    assert_eq!(keys, (10..20).map(kv).collect::<Vec<_>>());
+    let values: Vec<IVec> =
+        t.values(kv(10)..=kv(20)).map(|res| res.unwrap()).collect();
+    let expected: Vec<IVec> =
+        (10..21).map(|i| value(i).into()).collect();
+    assert_eq!(values, expected);
+
+    let all: Vec<Vec<u8>> = t
+        .range::<Vec<u8>, _>(..)
+        .map(|res| res.unwrap().0)
+        .collect();
+    let keys: Vec<Vec<u8>> =
+        t.keys::<Vec<u8>, _>(..).map(|res| res.unwrap()).collect();
+    assert_eq!(keys, all);
+    assert_eq!(keys.len(), N_PER_THREAD);
+    assert_eq!(t.values::<Vec<u8>, _>(..).count(), N_PER_THREAD);
+}
+
+#[test]
+fn tree_read_ahead() {
+    for &read_ahead in &[0, 2, 16] {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .blink_fanout(4)
+            .cache_capacity(1000)
+            .cache_bits(0)
+            .read_ahead(read_ahead)
+            .build();
+        let t = sled::Tree::start(config).unwrap();
+        for i in 0..N_PER_THREAD {
+            t.set(kv(i), vec![i as u8; 100]).unwrap();
+        }
+
+        // leaves read ahead for a scan that ends early, or that
+        // are merged away while it runs, don't change what it sees
+        let keys: Vec<Vec<u8>> =
+            t.keys(kv(10)..kv(90)).map(|res| res.unwrap()).collect();
+        assert_eq!(keys, (10..90).map(kv).collect::<Vec<_>>());
+        let mut scanned = 0;
+        for (i, res) in t.iter().enumerate() {
+            let (k, v) = res.unwrap();
+            assert_eq!(k, kv(i * 2));
+            assert_eq!(v, IVec::from(vec![(i * 2) as u8; 100]));
+            t.del(&*kv(i * 2 + 1)).unwrap();
+            scanned += 1;
+        }
+        assert_eq!(scanned, N_PER_THREAD / 2);
+    }
+}
+
+#[test]
+fn tree_write_throttling() {
+    let limit = 16 * 1024;
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .max_unflushed_bytes(Some(limit))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let mut throttled = 0;
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![i as u8; 1000]).unwrap();
+        // each write waits for the log to catch up before it is
+        // made, so the limit is only passed by about one write, which
+        // may be a whole node rewritten by a split
+        let pressure = t.pressure();
+        assert_eq!(pressure.max_unflushed_bytes, Some(limit));
+        assert!(pressure.unflushed_bytes <= limit + 64 * 1024);
+        if pressure.is_throttling() {
+            throttled += 1;
+            match t.try_set(b"refused".to_vec(), vec![]) {
+                Err(Error::Io(ref e))
+                    if e.kind() == std::io::ErrorKind::WouldBlock => {
+                }
+                other => panic!("expected WouldBlock, got {:?}", other),
+            }
+            let mut batch = sled::Batch::default();
+            batch.set(b"refused".to_vec(), vec![]);
+            assert!(t.try_apply_batch(batch).is_err());
+        }
+    }
+    assert!(throttled > 0);
+    assert_eq!(t.get(b"refused"), Ok(None));
+
+    t.flush().unwrap();
+    assert_eq!(t.pressure().unflushed_bytes, 0);
+    assert_eq!(t.pressure().level(), 0.0);
+    t.try_set(b"accepted".to_vec(), vec![1]).unwrap();
+    let mut batch = sled::Batch::default();
+    batch.del(kv(0));
+    t.try_apply_batch(batch).unwrap();
+    assert_eq!(t.get(&*kv(0)), Ok(None));
+    assert_eq!(t.get(&*kv(1)), Ok(Some(vec![1; 1000].into())));
+
+    let behind = sled::Pressure {
+        unflushed_bytes: 2 * limit,
+        max_unflushed_bytes: Some(limit),
+        resident_bytes: 0,
+        max_resident_bytes: None,
+    };
+    assert!(behind.is_throttling());
+    assert_eq!(behind.level(), 2.0);
+    let unlimited = sled::Pressure {
+        max_unflushed_bytes: None,
+        ..behind
+    };
+    assert!(!unlimited.is_throttling());
+    assert_eq!(unlimited.level(), 0.0);
+    let resident = sled::Pressure {
+        resident_bytes: 3 * limit,
+        max_resident_bytes: Some(limit),
+        ..behind
+    };
+    assert!(resident.is_throttling());
+    assert_eq!(resident.level(), 3.0);
+}
+
+#[test]
+fn tree_resident_throttling() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let limit = 64 * 1024;
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .cache_capacity(1 << 30)
+        .max_resident_bytes(Some(limit))
+        .merge_operator(concatenate_merge)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![i as u8; 1000]).unwrap();
+        // each write pages out enough before it is made, so the
+        // limit is only passed by about one write, which may be a
+        // whole node rewritten by a split
+        let pressure = t.pressure();
+        assert_eq!(pressure.max_resident_bytes, Some(limit));
+        assert!(pressure.resident_bytes <= limit + 64 * 1024);
+    }
+
+    // reads page every leaf back in without waiting, leaving the
+    // next write to page them out again
+    for i in 0..N_PER_THREAD {
+        let expected = vec![i as u8; 1000];
+        assert_eq!(t.get(&*kv(i)), Ok(Some(expected.into())));
+    }
+    assert!(t.pressure().is_throttling());
+    match t.try_del(&*kv(0)) {
+        Err(Error::Io(ref e))
+            if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        other => panic!("expected WouldBlock, got {:?}", other),
+    }
+    match t.try_merge(kv(0), vec![1]) {
+        Err(Error::Io(ref e))
+            if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        other => panic!("expected WouldBlock, got {:?}", other),
+    }
+    match t.try_cas(kv(0), None::<Vec<u8>>, Some(vec![1])) {
+        Err(Error::Io(ref e))
+            if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        other => panic!("expected WouldBlock, got {:?}", other),
+    }
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![0; 1000].into())));
+
+    t.set(kv(0), vec![]).unwrap();
+    assert!(t.pressure().resident_bytes <= limit + 64 * 1024);
+}
+
+#[test]
+fn tree_get_lt_gt() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let mut model = BTreeMap::new();
+    // leave runs of empty leaves to step across
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), kv(i)).unwrap();
+        model.insert(kv(i), kv(i));
+    }
+    for i in (50..100).chain(150..155).chain(290..N_PER_THREAD) {
+        t.del(&*kv(i)).unwrap();
+        model.remove(&kv(i));
+    }
+    t.del(&*kv(0)).unwrap();
+    model.remove(&kv(0));
+
+    for i in 0..N_PER_THREAD + 2 {
+        let key = kv(i);
+        let lt = model
+            .range(..key.clone())
+            .next_back()
+            .map(|(k, v)| (k.clone(), IVec::from(v)));
+        let gt = model
+            .range(key.clone()..)
+            .find(|&(k, _)| *k != key)
+            .map(|(k, v)| (k.clone(), IVec::from(v)));
+        assert_eq!(t.get_lt(&*key), Ok(lt));
+        assert_eq!(t.get_gt(&*key), Ok(gt));
+    }
+    assert_eq!(t.get_lt(&[]), Ok(None));
+    assert_eq!(t.get_gt(&[]), Ok(Some((kv(1), kv(1).into()))));
+}
+
+#[test]
+fn tree_pop_min_max() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    for i in 0..N {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    assert_eq!(t.first(), Ok(Some((kv(0), kv(0).into()))));
+    assert_eq!(t.last(), Ok(Some((kv(N - 1), kv(N - 1).into()))));
+
+    // every record goes to exactly one popper
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let tree = t.clone();
+        threads.push(thread::spawn(move || {
+            let mut popped = vec![];
+            loop {
+                let pop = if tn % 2 == 0 {
+                    tree.pop_min()
+                } else {
+                    tree.pop_max()
+                };
+                match pop.unwrap() {
+                    Some((k, v)) => {
+                        assert_eq!(k, v);
+                        popped.push(k);
+                    }
+                    None => return popped,
+                }
+            }
+        }));
+    }
+    let mut popped: Vec<Vec<u8>> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().unwrap())
+        .collect();
+    popped.sort();
+    let expected: Vec<Vec<u8>> = (0..N).map(kv).collect();
+    assert_eq!(popped, expected);
+
+    assert_eq!(t.first(), Ok(None));
+    assert_eq!(t.last(), Ok(None));
+    assert_eq!(t.pop_min(), Ok(None));
+    assert_eq!(t.pop_max(), Ok(None));
+}
+
+#[test]
+fn tree_remove_range() {
+    let path = "/tmp/test_tree_remove_range";
+    let start = || {
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .blink_fanout(4)
+            .merge_threshold(Some(2))
+            .build();
+        sled::Tree::start(config).unwrap()
+    };
+    let t = start();
+    let mut model = BTreeMap::new();
+    for i in 0..N {
+        t.set(kv(i), kv(i)).unwrap();
+        model.insert(kv(i), kv(i));
+    }
+    let mut remove = |t: &sled::Tree, lo: usize, hi: usize| {
+        let expected = model.range(kv(lo)..kv(hi)).count();
+        assert_eq!(t.remove_range(kv(lo)..kv(hi)), Ok(expected));
+        for i in lo..hi {
+            model.remove(&kv(i));
+        }
+    };
+
+    remove(&t, 10, 20);
+    remove(&t, 15, 400);
+    remove(&t, 700, 701);
+    remove(&t, 900, 900);
+    remove(&t, 1000, 1200);
+
+    let excluded = (
+        std::ops::Bound::Excluded(kv(1300)),
+        std::ops::Bound::Included(kv(1302)),
+    );
+    assert_eq!(t.remove_range(excluded), Ok(2));
+    model.remove(&kv(1301));
+    model.remove(&kv(1302));
+    assert_eq!(t.remove_range(kv(1400)..), Ok(N - 1400));
+    for i in 1400..N {
+        model.remove(&kv(i));
+    }
+
+    let check = |t: &sled::Tree| {
+        let fwd: Vec<(Vec<u8>, IVec)> =
+            t.iter().map(|res| res.unwrap()).collect();
+        let expected: Vec<(Vec<u8>, IVec)> = model
+            .iter()
+            .map(|(k, v)| (k.clone(), v.into()))
+            .collect();
+        assert_eq!(fwd, expected);
+        assert_eq!(t.iter_rev().count(), model.len());
+        for i in 0..N {
+            let expected = model.get(&kv(i)).cloned();
+            assert_eq!(t.get(&*kv(i)), Ok(expected.map(IVec::from)));
+        }
+    };
+    check(&t);
+    drop(t);
+
+    let t = start();
+    check(&t);
+    assert_eq!(t.remove_range::<Vec<u8>, _>(..), Ok(model.len()));
+    assert_eq!(t.iter().count(), 0);
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn tree_scan_prefix() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for group in &[&b"a"[..], b"b", b"b\xff", b"c"] {
+        for i in 0..100u8 {
+            let mut k = group.to_vec();
+            k.push(i);
+            t.set(k, vec![i]).unwrap();
+        }
+    }
+    let count = |prefix: &[u8]| {
+        t.scan_prefix(prefix)
+            .map(|res| {
+                let (k, _) = res.unwrap();
+                assert!(k.starts_with(prefix));
+            })
+            .count()
+    };
+
+    assert_eq!(count(b"a"), 100);
+    assert_eq!(count(b"b"), 200);
+    assert_eq!(count(b"b\xff"), 100);
+    assert_eq!(count(b"b\xff\x07"), 1);
+    assert_eq!(count(b"d"), 0);
+    assert_eq!(count(b""), 400);
+}
+
+#[test]
+fn tree_reads_share_values() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..10u8 {
+        t.set(vec![i], vec![i; 100]).unwrap();
+    }
+
+    // long values are handed out without copying their bytes
+    let a = t.get(&[3]).unwrap().unwrap();
+    let b = t.get(&[3]).unwrap().unwrap();
+    assert_eq!(a, vec![3; 100]);
+    assert_eq!(a.as_ptr(), b.as_ptr());
+    let (_, c) = t.scan(&[3]).next().unwrap().unwrap();
+    assert_eq!(a.as_ptr(), c.as_ptr());
+
+    // short values are stored inline
+    t.set(vec![20], vec![1, 2, 3]).unwrap();
+    assert_eq!(t.get(&[20]), Ok(Some(vec![1, 2, 3].into())));
+}
+
+#[test]
+fn tree_fetch_and_update() {
+    let config = ConfigBuilder::new().temporary(true).build();
+    let t = sled::Tree::start(config).unwrap();
+
+    // a failed cas hands back what it found
+    t.set(vec![1], vec![1]).unwrap();
+    assert_eq!(
+        t.cas(vec![1], Some(vec![0]), Some(vec![2])),
+        Err(Error::CasFailed(Some(vec![1].into())))
+    );
+    assert_eq!(
+        t.cas(vec![2], Some(vec![0]), Some(vec![2])),
+        Err(Error::CasFailed(None))
+    );
+
+    // concurrent increments never lose an update
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let t = t.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    t.fetch_and_update(b"n", |old| {
+                        let n = old.map_or(0, |v| {
+                            u16::from(v[0]) << 8 | u16::from(v[1])
+                        });
+                        let n = n + 1;
+                        Some(vec![(n >> 8) as u8, n as u8])
+                    })
+                    .unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(t.get(b"n"), Ok(Some(vec![1, 144].into())));
+
+    assert_eq!(t.fetch_and_del(b"n"), Ok(Some(vec![1, 144].into())));
+    assert_eq!(t.fetch_and_del(b"n"), Ok(None));
+    assert_eq!(t.get(b"n"), Ok(None));
+}
+
+#[test]
+fn tree_watch_prefix() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .merge_operator(concatenate_merge)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let mut all = t.watch_prefix(b"");
+    let mut a = t.watch_prefix(b"a");
+
+    t.set(b"a1".to_vec(), vec![1]).unwrap();
+    t.set(b"b1".to_vec(), vec![1]).unwrap();
+    t.merge(b"a1".to_vec(), vec![2]).unwrap();
+    t.cas(b"a2".to_vec(), None::<Vec<u8>>, Some(vec![3])).unwrap();
+    t.del(b"a1").unwrap();
+    let mut batch = Batch::default();
+    batch.set(b"a3".to_vec(), vec![4]);
+    batch.del(b"b1".to_vec());
+    t.apply_batch(batch).unwrap();
+    t.remove_range(b"a".to_vec()..b"b".to_vec()).unwrap();
+
+    let expected = vec![
+        Event::Set(b"a1".to_vec(), vec![1].into()),
+        Event::Merge(b"a1".to_vec(), vec![1, 2].into()),
+        Event::Set(b"a2".to_vec(), vec![3].into()),
+        Event::Del(b"a1".to_vec()),
+        Event::Set(b"a3".to_vec(), vec![4].into()),
+        Event::Del(b"a2".to_vec()),
+        Event::Del(b"a3".to_vec()),
+    ];
+    for event in &expected {
+        assert_eq!(a.next().unwrap().as_ref(), Ok(event));
+    }
+    assert_eq!(
+        all.next().unwrap(),
+        Ok(Event::Set(b"a1".to_vec(), vec![1].into()))
+    );
+    assert_eq!(
+        all.next().unwrap(),
+        Ok(Event::Set(b"b1".to_vec(), vec![1].into()))
+    );
+
+    // dropping the tree ends the subscription once it is drained
+    t.set(b"b2".to_vec(), vec![5]).unwrap();
+    t.clear().unwrap();
+    drop(t);
+    let rest: Vec<Event> = all.map(|r| r.unwrap()).collect();
+    assert_eq!(rest.len(), 9);
+    assert_eq!(rest[8], Event::Del(b"b2".to_vec()));
+    assert_eq!(a.next(), None);
+}
+
+#[test]
+fn tree_set_with_ttl() {
+    use std::time::Duration;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .expire_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..100u8 {
+        let ms = if i % 2 == 0 { 1 } else { 60_000 };
+        let ttl = Duration::from_millis(ms);
+        t.set_with_ttl(vec![i], vec![i], ttl).unwrap();
+    }
+    t.set(vec![200], vec![200]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    // expired keys are hidden from every read
+    assert_eq!(t.get(&[0]), Ok(None));
+    assert_eq!(t.get(&[1]), Ok(Some(vec![1].into())));
+    assert_eq!(t.contains_key(&[2]), Ok(false));
+    assert_eq!(t.get_gt(&[0]), Ok(Some((vec![1], vec![1].into()))));
+    assert_eq!(
+        t.get_lt(&[200]),
+        Ok(Some((vec![99], vec![99].into())))
+    );
+    let keys: Vec<u8> = t.iter().map(|r| r.unwrap().0[0]).collect();
+    assert_eq!(keys.len(), 51);
+    assert!(keys[..50].iter().all(|k| k % 2 == 1));
+    let rev: Vec<u8> =
+        t.iter_rev().map(|r| r.unwrap().0[0]).collect();
+    assert_eq!(rev.len(), 51);
+
+    // rewriting an expired key revives it without a TTL
+    t.set(vec![0], vec![7]).unwrap();
+    assert_eq!(t.get(&[0]), Ok(Some(vec![7].into())));
+
+    assert_eq!(t.len(), Ok(101));
+    assert_eq!(t.sweep_expired(), Ok(49));
+    assert_eq!(t.len(), Ok(52));
+    assert_eq!(t.sweep_expired(), Ok(0));
+
+    // expirations are recovered with the rest of the tree
+    t.set_with_ttl(vec![1], vec![1], Duration::from_millis(1))
+        .unwrap();
+    drop(t);
+    let t = sled::Tree::start(config).unwrap();
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(t.get(&[1]), Ok(None));
+    assert_eq!(t.get(&[3]), Ok(Some(vec![3].into())));
+}
+
+#[test]
+fn tree_sweeps_expired_keys_in_background() {
+    use std::time::Duration;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .expire_every_ms(Some(10))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..10u8 {
+        t.set_with_ttl(vec![i], vec![i], Duration::from_millis(1))
+            .unwrap();
+    }
+    let mut swept = false;
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(10));
+        if t.len() == Ok(0) {
+            swept = true;
+            break;
+        }
+    }
+    assert!(swept, "the sweeper never removed the expired keys");
+}
+
+#[test]
+fn tree_truncates_long_values() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let path = "/tmp/test_tree_truncates_long_values";
+    let _ = std::fs::remove_dir_all(path);
+    let start = |max_value_len: Option<usize>| {
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .max_value_len(max_value_len)
+            .merge_operator(concatenate_merge)
+            .build();
+        sled::Db::start(config)
+    };
+    let t = start(Some(4)).unwrap();
+
+    let before = t.metrics().values_truncated;
+    t.set(vec![1], vec![1, 2, 3, 4, 5, 6]).unwrap();
+    assert_eq!(t.get(&*vec![1]), Ok(Some(vec![1, 2, 3, 4].into())));
+    assert!(t.metrics().values_truncated > before);
+
+    t.set(vec![2], vec![1, 2]).unwrap();
+    assert_eq!(t.get(&*vec![2]), Ok(Some(vec![1, 2].into())));
+
+    // merge operator output is subject to the same policy
+    t.merge(vec![2], vec![3, 4, 5]).unwrap();
+    assert_eq!(t.get(&*vec![2]), Ok(Some(vec![1, 2, 3, 4].into())));
+
+    let mut iter = t.iter();
+    assert_eq!(
+        iter.next(),
+        Some(Ok((vec![1], vec![1, 2, 3, 4].into())))
+    );
+    assert_eq!(
+        iter.next(),
+        Some(Ok((vec![2], vec![1, 2, 3, 4].into())))
+    );
+    assert_eq!(iter.next(), None);
+    drop(iter);
+    drop(t);
+
+    // replaying the merge with another limit would change its
+    // result, so the limit is fixed when the database is created
+    let changed = start(Some(8)).map(|_| ());
+    let t = start(Some(4)).unwrap();
+    let res = t.get(&*vec![2]);
+    drop(t);
+    std::fs::remove_dir_all(path).unwrap();
+
+    match changed {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("opened with another limit: {:?}", other),
+    }
+    assert_eq!(res, Ok(Some(vec![1, 2, 3, 4].into())));
+}
+
+#[test]
+fn tree_interned_values() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .intern_values(true)
+        .merge_operator(test_merge_operator)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    let status = |i: usize| vec![0, (i % 3) as u8];
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), status(i)).unwrap();
+    }
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(status(i).into())));
+    }
+
+    for i in (0..N_PER_THREAD).filter(|i| i % 2 == 0) {
+        assert_eq!(t.del(&*kv(i)), Ok(Some(status(i).into())));
+    }
+    t.merge(kv(1), vec![7]).unwrap();
+    assert_eq!(t.get(&*kv(1)), Ok(Some(vec![0, 8].into())));
+
+    let expected: Vec<_> = (0..N_PER_THREAD)
+        .filter(|i| i % 2 == 1)
+        .map(|i| {
+            if i == 1 {
+                (kv(i), vec![0, 8].into())
+            } else {
+                (kv(i), status(i).into())
+            }
+        })
+        .collect();
+    let actual: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn tree_max_leaf_len() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(32)
+        .max_leaf_len(Some(3))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+    assert_eq!(t.iter().count(), N_PER_THREAD);
+}
+
+#[test]
+fn tree_key_set() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .key_set(true)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.contains_key(&*kv(i)), Ok(false));
+        t.set(kv(i), kv(i)).unwrap();
+        assert_eq!(t.contains_key(&*kv(i)), Ok(true));
+    }
+
+    for i in (0..N_PER_THREAD).filter(|i| i % 2 == 0) {
+        assert_eq!(t.del(&*kv(i)), Ok(Some(vec![].into())));
+        assert_eq!(t.contains_key(&*kv(i)), Ok(false));
+    }
+
+    // values are discarded, so iteration yields empty ones
+    let expected: Vec<_> = (0..N_PER_THREAD)
+        .filter(|i| i % 2 == 1)
+        .map(|i| (kv(i), IVec::default()))
+        .collect();
+    let actual: Vec<_> = t.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn tree_splitting_merge_operator() {
+    // appends, asking for a split once a value holds 8 merges
+    fn append_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> (Option<Vec<u8>>, bool) {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        let split = ret.len() >= 16;
+        (Some(ret), split)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(128)
+        .splitting_merge_operator(append_merge)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    for round in 0..8u8 {
+        for i in 0..N_PER_THREAD {
+            t.merge(kv(i), vec![round, round]).unwrap();
+        }
+    }
+
+    let expected: Vec<u8> =
+        (0..8u8).flat_map(|round| vec![round, round]).collect();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(expected.clone().into())));
+    }
+    assert_eq!(t.iter().count(), N_PER_THREAD);
+}
+
+#[test]
+fn tree_merge_operator_name_checked_at_open() {
+    let path = "/tmp/test_tree_merge_operator_name";
+    let start = |name: &str| {
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .merge_operator(test_merge_operator)
+            .merge_operator_name(name)
+            .build();
+        sled::Tree::start(config)
+    };
+
+    let t = start("concat").unwrap();
+    t.set(vec![1], vec![1]).unwrap();
+    drop(t);
+
+    let wrong = start("sum").map(|_| ());
+
+    let t = start("concat").unwrap();
+    let res = t.get(&[1]);
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+
+    match wrong {
+        Err(Error::Unsupported(_)) => {}
+        other => {
+            panic!("opened with the wrong operator: {:?}", other)
+        }
+    }
+    assert_eq!(res, Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_refuses_older_format() {
+    let path = "/tmp/test_tree_refuses_older_format";
+    let _ = std::fs::remove_dir_all(path);
+    let start = || {
+        let config =
+            ConfigBuilder::new().path(path.to_owned()).build();
+        sled::Tree::start(config)
+    };
+
+    let t = start().unwrap();
+    t.set(vec![1], vec![1]).unwrap();
+    drop(t);
+
+    // systems written before the format was versioned have a
+    // configuration file without its header, and are version 1
+    let conf = format!("{}/conf", path);
+    let written = std::fs::read(&conf).unwrap();
+    let header = b"pagecache format".len() + 4;
+    std::fs::write(&conf, &written[header..]).unwrap();
+    let older = start().map(|_| ());
+
+    std::fs::write(&conf, &written).unwrap();
+    let t = start().unwrap();
+    let res = t.get(&[1]);
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+
+    match older {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("opened an older format: {:?}", other),
+    }
+    assert_eq!(res, Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_max_node_size() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(128)
+        .max_node_size(Some(64 * 1024))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    // a few large values mixed in with many small ones
+    let value = |i: usize| {
+        let len = if i % 50 == 0 { 48 * 1024 } else { 8 };
+        vec![i as u8; len]
+    };
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), value(i)).unwrap();
+    }
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(value(i).into())));
+    }
+    assert_eq!(t.iter().count(), N_PER_THREAD);
+}
+
+#[test]
+fn tree_split_policy() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(32)
+        .merge_threshold(Some(2))
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    let narrow = db.open_tree(b"narrow").unwrap();
+    let wide = db.open_tree(b"wide").unwrap();
+    assert_eq!(wide.split_policy().fanout, 32);
+
+    let policy = SplitPolicy {
+        fanout: 4,
+        max_node_size: None,
+    };
+    narrow.set_split_policy(policy).unwrap();
+    // every handle to the tree shares its policy
+    let narrow = db.open_tree(b"narrow").unwrap();
+    assert_eq!(narrow.split_policy(), policy);
+    for i in 0..N_PER_THREAD {
+        narrow.set(kv(i), kv(i)).unwrap();
+        wide.set(kv(i), kv(i)).unwrap();
+    }
+    let narrow_stats = narrow.stats().unwrap();
+    let wide_stats = wide.stats().unwrap();
+    assert!(narrow_stats.leaves > wide_stats.leaves);
+    assert!(narrow_stats.height > wide_stats.height);
+
+    // nodes over a new, smaller limit split as they're written
+    let before = wide.stats().unwrap().leaves;
+    wide.set_split_policy(policy).unwrap();
+    for i in 0..N_PER_THREAD {
+        wide.set(kv(i), kv(i + 1)).unwrap();
+    }
+    assert!(wide.stats().unwrap().leaves > before);
+    for i in 0..N_PER_THREAD {
+        assert_eq!(wide.get(&*kv(i)), Ok(Some(kv(i + 1).into())));
+    }
+
+    let too_narrow = SplitPolicy {
+        fanout: 3,
+        max_node_size: None,
+    };
+    assert!(narrow.set_split_policy(too_narrow).is_err());
+    assert_eq!(narrow.split_policy(), policy);
+}
+
+#[test]
+fn tree_merges_underfull_leaves() {
+    let path = "/tmp/test_tree_merges_underfull_leaves";
+    let start = || {
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .blink_fanout(4)
+            .merge_threshold(Some(2))
+            .build();
+        sled::Tree::start(config).unwrap()
+    };
+    let kept: Vec<usize> = (0..N).filter(|i| i % 100 == 0).collect();
+    let check = |t: &sled::Tree| {
+        for i in 0..N {
+            let expected =
+                if i % 100 == 0 { Some(kv(i)) } else { None };
+            assert_eq!(t.get(&*kv(i)), Ok(expected.map(IVec::from)));
+        }
+        let fwd: Vec<Vec<u8>> =
+            t.iter().map(|res| res.unwrap().0).collect();
+        let rev: Vec<Vec<u8>> =
+            t.iter_rev().map(|res| res.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> =
+            kept.iter().map(|&i| kv(i)).collect();
+        let expected_rev: Vec<Vec<u8>> =
+            expected.iter().rev().cloned().collect();
+        assert_eq!(fwd, expected);
+        assert_eq!(rev, expected_rev);
+    };
+
+    let t = start();
+    for i in 0..N {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    // iterate while deleting, so the iterator steps over leaves
+    // that get merged away underneath it
+    let mut iter = t.iter();
+    for i in 0..N {
+        if i % 100 != 0 {
+            t.del(&*kv(i)).unwrap();
+        }
+        if i % 10 == 0 {
+            iter.next().unwrap().unwrap();
+        }
+    }
+    for res in iter {
+        let (k, _) = res.unwrap();
+        assert!(kept.iter().any(|&i| kv(i) == k));
+    }
+    check(&t);
+    drop(t);
+
+    let t = start();
+    check(&t);
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn tree_merges_alongside_writes() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .merge_threshold(Some(2))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..N {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    assert_eq!(t.len(), Ok(N));
+
+    // each thread deletes most of its keys, merging leaves that
+    // the others are still writing to
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let t = t.clone();
+        threads.push(thread::spawn(move || {
+            for i in (tn..N).step_by(N_THREADS) {
+                if i % 10 == 0 {
+                    t.set(kv(i), vec![1]).unwrap();
+                } else {
+                    t.del(&*kv(i)).unwrap();
+                }
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for i in 0..N {
+        let expected = if i % 10 == 0 { Some(vec![1]) } else { None };
+        assert_eq!(t.get(&*kv(i)), Ok(expected.map(IVec::from)));
+    }
+    assert_eq!(t.iter().count(), N / 10);
+    assert_eq!(t.len(), Ok(N / 10));
+    let report = t.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+}
+
+#[test]
+fn tree_len_and_clear() {
+    let path = "/tmp/test_tree_len_and_clear";
+    let start = || {
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .blink_fanout(4)
+            .merge_operator(test_merge_operator)
+            .build();
+        sled::Tree::start(config).unwrap()
+    };
+    let t = start();
+    assert_eq!(t.len(), Ok(0));
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![0, 1]).unwrap();
+    }
+    for i in 0..10 {
+        t.set(kv(i), vec![0, 2]).unwrap();
+        t.merge(kv(i), vec![1]).unwrap();
+        t.merge(kv(N_PER_THREAD + i), vec![1]).unwrap();
+    }
+    assert_eq!(t.len(), Ok(N_PER_THREAD + 10));
+
+    t.cas(kv(0), Some(vec![0, 3]), None::<Vec<u8>>).unwrap();
+    t.cas(kv(0), None::<Vec<u8>>, Some(vec![0, 1])).unwrap();
+    t.cas(kv(1), Some(vec![0, 3]), None::<Vec<u8>>).unwrap();
+    t.del(&*kv(2)).unwrap();
+    t.del(&*kv(2)).unwrap();
+    let mut batch = sled::Batch::default();
+    batch.set(kv(3), vec![0, 1]);
+    batch.del(kv(4));
+    batch.set(kv(N - 1), vec![0, 1]);
+    t.apply_batch(batch).unwrap();
+    t.remove_range(kv(100)..kv(200)).unwrap();
+    let expected = N_PER_THREAD + 10 - 3 + 1 - 100;
+    assert_eq!(t.len(), Ok(expected));
+    assert_eq!(t.iter().count(), expected);
+    drop(t);
+
+    // counted again after a restart
+    let t = start();
+    assert_eq!(t.len(), Ok(expected));
+    t.set(kv(N - 2), vec![0, 1]).unwrap();
+    assert_eq!(t.len(), Ok(expected + 1));
+
+    t.clear().unwrap();
+    assert_eq!(t.len(), Ok(0));
+    assert_eq!(t.iter().next(), None);
+    assert_eq!(t.get(&*kv(5)), Ok(None));
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![0, 1]).unwrap();
+    }
+    drop(t);
+
+    let t = start();
+    assert_eq!(t.len(), Ok(N_PER_THREAD));
+    assert_eq!(t.iter().count(), N_PER_THREAD);
+    t.clear().unwrap();
+    drop(t);
+
+    let t = start();
+    assert_eq!(t.len(), Ok(0));
+    assert_eq!(t.iter().count(), 0);
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn tree_max_key_len() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .max_key_len(Some(8))
+        .merge_operator(test_merge_operator)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    let at_limit = vec![1; 8];
+    let over = vec![1; 9];
+
+    t.set(at_limit.clone(), vec![0, 1]).unwrap();
+    t.merge(at_limit.clone(), vec![1]).unwrap();
+    assert_eq!(
+        t.cas(at_limit.clone(), Some(vec![0, 2]), Some(vec![0, 3])),
+        Ok(())
+    );
+    assert_eq!(t.get(&*at_limit), Ok(Some(vec![0, 3].into())));
+
+    match t.set(over.clone(), vec![]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("set of a long key returned {:?}", other),
+    }
+    match t.merge(over.clone(), vec![1]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("merge of a long key returned {:?}", other),
+    }
+    match t.cas(over.clone(), None::<Vec<u8>>, Some(vec![])) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("cas of a long key returned {:?}", other),
+    }
+    assert_eq!(t.get(&*over), Ok(None));
+    assert_eq!(t.del(&*over), Ok(None));
+}
+
+#[test]
+fn tree_int_keys() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .int_keys(true)
+        .blink_fanout(4)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let key = |i: u64| {
+        let k = i * 1_000_003;
+        (0..8).rev().map(|b| (k >> (8 * b)) as u8).collect::<Vec<_>>()
+    };
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..200 {
+        t.set(key(i), vec![i as u8]).unwrap();
+    }
+    t.set(b"short".to_vec(), vec![]).unwrap();
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..200 {
+        assert_eq!(t.get(&*key(i)), Ok(Some(vec![i as u8].into())));
+    }
+    assert_eq!(t.get(b"short"), Ok(Some(vec![].into())));
+    let keys: Vec<Vec<u8>> =
+        t.scan(&*key(50)).take(10).map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (50..60).map(key).collect::<Vec<_>>());
+}
+
+#[test]
+fn tree_block_compress_values() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .block_compress_values(true)
+        .blink_fanout(4)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        t.set(k.clone(), vec![i as u8; 20]).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        assert_eq!(t.get(&*k), Ok(Some(vec![i as u8; 20].into())));
+    }
+}
+
+#[test]
+fn tree_compress_values_above() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .compress_values_above(Some(16))
+        .blink_fanout(4)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+
+    let value = |i: usize| vec![i as u8; i % 40];
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), value(i)).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(value(i).into())));
+    }
+}
+
+#[test]
+fn tree_leaf_bloom_filters() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .leaf_bloom_filters(true)
+        .blink_fanout(4)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        if i % 2 == 0 {
+            t.set(kv(i), kv(i)).unwrap();
+        }
+    }
+    for i in 0..N_PER_THREAD {
+        if i % 4 == 0 {
+            t.del(&*kv(i)).unwrap();
+        }
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        let expected = if i % 2 == 0 && i % 4 != 0 {
+            Some(kv(i))
+        } else {
+            None
+        };
+        assert_eq!(t.get(&*kv(i)), Ok(expected.map(IVec::from)));
+    }
+}
+
+#[test]
+fn recover_tree() {
+    println!("========== recovery ==========");
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        let k = kv(i);
+        t.set(k.clone(), k).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..config.blink_fanout << 1 {
+        let k = kv(i as usize);
+        assert_eq!(t.get(&*k), Ok(Some(k.clone().into())));
+        t.del(&*k).unwrap();
+    }
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..config.blink_fanout << 1 {
+        let k = kv(i as usize);
+        assert_eq!(t.get(&*k), Ok(None));
+    }
+}
+
+#[test]
+fn recover_named_trees() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(2)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let value = |name: &[u8], i: usize| {
+        let mut v = name.to_vec();
+        v.extend_from_slice(&kv(i));
+        v
+    };
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    assert_eq!(db.tree_names(), Ok(vec![]));
+    for name in &[&b"a"[..], b"b", b"c"] {
+        let t = db.open_tree(name).unwrap();
+        for i in 0..N_PER_THREAD {
+            t.set(kv(i), value(name, i)).unwrap();
+        }
+    }
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), value(b"", i)).unwrap();
+    }
+    drop(db);
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let names = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+    assert_eq!(db.tree_names(), Ok(names));
+    for name in &[&b"a"[..], b"b", b"c"] {
+        let t = db.open_tree(name).unwrap();
+        for i in 0..N_PER_THREAD {
+            assert_eq!(
+                t.get(&*kv(i)),
+                Ok(Some(value(name, i).into()))
+            );
+        }
+    }
+    assert_eq!(db.drop_tree(b"b"), Ok(true));
+    assert_eq!(db.drop_tree(b"b"), Ok(false));
+    drop(db);
+
+    // plain trees still find the default tree among the others
+    let t = sled::Tree::start(config.clone()).unwrap();
+    assert_eq!(t.get(&*kv(7)), Ok(Some(value(b"", 7).into())));
+    drop(t);
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let names = vec![b"a".to_vec(), b"c".to_vec()];
+    assert_eq!(db.tree_names(), Ok(names));
+    let c = db.open_tree(b"c").unwrap();
+    assert_eq!(c.iter().count(), N_PER_THREAD);
+    assert_eq!(c.get(&*kv(9)), Ok(Some(value(b"c", 9).into())));
+    assert_eq!(db.get(&*kv(9)), Ok(Some(value(b"", 9).into())));
+
+    // the pages of the dropped tree are reused by a new one
+    let b = db.open_tree(b"b").unwrap();
+    assert_eq!(b.iter().count(), 0);
+    for i in 0..N_PER_THREAD {
+        b.set(kv(i), vec![]).unwrap();
+    }
+    assert_eq!(c.iter().count(), N_PER_THREAD);
+    drop((b, c, db));
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(a.get(&*kv(i)), Ok(Some(value(b"a", i).into())));
+        assert_eq!(b.get(&*kv(i)), Ok(Some(vec![].into())));
+    }
+}
+
+#[test]
+fn tree_with_mem_storage() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .storage(pagecache::MemStorage::default())
+        .flush_every_ms(None)
+        .build();
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    drop(t);
+
+    // the log never touches the database's directory
+    let mut log_path = config.get_path();
+    log_path.push("db");
+    assert!(!log_path.exists());
+
+    let t = sled::Tree::start(config).unwrap();
+    assert_eq!(t.iter().count(), N_PER_THREAD);
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+}
+
+// Keeps the log in memory, failing every write while `full` is set,
+// like a disk that has run out of space.
+struct FullStorage {
+    log: pagecache::MemStorage,
+    full: Arc<AtomicBool>,
+}
+
+impl pagecache::Storage for FullStorage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.log.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.full.load(SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no space left on device",
+            ));
+        }
+        self.log.write_at(buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.log.sync()
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.log.truncate(len)
+    }
+}
+
+#[test]
+fn db_poisoned_by_write_errors() {
+    let full = Arc::new(AtomicBool::new(false));
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .storage(FullStorage {
+            log: pagecache::MemStorage::default(),
+            full: full.clone(),
+        })
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    db.flush().unwrap();
+    assert!(!db.was_poisoned());
+
+    full.store(true, SeqCst);
+    // the write may only reach the log's buffer, but its flush
+    // can't succeed
+    let _ = db.set(kv(N_PER_THREAD), vec![1]);
+    match db.flush() {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected an IO error, got {:?}", other),
+    }
+    assert!(db.was_poisoned());
+
+    // writes keep failing without touching the disk, even once it
+    // has space again, while reads still work
+    full.store(false, SeqCst);
+    match db.set(kv(0), vec![2]) {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected an IO error, got {:?}", other),
+    }
+    match db.flush() {
+        Err(Error::Io(_)) => {}
+        other => panic!("expected an IO error, got {:?}", other),
+    }
+    assert!(db.was_poisoned());
+    for i in 0..N_PER_THREAD {
+        assert_eq!(db.get(&*kv(i)), Ok(Some(kv(i).into())));
+    }
+}
+
+// A stand-in for a real AEAD, enough to keep plaintext off the disk
+// and to tell keys apart.
+struct XorCipher(u8);
+
+impl pagecache::Cipher for XorCipher {
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed: Vec<u8> =
+            plaintext.iter().map(|b| b ^ self.0).collect();
+        sealed.push(self.0);
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let (&key, body) = sealed.split_last()?;
+        if key != self.0 {
+            return None;
+        }
+        Some(body.iter().map(|b| b ^ self.0).collect())
+    }
+}
+
+#[test]
+fn tree_encryption() {
+    let path = "/tmp/test_tree_encryption";
+    let _ = std::fs::remove_dir_all(path);
+    let builder = || {
+        ConfigBuilder::new()
+            .path(path.to_owned())
+            .flush_every_ms(None)
+            .snapshot_after_ops(100)
+    };
+    let secret =
+        |i: usize| format!("secret value {}", i).into_bytes();
+
+    let t = sled::Tree::start(
+        builder().encryption(XorCipher(0x5A)).build(),
+    ).unwrap();
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), secret(i)).unwrap();
+    }
+    drop(t);
+
+    // neither the log nor the snapshots hold any plaintext
+    for entry in std::fs::read_dir(path).unwrap() {
+        let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+        assert!(
+            !bytes.windows(6).any(|w| w == b"secret"),
+            "found plaintext on disk"
+        );
+    }
+
+    assert!(
+        sled::Tree::start(builder().build()).is_err(),
+        "opened an encrypted tree without a cipher"
+    );
+    assert!(
+        sled::Tree::start(builder().encryption(XorCipher(1)).build())
+            .is_err(),
+        "opened an encrypted tree with the wrong key"
+    );
+
+    let t = sled::Tree::start(
+        builder().encryption(XorCipher(0x5A)).build(),
+    ).unwrap();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(t.get(&*kv(i)), Ok(Some(secret(i).into())));
+    }
+    drop(t);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn db_generate_id() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let first: Vec<u64> =
+        (0..1000).map(|_| db.generate_id().unwrap()).collect();
+    assert!(first.windows(2).all(|w| w[0] < w[1]));
+    drop(db);
+
+    // ids from an earlier run are never handed out again, and the
+    // tree holding the reservation is not a named tree
+    let db = sled::Db::start(config.clone()).unwrap();
+    let id = db.generate_id().unwrap();
+    assert!(id > *first.last().unwrap());
+    assert_eq!(db.tree_names(), Ok(vec![]));
+
+    let db = Arc::new(db);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            thread::spawn(move || {
+                (0..1000)
+                    .map(|_| db.generate_id().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut ids: Vec<u64> = threads
+        .into_iter()
+        .flat_map(|t| t.join().unwrap())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 4000);
+    assert!(ids[0] > id);
+}
+
+#[test]
+fn db_open_typed_tree() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let names: TypedTree<String, u64, CaseInsensitive> =
+        db.open_typed_tree(b"names").unwrap();
+    names.set(&"Bob".to_owned(), &1).unwrap();
+    names.set(&"alice".to_owned(), &2).unwrap();
+    names.set(&"BOB".to_owned(), &3).unwrap();
+    assert_eq!(names.get(&"bOb".to_owned()), Ok(Some(3)));
+    let keys: Vec<String> =
+        names.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, vec!["alice".to_owned(), "bob".to_owned()]);
+
+    // a tree created untyped takes the first key codec it's
+    // opened with
+    db.open_tree(b"scores").unwrap();
+    let scores: TypedTree<i64, (), Reversed<Ordered>> =
+        db.open_typed_tree(b"scores").unwrap();
+    for i in -5..5 {
+        scores.set(&i, &()).unwrap();
+    }
+    drop((names, scores, db));
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let scores: TypedTree<i64, (), Reversed<Ordered>> =
+        db.open_typed_tree(b"scores").unwrap();
+    let keys: Vec<i64> =
+        scores.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (-5..5).rev().collect::<Vec<_>>());
+    let keys: Vec<i64> =
+        scores.range(3..-2).map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, vec![3, 2, 1, 0, -1]);
+
+    let wrong: DbResult<TypedTree<i64, ()>, ()> =
+        db.open_typed_tree(b"scores");
+    match wrong {
+        Err(Error::Unsupported(_)) => {}
+        _ => panic!("opened a tree with the wrong key codec"),
+    }
+    let names: TypedTree<String, u64, CaseInsensitive> =
+        db.open_typed_tree(b"names").unwrap();
+    assert_eq!(names.get(&"ALICE".to_owned()), Ok(Some(2)));
+    assert_eq!(db.tree_names().unwrap().len(), 2);
+}
+
+#[test]
+fn db_secondary_index() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    // every byte of a value is one of its index keys
+    fn bytes(_key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+        value.iter().map(|&b| vec![b]).collect()
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .blink_fanout(4)
+        .merge_operator(concatenate_merge)
+        .build();
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"records").unwrap();
+    let index = db.open_tree(b"index").unwrap();
+    for i in 0..N {
+        t.set(kv(i), vec![(i % 10) as u8]).unwrap();
+    }
+
+    // existing records are indexed when the index is declared
+    t.add_index("bytes", &index, bytes).unwrap();
+    let threes = t.get_by_index("bytes", &[3]).unwrap();
+    assert_eq!(threes.len(), N / 10);
+    assert_eq!(index.len(), Ok(N));
+
+    t.set(kv(3), vec![4]).unwrap();
+    t.merge(kv(4), vec![7]).unwrap();
+    t.del(&kv(13)).unwrap();
+    t.cas(kv(23), Some(vec![3]), Some(vec![4])).unwrap();
+    assert!(t.cas(kv(33), Some(vec![4]), None::<Vec<u8>>).is_err());
+    let mut batch = Batch::default();
+    batch.set(kv(43), vec![4]);
+    batch.del(kv(53));
+    t.apply_batch(batch).unwrap();
+    t.transaction(|tx| {
+        tx.set(kv(63), vec![4]);
+        tx.del(&kv(73))?;
+        Ok(())
+    })
+    .unwrap();
+
+    let threes = t.get_by_index("bytes", &[3]).unwrap();
+    assert_eq!(threes.len(), N / 10 - 7);
+    let fours: Vec<Vec<u8>> = t
+        .get_by_index("bytes", &[4])
+        .unwrap()
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    for i in &[3, 4, 23, 43, 63] {
+        assert!(fours.contains(&kv(*i)));
+    }
+    let sevens = t.get_by_index("bytes", &[7]).unwrap();
+    assert_eq!(sevens.len(), N / 10 + 1);
+    let high = t.index_range("bytes", &[8u8][..]..).unwrap();
+    assert_eq!(high.count(), N / 5);
+    let between: Vec<Vec<u8>> = t
+        .index_range("bytes", &[3u8][..]..=&[4u8][..])
+        .unwrap()
+        .map(|res| res.unwrap().0)
+        .collect();
+    assert!(between.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(between.len(), threes.len() + N / 10 + 4);
+
+    let ttl = std::time::Duration::from_secs(1);
+    assert!(t.set_with_ttl(kv(0), vec![0], ttl).is_err());
+    assert!(t.remove_range(kv(0)..kv(1)).is_err());
+    assert!(t.clear().is_err());
+    let other = db.open_tree(b"other").unwrap();
+    assert!(t.add_index("bytes", &other, bytes).is_err());
+    assert!(index.add_index("other", &other, bytes).is_err());
+    assert!(t.get_by_index("missing", &[0]).is_err());
+
+    // the index is written with the records, and only needs to be
+    // declared again after a restart
+    drop((t, index, other, db));
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"records").unwrap();
+    let index = db.open_tree(b"index").unwrap();
+    t.add_index("bytes", &index, bytes).unwrap();
+    assert_eq!(t.get_by_index("bytes", &[3]).unwrap(), threes);
+    assert_eq!(index.len(), Ok(N - 2));
+}
+
+#[test]
+fn tree_get_many() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in (0..N).filter(|i| i % 3 != 0) {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    // unsorted, repeated and missing keys all come back in order
+    let wanted: Vec<Vec<u8>> = (0..N)
+        .rev()
+        .chain(0..N / 2)
+        .map(|i| kv(i * 7 % N))
+        .chain(vec![vec![], vec![255; 4]])
+        .collect();
+    let keys: Vec<&[u8]> = wanted.iter().map(|k| &**k).collect();
+    let values = t.get_many(&keys).unwrap();
+    assert_eq!(values.len(), keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, t.get(key).unwrap());
+    }
+    assert_eq!(t.get_many(&[]), Ok(vec![]));
+}
+
+#[test]
+fn tree_first_gap() {
+    fn id(i: u16) -> Vec<u8> {
+        vec![(i >> 8) as u8, i as u8]
+    }
+    fn next(k: &[u8]) -> Vec<u8> {
+        id(((k[0] as u16) << 8 | k[1] as u16) + 1)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let gap = 1000;
+    for i in (0..2000).filter(|&i| i != gap) {
+        t.set(id(i), vec![]).unwrap();
+    }
+
+    // the used ids span many leaves before the gap
+    assert_eq!(t.first_gap(&id(0), next), Ok(id(gap)));
+    assert_eq!(t.first_gap(&id(gap + 1), next), Ok(id(2000)));
+
+    t.set(id(gap), vec![]).unwrap();
+    assert_eq!(t.first_gap(&id(0), next), Ok(id(2000)));
+}
+
+#[test]
+fn tree_stats_distinct_values() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(16)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..1000 {
+        t.set(kv(i), vec![(i % 2) as u8]).unwrap();
+    }
+
+    // every leaf holds both values, and no more
+    let stats = t.stats().unwrap();
+    assert_eq!(stats.records, 1000);
+    assert_eq!(stats.distinct_values, 2 * stats.leaves);
+}
+
+#[test]
+fn tree_stats_alongside_writes() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .merge_threshold(Some(2))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let t = t.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            for round in 0..3 {
+                for i in 0..N {
+                    t.set(kv(i), vec![round]).unwrap();
+                }
+                for i in 0..N {
+                    t.del(&*kv(i)).unwrap();
+                }
+            }
+            done.store(true, SeqCst);
+        })
+    };
+
+    // the walks neither wait for the writer nor trip over the
+    // leaves it splits and merges
+    while !done.load(SeqCst) {
+        let stats = t.stats().unwrap();
+        assert!(stats.records <= 2 * N);
+        let buckets = t.key_histogram().unwrap();
+        assert!(!buckets.is_empty());
+        let dump = t.dump_structure().unwrap();
+        assert_eq!(dump.nodes[0].id, dump.root);
+    }
+    writer.join().unwrap();
+    assert_eq!(t.stats().unwrap().records, 0);
+}
+
+#[test]
+fn tree_bulk_load() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .blink_fanout(4)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let t = db.open_tree(b"records").unwrap();
+    let loaded = t.bulk_load((0..N).map(|i| (kv(i), kv(i))));
+    assert_eq!(loaded, Ok(N));
+    assert_eq!(t.len(), Ok(N));
+
+    let report = t.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    let stats = t.stats().unwrap();
+    assert_eq!(stats.records, N);
+    assert!(stats.avg_leaf_fill > 0.9, "{:?}", stats);
+    for (i, res) in t.iter().enumerate() {
+        let (k, v) = res.unwrap();
+        assert_eq!((k, v.to_vec()), (kv(i), kv(i)));
+    }
+    assert_eq!(t.iter_rev().count(), N);
+    assert_eq!(t.get(&kv(N / 2)), Ok(Some(kv(N / 2).into())));
+
+    // the loaded tree takes writes like any other
+    t.set(vec![9; 4], vec![1]).unwrap();
+    t.del(&kv(7)).unwrap();
+    assert_eq!(t.len(), Ok(N));
+    assert!(t.bulk_load(vec![(vec![9; 5], vec![2])]).is_err());
+
+    // nothing is loaded from a stream that isn't ascending
+    let other = db.open_tree(b"other").unwrap();
+    let unsorted = vec![(kv(2), vec![]), (kv(1), vec![])];
+    assert!(other.bulk_load(unsorted).is_err());
+    assert_eq!(other.iter().count(), 0);
+    assert_eq!(other.bulk_load(vec![(kv(1), vec![1])]), Ok(1));
+
+    drop((t, other, db));
+    let db = sled::Db::start(config).unwrap();
+    let t = db.open_tree(b"records").unwrap();
+    assert_eq!(t.iter().count(), N);
+    assert_eq!(t.get(&kv(7)), Ok(None));
+    assert_eq!(t.get(&kv(8)), Ok(Some(kv(8).into())));
+}
+
+#[test]
+fn tree_key_histogram_and_sample() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    t.set(kv(0), vec![]).unwrap();
+    assert_eq!(t.key_sample(3), Ok(vec![]));
+    t.del(&kv(0)).unwrap();
+
+    t.bulk_load((0..N).map(|i| (kv(i), vec![]))).unwrap();
+    let count = |lo: &[u8], hi: Option<&Vec<u8>>| match hi {
+        Some(hi) => t.range(lo.to_vec()..hi.clone()).count(),
+        None => t.range(lo.to_vec()..).count(),
+    };
+
+    let buckets = t.key_histogram().unwrap();
+    assert_eq!(buckets.len(), t.stats().unwrap().leaves);
+    assert_eq!(buckets[0].lo, Vec::<u8>::new());
+    assert_eq!(buckets.last().unwrap().hi, None);
+    for pair in buckets.windows(2) {
+        assert_eq!(pair[0].hi.as_ref(), Some(&pair[1].lo));
+    }
+    let counted: Vec<&KeyBucket> =
+        buckets.iter().filter(|b| b.counted).collect();
+    assert!(counted.len() > 1 && counted.len() <= 16);
+    for bucket in counted {
+        let keys = count(&bucket.lo, bucket.hi.as_ref());
+        assert_eq!(bucket.keys, keys);
+    }
+    let estimate: usize = buckets.iter().map(|b| b.keys).sum();
+    assert!(estimate > N * 9 / 10 && estimate < N * 11 / 10);
+
+    // the split points divide the keys into quarters, give or take
+    // a leaf
+    let splits = t.key_sample(3).unwrap();
+    assert_eq!(splits.len(), 3);
+    let mut lo = vec![];
+    for hi in splits.iter().map(Some).chain(Some(None)) {
+        let quarter = count(&lo, hi) as isize;
+        assert!((quarter - N as isize / 4).abs() <= 4, "{}", quarter);
+        lo = hi.cloned().unwrap_or_default();
+    }
+}
+
+#[test]
+fn db_read_view() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let db = Arc::new(sled::Db::start(config).unwrap());
+    let users = db.open_tree(b"users").unwrap();
+    for i in (0..N).filter(|i| i % 2 == 0) {
+        db.set(kv(i), vec![0]).unwrap();
+        users.set(kv(i), vec![0]).unwrap();
+    }
+
+    let view = db.read_view().unwrap();
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            let users = db.open_tree(b"users").unwrap();
+            for i in 0..N {
+                if i % 3 == 0 {
+                    db.del(&kv(i)).unwrap();
+                } else {
+                    db.set(kv(i), vec![1]).unwrap();
+                }
+                let mut batch = Batch::default();
+                batch.set(kv(i), vec![1]);
+                users.apply_batch(batch).unwrap();
+            }
+        })
+    };
+    // scans racing the writer still see the records as they were
+    for _ in 0..3 {
+        let keys: Vec<Vec<u8>> =
+            view.iter().map(|res| res.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> =
+            (0..N).filter(|i| i % 2 == 0).map(kv).collect();
+        assert_eq!(keys, expected);
+        let users_view = view.tree(b"users").unwrap();
+        assert_eq!(users_view.iter().count(), N / 2);
+    }
+    writer.join().unwrap();
+
+    assert_eq!(view.get(&kv(2)), Ok(Some(vec![0].into())));
+    assert_eq!(view.get(&kv(1)), Ok(None));
+    assert_eq!(view.get(&kv(6)), Ok(Some(vec![0].into())));
+    assert_eq!(db.get(&kv(6)), Ok(None));
+    assert_eq!(view.scan(&kv(N - 10)).count(), 5);
+    assert_eq!(view.range(kv(10)..kv(20)).count(), 5);
+    assert!(view.tree(b"missing").is_none());
+    let users_view = view.tree(b"users").unwrap();
+    for res in users_view.iter() {
+        let (k, v) = res.unwrap();
+        assert_eq!(v.to_vec(), vec![0]);
+        assert_eq!(users.get(&k), Ok(Some(vec![1].into())));
+    }
+
+    assert!(db.clear().is_err());
+    assert!(users.remove_range(kv(0)..kv(1)).is_err());
+    assert!(db.drop_tree(b"users").is_err());
+
+    // the tree is written to directly again once the view is gone
+    drop(view);
+    assert_eq!(users.remove_range(kv(0)..kv(10)), Ok(10));
+    assert_eq!(db.drop_tree(b"users"), Ok(true));
+}
+
+#[test]
+fn tree_async() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let t = AsyncTree::with_threads(Tree::start(config).unwrap(), 3);
+
+    // many operations can be in flight at once
+    let sets: Vec<_> = (0..N).map(|i| t.set(kv(i), kv(i))).collect();
+    for set in sets {
+        block_on(set).unwrap();
+    }
+    let gets: Vec<_> = (0..N).map(|i| t.get(kv(i))).collect();
+    for (i, get) in gets.into_iter().enumerate() {
+        assert_eq!(block_on(get), Ok(Some(kv(i).into())));
+    }
+
+    assert_eq!(block_on(t.del(kv(1))), Ok(Some(kv(1).into())));
+    let mut batch = Batch::default();
+    batch.del(kv(2));
+    batch.set(kv(3), vec![3]);
+    block_on(t.apply_batch(batch)).unwrap();
+    block_on(t.flush()).unwrap();
+    assert_eq!(t.inner().len(), Ok(N - 2));
+    assert_eq!(t.inner().get(&kv(3)), Ok(Some(vec![3].into())));
+
+    // an operation runs to the end even if its future is dropped,
+    // and one thread runs operations in the order they were made
+    let serial = AsyncTree::with_threads(t.inner().clone(), 1);
+    drop(serial.set(kv(1), vec![1]));
+    assert_eq!(block_on(serial.get(kv(1))), Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_blobs() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .io_buf_size(50000)
+        .cache_capacity(1000)
+        .cache_bits(0)
+        .page_consolidation_threshold(3)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .blob_threshold(Some(64))
+        .build();
+    let big = |i: usize| {
+        let mut v = kv(i);
+        v.resize(100 + i % 50, i as u8);
+        v
+    };
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    for i in 0..N_PER_THREAD {
+        if i % 2 == 0 {
+            t.set(kv(i), big(i)).unwrap();
+        } else {
+            t.set(kv(i), kv(i)).unwrap();
+        }
+    }
+    for i in 0..N_PER_THREAD {
+        if i % 2 == 0 {
+            assert_eq!(t.get(&*kv(i)), Ok(Some(big(i).into())));
+        } else {
+            assert_eq!(t.get(&*kv(i)), Ok(Some(kv(i).into())));
+        }
+    }
+
+    // overwriting, deleting and swapping blobs frees the old ones
+    for i in (0..N_PER_THREAD).step_by(4) {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+    for i in (2..N_PER_THREAD).step_by(8) {
+        t.del(&*kv(i)).unwrap();
+    }
+    t.cas(kv(1), Some(kv(1)), Some(big(1))).unwrap();
+    drop(t);
+
+    let check = |t: &sled::Tree| {
+        for i in 0..N_PER_THREAD {
+            let expected = if i == 1 || i % 8 == 6 {
+                Some(big(i))
+            } else if i % 8 == 2 {
+                None
+            } else {
+                Some(kv(i))
+            };
+            assert_eq!(
+                t.get(&*kv(i)),
+                Ok(expected.map(|v| v.into()))
+            );
+        }
+    };
+    let t = sled::Tree::start(config.clone()).unwrap();
+    check(&t);
+    let deleted = (0..N_PER_THREAD).filter(|i| i % 8 == 2).count();
+    assert_eq!(t.iter().count(), N_PER_THREAD - deleted);
+
+    // batches store their large values the same way
+    let mut batch = sled::Batch::default();
+    batch.set(kv(0), big(0));
+    batch.del(kv(6));
+    t.apply_batch(batch).unwrap();
+    assert_eq!(t.get(&*kv(0)), Ok(Some(big(0).into())));
+    assert_eq!(t.get(&*kv(6)), Ok(None));
+    t.set(kv(0), kv(0)).unwrap();
+    t.set(kv(6), big(6)).unwrap();
+    drop(t);
+
+    let t = sled::Tree::start(config.clone()).unwrap();
+    check(&t);
+}
+
+#[test]
+fn db_export_import() {
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!(
+        "test_db_export_import_{}",
+        std::process::id()
+    ));
+    // a clone of a temporary config shares its files
+    let config = || {
+        ConfigBuilder::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .build()
+    };
+    let db = Arc::new(sled::Db::start(config()).unwrap());
+    let users = db.open_tree(b"users").unwrap();
+    let ttl = Duration::from_millis(1);
+    db.set_with_ttl(b"gone".to_vec(), vec![0], ttl).unwrap();
+    db.set_with_ttl(
+        b"kept".to_vec(),
+        vec![0],
+        Duration::from_secs(3600),
+    ).unwrap();
+    let last_id = db.generate_id().unwrap();
+
+    // every key is set in the default tree before the users tree,
+    // so a consistent export never has more users than defaults
+    let writer = {
+        let db = db.clone();
+        let users = users.clone();
+        thread::spawn(move || {
+            for i in 0..N_PER_THREAD {
+                db.set(kv(i), kv(i)).unwrap();
+                users.set(kv(i), kv(i)).unwrap();
+            }
+        })
+    };
+    db.export_to(&path).unwrap();
+    writer.join().unwrap();
+    thread::sleep(Duration::from_millis(5));
+
+    let restored = sled::Db::start(config()).unwrap();
+    restored.import_from(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(restored.tree_names(), Ok(vec![b"users".to_vec()]));
+    assert_eq!(restored.get(b"gone"), Ok(None));
+    assert_eq!(restored.get(b"kept"), Ok(Some(vec![0].into())));
+    let restored_users = restored.open_tree(b"users").unwrap();
+    let n_users = restored_users.iter().count();
+    let n_defaults = restored
+        .iter()
+        .filter(|res| res.as_ref().unwrap().0.len() == 3)
+        .count();
+    assert!(n_users <= n_defaults && n_defaults <= n_users + 1);
+    for i in 0..n_defaults {
+        assert_eq!(restored.get(&kv(i)), Ok(Some(kv(i).into())));
+    }
+    assert!(restored.generate_id().unwrap() > last_id);
+}
+
+#[test]
+fn db_portable_export_import() {
+    use std::time::Duration;
+
+    // a clone of a temporary config shares its files
+    let config = || {
+        ConfigBuilder::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .build()
+    };
+    let db = sled::Db::start(config()).unwrap();
+    let users = db.open_tree(b"users").unwrap();
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i)).unwrap();
+        users.set(kv(i), vec![i as u8; i % 300]).unwrap();
+    }
+    let ttl = Duration::from_millis(1);
+    db.set_with_ttl(b"gone".to_vec(), vec![0], ttl).unwrap();
+    let last_id = db.generate_id().unwrap();
+    let mut dump = vec![];
+    db.export(&mut dump).unwrap();
+    thread::sleep(Duration::from_millis(5));
+
+    let restored = sled::Db::start(config()).unwrap();
+    restored.import(&*dump).unwrap();
+    assert_eq!(restored.tree_names(), Ok(vec![b"users".to_vec()]));
+    assert_eq!(restored.get(b"gone"), Ok(None));
+    let restored_users = restored.open_tree(b"users").unwrap();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(restored.get(&*kv(i)), Ok(Some(kv(i).into())));
+        assert_eq!(
+            restored_users.get(&*kv(i)),
+            Ok(Some(vec![i as u8; i % 300].into()))
+        );
+    }
+    assert!(restored.generate_id().unwrap() > last_id);
+
+    // damage is caught by the checksums, and what was loaded
+    // before it was found is undone
+    let mut damaged = dump.clone();
+    let mid = damaged.len() / 2;
+    damaged[mid] ^= 1;
+    let other = sled::Db::start(config()).unwrap();
+    other.set(kv(0), vec![9]).unwrap();
+    match other.import(&*damaged) {
+        Err(Error::Corruption { .. }) => {}
+        other => panic!("expected Corruption, got {:?}", other),
+    }
+    assert_eq!(other.get(&*kv(0)), Ok(Some(vec![9].into())));
+    assert_eq!(other.iter().count(), 1);
+    assert_eq!(other.tree_names(), Ok(vec![]));
+
+    // and so is a dump cut short
+    let truncated = &dump[..dump.len() - 1];
+    assert!(other.import(truncated).is_err());
+    assert_eq!(other.get(&*kv(0)), Ok(Some(vec![9].into())));
+    assert_eq!(other.iter().count(), 1);
+    assert_eq!(other.tree_names(), Ok(vec![]));
+
+    match other.import(&b"not a dump at all"[..]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+}
+
+#[test]
+fn db_export_since() {
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let full = dir.join(format!("test_db_export_since_full_{}", id));
+    let changes =
+        dir.join(format!("test_db_export_since_changes_{}", id));
+    // a clone of a temporary config shares its files
+    let config = || {
+        ConfigBuilder::new()
+            .temporary(true)
+            .blink_fanout(4)
+            .max_leaf_len(Some(8))
+            .flush_every_ms(None)
+            .build()
+    };
+    let db = sled::Db::start(config()).unwrap();
+    for i in 0..N {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    db.open_tree(b"old").unwrap().set(kv(0), kv(0)).unwrap();
+    db.flush().unwrap();
+    let lsn = db.stable_lsn();
+    db.export_to(&full).unwrap();
+
+    db.del(&kv(0)).unwrap();
+    db.set(kv(N / 2), vec![1]).unwrap();
+    db.remove_range(kv(N - 20)..kv(N - 10)).unwrap();
+    let users = db.open_tree(b"users").unwrap();
+    for i in 0..N / 10 {
+        users.set(kv(i % 10), kv(i)).unwrap();
+    }
+    db.open_tree(b"empty").unwrap();
+    db.open_tree(b"gone").unwrap().set(kv(0), kv(0)).unwrap();
+    assert_eq!(db.drop_tree(b"gone"), Ok(true));
+    assert_eq!(db.drop_tree(b"old"), Ok(true));
+    db.export_since(lsn, &changes).unwrap();
+    assert!(
+        std::fs::metadata(&changes).unwrap().len()
+            < std::fs::metadata(&full).unwrap().len() / 2
+    );
+
+    let restored = sled::Db::start(config()).unwrap();
+    restored.import_from(&full).unwrap();
+    assert_eq!(restored.tree_names(), Ok(vec![b"old".to_vec()]));
+    restored.import_from(&changes).unwrap();
+    std::fs::remove_file(&full).unwrap();
+    std::fs::remove_file(&changes).unwrap();
+
+    let records = |t: &sled::Tree| -> Vec<(Vec<u8>, Vec<u8>)> {
+        t.iter()
+            .map(|res| {
+                let (k, v) = res.unwrap();
+                (k, v.to_vec())
+            })
+            .collect()
+    };
+    assert_eq!(records(&restored), records(&db));
+    assert_eq!(
+        restored.tree_names(),
+        Ok(vec![b"empty".to_vec(), b"users".to_vec()])
+    );
+    assert_eq!(
+        records(&restored.open_tree(b"users").unwrap()),
+        records(&users)
+    );
+
+    // once the log has been cleaned past the lsn, the changes
+    // since can no longer be read back
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(100)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    db.set(kv(0), kv(0)).unwrap();
+    db.flush().unwrap();
+    let lsn = db.stable_lsn();
+    for i in 0..N {
+        db.set(kv(i % 10), kv(i)).unwrap();
+        if i % 10 == 0 {
+            db.flush().unwrap();
+        }
+    }
+    match db.export_since(lsn, &changes) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    let _ = std::fs::remove_file(&changes);
+}
+
+#[test]
+fn db_replication_stream() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .max_leaf_len(Some(8))
+        .flush_every_ms(None)
+        .build();
+    let leader = Arc::new(sled::Db::start(config.clone()).unwrap());
+    let follower = sled::Db::start(config.clone()).unwrap();
+    let mut changes = leader.replication_stream(0);
+
+    let writer = {
+        let leader = leader.clone();
+        thread::spawn(move || {
+            let users = leader.open_tree(b"users").unwrap();
+            for i in 0..N_PER_THREAD {
+                leader.set(kv(i), kv(i)).unwrap();
+                users.set(kv(i), kv(i)).unwrap();
+                if i % 3 == 0 {
+                    leader.del(&kv(i / 2)).unwrap();
+                }
+                // the stream only reads what has been flushed
+                if i % 50 == 0 {
+                    leader.flush().unwrap();
+                }
+            }
+            users.set(b"done".to_vec(), vec![]).unwrap();
+            leader.flush().unwrap();
+        })
+    };
+
+    // changes come out in the order they were logged, so the
+    // marker is the last of them
+    let follower_users = follower.open_tree(b"users").unwrap();
+    let mut last_lsn = 0;
+    while follower_users.get(b"done").unwrap().is_none() {
+        let change = changes.next().unwrap().unwrap();
+        assert!(change.lsn >= last_lsn);
+        last_lsn = change.lsn;
+        follower.apply_change(&change).unwrap();
+    }
+    writer.join().unwrap();
+
+    let records = |t: &sled::Tree| -> Vec<(Vec<u8>, Vec<u8>)> {
+        t.iter()
+            .map(|res| {
+                let (k, v) = res.unwrap();
+                (k, v.to_vec())
+            })
+            .collect()
+    };
+    assert_eq!(records(&follower), records(&leader));
+    assert_eq!(
+        records(&follower_users),
+        records(&leader.open_tree(b"users").unwrap())
+    );
+
+    // a damaged change is refused
+    let mut stream = leader.replication_stream(0);
+    let mut change = stream.next().unwrap().unwrap();
+    change.key.push(0);
+    assert!(follower.apply_change(&change).is_err());
+}
+
+#[test]
+fn db_replication_stream_ops() {
+    fn concatenate_merge(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .merge_operator(concatenate_merge)
+        .build();
+    let leader = sled::Db::start(config.clone()).unwrap();
+    let follower = sled::Db::start(config.clone()).unwrap();
+    let mut changes = leader.replication_stream(0);
+
+    let cleared = leader.open_tree(b"cleared").unwrap();
+    for i in 0..20 {
+        leader.set(kv(i), vec![i as u8]).unwrap();
+        cleared.set(kv(i), vec![i as u8]).unwrap();
+    }
+    leader.merge(kv(1), vec![7]).unwrap();
+    leader.merge(kv(1), vec![8]).unwrap();
+    leader.merge(kv(100), vec![9]).unwrap();
+    let far = std::time::Duration::from_secs(3600);
+    leader.set_with_ttl(kv(2), vec![2, 2], far).unwrap();
+    leader.del(&kv(3)).unwrap();
+    leader.remove_range(kv(10)..kv(15)).unwrap();
+    cleared.clear().unwrap();
+    cleared.set(kv(5), vec![5]).unwrap();
+    let mut batch = sled::Batch::default();
+    batch.set(kv(4), vec![4, 4]);
+    batch.del(kv(5));
+    leader.apply_batch(batch).unwrap();
+    leader.set(b"done".to_vec(), vec![]).unwrap();
+    leader.flush().unwrap();
+
+    let mut ops = vec![];
+    while follower.get(b"done").unwrap().is_none() {
+        let change = changes.next().unwrap().unwrap();
+        ops.push(change.op.clone());
+        follower.apply_change(&change).unwrap();
+    }
+    // each write comes out on its own
+    assert_eq!(
+        ops.iter()
+            .filter(|op| match **op {
+                sled::ChangeOp::Merge(_) => true,
+                _ => false,
+            })
+            .count(),
+        3
+    );
+    assert!(ops.contains(&sled::ChangeOp::Del));
+
+    let records = |t: &sled::Tree| -> Vec<(Vec<u8>, Vec<u8>)> {
+        t.iter()
+            .map(|res| {
+                let (k, v) = res.unwrap();
+                (k, v.to_vec())
+            })
+            .collect()
+    };
+    assert_eq!(records(&follower), records(&leader));
+    assert_eq!(follower.get(&kv(1)), Ok(Some(vec![1, 7, 8].into())));
+    let follower_cleared = follower.open_tree(b"cleared").unwrap();
+    assert_eq!(records(&follower_cleared), records(&cleared));
+    assert_eq!(records(&cleared), vec![(kv(5), vec![5])]);
+    // the TTL came along with the value
+    assert!(ops.iter().any(|op| match *op {
+        sled::ChangeOp::Set(ref v, Some(_)) => *v == vec![2, 2],
+        _ => false,
+    }));
+}
+
+#[test]
+fn db_compact() {
+    let path = "/tmp/test_db_compact";
+    let _ = std::fs::remove_dir_all(path);
+    let config = ConfigBuilder::new()
+        .path(path.to_owned())
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(1_000_000)
+        .segment_cleanup_threshold(0.01)
+        .compaction_threshold(0.9)
+        .build();
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    for i in 0..N {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    // overwrite a few keys many times, leaving the segments that
+    // held their old versions mostly dead
+    for round in 0..20 {
+        for i in 0..10 {
+            db.set(kv(i), vec![round]).unwrap();
+        }
+    }
+    db.flush().unwrap();
+
+    let freed = db.compact().unwrap();
+    assert!(freed > 0, "compaction freed nothing");
+    for i in 0..N {
+        let expected = if i < 10 { vec![19] } else { kv(i) };
+        assert_eq!(db.get(&kv(i)), Ok(Some(expected.into())));
+    }
+    drop(db);
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    for i in 0..N {
+        let expected = if i < 10 { vec![19] } else { kv(i) };
+        assert_eq!(db.get(&kv(i)), Ok(Some(expected.into())));
+    }
+    drop(db);
+    std::fs::remove_dir_all(path).unwrap();
 }
 
 #[test]
-fn recover_tree() {
-    println!("========== recovery ==========");
+fn db_stats() {
     let config = ConfigBuilder::new()
         .temporary(true)
-        .blink_fanout(2)
-        .io_buf_size(5000)
+        .blink_fanout(4)
         .flush_every_ms(None)
-        .snapshot_after_ops(100)
         .build();
-    let t = sled::Tree::start(config.clone()).unwrap();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let users = db.open_tree(b"users").unwrap();
+    for i in 0..N {
+        users.set(kv(i), vec![0, 1]).unwrap();
+    }
+    db.set(b"a".to_vec(), vec![]).unwrap();
+    db.flush().unwrap();
+
+    let stats = db.stats().unwrap();
+    assert!(stats.size_on_disk > 0);
+    assert_eq!(stats.size_on_disk, db.size_on_disk().unwrap());
+    // a new tree starts out as a root index over one leaf
+    assert_eq!(stats.default.height, 2);
+    assert_eq!(stats.default.pages, 2);
+    assert_eq!(stats.default.leaves, 1);
+    assert_eq!(stats.default.records, 1);
+    assert_eq!(stats.default.key_bytes, 1);
+    assert_eq!(stats.default.value_bytes, 0);
+    assert_eq!(stats.default.avg_leaf_fill, 0.25);
+
+    assert_eq!(stats.trees.len(), 1);
+    let (ref name, ref tree) = stats.trees[0];
+    assert_eq!(name, b"users");
+    assert_eq!(tree.records, N);
+    assert_eq!(tree.key_bytes, N * 3);
+    assert_eq!(tree.value_bytes, N * 2);
+    assert!(tree.height > 2);
+    assert!(tree.pages > tree.leaves);
+    assert!(tree.avg_leaf_fill > 0.0 && tree.avg_leaf_fill <= 1.0);
+    assert_eq!(tree.records as f64 / tree.leaves as f64 / 4.0,
+               tree.avg_leaf_fill);
+
+    let segments = stats.segments;
+    assert!(segments.active + segments.inactive > 0);
+    assert!(segments.live_pages > 0);
+}
+
+// Keeps every snapshot it is given.
+struct CollectingSink(Arc<std::sync::Mutex<Vec<MetricsSnapshot>>>);
+
+impl MetricsSink for CollectingSink {
+    fn report(&self, metrics: &MetricsSnapshot) {
+        self.0.lock().unwrap().push(metrics.clone());
+    }
+}
+
+#[test]
+fn db_metrics() {
+    use std::time::Duration;
+
+    // not temporary, so the log is fsynced
+    let path = "/tmp/test_db_metrics";
+    let _ = std::fs::remove_dir_all(path);
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let config = ConfigBuilder::new()
+        .path(path.to_owned())
+        .blink_fanout(4)
+        .metrics_sink(CollectingSink(reports.clone()))
+        .metrics_every_ms(Some(10))
+        .build();
+    let db = sled::Db::start(config).unwrap();
+
+    let before = db.metrics();
+    for i in 0..N {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    for i in 0..N {
+        assert_eq!(db.get(&kv(i)), Ok(Some(kv(i).into())));
+    }
+    db.flush().unwrap();
+    let after = db.metrics();
+    assert!(after.splits > before.splits);
+    assert!(after.log_reservations >= before.log_reservations + N);
+    assert!(after.cache_hits >= before.cache_hits + N);
+    assert!(after.fsyncs > before.fsyncs);
+
+    // the counts only grow, and the sink sees them do so
+    thread::sleep(Duration::from_millis(100));
+    drop(db);
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() > 1);
+    let last = reports.last().unwrap();
+    assert!(last.splits >= after.splits);
+    for pair in reports.windows(2) {
+        assert!(pair[1].log_reservations >= pair[0].log_reservations);
+    }
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn db_verify_integrity() {
+    let path = "/tmp/test_db_verify_integrity";
+    let _ = std::fs::remove_dir_all(path);
+    let config = ConfigBuilder::new()
+        .path(path.to_owned())
+        .blink_fanout(4)
+        .build();
+
+    {
+        let db = sled::Db::start(config.clone()).unwrap();
+        let users = db.open_tree(b"users").unwrap();
+        for i in 0..N {
+            db.set(kv(i), kv(i)).unwrap();
+            users.set(kv(i), kv(i)).unwrap();
+        }
+        for i in 0..N / 2 {
+            db.del(&kv(i)).unwrap();
+        }
+
+        let report = db.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.default.pages > N / 4);
+        assert!(report.default.frags >= report.default.pages);
+        assert_eq!(report.trees[0].0, b"users".to_vec());
+        assert!(report.trees[0].1.pages > N / 4);
+    }
+
+    // recovery rebuilds the same structure from the log
+    let db = sled::Db::start(config).unwrap();
+    let report = db.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert!(report.default.frags >= report.default.pages);
+    drop(db);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn db_snapshot() {
+    let path = "/tmp/test_db_snapshot";
+    let _ = std::fs::remove_dir_all(path);
+    let config = ConfigBuilder::new()
+        .path(path.to_owned())
+        .blink_fanout(4)
+        .snapshot_after_ops(1 << 20)
+        .build();
+
+    {
+        let db = sled::Db::start(config.clone()).unwrap();
+        for i in 0..N {
+            db.set(kv(i), kv(i)).unwrap();
+        }
+        db.snapshot().unwrap();
+    }
+    assert!(!config.get_snapshot_files().unwrap().is_empty());
+
+    let db = sled::Db::start(config).unwrap();
+    for i in 0..N {
+        assert_eq!(db.get(&kv(i)), Ok(Some(kv(i).into())));
+    }
+    drop(db);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn db_resident_bytes() {
+    let capacity = 4096;
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .cache_capacity(capacity)
+        .cache_bits(0)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+
+    for i in 0..N {
+        db.set(kv(i), kv(i)).unwrap();
+    }
+    for i in 0..N {
+        assert_eq!(db.get(&kv(i)), Ok(Some(kv(i).into())));
+    }
+
+    // pages past the budget are paged out, leaving at most the
+    // one most recently read over it
+    let resident = db.resident_bytes();
+    assert!(resident > 0);
+    assert!(resident <= capacity * 2, "{} resident", resident);
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.resident_bytes, db.resident_bytes());
+}
+
+#[test]
+fn tree_flush_async() {
+    use std::time::{Duration, Instant};
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    t.set(kv(1), kv(1)).unwrap();
+    let flushed = t.flush_async();
+    assert!(!flushed.is_done());
+    flushed.wait().unwrap();
+    assert!(t.flush_async().is_done());
+
+    // the background flusher finishes it without waiting on it
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(Some(10))
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    t.set(kv(1), kv(1)).unwrap();
+    let flushed = t.flush_async();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !flushed.is_done() {
+        assert!(Instant::now() < deadline, "flush never finished");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn tree_set_with_durability() {
+    for &temporary in &[false, true] {
+        let path = "/tmp/test_tree_set_with_durability";
+        let _ = std::fs::remove_dir_all(path);
+        let config = ConfigBuilder::new()
+            .path(path.to_owned())
+            .temporary(temporary)
+            .flush_every_ms(None)
+            .build();
+        let t = sled::Tree::start(config).unwrap();
+
+        t.set_with_durability(kv(1), kv(1), Durability::Buffered)
+            .unwrap();
+        assert!(!t.flush_async().is_done());
+
+        t.set_with_durability(kv(2), kv(2), Durability::Flushed)
+            .unwrap();
+        assert!(t.flush_async().is_done());
+
+        t.set_with_durability(kv(3), kv(3), Durability::Buffered)
+            .unwrap();
+        t.set_with_durability(kv(4), kv(4), Durability::Synced)
+            .unwrap();
+        assert!(t.flush_async().is_done());
+
+        for i in 1..5 {
+            assert_eq!(t.get(&*kv(i)), Ok(Some(kv(i).into())));
+        }
+        drop(t);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn db_read_only() {
+    let path = "/tmp/test_db_read_only";
+    let _ = std::fs::remove_dir_all(path);
+    let builder = || {
+        ConfigBuilder::new()
+            .path(path.to_owned())
+            .snapshot_after_ops(100)
+    };
+    let read_only = || builder().read_only(true).build();
+    let files = || {
+        let mut files: Vec<(std::path::PathBuf, Vec<u8>)> =
+            std::fs::read_dir(path)
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    let bytes = std::fs::read(&path).unwrap();
+                    (path, bytes)
+                })
+                .collect();
+        files.sort();
+        files
+    };
+
+    match sled::Db::start(read_only()) {
+        Err(Error::Unsupported(_)) => {}
+        other => {
+            panic!("opened a missing database: {:?}", other.err())
+        }
+    }
+    assert!(!std::path::Path::new(path).exists());
+
+    let db = sled::Db::start(builder().build()).unwrap();
+    let users = db.open_tree(b"users").unwrap();
     for i in 0..N_PER_THREAD {
-        let k = kv(i);
-        t.set(k.clone(), k).unwrap();
+        db.set(kv(i), kv(i)).unwrap();
+        users.set(kv(i), vec![1]).unwrap();
     }
-    drop(t);
+    db.flush().unwrap();
+
+    // a reader can open the directory while the writer has it
+    let reader = sled::Db::start(read_only()).unwrap();
+    assert_eq!(reader.get(&*kv(1)), Ok(Some(kv(1).into())));
+    drop(reader);
+    drop(users);
+    drop(db);
+
+    let before = files();
+    let reader = sled::Db::start(read_only()).unwrap();
+    let users = reader.open_tree(b"users").unwrap();
+    for i in 0..N_PER_THREAD {
+        assert_eq!(reader.get(&*kv(i)), Ok(Some(kv(i).into())));
+        assert_eq!(users.get(&*kv(i)), Ok(Some(vec![1].into())));
+    }
+    assert_eq!(users.iter().count(), N_PER_THREAD);
+
+    let refused = |res: Result<(), Error<()>>| match res {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("read-only write returned {:?}", other),
+    };
+    refused(reader.set(kv(1), vec![2]));
+    refused(reader.del(&*kv(1)).map(|_| ()));
+    refused(reader.merge(kv(1), vec![2]));
+    refused(reader.remove_range(kv(0)..kv(5)).map(|_| ()));
+    refused(reader.clear());
+    refused(reader.open_tree(b"other").map(|_| ()));
+    refused(reader.drop_tree(b"users").map(|_| ()));
+    refused(reader.generate_id().map(|_| ()));
+    match reader.cas(kv(1), Some(kv(1)), Some(vec![2])) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("read-only cas returned {:?}", other),
+    }
+    assert_eq!(reader.get(&*kv(1)), Ok(Some(kv(1).into())));
+    drop(users);
+    drop(reader);
+
+    // nothing on disk was touched
+    assert!(before == files(), "a read-only open changed the files");
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn db_locks_directory() {
+    let path = "/tmp/test_db_locks_directory";
+    let _ = std::fs::remove_dir_all(path);
+    let builder = || ConfigBuilder::new().path(path.to_owned());
+
+    let db = sled::Db::start(builder().build()).unwrap();
+    db.set(kv(1), kv(1)).unwrap();
+    db.flush().unwrap();
+
+    // the lock is per open of the file, so a second open in this
+    // process is refused just like one from another process
+    match sled::Db::start(builder().build()) {
+        Err(Error::Unsupported(ref msg)) => {
+            assert!(msg.contains("already open"), "{}", msg)
+        }
+        other => {
+            panic!("opened a locked database: {:?}", other.err())
+        }
+    }
+    let reader =
+        sled::Db::start(builder().read_only(true).build()).unwrap();
+    assert_eq!(reader.get(&*kv(1)), Ok(Some(kv(1).into())));
+    drop(reader);
+
+    drop(db);
+    let db = sled::Db::start(builder().build()).unwrap();
+    assert_eq!(db.get(&*kv(1)), Ok(Some(kv(1).into())));
+    drop(db);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn typed_tree_orders_keys() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+
+    let nums: TypedTree<u64, Vec<String>> = TypedTree::new(t.clone());
+    for i in (0..600).rev() {
+        nums.set(&i, &vec![i.to_string()]).unwrap();
+    }
+    assert_eq!(nums.get(&256), Ok(Some(vec!["256".to_owned()])));
+    let keys: Vec<u64> = nums.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (0..600).collect::<Vec<_>>());
+
+    let keys: Vec<u64> =
+        nums.range(250..260).map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (250..260).collect::<Vec<_>>());
+
+    assert_eq!(nums.del(&0), Ok(Some(vec!["0".to_owned()])));
+    assert_eq!(nums.contains_key(&0), Ok(false));
+    assert_eq!(nums.scan(&598).count(), 2);
+
+    // keys written by another codec can't be decoded
+    let wide: TypedTree<(String, i64), u8> = TypedTree::new(t);
+    assert!(wide.iter().next().unwrap().is_err());
+    for k in nums.iter().map(|r| r.unwrap().0).collect::<Vec<_>>() {
+        nums.del(&k).unwrap();
+    }
+
+    let mut expected = vec![];
+    for name in &["b", "a", "", "a\u{0}", "ab"] {
+        for n in &[5, -1, 0, i64::min_value(), i64::max_value()] {
+            let key = (name.to_string(), *n);
+            wide.set(&key, &0).unwrap();
+            expected.push(key);
+        }
+    }
+    expected.sort();
+    let keys: Vec<(String, i64)> =
+        wide.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn tree_dump_structure() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..100 {
+        t.set(kv(i), kv(i)).unwrap();
+    }
+
+    let dump = t.dump_structure().unwrap();
+    let root = &dump.nodes[0];
+    assert_eq!(root.id, dump.root);
+    assert_eq!(root.level, 0);
+    assert!(!root.leaf);
+    assert_eq!(root.lo, Vec::<u8>::new());
+    assert_eq!(root.hi, None);
+
+    // every child is a node one level down, and the leaves hold
+    // every record between them
+    for node in &dump.nodes {
+        assert!(node.frags >= 1);
+        for child in &node.children {
+            let child =
+                dump.nodes.iter().find(|n| n.id == *child).unwrap();
+            assert_eq!(child.level, node.level + 1);
+        }
+    }
+    let leaves: Vec<_> =
+        dump.nodes.iter().filter(|n| n.leaf).collect();
+    assert!(leaves.len() > 1);
+    assert_eq!(leaves.iter().map(|n| n.len).sum::<usize>(), 100);
+    for pair in leaves.windows(2) {
+        assert_eq!(pair[0].next, Some(pair[1].id));
+        assert_eq!(pair[0].hi, Some(pair[1].lo.clone()));
+    }
+    for leaf in &leaves {
+        assert!(leaf.bytes > 0);
+        assert!(leaf.fill > 0. && leaf.fill <= 1.);
+    }
+
+    let dot = dump.to_dot();
+    assert!(dot.starts_with("digraph tree {"));
+    let edge = format!("n{} -> n{};", root.id, root.children[0]);
+    assert!(dot.contains(&edge));
 
+    // each node reports how its records are encoded
+    assert!(dump.nodes.iter().all(|n| !n.encoding.value_dict));
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .intern_values(true)
+        .build();
+    let t = sled::Tree::start(config).unwrap();
+    for i in 0..100 {
+        t.set(kv(i), kv(i % 3)).unwrap();
+    }
+    let dump = t.dump_structure().unwrap();
+    for node in &dump.nodes {
+        assert_eq!(node.encoding.value_dict, node.leaf);
+    }
+}
+
+#[test]
+fn tree_apply_batch() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .max_leaf_len(Some(3))
+        .flush_every_ms(None)
+        .merge_operator(test_merge_operator)
+        .build();
     let t = sled::Tree::start(config.clone()).unwrap();
-    for i in 0..config.blink_fanout << 1 {
-        let k = kv(i as usize);
-        assert_eq!(t.get(&*k), Ok(Some(k.clone())));
-        t.del(&*k).unwrap();
+    for i in 0..10 {
+        t.set(kv(i), vec![0, 1]).unwrap();
+    }
+
+    // enough new keys to split leaves within the batch
+    let mut batch = sled::Batch::default();
+    for i in 10..N_PER_THREAD {
+        batch.set(kv(i), vec![0, 2]);
+    }
+    for i in 0..5 {
+        batch.del(kv(i));
     }
+    batch.merge(kv(7), vec![3]);
+    batch.merge(kv(N_PER_THREAD), vec![4]);
+    assert_eq!(batch.len(), N_PER_THREAD - 10 + 7);
+    t.apply_batch(batch).unwrap();
+
+    let check = |t: &sled::Tree| {
+        for i in 0..5 {
+            assert_eq!(t.get(&*kv(i)), Ok(None));
+        }
+        assert_eq!(t.get(&*kv(6)), Ok(Some(vec![0, 1].into())));
+        assert_eq!(t.get(&*kv(7)), Ok(Some(vec![0, 4].into())));
+        assert_eq!(t.get(&*kv(10)), Ok(Some(vec![0, 2].into())));
+        assert_eq!(
+            t.get(&*kv(N_PER_THREAD)),
+            Ok(Some(vec![0, 4].into()))
+        );
+        let count = N_PER_THREAD + 1 - 5;
+        assert_eq!(t.iter().count(), count);
+        assert_eq!(t.iter_rev().count(), count);
+    };
+    check(&t);
     drop(t);
 
+    let t = sled::Tree::start(config).unwrap();
+    check(&t);
+}
+
+#[test]
+fn tree_apply_batch_all_or_nothing() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
     let t = sled::Tree::start(config.clone()).unwrap();
-    for i in 0..config.blink_fanout << 1 {
-        let k = kv(i as usize);
-        assert_eq!(t.get(&*k), Ok(None));
+    t.set(kv(0), vec![1]).unwrap();
+
+    // too big for one log record, so none of it may be written
+    let mut batch = sled::Batch::default();
+    for i in 0..N_PER_THREAD {
+        batch.set(kv(i), vec![2; 100]);
+    }
+    match t.apply_batch(batch) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected Unsupported, got {:?}", other),
+    }
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![1].into())));
+    assert_eq!(t.iter().count(), 1);
+    drop(t);
+
+    let t = sled::Tree::start(config).unwrap();
+    assert_eq!(t.get(&*kv(0)), Ok(Some(vec![1].into())));
+    assert_eq!(t.iter().count(), 1);
+}
+
+#[test]
+fn tree_apply_batch_alongside_writes() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .max_leaf_len(Some(8))
+        .flush_every_ms(None)
+        .build();
+    let t = Arc::new(sled::Tree::start(config).unwrap());
+    let last = kv(N_PER_THREAD - 1);
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![0]).unwrap();
+    }
+    let done = Arc::new(AtomicBool::new(false));
+
+    // each batch sets both ends of the tree to the next count,
+    // while writes to the leaves in between make it replan
+    let writer = {
+        let t = t.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut i = 1;
+            while !done.load(SeqCst) {
+                t.set(kv(1), vec![i as u8]).unwrap();
+                t.set(kv(N_PER_THREAD - 2), vec![i as u8]).unwrap();
+                i += 1;
+            }
+        })
+    };
+    let batcher = {
+        let t = t.clone();
+        let last = last.clone();
+        thread::spawn(move || {
+            for i in 1..100 {
+                let mut batch = sled::Batch::default();
+                batch.set(kv(0), vec![i]);
+                batch.set(last.clone(), vec![i]);
+                t.apply_batch(batch).unwrap();
+            }
+        })
+    };
+
+    // a get never waits for a batch, nor sees half of one
+    let mut seen = 0;
+    while seen < 99 {
+        let first = t.get(&*kv(0)).unwrap().unwrap()[0];
+        let second = t.get(&*last).unwrap().unwrap()[0];
+        assert!(second >= first);
+        seen = first;
+    }
+    batcher.join().unwrap();
+    done.store(true, SeqCst);
+    writer.join().unwrap();
+    assert_eq!(t.get(&*last), Ok(Some(vec![99].into())));
+}
+
+#[test]
+fn tree_transaction() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_fanout(4)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    a.set(kv(1), vec![1]).unwrap();
+
+    // an error aborts without writing anything
+    let res: DbResult<(), ()> = a.transaction(|tx| {
+        tx.set(kv(2), vec![2]);
+        tx.tree(&b)?.set(kv(2), vec![2]);
+        Err(Error::Unsupported("abort".to_owned()))
+    });
+    assert_eq!(res, Err(Error::Unsupported("abort".to_owned())));
+    assert_eq!(a.get(&*kv(2)), Ok(None));
+    assert_eq!(b.get(&*kv(2)), Ok(None));
+
+    let moved = a
+        .transaction(|tx| {
+            let b = tx.tree(&b)?;
+            let old = tx.del(&*kv(1))?;
+            assert_eq!(tx.get(&*kv(1)), Ok(None));
+            b.set(kv(1), old.clone().unwrap());
+            assert_eq!(b.get(&*kv(1)), Ok(old.clone()));
+            Ok(old)
+        })
+        .unwrap();
+    assert_eq!(moved, Some(vec![1].into()));
+    assert_eq!(a.get(&*kv(1)), Ok(None));
+    assert_eq!(b.get(&*kv(1)), Ok(Some(vec![1].into())));
+    drop((a, b, db));
+
+    let db = sled::Db::start(config).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    assert_eq!(a.get(&*kv(1)), Ok(None));
+    assert_eq!(b.get(&*kv(1)), Ok(Some(vec![1].into())));
+}
+
+#[test]
+fn tree_merge_in_batches_and_transactions() {
+    // appends, except that merging an empty value removes the key
+    fn append_or_remove(
+        _key: &[u8],
+        old_value: Option<&[u8]>,
+        merged_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        if merged_bytes.is_empty() {
+            return None;
+        }
+        let mut ret =
+            old_value.map(|ov| ov.to_vec()).unwrap_or(vec![]);
+        ret.extend_from_slice(merged_bytes);
+        Some(ret)
+    }
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .merge_operator(append_or_remove)
+        .build();
+    let db = sled::Db::start(config.clone()).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    let mut events = a.watch_prefix(b"");
+    a.set(kv(1), vec![1]).unwrap();
+
+    // merges apply in order with the batch's other writes
+    let mut batch = Batch::default();
+    batch.merge(kv(1), vec![2]);
+    batch.set(kv(2), vec![3]);
+    batch.merge(kv(2), vec![4]);
+    batch.merge(kv(2), vec![5]);
+    batch.del(kv(3));
+    batch.merge(kv(3), vec![6]);
+    a.apply_batch(batch).unwrap();
+    assert_eq!(a.get(&*kv(1)), Ok(Some(vec![1, 2].into())));
+    assert_eq!(a.get(&*kv(2)), Ok(Some(vec![3, 4, 5].into())));
+    assert_eq!(a.get(&*kv(3)), Ok(Some(vec![6].into())));
+
+    // and subscribers see what each merge resolved to
+    let expected = vec![
+        Event::Set(kv(1), vec![1].into()),
+        Event::Merge(kv(1), vec![1, 2].into()),
+        Event::Set(kv(2), vec![3].into()),
+        Event::Merge(kv(2), vec![3, 4].into()),
+        Event::Merge(kv(2), vec![3, 4, 5].into()),
+        Event::Del(kv(3)),
+        Event::Merge(kv(3), vec![6].into()),
+    ];
+    for event in &expected {
+        assert_eq!(events.next().unwrap().as_ref(), Ok(event));
+    }
+    a.merge(kv(3), vec![]).unwrap();
+    assert_eq!(events.next().unwrap(), Ok(Event::Del(kv(3))));
+
+    a.transaction(|tx| {
+        let b = tx.tree(&b)?;
+        tx.merge(kv(1), vec![7])?;
+        assert_eq!(tx.get(&*kv(1)), Ok(Some(vec![1, 2, 7].into())));
+        tx.merge(kv(2), vec![])?;
+        assert_eq!(tx.get(&*kv(2)), Ok(None));
+        b.set(kv(1), vec![8]);
+        b.merge(kv(1), vec![9])?;
+        Ok(())
+    }).unwrap();
+    assert_eq!(
+        events.next().unwrap(),
+        Ok(Event::Set(kv(1), vec![1, 2, 7].into()))
+    );
+    assert_eq!(events.next().unwrap(), Ok(Event::Del(kv(2))));
+
+    let check = |a: &sled::Tree, b: &sled::Tree| {
+        assert_eq!(a.get(&*kv(1)), Ok(Some(vec![1, 2, 7].into())));
+        assert_eq!(a.get(&*kv(2)), Ok(None));
+        assert_eq!(a.get(&*kv(3)), Ok(None));
+        assert_eq!(b.get(&*kv(1)), Ok(Some(vec![8, 9].into())));
+    };
+    check(&a, &b);
+    drop((events, a, b, db));
+
+    let db = sled::Db::start(config).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    check(&a, &b);
+}
+
+#[test]
+fn concurrent_tree_transactions() {
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config).unwrap();
+    let a = db.open_tree(b"a").unwrap();
+    let b = db.open_tree(b"b").unwrap();
+    let n = 50;
+
+    // every thread moves one unit from a to b at a time, so any
+    // lost update breaks the total
+    a.set(kv(0), vec![(N_THREADS * n) as u8]).unwrap();
+    b.set(kv(0), vec![0]).unwrap();
+    let mut threads = vec![];
+    for _ in 0..N_THREADS {
+        let (a, b) = (a.clone(), b.clone());
+        threads.push(thread::spawn(move || {
+            for _ in 0..n {
+                a.transaction(|tx| {
+                    let b = tx.tree(&b)?;
+                    let from = tx.get(&*kv(0))?.unwrap()[0];
+                    let to = b.get(&*kv(0))?.unwrap()[0];
+                    tx.set(kv(0), vec![from - 1]);
+                    b.set(kv(0), vec![to + 1]);
+                    Ok(())
+                }).unwrap();
+            }
+        }));
     }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(a.get(&*kv(0)), Ok(Some(vec![0].into())));
+    assert_eq!(
+        b.get(&*kv(0)),
+        Ok(Some(vec![(N_THREADS * n) as u8].into()))
+    );
 }
 
 #[derive(Debug, Clone)]
@@ -208,6 +3547,7 @@ enum Op {
     Del(u8),
     Cas(u8, u8, u8),
     Scan(u8, usize),
+    ScanRev(u8, usize),
     Restart,
 }
 
@@ -219,7 +3559,7 @@ impl Arbitrary for Op {
             return Restart;
         }
 
-        let choice = g.gen_range(0, 6);
+        let choice = g.gen_range(0, 7);
 
         match choice {
             0 => Set(g.gen::<u8>(), g.gen::<u8>()),
@@ -228,6 +3568,7 @@ impl Arbitrary for Op {
             3 => Del(g.gen::<u8>()),
             4 => Cas(g.gen::<u8>(), g.gen::<u8>(), g.gen::<u8>()),
             5 => Scan(g.gen::<u8>(), g.gen_range::<usize>(0, 40)),
+            6 => ScanRev(g.gen::<u8>(), g.gen_range::<usize>(0, 40)),
             _ => panic!("impossible choice"),
         }
     }
@@ -257,6 +3598,10 @@ impl Arbitrary for Op {
                 vec![Scan(*k / 2, *len), Scan(*k - 1, *len)]
                     .into_iter(),
             ),
+            ScanRev(ref k, ref len) if *k > 0 => Box::new(
+                vec![ScanRev(*k / 2, *len), ScanRev(*k - 1, *len)]
+                    .into_iter(),
+            ),
             Del(ref k) if *k > 0 => {
                 Box::new(vec![Del(*k / 2), Del(*k - 1)].into_iter())
             }
@@ -332,7 +3677,7 @@ fn prop_tree_matches_btreemap(
             }
             Cas(k, old, new) => {
                 let tree_old = tree.get(&*vec![k]).unwrap();
-                if tree_old == Some(vec![0, old]) {
+                if tree_old == Some(vec![0, old].into()) {
                     tree.set(vec![k], vec![0, new]).unwrap();
                 }
 
@@ -345,7 +3690,7 @@ fn prop_tree_matches_btreemap(
                 let mut tree_iter =
                     tree.scan(&*vec![k]).take(len).map(|res| {
                         let (ref tk, ref tv) = res.unwrap();
-                        (tk[0], tv.clone())
+                        (tk[0], tv.to_vec())
                     });
                 let ref_iter = reference
                     .iter()
@@ -359,13 +3704,34 @@ fn prop_tree_matches_btreemap(
                     );
                 }
             }
+            ScanRev(k, len) => {
+                let mut tree_iter =
+                    tree.scan_rev(&*vec![k]).take(len).map(|res| {
+                        let (ref tk, ref tv) = res.unwrap();
+                        (tk[0], tv.to_vec())
+                    });
+                let ref_iter = reference
+                    .range(..=k)
+                    .rev()
+                    .take(len)
+                    .map(|(ref rk, ref rv)| (**rk, **rv));
+                for r in ref_iter {
+                    assert_eq!(
+                        Some((r.0, u16_to_bytes(r.1))),
+                        tree_iter.next()
+                    );
+                }
+            }
             Restart => {
+                assert_eq!(tree.len(), Ok(reference.len()));
                 drop(tree);
                 tree = sled::Tree::start(config.clone()).unwrap();
+                assert_eq!(tree.len(), Ok(reference.len()));
             }
         }
     }
 
+    assert_eq!(tree.len(), Ok(reference.len()));
     true
 }
 
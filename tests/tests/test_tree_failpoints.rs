@@ -34,11 +34,14 @@ impl Arbitrary for Op {
             "zero garbage segment",
             "zero garbage segment post",
             "buffer write",
+            "buffer sync",
             "buffer write post",
+            "segment next",
             "write_config bytes",
             "write_config crc",
             "write_config post",
             "trailer write",
+            "trailer sync",
             "trailer write post",
             "snap write",
             "snap write len",
@@ -258,7 +261,7 @@ fn run_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
             }
             FailPoint(fp) => {
                 fail_points.insert(fp.clone());
-                fail::cfg(&*fp, "return").expect(
+                failpoints::fail_at(fp, 0).expect(
                     "should be able to configure failpoint",
                 );
             }
@@ -804,3 +807,42 @@ fn failpoints_bug_12() {
         false,
     ))
 }
+
+#[test]
+fn failpoints_sync_and_rotation() {
+    assert!(prop_tree_crashes_nicely(
+        vec![Set, FailPoint("buffer sync"), Set, Set, Restart, Set],
+        false,
+    ));
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            FailPoint("segment next"),
+            Set,
+            Set,
+            Set,
+            Set,
+            Set,
+            Restart,
+            Set,
+        ],
+        false,
+    ));
+    assert!(prop_tree_crashes_nicely(
+        vec![
+            Set,
+            Set,
+            Set,
+            FailPoint("trailer sync"),
+            Set,
+            Set,
+            Restart,
+        ],
+        true,
+    ));
+}
+
+#[test]
+fn failpoints_unknown_name() {
+    assert!(failpoints::fail_at("no such point", 0).is_err());
+    assert_eq!(failpoints::FAIL_POINTS.len(), 23);
+}